@@ -12,9 +12,10 @@ use rand::distributions::Alphanumeric;
 use rand::{thread_rng, Rng};
 use serde::{Serialize, Deserialize};
 
+use std::collections::HashMap;
 use std::error::Error;
 use std::sync::{Arc, Mutex};
-use std::time::{SystemTime, UNIX_EPOCH};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 
 // use tokio::task;
@@ -42,8 +43,80 @@ pub struct Args {
     pub p2p_port: u16,
     #[arg(short, long, default_value_t = false)]
     pub encrypt: bool,
+    /// Api key named "default", more keys can be listed in keys.json in the state directory
     #[arg(short, long, default_value = "thalamus")]
     pub key: String,
+    /// Start even though --key is the well known default, anyone can then use the api
+    #[arg(long, default_value_t = false)]
+    pub allow_default_key: bool,
+    /// Serve a Swagger UI for /api/openapi.json at /api/docs
+    #[arg(long, default_value_t = false)]
+    pub api_docs: bool,
+    /// Serve https instead of http and reach other nodes over https
+    #[arg(long, default_value_t = false)]
+    pub tls: bool,
+    /// PEM certificate, a self-signed one is generated in <state-dir>/tls when omitted
+    #[arg(long)]
+    pub tls_cert: Option<String>,
+    /// PEM private key for --tls-cert
+    #[arg(long)]
+    pub tls_key: Option<String>,
+    /// PEM bundle of peer certificates to trust instead of the system roots
+    #[arg(long)]
+    pub tls_pin: Option<String>,
+    /// Accept any certificate from other nodes (testing only)
+    #[arg(long, default_value_t = false)]
+    pub tls_insecure: bool,
+    /// Seconds to let requests and jobs finish after SIGTERM/SIGINT
+    #[arg(long, default_value_t = 30)]
+    pub shutdown_timeout: u64,
+    /// Directory everything else lives under (THALAMUS_ROOT, default /opt/thalamus)
+    #[arg(long)]
+    pub data_root: Option<String>,
+    /// Model weights (THALAMUS_MODELS_DIR, default <data-root>/models)
+    #[arg(long)]
+    pub models_dir: Option<String>,
+    /// Inference binaries (THALAMUS_BIN_DIR, default <data-root>/bin)
+    #[arg(long)]
+    pub bin_dir: Option<String>,
+    /// Uploads and intermediate files (THALAMUS_TMP_DIR, default <data-root>/tmp)
+    #[arg(long)]
+    pub tmp_dir: Option<String>,
+    /// thalamus.db, keys.json, pid and tls (THALAMUS_STATE_DIR, default <data-root>)
+    #[arg(long)]
+    pub state_dir: Option<String>,
+    /// Run as the current user: no sudo, data in $XDG_DATA_HOME/thalamus, a systemd --user
+    /// unit, and system packages are listed as prerequisites instead of installed (THALAMUS_ROOTLESS=1)
+    #[arg(long, default_value_t = false)]
+    pub rootless: bool,
+    /// thalamus.toml to read (THALAMUS_CONFIG, default <state-dir>/thalamus.toml), flags given here win over it
+    #[arg(long)]
+    pub config: Option<String>,
+    /// Replace the node registry in thalamus.db with a clients.json style file and exit
+    #[arg(long)]
+    pub import_registry: Option<String>,
+    /// Write the node registry from thalamus.db as a clients.json style file and exit
+    #[arg(long)]
+    pub export_registry: Option<String>,
+    /// Print the disk used by each model against the [storage] budget and exit
+    #[arg(long, default_value_t = false)]
+    pub storage_report: bool,
+    /// Remove models not used for this many seconds, then the least recently used ones until
+    /// the models directory fits in [storage] max_bytes, and exit
+    #[arg(long)]
+    pub evict_unused: Option<i64>,
+    /// Write the registry, thalamus.toml and pid as a bundle signed with --key and exit
+    #[arg(long)]
+    pub export_bundle: Option<String>,
+    /// Put keys.json in the bundle written by --export-bundle
+    #[arg(long, default_value_t = false)]
+    pub bundle_include_keys: bool,
+    /// Apply a bundle made with --export-bundle, signed with the same --key, and exit
+    #[arg(long)]
+    pub import_bundle: Option<String>,
+    /// Take the pid in the bundle given to --import-bundle, for a reinstall of the exporting node
+    #[arg(long, default_value_t = false)]
+    pub restore_identity: bool,
 }
 
 pub async fn nodex_discovery(thalamus: Arc<Mutex<ThalamusClient>>){
@@ -80,119 +153,13 @@ pub async fn nodex_discovery(thalamus: Arc<Mutex<ThalamusClient>>){
     }
 }
 
-pub async fn mdns_discovery(thalamus: Arc<Mutex<ThalamusClient>>, discovery: simple_mdns::async_discovery::ServiceDiscovery) -> Result<simple_mdns::async_discovery::ServiceDiscovery, std::io::Error> {
-    let services = discovery.get_known_services().await;
-    if services.len() > 0 {
-        for xy in services{
-            // Register using ip address
-            for ipfx in xy.ip_addresses.clone(){
-                let ipx = ipfx.to_string();
-                let port = xy.ports[0];
-                if !ipx.to_string().ends_with(".1"){
-                    let version = async_fetch_version(ipx.as_str(), port).await;
-                    match version {
-                        Ok(v) => {
-                            let mut thalamus_x = thalamus.lock().unwrap();
-
-               
-                            let existing_index = thalamus_x.nodes.clone().iter().position(|r| r.pid == v.pid.to_string());
-                            match existing_index {
-                                Some(index) => {
-      
-                                    thalamus_x.nodes[index].is_online = true;
-                                    thalamus_x.nodes[index].last_ping = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
-                                    // log::info!("NODE_ONLINE: {:?}", thalamus_x.nodes[index].clone());
-                                
-                                    thalamus_x.save();
-                                    
-                                    
-                                    std::mem::drop(thalamus_x);
-                                },
-                                None => {
-                                    let v_thc = v.clone();
-                               
-                                        
-                                    let thalamus_node = ThalamusNode::new(v_thc.pid.to_string(), v_thc.version.to_string(), ipx.clone(), port);
-                                    log::info!("NEW_NODE: {:?}", thalamus_node.clone());
-                                    thalamus_x.nodes.push(thalamus_node);
-                                    thalamus_x.save();
-                                    std::mem::drop(thalamus_x);
-
-                                    calc_stats(Arc::clone(&thalamus), v_thc.pid.to_string(), v_thc.version.to_string(), ipx.clone(), port);
-                                    
-                        
-                                }
-                            }
-                            
-                        },
-                        Err(e) => {
-                            log::error!("fetch_thalamus_version_error: {}", e);
-                            let mut thalamus_x = thalamus.lock().unwrap();
-      
-         
-                            let existing_index = thalamus_x.nodes.clone().iter().position(|r| r.ip_address == format!("{}:{}", ipx, port).as_str());
-                            match existing_index {
-                                Some(index) => {
-
-                                    thalamus_x.nodes[index].is_online = false;
-                                    thalamus_x.save();
-                                    log::warn!("NODE_OFFLINE: {:?}", thalamus_x.nodes[index].clone());
-                       
-                                    std::mem::drop(thalamus_x);
-                                   
-                                },
-                                None => {
-                                    std::mem::drop(thalamus_x);
-                                }
-                            }
-                        }
-                    }
-                }
-            }
-
-            // flag missing nodes as offline
-            let mut thalamus_x = thalamus.lock().unwrap();
-            for node in &mut thalamus_x.nodes {
-
-                let existing_index = xy.ip_addresses.clone().iter().position(|r| node.ip_address.contains(format!("{}", r).as_str()));
-                match existing_index {
-                    Some(_) => {
-
-                    },
-                    None => {
-                        node.is_online = false;
-                    }
-                }
-            }
-            thalamus_x.save();
-            std::mem::drop(thalamus_x);
-
-
-            // flag missing nodes as offline
-            // TODO: fetch fresh stats and reaverage them, and check for missing capabilities based on stats
-            let thalamus_x = thalamus.lock().unwrap();
-            let thx = thalamus_x.clone();
-            std::mem::drop(thalamus_x);
-            for node in thx.nodes {
-                if !node.jobs.iter().any(|i| i.job_identifier=="calculate_stats") && node.stats.whisper_stt_tiny.is_none(){
-                    log::warn!("STATS ARE MISSING FOR NODE: {:?}.....CALCULATING NOW...", node.pid.clone());
-                    calc_stats(Arc::clone(&thalamus), node.pid.to_string(), node.version.to_string(), format!("{}", node.ip_address), node.port);
-                }
-            }
-            
-
-        }
-    }
-
-    Ok(discovery)
-}
-
 pub fn calc_stats(thalamus: Arc<Mutex<ThalamusClient>>, pid: String, version: String, ipx: String, port: u16){
     // Calculate Stats for new node
     let node_thc = Arc::clone(&thalamus);
     std::thread::spawn(move || {
 
-        // Commit job to memory
+        // Commit job to memory, only saved along with the stats so a restart mid-benchmark
+        // doesn't leave it on disk
         let job = ThalamusNodeJob::new("calculate_stats".to_string());
         let mut thalamus_x = node_thc.lock().unwrap();
         for node in &mut thalamus_x.nodes{
@@ -200,12 +167,16 @@ pub fn calc_stats(thalamus: Arc<Mutex<ThalamusClient>>, pid: String, version: St
                 node.jobs.push(job.clone());
             }
         }
-        thalamus_x.save();
         std::mem::drop(thalamus_x);
 
         // Generate stats using dummy node data
         let node_ref = ThalamusNode::new(pid.to_string(), version.to_string(), ipx, port);
         let stats = ThalamusNodeStats::calculate(node_ref.clone());
+        if let Some(store) = crate::thalamus::store::get() {
+            if let Err(e) = store.record_benchmark(&pid, &stats) {
+                log::error!("Unable to record benchmark for {}: {}", pid, e);
+            }
+        }
 
         // Commit stats to memory
         let mut thalamus_x = node_thc.lock().unwrap();
@@ -223,15 +194,70 @@ pub fn calc_stats(thalamus: Arc<Mutex<ThalamusClient>>, pid: String, version: St
 }
 
 
+/// Version of the clients.json layout, bump it and add a step to `migrate` when
+/// ThalamusNode or ThalamusNodeStats change in a way serde defaults can't cover
+pub const SCHEMA_VERSION: u64 = 1;
+
+/// Brings a saved client up to SCHEMA_VERSION, files from before versioning are version 0
+pub fn migrate(mut save: serde_json::Value) -> Result<serde_json::Value, Box<dyn Error>> {
+    let mut version = save.get("schema_version").and_then(|v| v.as_u64()).unwrap_or(0);
+    if version > SCHEMA_VERSION {
+        return Err(format!("clients.json has schema_version {} but this build only understands up to {}", version, SCHEMA_VERSION).into());
+    }
+    if version < SCHEMA_VERSION {
+        log::warn!("Migrating clients from schema_version {} to {}", version, SCHEMA_VERSION);
+    }
+
+    while version < SCHEMA_VERSION {
+        match version {
+            // 0 -> 1: version 0 saved calculate_stats jobs as soon as a benchmark started, so
+            // one interrupted by a restart stayed queued forever and counted as an active job
+            // against the node. They are dropped, the benchmark runs again when it is rediscovered
+            0 => {
+                if let Some(nodes) = save.get_mut("nodes").and_then(|n| n.as_array_mut()) {
+                    for node in nodes.iter_mut().filter_map(|n| n.as_object_mut()) {
+                        if let Some(jobs) = node.get_mut("jobs").and_then(|j| j.as_array_mut()) {
+                            jobs.retain(|job| job.get("job_identifier").and_then(|j| j.as_str()) != Some("calculate_stats"));
+                        }
+                    }
+                }
+            },
+            _ => {},
+        }
+        version += 1;
+    }
+
+    save["schema_version"] = serde_json::json!(SCHEMA_VERSION);
+    return Ok(save);
+}
+
+/// Nodes not pinged for this long are left out of node selection
+pub const STALE_PING_SECS: i64 = 10 * 60;
+
+/// Services a node can be selected for, by the benchmark scores they have
+pub const SELECTABLE_SERVICES: [&str; 6] = ["whisper_stt", "whisper_vwav", "llama", "srgan", "tts", "nst"];
+
+/// A node's place in a ranking, lower scores are faster
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RankedNode {
+    pub node: ThalamusNode,
+    /// Benchmark time in milliseconds
+    pub score: i64,
+    pub active_jobs: usize,
+}
+
 /// Struct for storing all nodes connected to the client
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ThalamusClient {
+    #[serde(default)]
+    pub schema_version: u64,
     pub nodes: Vec<ThalamusNode>,
 }
 impl ThalamusClient {
     pub fn new() -> ThalamusClient {
         let x: Vec<ThalamusNode> = Vec::new();
         ThalamusClient { 
+            schema_version: SCHEMA_VERSION,
             nodes: x,
         }
     }
@@ -282,108 +308,412 @@ impl ThalamusClient {
 
 
 
-    pub fn save(&self){
-        std::fs::File::create("/opt/thalamus/clients.json").expect("create failed");
-        let j = serde_json::to_string(&self).unwrap();
-        std::fs::write("/opt/thalamus/clients.json", j).expect("Unable to write file");
+    /// Atomically writes the registry as json, used for --export-registry
+    pub fn save_json(&self, path: &str) -> Result<(), Box<dyn Error>>{
+        let mut client = self.clone();
+        client.schema_version = SCHEMA_VERSION;
+        let j = serde_json::to_string(&client)?;
+        crate::thalamus::tools::write_atomic(path, j.as_bytes())?;
+        return Ok(());
+    }
 
+    /// Atomically replaces clients.json, and clients.bak.json while there are nodes to keep
+    fn try_save_json(&self) -> Result<(), Box<dyn Error>>{
+        self.save_json(&crate::thalamus::paths::state("clients.json"))?;
         if self.nodes.len() > 0 {
-            std::fs::File::create("/opt/thalamus/clients.bak.json").expect("create failed");
-            let j = serde_json::to_string(&self).unwrap();
-            std::fs::write("/opt/thalamus/clients.bak.json", j).expect("Unable to write file");
+            self.save_json(&crate::thalamus::paths::state("clients.bak.json"))?;
+        }
+        return Ok(());
+    }
+
+    /// Saves to thalamus.db when the store is open, clients.json otherwise
+    pub fn try_save(&self) -> Result<(), Box<dyn Error>>{
+        match crate::thalamus::store::get() {
+            Some(store) => store.save_client(self)?,
+            None => self.try_save_json()?,
+        }
+        return Ok(());
+    }
+
+    pub fn save(&self){
+        if let Err(e) = self.try_save() {
+            log::error!("Unable to save the node registry: {}", e);
+        }
+    }
+
+    /// Parses a saved client, migrating older layouts
+    pub fn from_json(data: &str) -> Result<ThalamusClient, Box<dyn Error>>{
+        let save: serde_json::Value = serde_json::from_str(data)?;
+        return Ok(serde_json::from_value(migrate(save)?)?);
+    }
+
+    pub fn load_from(path: &str) -> Result<ThalamusClient, Box<dyn Error>>{
+        let data = std::fs::read_to_string(path).map_err(|e| format!("unable to read {}: {}", path, e))?;
+        return Ok(Self::from_json(&data).map_err(|e| format!("unable to parse {}: {}", path, e))?);
+    }
+
+    /// Loads from thalamus.db when the store is open, importing clients.json the first
+    /// time, and from clients.json otherwise
+    pub fn load() -> Result<ThalamusClient, Box<dyn Error>>{
+        let store = match crate::thalamus::store::get() {
+            Some(store) => store,
+            None => return Self::load_json(),
+        };
+
+        let path = crate::thalamus::paths::state("clients.json");
+        let backup = crate::thalamus::paths::state("clients.bak.json");
+        if store.is_empty()? && (std::path::Path::new(&path).exists() || std::path::Path::new(&backup).exists()) {
+            let client = Self::load_json()?;
+            store.save_client(&client)?;
+
+            // Renamed so a registry emptied later is not imported again on the next start
+            for file in [&path, &backup] {
+                if std::path::Path::new(file).exists() {
+                    std::fs::rename(file, format!("{}.imported", file))?;
+                }
+            }
+            log::warn!("Imported {} nodes from {} into {}", client.nodes.len(), path, crate::thalamus::store::FILE);
+            return Ok(client);
         }
+        return Ok(store.load_client()?);
     }
 
-    pub fn load(retries: i64) -> Result<ThalamusClient, Box<dyn Error>>{
+    /// Loads clients.json, falling back to clients.bak.json. Only a first run with
+    /// neither file present starts an empty registry, anything else is an error
+    pub fn load_json() -> Result<ThalamusClient, Box<dyn Error>>{
+        let path = crate::thalamus::paths::state("clients.json");
+        let backup = crate::thalamus::paths::state("clients.bak.json");
 
-        if !std::path::Path::new("/opt/thalamus/clients.json").exists(){
+        if !std::path::Path::new(&path).exists() && !std::path::Path::new(&backup).exists() {
             let new_c = ThalamusClient::new();
-            new_c.save();
+            new_c.try_save_json()?;
             return Ok(new_c);
         }
 
-        let save_file = std::fs::read_to_string("/opt/thalamus/clients.json");
-        match save_file {
-            Ok(save_data) => {
-                let v: Result<ThalamusClient, _> = serde_json::from_str(&save_data);
-                match v {
-                    Ok(v2) => {
-                        return Ok(v2);
-                    },
-                    Err(e) => {
-                        log::error!("{}", format!("Unable to parse save file: {}", e));
-                        
-                        if retries < 10 {
-                            std::fs::copy("/opt/thalamus/clients.bak.json", "/opt/thalamus/clients.json")?;
-                            std::thread::sleep(std::time::Duration::from_secs(2));
-                            return Self::load(retries + 1);
-                        } else {
-                            log::warn!("Unable to parse save file after 10 attempts....creating new save file.");
-                            let new_c = ThalamusClient::new();
-                            new_c.save();
-                            return Ok(new_c);
-                        }
-                 
-                    }
-                }
-                
+        let error = match Self::load_from(&path) {
+            Ok(client) => return Ok(client),
+            Err(e) => e,
+        };
+        log::error!("{}", error);
+
+        if !std::path::Path::new(&backup).exists() {
+            return Err(format!("{} (no {} to recover from)", error, backup).into());
+        }
+        match Self::load_from(&backup) {
+            Ok(client) => {
+                log::warn!("Recovered {} nodes from {}", client.nodes.len(), backup);
+                client.try_save_json()?;
+                return Ok(client);
             },
-            Err(e) => {
-                log::error!("{}", format!("Unable to read save file: {}", e));
-                if retries < 10 {
-                    std::fs::copy("/opt/thalamus/clients.bak.json", "/opt/thalamus/clients.json")?;
-                    std::thread::sleep(std::time::Duration::from_secs(2));
-                    return Self::load(retries + 1);
-                } else {
-                    log::warn!("Unable to read save file after 10 attempts....creating new save file.");
-                    let new_c = ThalamusClient::new();
-                    new_c.save();
-                    return Ok(new_c);
-                }
+            Err(backup_error) => {
+                return Err(format!("{}; backup failed too: {}", error, backup_error).into());
+            }
+        }
+    }
+
+    /// Online nodes that have a benchmark for the service (and model), fastest first.
+    /// Nodes not pinged in the last max_age_secs are skipped, equal scores go to the node
+    /// with fewer jobs in flight
+    pub fn rank_nodes(&self, service: &str, model: Option<&str>, max_age_secs: i64) -> Result<Vec<RankedNode>, Box<dyn Error>>{
+        ThalamusNodeStats::check_selector(service, model)?;
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+
+        let mut ranking: Vec<RankedNode> = Vec::new();
+        for node in self.nodes.iter() {
+            if !node.is_online || now - node.last_ping > max_age_secs {
+                continue;
+            }
+            if let Some(score) = node.stats.score(service, model) {
+                ranking.push(RankedNode { score: score, active_jobs: node.active_jobs(), node: node.clone() });
             }
         }
+        ranking.sort_by_key(|ranked| (ranked.score, ranked.active_jobs));
+        return Ok(ranking);
     }
 
-    // pub fn select_optimal_node(&self, node_type: String) -> Result<ThalamusNode, Box<dyn Error + '_>> {
-    //     let nodex = self.nodes.lock()?;
-    //     let nodes = nodex.clone();
-    //     std::mem::drop(nodex);
+    /// Best node for a service, see rank_nodes
+    pub fn select_optimal_node(&self, service: &str, model: Option<&str>) -> Result<ThalamusNode, Box<dyn Error>>{
+        match self.rank_nodes(service, model, STALE_PING_SECS)?.into_iter().next() {
+            Some(ranked) => return Ok(ranked.node),
+            None => return Err(format!("no online node has benchmarked {}{}", service, model.map(|m| format!(" {}", m)).unwrap_or_default()).into()),
+        }
+    }
 
-    //     let mut selected_node = nodes[0].clone();
-    //     for node in nodes {
-    //         let stats = node.stats.clone();
-    //         if stats.whisper_stt_score < Some(fastest_whisper_stt_score) && node_type.contains("stt") {
-    //             fastest_whisper_stt_score = stats.whisper_stt_score;
-    //             selected_node = node.clone();
-    //         }
-    //         if stats.whisper_vwav_score < fastest_whisper_vwav_score && node_type.contains("vwav") {
-    //             fastest_whisper_vwav_score = stats.whisper_vwav_score;
-    //             selected_node = node.clone();
-    //         }
-    //         if stats.srgan < fastest_srgan_score && node_type.contains("srgan") {
-    //             fastest_srgan_score = stats.srgan;
-    //             selected_node = node.clone();
-    //         }
-    //         if stats.llama_score < fastest_llama_score && node_type.contains("llama") {
-    //             fastest_llama_score = stats.llama_score;
-    //             selected_node = node.clone();
-    //         }
-    //     }
-        
-    //     return Ok(selected_node);
-    // }
+    /// Nodes to try for a service in order: ranked by benchmark, then the other fresh online
+    /// nodes by job count, with degraded nodes moved to the back
+    pub fn candidates(&self, service: &str, model: Option<&str>) -> Result<Vec<ThalamusNode>, Box<dyn Error>>{
+        let mut nodes: Vec<ThalamusNode> = Vec::new();
+        if SELECTABLE_SERVICES.contains(&service) {
+            nodes = self.rank_nodes(service, model, STALE_PING_SECS)?.into_iter().map(|ranked| ranked.node).collect();
+        }
+
+        let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+        let mut unscored: Vec<ThalamusNode> = self.nodes.iter()
+            .filter(|node| node.is_online && now - node.last_ping <= STALE_PING_SECS && !nodes.iter().any(|ranked| ranked.pid == node.pid))
+            .cloned().collect();
+        unscored.sort_by_key(|node| node.active_jobs());
+        nodes.extend(unscored);
+
+        // Stable, each group keeps its order
+        nodes.sort_by_key(|node| is_degraded(&node.pid, now));
+        return Ok(nodes);
+    }
+
+    /// Sends a service request to the candidates in order until one answers. Connection
+    /// failures, timeouts and 5xx replies mark the node degraded and move on to the next
+    /// one, any other error is returned as is
+    pub fn call(&self, request: &ServiceCall, options: &CallOptions) -> Result<CallReply, Box<dyn Error>>{
+        let (service, model) = request.selector();
+        let candidates = self.candidates(service, model)?;
+        if candidates.len() == 0 {
+            return Err(format!("no online node can run {}", service).into());
+        }
+
+        let mut failures: Vec<String> = Vec::new();
+        for node in candidates.iter().take(options.max_attempts.max(1)) {
+            match request.attempt(node, options.attempt_timeout) {
+                Ok(body) => return Ok(CallReply { pid: node.pid.clone(), attempts: failures.len() + 1, body: body }),
+                Err(CallFailure::Retry(reason)) => {
+                    log::warn!("{} failed on {} ({}:{}), trying the next node: {}", service, node.pid, node.ip_address, node.port, reason);
+                    mark_degraded(&node.pid, options.degraded_secs);
+                    failures.push(format!("{}: {}", node.pid, reason));
+                },
+                Err(CallFailure::Fatal(e)) => return Err(e),
+            }
+        }
+        return Err(format!("{} failed on every node tried: {}", service, failures.join("; ")).into());
+    }
+}
+
+/// Nodes that failed a call, by pid, with the unix time they are avoided until
+static DEGRADED: Mutex<Option<HashMap<String, i64>>> = Mutex::new(None);
+
+pub fn mark_degraded(pid: &str, secs: i64) {
+    let until = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64 + secs;
+    DEGRADED.lock().unwrap().get_or_insert_with(HashMap::new).insert(pid.to_string(), until);
+}
+
+pub fn is_degraded(pid: &str, now: i64) -> bool {
+    return DEGRADED.lock().unwrap().as_ref().and_then(|degraded| degraded.get(pid).cloned()).map_or(false, |until| until > now);
+}
+
+pub fn clear_degraded(pid: &str) {
+    if let Some(degraded) = DEGRADED.lock().unwrap().as_mut() {
+        degraded.remove(pid);
+    }
+}
+
+/// How ThalamusClient::call retries
+#[derive(Debug, Clone)]
+pub struct CallOptions {
+    /// Each node gets this long to answer, body included
+    pub attempt_timeout: Duration,
+    /// Nodes tried before giving up
+    pub max_attempts: usize,
+    /// A failed node goes to the back of the candidates for this long
+    pub degraded_secs: i64,
+}
+impl Default for CallOptions {
+    fn default() -> CallOptions {
+        CallOptions { attempt_timeout: Duration::from_secs(5 * 60), max_attempts: 3, degraded_secs: 60 }
+    }
+}
+
+/// A service request ThalamusClient::call can send to any node
+#[derive(Debug, Clone)]
+pub enum ServiceCall {
+    WhisperStt { file_path: String, model: String },
+    WhisperVwav { file_path: String, model: String },
+    Llama { prompt: String, model: String },
+    Tts { text: String, primary: String, fallback: String },
+    Srgan { file_path: String },
+    Yolov7 { file_path: String },
+}
+
+/// Body of the reply and the node that sent it
+#[derive(Debug, Clone)]
+pub struct CallReply {
+    pub pid: String,
+    pub attempts: usize,
+    pub body: Vec<u8>,
+}
+
+enum CallFailure {
+    /// Another node may well succeed
+    Retry(String),
+    Fatal(Box<dyn Error>),
+}
+
+impl ServiceCall {
+    /// Benchmark that ranks the nodes for this call, yolov7 has none
+    pub fn selector(&self) -> (&'static str, Option<&str>) {
+        match self {
+            ServiceCall::WhisperStt { model, .. } => return ("whisper_stt", Some(model.as_str())),
+            ServiceCall::WhisperVwav { model, .. } => return ("whisper_vwav", Some(model.as_str())),
+            ServiceCall::Llama { model, .. } => return ("llama", Some(model.as_str())),
+            ServiceCall::Tts { .. } => return ("tts", None),
+            ServiceCall::Srgan { .. } => return ("srgan", None),
+            ServiceCall::Yolov7 { .. } => return ("yolov7", None),
+        }
+    }
+
+    // Forms can't be reused, so each attempt builds its own
+    fn request(&self, node: &ThalamusNode, client: &reqwest::blocking::Client) -> Result<reqwest::blocking::RequestBuilder, std::io::Error> {
+        use reqwest::blocking::multipart::Form;
+        match self {
+            ServiceCall::WhisperStt { file_path, model } => {
+                return Ok(client.post(node.url("/api/services/whisper")).multipart(Form::new().text("method", model.clone()).file("speech", file_path.as_str())?));
+            },
+            ServiceCall::WhisperVwav { file_path, model } => {
+                return Ok(client.post(node.url("/api/services/whisper/vwav")).multipart(Form::new().text("method", model.clone()).file("speech", file_path.as_str())?));
+            },
+            ServiceCall::Llama { prompt, model } => {
+                return Ok(client.post(node.url("/api/services/llama")).form(&[("model", model.as_str()), ("prompt", prompt.as_str())]));
+            },
+            ServiceCall::Tts { text, primary, fallback } => {
+                return Ok(client.post(node.url("/api/services/tts")).form(&[("text", text.as_str()), ("primary", primary.as_str()), ("fallback", fallback.as_str())]));
+            },
+            ServiceCall::Srgan { file_path } => {
+                let file_name = std::path::Path::new(file_path).file_name().map(|name| name.to_string_lossy().to_string()).unwrap_or_default();
+                return Ok(client.post(node.url("/api/services/image/srgan")).multipart(Form::new().text("filename", file_name).file("input_file", file_path.as_str())?));
+            },
+            ServiceCall::Yolov7 { file_path } => {
+                return Ok(client.post(node.url("/api/services/image/yolo/v7")).multipart(Form::new().file("image_file", file_path.as_str())?));
+            },
+        }
+    }
+
+    fn attempt(&self, node: &ThalamusNode, timeout: Duration) -> Result<Vec<u8>, CallFailure> {
+        let retryable = |e: reqwest::Error| {
+            if e.is_connect() || e.is_timeout() {
+                return CallFailure::Retry(e.to_string());
+            }
+            return CallFailure::Fatal(e.into());
+        };
+
+        let client = node.http_client_with_timeout(Some(timeout)).map_err(CallFailure::Fatal)?;
+        let request = self.request(node, &client).map_err(|e| CallFailure::Fatal(e.into()))?;
+        let response = request.send().map_err(retryable)?;
+        if response.status().is_server_error() {
+            return Err(CallFailure::Retry(format!("{} answered {}", node.pid, response.status())));
+        }
+        let response = response.error_for_status().map_err(|e| CallFailure::Fatal(e.into()))?;
+        return Ok(response.bytes().map_err(retryable)?.to_vec());
+    }
+}
+
+
+
+// Key sent to other nodes by ThalamusNode's client methods, set from --key at startup
+static API_KEY: Mutex<Option<String>> = Mutex::new(None);
+
+pub fn set_api_key(key: Option<String>) {
+    *API_KEY.lock().unwrap() = key;
 }
 
+pub fn api_key() -> Option<String> {
+    return API_KEY.lock().unwrap().clone();
+}
 
+/// This node's pid, written by setup
+pub fn local_pid() -> Option<String> {
+    return std::fs::read_to_string(crate::thalamus::paths::state("pid")).ok().map(|pid| pid.trim().to_string()).filter(|pid| pid.len() > 0);
+}
+
+/// An address as it goes in a url, IPv6 addresses are bracketed
+pub fn host(ip_address: &str) -> String {
+    if ip_address.contains(':') && !ip_address.starts_with('[') {
+        return format!("[{}]", ip_address);
+    }
+    return ip_address.to_string();
+}
+
+/// How other nodes' certificates are checked when the mesh runs over https
+#[derive(Debug, Clone)]
+pub enum TlsTrust {
+    System,
+    Pinned(Vec<String>),
+    Insecure,
+}
+
+#[derive(Debug, Clone)]
+pub struct MeshTls {
+    pub enabled: bool,
+    pub trust: TlsTrust,
+}
+impl MeshTls {
+    pub fn from_args(args: &Args) -> Result<MeshTls, Box<dyn Error>> {
+        let trust = match (&args.tls_pin, args.tls_insecure) {
+            (_, true) => TlsTrust::Insecure,
+            (Some(path), false) => {
+                let certs = crate::thalamus::tls::pem_certificates(std::fs::read_to_string(path)?.as_str());
+                if certs.len() == 0 {
+                    return Err(format!("no certificates found in {}", path).into());
+                }
+                TlsTrust::Pinned(certs)
+            },
+            (None, false) => TlsTrust::System,
+        };
+        return Ok(MeshTls { enabled: args.tls, trust: trust });
+    }
+}
+
+static MESH_TLS: Mutex<Option<MeshTls>> = Mutex::new(None);
+
+pub fn set_mesh_tls(tls: Option<MeshTls>) {
+    *MESH_TLS.lock().unwrap() = tls;
+}
+
+pub fn mesh_tls() -> Option<MeshTls> {
+    return MESH_TLS.lock().unwrap().clone();
+}
+
+/// http or https depending on whether the mesh runs over tls
+pub fn scheme() -> &'static str {
+    match mesh_tls() {
+        Some(tls) if tls.enabled => "https",
+        _ => "http",
+    }
+}
+
+pub fn blocking_client_builder() -> Result<reqwest::blocking::ClientBuilder, Box<dyn Error>> {
+    let mut builder = reqwest::blocking::Client::builder();
+    match mesh_tls().map(|tls| tls.trust) {
+        Some(TlsTrust::Pinned(certs)) => {
+            builder = builder.tls_built_in_root_certs(false);
+            for cert in certs {
+                builder = builder.add_root_certificate(reqwest::Certificate::from_pem(cert.as_bytes())?);
+            }
+        },
+        Some(TlsTrust::Insecure) => builder = builder.danger_accept_invalid_certs(true),
+        _ => {}
+    }
+    return Ok(builder);
+}
+
+pub fn async_client_builder() -> Result<reqwest::ClientBuilder, Box<dyn Error>> {
+    let mut builder = reqwest::Client::builder();
+    match mesh_tls().map(|tls| tls.trust) {
+        Some(TlsTrust::Pinned(certs)) => {
+            builder = builder.tls_built_in_root_certs(false);
+            for cert in certs {
+                builder = builder.add_root_certificate(reqwest::Certificate::from_pem(cert.as_bytes())?);
+            }
+        },
+        Some(TlsTrust::Insecure) => builder = builder.danger_accept_invalid_certs(true),
+        _ => {}
+    }
+    return Ok(builder);
+}
 
 pub fn fetch_version(host: &str, port: u16) -> Result<VersionReply, Box<dyn Error>> {
-    let client = reqwest::blocking::Client::builder().build()?;
-    return Ok(client.get(format!("http://{}:{}/api/thalamus/version", host, port.clone())).send()?.json()?);
+    let client = blocking_client_builder()?.build()?;
+    return Ok(client.get(format!("{}://{}:{}/api/thalamus/version", scheme(), crate::host(host), port.clone())).send()?.json()?);
 }
 
 pub async fn async_fetch_version(host: &str, port: u16) -> Result<VersionReply, Box<dyn Error>> {
-    let client = reqwest::Client::builder().build()?;
-    return Ok(client.get(format!("http://{}:{}/api/thalamus/version", host, port.clone())).send().await?.json().await?);
+    let client = async_client_builder()?.build()?;
+    return Ok(client.get(format!("{}://{}:{}/api/thalamus/version", scheme(), crate::host(host), port.clone())).send().await?.json().await?);
 }
 
 
@@ -397,11 +727,18 @@ pub struct ThalamusNode {
     pub ip_address: String, // unique
     pub version: String,
     pub port: u16,
+    #[serde(default)]
     pub jobs: Vec<ThalamusNodeJob>,
     pub capablities: Option<Vec<ThalamusNodeCapability>>,
+    #[serde(default)]
     pub last_ping: i64,
+    #[serde(default = "ThalamusNodeStats::new")]
     pub stats: ThalamusNodeStats,
+    #[serde(default)]
     pub is_online: bool,
+    /// Overrides the shared api key for this node, never persisted or served over /api/nodex
+    #[serde(skip)]
+    pub api_key: Option<String>,
 }
 impl ThalamusNode {
 
@@ -417,18 +754,45 @@ impl ThalamusNode {
             last_ping: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
             stats: ThalamusNodeStats::new(),
             is_online: true,
+            api_key: None,
         };
         let stats = ThalamusNodeStats::new();
         node.stats = stats;
         return node;
     }
 
+    /// Jobs that are queued or running, finished ones stay listed for a while
+    pub fn active_jobs(&self) -> usize {
+        return self.jobs.iter().filter(|job| match job.status.as_deref() {
+            Some("completed") | Some("failed") => false,
+            _ => true,
+        }).count();
+    }
+
+    /// Blocking client that sends this node's api key (or the shared one) as a bearer token
+    pub fn http_client(&self) -> Result<reqwest::blocking::Client, Box<dyn Error>> {
+        return self.http_client_with_timeout(None);
+    }
+
+    /// Same as http_client, giving up on requests that take longer than timeout
+    pub fn http_client_with_timeout(&self, timeout: Option<Duration>) -> Result<reqwest::blocking::Client, Box<dyn Error>> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        if let Some(key) = self.api_key.clone().or(api_key()) {
+            headers.insert(reqwest::header::AUTHORIZATION, reqwest::header::HeaderValue::from_str(format!("Bearer {}", key).as_str())?);
+        }
+        return Ok(blocking_client_builder()?.timeout(timeout).default_headers(headers).build()?);
+    }
+
+    pub fn url(&self, path: &str) -> String {
+        return format!("{}://{}:{}{}", scheme(), host(&self.ip_address), self.port.clone(), path);
+    }
+
     pub fn yolov7(&self, file_path: String) -> Result<STTReply, Box<dyn Error>>{
         let form = reqwest::blocking::multipart::Form::new().file("image_file", file_path.as_str())?;
 
-        let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+        let client = self.http_client()?;
 
-        return Ok(client.post(format!("http://{}:{}/api/services/image/yolo/v7", self.ip_address.clone(), self.port.clone()))
+        return Ok(client.post(self.url("/api/services/image/yolo/v7"))
         .multipart(form)
         .send()?.json()?);
     }
@@ -436,19 +800,19 @@ impl ThalamusNode {
     pub fn whisper_stt_tiny(&self, file_path: String) -> Result<STTReply, Box<dyn Error>>{
         let form = reqwest::blocking::multipart::Form::new().text("method", "tiny").file("speech", file_path.as_str())?;
 
-        let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+        let client = self.http_client()?;
 
-        return Ok(client.post(format!("http://{}:{}/api/services/whisper", self.ip_address.clone(), self.port.clone()))
+        return Ok(client.post(self.url("/api/services/whisper"))
         .multipart(form)
         .send()?.json()?);
     }
 
     pub fn whisper_stt_base(&self, file_path: String) -> Result<STTReply, Box<dyn Error>>{
-        let form = reqwest::blocking::multipart::Form::new().text("method", "basic").file("speech", file_path.as_str())?;
+        let form = reqwest::blocking::multipart::Form::new().text("method", "base").file("speech", file_path.as_str())?;
 
-        let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+        let client = self.http_client()?;
 
-        return Ok(client.post(format!("http://{}:{}/api/services/whisper", self.ip_address.clone(), self.port.clone()))
+        return Ok(client.post(self.url("/api/services/whisper"))
         .multipart(form)
         .send()?.json()?);
     }
@@ -456,9 +820,9 @@ impl ThalamusNode {
     pub fn whisper_stt_medium(&self, file_path: String) -> Result<STTReply, Box<dyn Error>>{
         let form = reqwest::blocking::multipart::Form::new().text("method", "medium").file("speech", file_path.as_str())?;
 
-        let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+        let client = self.http_client()?;
 
-        return Ok(client.post(format!("http://{}:{}/api/services/whisper", self.ip_address.clone(), self.port.clone()))
+        return Ok(client.post(self.url("/api/services/whisper"))
         .multipart(form)
         .send()?.json()?);
     }
@@ -466,22 +830,22 @@ impl ThalamusNode {
     pub fn whisper_stt_large(&self, file_path: String) -> Result<STTReply, Box<dyn Error>>{
         let form = reqwest::blocking::multipart::Form::new().text("method", "large").file("speech", file_path.as_str())?;
 
-        let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+        let client = self.http_client()?;
 
-        return Ok(client.post(format!("http://{}:{}/api/services/whisper", self.ip_address.clone(), self.port.clone()))
+        return Ok(client.post(self.url("/api/services/whisper"))
         .multipart(form)
         .send()?.json()?);
     }
 
     pub fn whisper_vwav_tiny(&self, file_path: String) -> Result<Vec<u8>, Box<dyn Error>>{
         
-        let url = format!("http://{}:{}/api/services/whisper/vwav", self.ip_address.clone(), self.port.clone());
+        let url = self.url("/api/services/whisper/vwav");
 
         log::info!("Fetching VWAV from {}", url);
         
         let form = reqwest::blocking::multipart::Form::new().text("method", "tiny").file("speech", file_path.as_str())?;
 
-        let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+        let client = self.http_client()?;
 
         let bytes = client.post(url)
         .multipart(form)
@@ -492,14 +856,14 @@ impl ThalamusNode {
 
     pub fn whisper_vwav_base(&self, file_path: String) -> Result<Vec<u8>, Box<dyn Error>>{
                 
-        let url = format!("http://{}:{}/api/services/whisper/vwav", self.ip_address.clone(), self.port.clone());
+        let url = self.url("/api/services/whisper/vwav");
 
         log::info!("Fetching VWAV from {}", url);
         
         
         let form = reqwest::blocking::multipart::Form::new().text("method", "base").file("speech", file_path.as_str())?;
 
-        let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+        let client = self.http_client()?;
 
         let bytes = client.post(url)
         .multipart(form)
@@ -509,13 +873,13 @@ impl ThalamusNode {
     }
 
     pub fn whisper_vwav_medium(&self, file_path: String) -> Result<Vec<u8>, Box<dyn Error>>{
-        let url = format!("http://{}:{}/api/services/whisper/vwav", self.ip_address.clone(), self.port.clone());
+        let url = self.url("/api/services/whisper/vwav");
 
         log::info!("Fetching VWAV from {}", url);
         
         let form = reqwest::blocking::multipart::Form::new().text("method", "medium").file("speech", file_path.as_str())?;
 
-        let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+        let client = self.http_client()?;
 
         let bytes = client.post(url)
         .multipart(form)
@@ -525,13 +889,13 @@ impl ThalamusNode {
     }
 
     pub fn whisper_vwav_large(&self, file_path: String) -> Result<Vec<u8>, Box<dyn Error>>{
-        let url = format!("http://{}:{}/api/services/whisper/vwav", self.ip_address.clone(), self.port.clone());
+        let url = self.url("/api/services/whisper/vwav");
 
         log::info!("Fetching VWAV from {}", url);
         
         let form = reqwest::blocking::multipart::Form::new().text("method", "large").file("speech", file_path.as_str())?;
 
-        let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+        let client = self.http_client()?;
 
         let bytes = client.post(url)
         .multipart(form)
@@ -552,9 +916,9 @@ impl ThalamusNode {
 
         let form = reqwest::blocking::multipart::Form::new().text("filename", new_file_name).file("input_file", file_path.as_str())?;
 
-        let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+        let client = self.http_client()?;
 
-        let bytes = client.post(format!("http://{}:{}/api/services/image/srgan", self.ip_address.clone(), self.port.clone()))
+        let bytes = client.post(self.url("/api/services/image/srgan"))
         .multipart(form)
         .send()?.bytes()?;
 
@@ -564,31 +928,122 @@ impl ThalamusNode {
     pub fn llama(&self, prompt: String, model: String) -> Result<String, Box<dyn Error>>{
         let params = [("model", model.as_str()), ("prompt", prompt.as_str())];
 
-        let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+        let client = self.http_client()?;
 
-        let bytes = client.post(format!("http://{}:{}/api/services/llama", self.ip_address.clone(), self.port.clone()))
+        let bytes = client.post(self.url("/api/services/llama"))
         .form(&params)
         .send()?.text()?;
 
         return Ok(bytes.to_string());
     }
 
+    /// Streams llama's output, calling on_token for each chunk as it arrives, returns the whole text
+    pub fn llama_stream<F: FnMut(&str)>(&self, prompt: String, model: String, mut on_token: F) -> Result<String, Box<dyn Error>>{
+        use std::io::BufRead;
+
+        let params = [("model", model.as_str()), ("prompt", prompt.as_str())];
+
+        let client = self.http_client()?;
+
+        let response = client.post(self.url("/api/services/llama?stream=true"))
+        .header(reqwest::header::ACCEPT, "text/event-stream")
+        .form(&params)
+        .send()?.error_for_status()?;
+
+        let mut text = String::new();
+        let mut event = String::new();
+        for line in std::io::BufReader::new(response).lines() {
+            let line = line?;
+            if let Some(name) = line.strip_prefix("event: ") {
+                event = name.to_string();
+            } else if let Some(data) = line.strip_prefix("data: ") {
+                let data: serde_json::Value = serde_json::from_str(data)?;
+                match event.as_str() {
+                    "done" => return Ok(text),
+                    "error" => return Err(data["message"].as_str().unwrap_or("llama failed").to_string().into()),
+                    _ => {
+                        if let Some(token) = data["token"].as_str() {
+                            on_token(token);
+                            text.push_str(token);
+                        }
+                    }
+                }
+            } else if line.is_empty() {
+                event.clear();
+            }
+        }
+
+        return Ok(text);
+    }
+
     pub fn tts(&self, prompt: String, primary: String, fallback: String) -> Result<Vec<u8>, Box<dyn Error>>{
         let params = [("text", prompt.as_str()), ("primary", primary.as_str()), ("fallback", fallback.as_str())];
 
-        let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+        let client = self.http_client()?;
 
-        let bytes = client.post(format!("http://{}:{}/api/services/tts", self.ip_address.clone(), self.port.clone()))
+        let bytes = client.post(self.url("/api/services/tts"))
         .form(&params)
         .send()?.bytes()?;
 
         return Ok(bytes.to_vec());
     }
 
+    /// Tells this node we are shutting down so it stops routing work to us
+    pub fn leave(&self, port: u16) -> Result<(), Box<dyn Error>>{
+        let params = [("port", port.to_string())];
+
+        let client = self.http_client()?;
+
+        client.post(self.url("/api/nodex/leave"))
+        .timeout(std::time::Duration::from_secs(2))
+        .form(&params)
+        .send()?.error_for_status()?;
+
+        return Ok(());
+    }
+
+    /// Stores a file on this node, services on the node then take the returned id instead of an upload
+    pub fn upload_file(&self, file_path: &str) -> Result<crate::thalamus::files::FileRecord, Box<dyn Error>>{
+        let form = reqwest::blocking::multipart::Form::new().file("file", file_path)?;
+
+        let client = self.http_client()?;
+
+        return Ok(client.post(self.url("/api/files"))
+        .multipart(form)
+        .send()?.error_for_status()?.json()?);
+    }
+
+    pub fn download_file(&self, id: &str) -> Result<Vec<u8>, Box<dyn Error>>{
+        let client = self.http_client()?;
+
+        let bytes = client.get(self.url(format!("/api/files/{}", id).as_str()))
+        .send()?.error_for_status()?.bytes()?;
+
+        return Ok(bytes.to_vec());
+    }
+
+    /// Status of a job started on this node with ?async=true
+    pub fn job(&self, oid: &str) -> Result<ThalamusNodeJob, Box<dyn Error>>{
+        let client = self.http_client()?;
+
+        return Ok(client.get(self.url(format!("/api/jobs/{}", oid).as_str()))
+        .send()?.error_for_status()?.json()?);
+    }
+
+    /// Output of a completed job, the same body the synchronous call returns
+    pub fn job_result(&self, oid: &str) -> Result<Vec<u8>, Box<dyn Error>>{
+        let client = self.http_client()?;
+
+        let bytes = client.get(self.url(format!("/api/jobs/{}/result", oid).as_str()))
+        .send()?.error_for_status()?.bytes()?;
+
+        return Ok(bytes.to_vec());
+    }
+
     pub fn nodex(&self) -> Result<Vec<ThalamusNode>, Box<dyn Error>>{
-        let client = reqwest::blocking::Client::builder().timeout(None).build()?;
+        let client = self.http_client()?;
 
-        let mut url = format!("http://{}:{}/api/nodex", self.ip_address.clone(), self.port.clone());
+        let mut url = self.url("/api/nodex");
         if !url.contains(":") {
             url = format!("{}:{}", url, self.port.clone());
         }
@@ -621,7 +1076,7 @@ impl ThalamusNode {
         let node_c = self.clone();
         let _t = thread::spawn(move || {
             let start_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
-            let _ = node_c.yolov7("/opt/thalamus/test.jpg".to_string()).unwrap();
+            let _ = node_c.yolov7(crate::thalamus::paths::root("test.jpg")).unwrap();
             let end_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
             let time_elapsed = Some(end_timestamp - start_timestamp);
 
@@ -640,7 +1095,7 @@ impl ThalamusNode {
         let node_c = self.clone();
         let _t = thread::spawn(move || {
             let start_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
-            let _srgan = node_c.srgan("/opt/thalamus/test.jpg".to_string()).unwrap();
+            let _srgan = node_c.srgan(crate::thalamus::paths::root("test.jpg")).unwrap();
             let end_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
             let time_elapsed = Some(end_timestamp - start_timestamp);
 
@@ -677,16 +1132,16 @@ impl ThalamusNode {
         let _t = thread::spawn(move || {
             let start_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
             if model == "tiny".to_string() {
-                let _stt = node_c.whisper_stt_tiny("/opt/thalamus/test.wav".to_string()).unwrap();
+                let _stt = node_c.whisper_stt_tiny(crate::thalamus::paths::root("test.wav")).unwrap();
             }
             if model == "base".to_string() {
-                let _stt = node_c.whisper_stt_base("/opt/thalamus/test.wav".to_string()).unwrap();
+                let _stt = node_c.whisper_stt_base(crate::thalamus::paths::root("test.wav")).unwrap();
             }
             if model == "medium".to_string() {
-                let _stt = node_c.whisper_stt_medium("/opt/thalamus/test.wav".to_string()).unwrap();
+                let _stt = node_c.whisper_stt_medium(crate::thalamus::paths::root("test.wav")).unwrap();
             }
             if model == "large".to_string() {
-                let _stt = node_c.whisper_stt_large("/opt/thalamus/test.wav".to_string()).unwrap();
+                let _stt = node_c.whisper_stt_large(crate::thalamus::paths::root("test.wav")).unwrap();
             } 
            
             let end_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
@@ -707,16 +1162,16 @@ impl ThalamusNode {
         let _t = thread::spawn(move || {
             let start_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
             if model == "tiny".to_string() {
-                let _stt = node_c.whisper_vwav_tiny("/opt/thalamus/test.wav".to_string()).unwrap();
+                let _stt = node_c.whisper_vwav_tiny(crate::thalamus::paths::root("test.wav")).unwrap();
             }
             if model == "base".to_string() {
-                let _stt = node_c.whisper_vwav_base("/opt/thalamus/test.wav".to_string()).unwrap();
+                let _stt = node_c.whisper_vwav_base(crate::thalamus::paths::root("test.wav")).unwrap();
             }
             if model == "medium".to_string() {
-                let _stt = node_c.whisper_vwav_medium("/opt/thalamus/test.wav".to_string()).unwrap();
+                let _stt = node_c.whisper_vwav_medium(crate::thalamus::paths::root("test.wav")).unwrap();
             }
             if model == "large".to_string() {
-                let _stt = node_c.whisper_vwav_large("/opt/thalamus/test.wav".to_string()).unwrap();
+                let _stt = node_c.whisper_vwav_large(crate::thalamus::paths::root("test.wav")).unwrap();
             } 
            
             let end_timestamp = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis() as i64;
@@ -755,7 +1210,7 @@ impl ThalamusNodeJob {
     }
 }
 
-/// Struct for storing the stats of each node
+/// Struct for storing the stats of each node, new scores must be Options so older saves still load
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct ThalamusNodeStats {
     pub tts_score: Option<i64>,
@@ -801,6 +1256,47 @@ impl ThalamusNodeStats {
         }
     }
 
+    /// Fails on services and models nodes are not benchmarked for
+    pub fn check_selector(service: &str, model: Option<&str>) -> Result<(), Box<dyn Error>> {
+        let models: &[&str] = match service {
+            "whisper_stt" | "whisper_vwav" => &crate::thalamus::services::whisper::MODELS,
+            "llama" => &crate::thalamus::services::llama::MODELS,
+            "srgan" | "tts" | "nst" => &[],
+            _ => return Err(format!("unknown service {}, expected one of: {}", service, SELECTABLE_SERVICES.join(", ")).into()),
+        };
+        if let Some(model) = model {
+            if !models.contains(&model) {
+                return Err(format!("unknown model {} for {}", model, service).into());
+            }
+        }
+        return Ok(());
+    }
+
+    /// Benchmark time for a service, per model when one is given and the overall score otherwise
+    pub fn score(&self, service: &str, model: Option<&str>) -> Option<i64> {
+        match (service, model) {
+            ("whisper_stt", None) => return self.whisper_stt_score,
+            ("whisper_stt", Some("tiny")) => return self.whisper_stt_tiny,
+            ("whisper_stt", Some("base")) => return self.whisper_stt_base,
+            ("whisper_stt", Some("medium")) => return self.whisper_stt_medium,
+            ("whisper_stt", Some("large")) => return self.whisper_stt_large,
+            ("whisper_vwav", None) => return self.whisper_vwav_score,
+            ("whisper_vwav", Some("tiny")) => return self.whisper_vwav_tiny,
+            ("whisper_vwav", Some("base")) => return self.whisper_vwav_base,
+            ("whisper_vwav", Some("medium")) => return self.whisper_vwav_medium,
+            ("whisper_vwav", Some("large")) => return self.whisper_vwav_large,
+            ("llama", None) => return self.llama_score,
+            ("llama", Some("7B")) => return self.llama_7b,
+            ("llama", Some("13B")) => return self.llama_13b,
+            ("llama", Some("30B")) => return self.llama_30b,
+            ("llama", Some("65B")) => return self.llama_65b,
+            ("srgan", _) => return self.srgan_score,
+            ("tts", _) => return self.tts_score,
+            ("nst", _) => return self.nst_score,
+            _ => return None,
+        }
+    }
+
     pub fn calculate(node: ThalamusNode) -> ThalamusNodeStats {
 
         log::info!("Calculating stats for node {}.....", node.pid);
@@ -1159,6 +1655,88 @@ mod tests {
         assert_eq!(client.nodes.len(), 0);
     }
 
+    #[test]
+    fn test_client_migration() {
+        let v0 = r#"{"nodes": [{"pid": "abc", "ip_address": "10.0.0.2", "version": "0.0.13", "port": 8050, "capablities": null, "last_ping": 0}]}"#;
+        let client = ThalamusClient::from_json(v0).unwrap();
+        assert_eq!(client.schema_version, SCHEMA_VERSION);
+        assert_eq!(client.nodes[0].pid, "abc");
+        assert!(!client.nodes[0].is_online);
+        assert!(client.nodes[0].stats.llama_score.is_none());
+
+        // A clients.json as version 0 left it when restarted mid-benchmark
+        let job = |oid: &str, identifier: &str| serde_json::json!({"oid": oid, "job_identifier": identifier, "url": null, "status": null, "progress": null, "started_at": 0});
+        let mut node = serde_json::to_value(ThalamusNode::new("abc".to_string(), "0.0.13".to_string(), "10.0.0.2".to_string(), 8050)).unwrap();
+        node["jobs"] = serde_json::json!([job("a", "calculate_stats"), job("b", "stt")]);
+        let v0 = serde_json::json!({"nodes": [node.clone()]});
+        let client = ThalamusClient::from_json(&v0.to_string()).unwrap();
+        assert_eq!(client.nodes[0].jobs.len(), 1);
+        assert_eq!(client.nodes[0].jobs[0].oid, "b");
+        assert_eq!(client.nodes[0].active_jobs(), 1);
+
+        // Version 1 files are left alone
+        let v1 = serde_json::json!({"schema_version": SCHEMA_VERSION, "nodes": [node]});
+        assert_eq!(ThalamusClient::from_json(&v1.to_string()).unwrap().nodes[0].jobs.len(), 2);
+
+        let future = format!(r#"{{"schema_version": {}, "nodes": []}}"#, SCHEMA_VERSION + 1);
+        assert!(ThalamusClient::from_json(&future).is_err());
+    }
+
+    #[test]
+    fn test_select_optimal_node() {
+        let mut client = ThalamusClient::new();
+        for (pid, base, jobs) in [("slow", Some(900), 0), ("busy", Some(300), 2), ("idle", Some(300), 0), ("untested", None, 0)] {
+            let mut node = ThalamusNode::new(pid.to_string(), "0.0.14".to_string(), "10.0.0.2".to_string(), 8050);
+            node.stats.whisper_stt_base = base;
+            for _ in 0..jobs {
+                node.jobs.push(ThalamusNodeJob::new("stt".to_string()));
+            }
+            client.nodes.push(node);
+        }
+        let mut offline = ThalamusNode::new("offline".to_string(), "0.0.14".to_string(), "10.0.0.3".to_string(), 8050);
+        offline.stats.whisper_stt_base = Some(100);
+        offline.is_online = false;
+        client.nodes.push(offline);
+        let mut stale = ThalamusNode::new("stale".to_string(), "0.0.14".to_string(), "10.0.0.4".to_string(), 8050);
+        stale.stats.whisper_stt_base = Some(100);
+        stale.last_ping -= STALE_PING_SECS + 1;
+        client.nodes.push(stale);
+
+        let ranking = client.rank_nodes("whisper_stt", Some("base"), STALE_PING_SECS).unwrap();
+        let pids: Vec<&str> = ranking.iter().map(|ranked| ranked.node.pid.as_str()).collect();
+        assert_eq!(pids, vec!["idle", "busy", "slow"]);
+        assert_eq!(client.select_optimal_node("whisper_stt", Some("base")).unwrap().pid, "idle");
+        assert!(client.select_optimal_node("srgan", None).is_err());
+        assert!(client.rank_nodes("whisper_stt", Some("huge"), STALE_PING_SECS).is_err());
+        assert!(client.rank_nodes("ocr", None, STALE_PING_SECS).is_err());
+    }
+
+    #[test]
+    fn test_call_failover() {
+        let server = rouille::Server::new("127.0.0.1:0", |_request: &rouille::Request| rouille::Response::text("hello")).unwrap();
+        let port = server.server_addr().port();
+        std::thread::spawn(move || server.run());
+
+        // The fastest node refuses connections, the next one answers
+        let mut client = ThalamusClient::new();
+        let mut down = ThalamusNode::new("failover_down".to_string(), "0.0.14".to_string(), "127.0.0.1".to_string(), 1);
+        down.stats.llama_7b = Some(100);
+        client.nodes.push(down);
+        let mut up = ThalamusNode::new("failover_up".to_string(), "0.0.14".to_string(), "127.0.0.1".to_string(), port);
+        up.stats.llama_7b = Some(200);
+        client.nodes.push(up);
+
+        let request = ServiceCall::Llama { prompt: "hi".to_string(), model: "7B".to_string() };
+        let reply = client.call(&request, &CallOptions::default()).unwrap();
+        assert_eq!(reply.pid, "failover_up");
+        assert_eq!(reply.attempts, 2);
+        assert_eq!(reply.body, b"hello".to_vec());
+
+        // Avoided until it recovers
+        let order: Vec<String> = client.candidates("llama", Some("7B")).unwrap().into_iter().map(|node| node.pid).collect();
+        assert_eq!(order, vec!["failover_up".to_string(), "failover_down".to_string()]);
+    }
+
     #[test]
     fn test_thalamus_node_new() {
         let node = ThalamusNode::new(
@@ -1224,6 +1802,29 @@ mod tests {
             p2p_port: 62649,
             encrypt: false,
             key: "thalamus".to_string(),
+            allow_default_key: false,
+            api_docs: false,
+            tls: false,
+            tls_cert: None,
+            tls_key: None,
+            tls_pin: None,
+            tls_insecure: false,
+            shutdown_timeout: 30,
+            data_root: None,
+            models_dir: None,
+            bin_dir: None,
+            tmp_dir: None,
+            state_dir: None,
+            rootless: false,
+            config: None,
+            import_registry: None,
+            export_registry: None,
+            storage_report: false,
+            evict_unused: None,
+            export_bundle: None,
+            bundle_include_keys: false,
+            import_bundle: None,
+            restore_identity: false,
         };
         assert_eq!(args.lang, "en");
         assert_eq!(args.max_threads, 6);
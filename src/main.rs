@@ -41,15 +41,10 @@
 // use std::error::Error;
 use tokio::task;
 use rouille::Server;
-use rouille::Response;
-use simple_dns::{Name, CLASS, ResourceRecord, rdata::{RData, A, SRV}};
-use simple_mdns::sync_discovery::SimpleMdnsResponder;
-use std::{net::IpAddr};
 use std::sync::Arc;
 use std::sync::Mutex;
-use local_ip_address::list_afinet_netifas;
 const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
-use clap::Parser;
+use clap::{CommandFactory, FromArgMatches};
 use std::io::Write;
 
 pub fn init_log(path: String) -> Result<(), std::io::Error>{
@@ -64,11 +59,34 @@ async fn main() {
 
     // Escelate to sudo, setup logging, etc.
     clearscreen::clear().unwrap();
-    sudo::with_env(&["LIBTORCH", "LD_LIBRARY_PATH", "PG_DBNAME", "PG_USER", "PG_PASS", "PG_ADDRESS"]).unwrap();
+
+    // Parsed before logging starts since the log lives under the data root,
+    // thalamus.toml fills in whatever was not given on the command line
+    let matches = thalamus::Args::command().get_matches();
+    let mut args = thalamus::Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+
+    // --rootless never re-executes itself under sudo
+    args.rootless = thalamus::thalamus::paths::is_rootless(&args);
+    if !args.rootless {
+        sudo::with_env(&["LIBTORCH", "LD_LIBRARY_PATH", "PG_DBNAME", "PG_USER", "PG_PASS", "PG_ADDRESS", "THALAMUS_"]).unwrap();
+    }
     // simple_logger::SimpleLogger::new().with_colors(true).with_level(log::LevelFilter::Info).with_timestamps(true).init().unwrap();
 
-    init_log("/opt/thalamus/output.log".to_string()).unwrap();
-    simple_logger::SimpleLogger::new().with_colors(true).with_output_file("/opt/thalamus/output.log".to_string()).init().unwrap();
+    let config_path = thalamus::thalamus::config::path(&args);
+    let config = match thalamus::thalamus::config::load(&config_path) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(1);
+        }
+    };
+    config.apply(&mut args, &matches);
+    thalamus::thalamus::config::init(config_path, config);
+    thalamus::thalamus::paths::init(&args);
+
+    let _ = thalamus::thalamus::tools::mkdir(&thalamus::thalamus::paths::get().root);
+    init_log(thalamus::thalamus::paths::root("output.log")).unwrap();
+    simple_logger::SimpleLogger::new().with_colors(true).with_output_file(thalamus::thalamus::paths::root("output.log")).init().unwrap();
 
 
 
@@ -85,37 +103,152 @@ async fn main() {
         None => println!("Version: Unknown"),
     };
 
-    let args = thalamus::Args::parse();
     println!("{:?}", args);
 
+    // Other nodes are called with our --key unless they have one of their own
+    thalamus::set_api_key(Some(args.key.clone()));
+
+    // Mesh clients follow --tls and trust the pinned certificates if any
+    match thalamus::MeshTls::from_args(&args) {
+        Ok(tls) => thalamus::set_mesh_tls(Some(tls)),
+        Err(e) => log::error!("Unable to load --tls-pin certificates: {}", e),
+    }
+
  
 
+    // Node registry, job and benchmark history
+    let store_path = thalamus::thalamus::paths::state(thalamus::thalamus::store::FILE);
+    if let Err(e) = thalamus::thalamus::store::init(&store_path) {
+        log::error!("{}", e);
+        std::process::exit(1);
+    }
+    if let Some(path) = &args.import_registry {
+        match thalamus::ThalamusClient::load_from(path).and_then(|client| client.try_save().map(|_| client)) {
+            Ok(client) => println!("Imported {} nodes from {}", client.nodes.len(), path),
+            Err(e) => {
+                eprintln!("Unable to import {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+    if let Some(path) = &args.export_registry {
+        match thalamus::ThalamusClient::load().and_then(|client| client.save_json(path).map(|_| client)) {
+            Ok(client) => println!("Exported {} nodes to {}", client.nodes.len(), path),
+            Err(e) => {
+                eprintln!("Unable to export {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+    if let Some(path) = &args.export_bundle {
+        let bundle = thalamus::ThalamusClient::load().map_err(|e| e.to_string())
+            .and_then(|client| thalamus::thalamus::bundle::export(&client, &args.key, args.bundle_include_keys).map_err(|e| e.to_string()))
+            .and_then(|bundle| serde_json::to_string(&bundle).map_err(|e| e.to_string()))
+            .and_then(|data| thalamus::thalamus::tools::write_atomic(path, data.as_bytes()).map_err(|e| e.to_string()));
+        match bundle {
+            Ok(_) => println!("Exported this node to {}", path),
+            Err(e) => {
+                eprintln!("Unable to export {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+    if let Some(path) = &args.import_bundle {
+        let config_path = thalamus::thalamus::config::path(&args);
+        let report = std::fs::read_to_string(path).map_err(|e| e.to_string())
+            .and_then(|data| serde_json::from_str::<thalamus::thalamus::bundle::SignedBundle>(&data).map_err(|e| e.to_string()))
+            .and_then(|bundle| {
+                let mut client = thalamus::ThalamusClient::load().map_err(|e| e.to_string())?;
+                let report = thalamus::thalamus::bundle::import(&bundle, &args.key, &mut client, &config_path, args.restore_identity).map_err(|e| e.to_string())?;
+                client.try_save().map_err(|e| e.to_string())?;
+                Ok(report)
+            });
+        match report {
+            Ok(report) => println!("Imported {}: {} nodes added, {} updated", path, report.nodes_added, report.nodes_updated),
+            Err(e) => {
+                eprintln!("Unable to import {}: {}", path, e);
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+    if args.storage_report {
+        match thalamus::thalamus::storage::report() {
+            Ok(report) => println!("{}", serde_json::to_string_pretty(&report).unwrap()),
+            Err(e) => {
+                eprintln!("Unable to report storage: {}", e);
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+    if let Some(secs) = args.evict_unused {
+        match thalamus::thalamus::storage::evict(Some(secs)) {
+            Ok(report) => println!("Evicted {} models ({} bytes)", report.evicted.len(), report.freed_bytes),
+            Err(e) => {
+                eprintln!("Unable to evict models: {}", e);
+                std::process::exit(1);
+            }
+        }
+        std::process::exit(0);
+    }
+
+    // The default key is public, serving the api with it gives it to anyone who asks
+    let keys = thalamus::thalamus::http::auth::KeyStore::new(&thalamus::thalamus::paths::state("keys.json"), args.key.clone());
+    if keys.accepts_default_key() {
+        if !args.allow_default_key {
+            eprintln!("--key is the default \"{}\", set a key of your own (or disable_cli_key in keys.json) or pass --allow-default-key", thalamus::thalamus::http::auth::DEFAULT_KEY);
+            std::process::exit(1);
+        }
+        log::warn!("RUNNING WITH THE DEFAULT API KEY, ANYONE WHO CAN REACH THIS NODE CAN USE ITS API");
+    }
+
     // Install Thalamus
     match std::env::current_exe() {
         Ok(exe_path) => {
             let current_exe_path = format!("{}", exe_path.display());
 
-            if current_exe_path.as_str() != "/opt/thalamus/bin/thalamus"{
-                match thalamus::thalamus::setup::install(args.clone()){
-                    Ok(_) => log::warn!("Installed thalamus"),
-                    Err(e) => log::error!("Error installing thalamus: {}", e),
-                };
-                match thalamus::thalamus::setup::install_client(){
-                    Ok(_) => log::warn!("Installed thalamus client"),
-                    Err(e) => log::error!("Error installing thalamus client: {}", e),
-                };
+            if current_exe_path != thalamus::thalamus::paths::bin("thalamus"){
+                // Downloads use the blocking http client, which can't run on the runtime thread
+                let install_args = args.clone();
+                let installed = task::spawn_blocking(move || {
+                    match thalamus::thalamus::setup::install(install_args){
+                        Ok(_) => log::warn!("Installed thalamus"),
+                        Err(e) => log::error!("Error installing thalamus: {}", e),
+                    };
+                    match thalamus::thalamus::setup::install_client(){
+                        Ok(_) => log::warn!("Installed thalamus client"),
+                        Err(e) => log::error!("Error installing thalamus client: {}", e),
+                    };
+                }).await;
+                if let Err(e) = installed {
+                    log::error!("Error installing thalamus: {}", e);
+                }
             }
         },
         Err(e) => log::error!("Error getting current executable path: {}", e),
     };
 
+    // Clears out uploads and inference byproducts left in tmp/
+    thalamus::thalamus::scratch::spawn_sweeper();
+
     // Initialize tts server
     thalamus::thalamus::services::tts::init(args.clone());
 
     // Setup Thalamus Client
-    let thalamus = Arc::new(Mutex::new(thalamus::ThalamusClient::load(0).unwrap()));
+    let client = match thalamus::ThalamusClient::load() {
+        Ok(client) => client,
+        Err(e) => {
+            log::error!("Unable to load the node registry, fix or remove it to start fresh: {}", e);
+            std::process::exit(1);
+        }
+    };
+    let thalamus = Arc::new(Mutex::new(client.clone()));
 
-    let thalamus_async = Arc::new(futures::lock::Mutex::new(thalamus::ThalamusClient::load(0).unwrap()));
+    let thalamus_async = Arc::new(futures::lock::Mutex::new(client));
     
     // Initialize the p2p server
     // let p2p_server = task::spawn(async {
@@ -123,98 +256,66 @@ async fn main() {
     // });
 
 
-    // let thalamus_discovery_thc = Arc::clone(&thalamus);
-    // let discovery_server = task::spawn(async move{
-        
-    //     let mut discoverx = simple_mdns::async_discovery::ServiceDiscovery::new("a", "_thalamus._tcp.local", 10).unwrap();
-    //     let mut i = 0;
-    //     loop{
-    //         discoverx = thalamus::mdns_discovery(Arc::clone(&thalamus_discovery_thc), discoverx).await.unwrap();
-    //         // thalamus::nodex_discovery(Arc::clone(&thalamus_discovery_thc)).await;
-    //         tokio::time::sleep(tokio::time::Duration::from_secs(1)).await;
-    //         i += 1;
-    //         if i > 10 {
-    //             discoverx = simple_mdns::async_discovery::ServiceDiscovery::new("a", "_thalamus._tcp.local", 10).unwrap();
-    //             i = 0;
-    //         }
-    //     }
-    // });
+    // Advertise this node over mDNS and add the nodes it finds to the registry
+    if thalamus::thalamus::config::get().discovery.enabled {
+        task::spawn(thalamus::thalamus::discovery::run(Arc::clone(&thalamus), args.www_port));
+    }
 
-    // MDNS Responder thread
-    // let thx_port = args.www_port.clone();
-    // std::thread::spawn(move || {
-    //     let network_interfaces = list_afinet_netifas().unwrap();
-    //     let mut responder = SimpleMdnsResponder::new(10);
-    //     let srv_name = Name::new_unchecked("_thalamus._tcp.local");
-
-    //     loop{
-
-    //         responder.clear();
-        
-    //         for (_name, ip) in network_interfaces.iter() {
-    //             if !ip.is_loopback() && !format!("{}", ip.clone()).contains(":") && !format!("{}", ip.clone()).ends_with(".1"){
-    //                 match *ip {
-    //                     IpAddr::V4(ipv4) => { 
-    //                         responder.add_resource(ResourceRecord::new(
-    //                             srv_name.clone(),
-    //                             CLASS::IN,
-    //                             10,
-    //                             RData::A(A { address: ipv4.into() }),
-    //                         ));
-    //                     },
-    //                     IpAddr::V6(_ipv6) => { /* handle IPv6 */ }
-    //                 }
-
-                    
-    //             }
-    //         }
-        
-    //         responder.add_resource(ResourceRecord::new(
-    //             srv_name.clone(),
-    //             CLASS::IN,
-    //             10,
-    //             RData::SRV(SRV {
-    //                 port: thx_port,
-    //                 priority: 0,
-    //                 weight: 0,
-    //                 target: srv_name.clone()
-    //             })
-    //         ));
-
-    //         std::thread::sleep(std::time::Duration::from_secs(10));
-
-    //     }
-
-            
-        
-    // });
+    // Probe known nodes to keep is_online and last_ping current
+    thalamus::thalamus::heartbeat::spawn(Arc::clone(&thalamus));
 
     // Main Thread
     let main_thc = Arc::clone(&thalamus);
     let www_port = args.www_port.clone();
     let max_threads = args.max_threads.clone();
-    std::thread::spawn(move || {
+    let http_args = args.clone();
+    let http_context = thalamus::thalamus::http::HttpContext::new(http_args.clone(), Arc::clone(&main_thc));
+    let shutdown_context = http_context.clone();
+    let http_thread = std::thread::spawn(move || {
         match std::env::current_exe() {
             Ok(exe_path) => {
                 let current_exe_path = format!("{}", exe_path.display());
-                let main_sub_thc = Arc::clone(&main_thc);
-                if current_exe_path.as_str() == "/opt/thalamus/bin/thalamus"{
-                    let server = Server::new(format!("0.0.0.0:{}", www_port).as_str(), move |request| {
-                        match thalamus::thalamus::http::handle(request, Arc::clone(&main_sub_thc)){
-                            Ok(request) => {
-                                log::info!("HTTP: {:?}", request);
-                                return request;
+                if current_exe_path == thalamus::thalamus::paths::bin("thalamus"){
+                    let handler = move |request: &rouille::Request| {
+                        let started = std::time::Instant::now();
+                        let request_id = thalamus::thalamus::http::request_id(request);
+                        let response = match thalamus::thalamus::http::handle(request, &http_context){
+                            Ok(response) => {
+                                log::info!("HTTP: {:?}", response);
+                                response.with_unique_header("X-Request-Id", request_id)
                             },
                             Err(err) => {
-                                log::error!("HTTP_ERROR: {}", err);
-                                return Response::empty_404();
+                                log::error!("HTTP_ERROR[{}]: {} {}: {}", request_id, request.method(), request.url(), err);
+                                thalamus::thalamus::http::errors::error_response(&err, &request_id)
+                            }
+                        };
+                        let route = thalamus::thalamus::http::route_label(request, &http_context);
+                        thalamus::thalamus::metrics::observe_request(request.method(), route.as_str(), response.status_code, started.elapsed());
+                        return response;
+                    };
+
+                    let address = format!("0.0.0.0:{}", www_port);
+                    let server = if http_args.tls {
+                        match thalamus::thalamus::tls::load_or_generate(&http_args) {
+                            Ok((cert, key)) => {
+                                log::warn!("Serving https on {}", address);
+                                Server::new_ssl(address.as_str(), handler, cert, key)
+                            },
+                            Err(e) => {
+                                log::error!("Unable to load tls certificate: {}", e);
+                                return;
                             }
                         }
-                    }).unwrap().pool_size(max_threads.into());
+                    } else {
+                        Server::new(address.as_str(), handler)
+                    }.unwrap().pool_size(max_threads.into());
                 
-                    loop {
-                        server.poll();
+                    // Keeps answering, with 503 for new requests, until shutdown has drained.
+                    // Dropping the server then closes the listener
+                    while !thalamus::thalamus::shutdown::is_stopped() {
+                        server.poll_timeout(std::time::Duration::from_millis(100));
                     }
+                    log::warn!("Web server stopped accepting connections");
                 } 
             },
             Err(e) => log::error!("failed to get current exe path: {e}"),
@@ -228,7 +329,49 @@ async fn main() {
     //     p2p_server,
     //     discovery_server,
     // );
-    loop {}
+
+    // SIGHUP re-reads thalamus.toml
+    tokio::spawn(thalamus::thalamus::config::watch_sighup());
+
+    match thalamus::thalamus::shutdown::wait_for_signal().await {
+        Ok(signal) => log::warn!("{} received, shutting down", signal),
+        Err(e) => log::error!("Unable to listen for signals, shutting down: {}", e),
+    }
+
+    // Refuse new requests, let in-flight requests, streams and jobs finish. Off the runtime
+    // thread, as draining sleeps and leaving uses the blocking client
+    let shutdown_args = args.clone();
+    let shutdown_thalamus = Arc::clone(&thalamus);
+    let shutdown = task::spawn_blocking(move || {
+        thalamus::thalamus::shutdown::begin();
+        let timeout = std::time::Duration::from_secs(shutdown_args.shutdown_timeout);
+        let drained = thalamus::thalamus::shutdown::drain(&shutdown_context.jobs, timeout);
+        if !drained {
+            log::warn!("Shutting down before everything finished");
+        }
+        thalamus::thalamus::shutdown::stop();
+        let _ = http_thread.join();
+        thalamus::thalamus::shutdown::kill_children(std::time::Duration::from_secs(5));
+
+        // Persist the mesh and tell peers we are leaving
+        let thalamus_x = shutdown_thalamus.lock().unwrap();
+        let thx_clone = thalamus_x.clone();
+        thalamus_x.save();
+        std::mem::drop(thalamus_x);
+
+        for node in thx_clone.nodes.iter().filter(|node| node.is_online) {
+            match node.leave(shutdown_args.www_port) {
+                Ok(_) => log::info!("Told {}:{} we are leaving", node.ip_address, node.port),
+                Err(e) => log::warn!("Unable to tell {}:{} we are leaving: {}", node.ip_address, node.port, e),
+            }
+        }
+    });
+    if let Err(e) = shutdown.await {
+        log::error!("Shutdown failed: {}", e);
+    }
+
+    log::warn!("Thalamus stopped");
+    std::process::exit(0);
 
 }
 
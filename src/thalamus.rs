@@ -9,4 +9,20 @@
 pub mod http;
 pub mod tools;
 pub mod setup;
-pub mod services;
\ No newline at end of file
+pub mod services;
+pub mod tls;
+pub mod jobs;
+pub mod health;
+pub mod metrics;
+pub mod shutdown;
+pub mod paths;
+pub mod config;
+pub mod store;
+pub mod scratch;
+pub mod files;
+pub mod cache;
+pub mod storage;
+pub mod bundle;
+pub mod discovery;
+pub mod events;
+pub mod heartbeat;
\ No newline at end of file
@@ -0,0 +1,292 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// Node bundles
+// One JSON file holding everything a node needs to come back as itself or to join a mesh
+// it has never seen: the node registry with its benchmark stats, thalamus.toml, the pid
+// and, only when asked for, keys.json. The payload is signed with HMAC-SHA256 keyed by
+// --key, so the node importing it has to run with the same --key, and no bundle is signed
+// or opened with the default key anyone could forge one with. Made with --export-bundle or
+// GET /api/admin/export, applied with --import-bundle or POST /api/admin/import, both of
+// which need an admin key.
+// Imported nodes are merged into the registry, the rest replaces what is on disk and the
+// config takes effect on the next start. The importing node keeps its own pid, so a new
+// machine joins the mesh next to the exporting node, unless it restores the identity of
+// the exporter (a reinstall), which also takes effect on the next start.
+
+use hmac::{Hmac, Mac};
+use serde::{Serialize, Deserialize};
+use sha2::Sha256;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::ThalamusClient;
+
+use error_chain::error_chain;
+error_chain! {
+    foreign_links {
+        Io(std::io::Error);
+        JSONError(serde_json::Error);
+        TomlError(toml::de::Error);
+    }
+
+    errors {
+        BadSignature {
+            description("bad bundle signature")
+            display("the bundle signature does not match, it was changed or signed with another --key")
+        }
+        UnsupportedFormat(version: u64) {
+            description("unsupported bundle format")
+            display("bundle format {} is newer than this node supports ({})", version, FORMAT_VERSION)
+        }
+        DefaultKey {
+            description("default key")
+            display("bundles can't be signed with the default --key, start the node with a key of its own")
+        }
+    }
+}
+
+pub const FORMAT_VERSION: u64 = 1;
+
+const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Bundle {
+    pub format_version: u64,
+    pub created_at: i64,
+    /// Thalamus version of the exporting node
+    pub version: String,
+    pub pid: Option<String>,
+    /// ThalamusClient as saved by save_json, migrated on import
+    pub registry: serde_json::Value,
+    /// thalamus.toml as written
+    pub config: Option<String>,
+    pub keys: Option<crate::thalamus::http::auth::KeysFile>,
+}
+
+/// What goes on disk, the signature covers the payload bytes exactly as written
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct SignedBundle {
+    pub payload: String,
+    /// Hex HMAC-SHA256 of the payload
+    pub signature: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ImportReport {
+    pub nodes_added: usize,
+    pub nodes_updated: usize,
+    /// The pid this node has after the import
+    pub pid: Option<String>,
+    pub config: bool,
+    pub keys: bool,
+    /// The pid or thalamus.toml changed
+    pub restart_required: bool,
+}
+
+fn mac(key: &str) -> Hmac<Sha256> {
+    return Hmac::<Sha256>::new_from_slice(key.as_bytes()).expect("HMAC takes keys of any length");
+}
+
+fn hex(bytes: &[u8]) -> String {
+    return bytes.iter().map(|b| format!("{:02x}", b)).collect();
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    return (0..hex.len()).step_by(2).map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok()).collect();
+}
+
+pub fn sign(bundle: &Bundle, key: &str) -> Result<SignedBundle> {
+    if key == crate::thalamus::http::auth::DEFAULT_KEY {
+        return Err(ErrorKind::DefaultKey.into());
+    }
+    let payload = serde_json::to_string(bundle)?;
+    let mut mac = mac(key);
+    mac.update(payload.as_bytes());
+    return Ok(SignedBundle { signature: hex(&mac.finalize().into_bytes()), payload: payload });
+}
+
+/// Checks the signature and returns the bundle inside
+pub fn open(signed: &SignedBundle, key: &str) -> Result<Bundle> {
+    if key == crate::thalamus::http::auth::DEFAULT_KEY {
+        return Err(ErrorKind::DefaultKey.into());
+    }
+    let signature = unhex(&signed.signature).ok_or(ErrorKind::BadSignature)?;
+    let mut mac = mac(key);
+    mac.update(signed.payload.as_bytes());
+    // Constant time comparison
+    mac.verify_slice(&signature).map_err(|_| ErrorKind::BadSignature)?;
+
+    let bundle: Bundle = serde_json::from_str(&signed.payload)?;
+    if bundle.format_version > FORMAT_VERSION {
+        return Err(ErrorKind::UnsupportedFormat(bundle.format_version).into());
+    }
+    return Ok(bundle);
+}
+
+fn read_optional(path: &str) -> Result<Option<String>> {
+    if !std::path::Path::new(path).exists() {
+        return Ok(None);
+    }
+    return Ok(Some(std::fs::read_to_string(path)?));
+}
+
+/// Bundles this node's registry, config and pid, and keys.json with include_keys
+pub fn export(client: &ThalamusClient, key: &str, include_keys: bool) -> Result<SignedBundle> {
+    let mut registry = client.clone();
+    registry.schema_version = crate::SCHEMA_VERSION;
+
+    let keys = match read_optional(&crate::thalamus::paths::state("keys.json"))? {
+        Some(data) if include_keys => Some(serde_json::from_str(&data)?),
+        _ => None,
+    };
+    let config = match crate::thalamus::config::loaded_path() {
+        Some(path) => read_optional(&path)?,
+        None => None,
+    };
+
+    let bundle = Bundle {
+        format_version: FORMAT_VERSION,
+        created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
+        version: VERSION.unwrap_or("unknown").to_string(),
+        pid: read_optional(&crate::thalamus::paths::state("pid"))?.map(|pid| pid.trim().to_string()),
+        registry: serde_json::to_value(&registry)?,
+        config: config,
+        keys: keys,
+    };
+    return sign(&bundle, key);
+}
+
+/// Adds or refreshes the bundled nodes in client, other than own_pid, the node importing them.
+/// Returns how many were added and updated
+pub fn merge_registry(client: &mut ThalamusClient, registry: ThalamusClient, own_pid: Option<&str>) -> (usize, usize) {
+    let (mut added, mut updated) = (0, 0);
+    for node in registry.nodes {
+        // A node is not its own peer
+        if Some(node.pid.as_str()) == own_pid {
+            continue;
+        }
+        match client.nodes.iter().position(|existing| existing.pid == node.pid) {
+            Some(index) => {
+                client.nodes[index] = node;
+                updated += 1;
+            },
+            None => {
+                client.nodes.push(node);
+                added += 1;
+            }
+        }
+    }
+    return (added, updated);
+}
+
+/// Merges the bundled nodes into client and writes thalamus.toml and keys.json, and the
+/// bundled pid with restore_identity. The caller saves client
+pub fn import(signed: &SignedBundle, key: &str, client: &mut ThalamusClient, config_path: &str, restore_identity: bool) -> Result<ImportReport> {
+    let bundle = open(signed, key)?;
+    let registry = ThalamusClient::from_json(&bundle.registry.to_string()).map_err(|e| format!("invalid registry in bundle: {}", e))?;
+    if let Some(config) = &bundle.config {
+        // Refused before anything is written
+        let _: crate::thalamus::config::Config = toml::from_str(config)?;
+    }
+
+    let pid_path = crate::thalamus::paths::state("pid");
+    let local_pid = read_optional(&pid_path)?.map(|pid| pid.trim().to_string()).filter(|pid| pid.len() > 0);
+    let own_pid = match &bundle.pid {
+        Some(pid) if restore_identity => Some(pid.clone()),
+        _ => local_pid.clone(),
+    };
+
+    let mut report = ImportReport::default();
+    (report.nodes_added, report.nodes_updated) = merge_registry(client, registry, own_pid.as_deref());
+
+    if restore_identity {
+        if let Some(pid) = &bundle.pid {
+            if local_pid.as_ref() != Some(pid) {
+                crate::thalamus::tools::write_atomic(&pid_path, pid.as_bytes())?;
+                report.restart_required = true;
+            }
+        }
+    }
+    report.pid = own_pid;
+    if let Some(config) = &bundle.config {
+        if read_optional(config_path)?.as_ref() != Some(config) {
+            crate::thalamus::tools::write_atomic(config_path, config.as_bytes())?;
+            report.restart_required = true;
+        }
+        report.config = true;
+    }
+    if let Some(keys) = &bundle.keys {
+        // Picked up by the key store on the next request
+        crate::thalamus::tools::write_atomic(&crate::thalamus::paths::state("keys.json"), serde_json::to_string_pretty(keys)?.as_bytes())?;
+        report.keys = true;
+    }
+
+    log::warn!("Imported a bundle from {} made at {}: {} nodes added, {} updated", bundle.pid.unwrap_or_default(), bundle.created_at, report.nodes_added, report.nodes_updated);
+    return Ok(report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sign_and_open() {
+        let bundle = Bundle {
+            format_version: FORMAT_VERSION,
+            created_at: 0,
+            version: "0.0.14".to_string(),
+            pid: Some("abc".to_string()),
+            registry: serde_json::to_value(ThalamusClient::new()).unwrap(),
+            config: Some("www_port = 8051\n".to_string()),
+            keys: None,
+        };
+        let signed = sign(&bundle, "mesh-key").unwrap();
+        assert_eq!(open(&signed, "mesh-key").unwrap().config, bundle.config);
+        assert!(open(&signed, "another").is_err());
+
+        let mut tampered = signed.clone();
+        tampered.payload = tampered.payload.replace("8051", "8052");
+        assert!(open(&tampered, "mesh-key").is_err());
+        tampered.signature = "zz".to_string();
+        assert!(open(&tampered, "mesh-key").is_err());
+
+        // Anyone can sign with the default key
+        let default_key = crate::thalamus::http::auth::DEFAULT_KEY;
+        assert!(matches!(sign(&bundle, default_key).unwrap_err().kind(), ErrorKind::DefaultKey));
+        assert!(matches!(open(&signed, default_key).unwrap_err().kind(), ErrorKind::DefaultKey));
+    }
+
+    fn node(pid: &str) -> crate::ThalamusNode {
+        return crate::ThalamusNode::new(pid.to_string(), "0.0.14".to_string(), "10.0.0.2".to_string(), 8050);
+    }
+
+    #[test]
+    fn test_merge_registry() {
+        // The exporter's registry lists itself and a peer
+        let mut registry = ThalamusClient::new();
+        registry.nodes.push(node("exporter"));
+        registry.nodes.push(node("peer"));
+
+        // A new machine joins next to both under its own pid
+        let mut client = ThalamusClient::new();
+        client.nodes.push(node("joiner"));
+        assert_eq!(merge_registry(&mut client, registry.clone(), Some("joiner")), (2, 0));
+        let pids: Vec<&str> = client.nodes.iter().map(|node| node.pid.as_str()).collect();
+        assert_eq!(pids, vec!["joiner", "exporter", "peer"]);
+
+        // A reinstall restoring the exporter's pid only takes its peers
+        let mut client = ThalamusClient::new();
+        client.nodes.push(node("peer"));
+        assert_eq!(merge_registry(&mut client, registry, Some("exporter")), (0, 1));
+        assert_eq!(client.nodes.len(), 1);
+    }
+}
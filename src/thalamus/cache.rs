@@ -0,0 +1,251 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// On-disk result cache for whisper, tts, srgan and yolo
+// Off unless [cache] enabled = true. Results are kept in cache/<key>, where the key is the
+// sha256 of the service, the sha256 of its input and every parameter that changes the
+// output (model, voices). The least recently used entries are evicted once the cache grows
+// past [cache] max_bytes. A request skips the cache with ?cache=false or
+// "Cache-Control: no-store", and refreshes its entry with "Cache-Control: no-cache".
+// Replies carry X-Thalamus-Cache: hit, miss or bypass. Hits still honor ?async=true and
+// reply 202 with an already completed job.
+
+use rouille::Request;
+use rouille::Response;
+use sha2::{Sha256, Digest};
+use std::collections::HashMap;
+use std::fs;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+pub const HEADER: &str = "X-Thalamus-Cache";
+
+/// How a request uses the cache
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Mode {
+    /// Caching is off on this node
+    Disabled,
+    /// Neither read nor written
+    Bypass,
+    /// Recomputed and written back
+    Refresh,
+    Use,
+}
+impl Mode {
+    /// Whether a key is needed, hashing large inputs is skipped otherwise
+    pub fn active(&self) -> bool {
+        return *self == Mode::Use || *self == Mode::Refresh;
+    }
+}
+
+/// A cached reply body
+#[derive(Debug, Clone, PartialEq)]
+pub struct Cached {
+    pub mime: String,
+    pub data: Vec<u8>,
+}
+
+struct Entry {
+    size: u64,
+    last_used: SystemTime,
+}
+
+// Entries on disk, read from the directory the first time the cache is used
+static INDEX: Mutex<Option<HashMap<String, Entry>>> = Mutex::new(None);
+
+pub fn dir() -> String {
+    return crate::thalamus::paths::root("cache");
+}
+
+pub fn mode(request: &Request) -> Mode {
+    if !crate::thalamus::config::get().cache.enabled {
+        return Mode::Disabled;
+    }
+    if let Some(cache) = request.get_param("cache") {
+        if cache == "false" || cache == "0" {
+            return Mode::Bypass;
+        }
+    }
+    if let Some(cache_control) = request.header("Cache-Control") {
+        let directives: Vec<&str> = cache_control.split(',').map(|d| d.trim()).collect();
+        if directives.contains(&"no-store") {
+            return Mode::Bypass;
+        }
+        if directives.contains(&"no-cache") {
+            return Mode::Refresh;
+        }
+    }
+    return Mode::Use;
+}
+
+/// Key for a service, the sha256 of its input and the parameters that shape its output
+pub fn key(service: &str, input_hash: &str, params: &[&str]) -> String {
+    let mut hasher = Sha256::new();
+    hasher.update(service.as_bytes());
+    hasher.update(b"\0");
+    hasher.update(input_hash.as_bytes());
+    for param in params.iter() {
+        hasher.update(b"\0");
+        hasher.update(param.as_bytes());
+    }
+    return format!("{:x}", hasher.finalize());
+}
+
+/// Input hash for text inputs, files go through tools::hash_check
+pub fn text_hash(text: &str) -> String {
+    return format!("{:x}", Sha256::digest(text.as_bytes()));
+}
+
+fn entry_path(key: &str) -> String {
+    return Path::new(&dir()).join(key).display().to_string();
+}
+
+fn with_index<T, F: FnOnce(&mut HashMap<String, Entry>) -> T>(f: F) -> T {
+    let mut index = INDEX.lock().unwrap();
+    let entries = index.get_or_insert_with(|| {
+        let mut entries = HashMap::new();
+        if let Ok(dir) = fs::read_dir(dir()) {
+            for entry in dir.flatten() {
+                let name = entry.file_name().to_string_lossy().to_string();
+                if let Ok(metadata) = entry.metadata() {
+                    if metadata.is_file() && crate::thalamus::files::is_valid_id(&name) {
+                        entries.insert(name, Entry { size: metadata.len(), last_used: metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH) });
+                    }
+                }
+            }
+        }
+        entries
+    });
+    return f(entries);
+}
+
+pub fn get(key: &str) -> Option<Cached> {
+    let path = entry_path(key);
+    let data = match fs::read(&path) {
+        Ok(data) => data,
+        Err(_) => {
+            with_index(|entries| entries.remove(key));
+            return None;
+        }
+    };
+
+    // mime type, a newline, then the body
+    let split = data.iter().position(|b| *b == b'\n')?;
+    let mime = String::from_utf8_lossy(&data[..split]).to_string();
+    let now = SystemTime::now();
+    with_index(|entries| {
+        if let Some(entry) = entries.get_mut(key) {
+            entry.last_used = now;
+        }
+    });
+    // Keeps the recency order across restarts
+    if let Ok(file) = fs::OpenOptions::new().write(true).open(&path) {
+        let _ = file.set_modified(now);
+    }
+    return Some(Cached { mime: mime, data: data[split + 1..].to_vec() });
+}
+
+pub fn put(key: &str, mime: &str, data: &[u8]) {
+    let max_bytes = crate::thalamus::config::get().cache.max_bytes;
+    let mut contents = format!("{}\n", mime).into_bytes();
+    contents.extend_from_slice(data);
+    let size = contents.len() as u64;
+    if size > max_bytes {
+        return;
+    }
+
+    if let Err(e) = fs::create_dir_all(dir()).and_then(|_| crate::thalamus::tools::write_atomic(&entry_path(key), &contents)) {
+        log::error!("Unable to cache {}: {}", key, e);
+        return;
+    }
+
+    let evicted = with_index(|entries| {
+        entries.insert(key.to_string(), Entry { size: size, last_used: SystemTime::now() });
+        return evict(entries, max_bytes);
+    });
+    for key in evicted.iter() {
+        let _ = fs::remove_file(entry_path(key));
+    }
+}
+
+// Least recently used keys to drop so the rest fits in max_bytes
+fn evict(entries: &mut HashMap<String, Entry>, max_bytes: u64) -> Vec<String> {
+    let mut total: u64 = entries.values().map(|entry| entry.size).sum();
+    let mut by_age: Vec<(String, SystemTime, u64)> = entries.iter().map(|(key, entry)| (key.clone(), entry.last_used, entry.size)).collect();
+    by_age.sort_by_key(|(_, last_used, _)| *last_used);
+
+    let mut evicted = Vec::new();
+    for (key, _, size) in by_age {
+        if total <= max_bytes {
+            break;
+        }
+        entries.remove(&key);
+        total -= size;
+        evicted.push(key);
+    }
+    return evicted;
+}
+
+/// Bytes used by the cache and how many entries it holds
+pub fn usage() -> (u64, usize) {
+    return with_index(|entries| (entries.values().map(|entry| entry.size).sum(), entries.len()));
+}
+
+/// A cached reply labelled as a hit, answered as a completed job for async callers
+pub fn hit(request: &Request, context: &crate::thalamus::http::HttpContext, job_identifier: &str, output: crate::thalamus::jobs::JobOutput) -> crate::thalamus::http::Result<Response> {
+    let response = crate::thalamus::jobs::respond_completed(request, context, job_identifier, output)?;
+    return Ok(response.with_unique_header(HEADER, "hit"));
+}
+
+/// Labels a reply with how the cache was used, nothing when it is off
+pub fn label(response: Response, mode: Mode) -> Response {
+    match mode {
+        Mode::Disabled => return response,
+        Mode::Bypass => return response.with_unique_header(HEADER, "bypass"),
+        Mode::Refresh | Mode::Use => return response.with_unique_header(HEADER, "miss"),
+    }
+}
+
+/// Looks the key up unless the request bypasses or refreshes the cache
+pub fn lookup(mode: Mode, key: &str) -> Option<Cached> {
+    match mode {
+        Mode::Use => return get(key),
+        _ => return None,
+    }
+}
+
+/// Stores a result unless the request bypasses the cache
+pub fn store(mode: Mode, key: &str, mime: &str, data: &[u8]) {
+    match mode {
+        Mode::Use | Mode::Refresh => put(key, mime, data),
+        _ => {},
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    #[test]
+    fn test_key_and_eviction() {
+        assert_eq!(key("tts", "abc", &["larynx:x"]), key("tts", "abc", &["larynx:x"]));
+        assert_ne!(key("tts", "abc", &["larynx:x"]), key("tts", "abc", &["larynx:y"]));
+        assert_ne!(key("tts", "ab", &["clarynx:x"]), key("tts", "abc", &["larynx:x"]));
+
+        let now = SystemTime::now();
+        let mut entries = HashMap::new();
+        entries.insert("old".to_string(), Entry { size: 60, last_used: now - Duration::from_secs(60) });
+        entries.insert("new".to_string(), Entry { size: 60, last_used: now });
+        assert_eq!(evict(&mut entries, 100), vec!["old".to_string()]);
+        assert!(entries.contains_key("new"));
+        assert!(evict(&mut entries, 100).is_empty());
+    }
+}
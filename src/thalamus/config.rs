@@ -0,0 +1,407 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// thalamus.toml
+// Read from --config, THALAMUS_CONFIG or thalamus.toml in the state directory.
+// Top level keys mirror the command line flags and only apply when the flag was not
+// given; they need a restart, as does [discovery]. The [tts], [tmp], [cache], [storage],
+// [forward], [heartbeat] and [services.<name>] tables are re-read on SIGHUP or
+// POST /api/admin/reload.
+//
+//   www_port = 8050
+//   max_threads = 8
+//
+//   [tts]
+//   default_voice = "larynx:southern_english_female-glow_tts"
+//   fallback_voice = "opensamfoundation"
+//   timeout_secs = 10
+//
+//   [tmp]
+//   ttl_secs = 7200
+//   max_bytes = 5368709120
+//
+//   [cache]
+//   enabled = true
+//   max_bytes = 1073741824
+//
+//   [storage]
+//   max_bytes = 21474836480
+//
+//   [forward]
+//   enabled = true
+//   max_in_flight = 4
+//   faster_peer_ratio = 0.5
+//
+//   [discovery]
+//   enabled = true
+//   ipv6 = false
+//
+//   [heartbeat]
+//   interval_secs = 30
+//   max_misses = 3
+//   evict_after_secs = 604800
+//
+//   [services.llama]
+//   default_model = "13B"
+//
+//   [services.srgan]
+//   enabled = false
+
+use clap::parser::ValueSource;
+use serde::{Serialize, Deserialize};
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+
+use error_chain::error_chain;
+error_chain! {
+    foreign_links {
+        Io(std::io::Error);
+        TomlError(toml::de::Error);
+    }
+}
+
+// Tables that take effect on reload, every other key needs a restart
+const RELOADABLE: [&str; 7] = ["tts", "tmp", "cache", "storage", "forward", "heartbeat", "services"];
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    pub lang: Option<String>,
+    pub max_threads: Option<u8>,
+    pub www_port: Option<u16>,
+    pub p2p_port: Option<u16>,
+    pub encrypt: Option<bool>,
+    pub key: Option<String>,
+    pub allow_default_key: Option<bool>,
+    pub api_docs: Option<bool>,
+    pub tls: Option<bool>,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    pub tls_pin: Option<String>,
+    pub tls_insecure: Option<bool>,
+    pub shutdown_timeout: Option<u64>,
+    pub data_root: Option<String>,
+    pub models_dir: Option<String>,
+    pub bin_dir: Option<String>,
+    pub tmp_dir: Option<String>,
+    pub state_dir: Option<String>,
+    pub tts: TtsConfig,
+    pub tmp: TmpConfig,
+    pub cache: CacheConfig,
+    pub storage: StorageConfig,
+    pub forward: ForwardConfig,
+    pub discovery: DiscoveryConfig,
+    pub heartbeat: HeartbeatConfig,
+    pub services: BTreeMap<String, ServiceConfig>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TtsConfig {
+    /// Host port the OpenTTS container is published on, needs a restart
+    pub opentts_port: u16,
+    /// Used when a request has no primary voice
+    pub default_voice: String,
+    /// Used when a request has no fallback voice
+    pub fallback_voice: String,
+    pub timeout_secs: u64,
+}
+impl Default for TtsConfig {
+    fn default() -> TtsConfig {
+        TtsConfig {
+            opentts_port: 5500,
+            default_voice: "coqui-tts:en_ljspeech".to_string(),
+            fallback_voice: "opensamfoundation".to_string(),
+            timeout_secs: 5,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct TmpConfig {
+    /// Uploads and byproducts older than this are swept from tmp/
+    pub ttl_secs: u64,
+    /// Oldest entries are swept until tmp/ fits
+    pub max_bytes: u64,
+    pub sweep_interval_secs: u64,
+}
+impl Default for TmpConfig {
+    fn default() -> TmpConfig {
+        TmpConfig {
+            ttl_secs: 60 * 60,
+            max_bytes: 10 * 1024 * 1024 * 1024,
+            sweep_interval_secs: 5 * 60,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct CacheConfig {
+    /// Reuse whisper, tts, srgan and yolo results for inputs seen before
+    pub enabled: bool,
+    /// Least recently used results are evicted past this
+    pub max_bytes: u64,
+}
+impl Default for CacheConfig {
+    fn default() -> CacheConfig {
+        CacheConfig { enabled: false, max_bytes: 1024 * 1024 * 1024 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Default)]
+#[serde(default, deny_unknown_fields)]
+pub struct StorageConfig {
+    /// Budget for the models directory, downloads that would go past it are refused
+    pub max_bytes: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ForwardConfig {
+    /// Send service requests this node can't serve to a peer
+    pub enabled: bool,
+    /// Requests that already made this many hops are served here or fail
+    pub max_hops: u32,
+    /// Forward while more requests than this are in flight
+    pub max_in_flight: Option<usize>,
+    /// Forward when a peer's benchmark takes less than this share of ours
+    pub faster_peer_ratio: Option<f64>,
+    pub timeout_secs: u64,
+    /// Larger request bodies are served here, forwarding holds the whole body in memory
+    pub max_body_bytes: u64,
+}
+impl Default for ForwardConfig {
+    fn default() -> ForwardConfig {
+        ForwardConfig { enabled: false, max_hops: 2, max_in_flight: None, faster_peer_ratio: None, timeout_secs: 10 * 60, max_body_bytes: 64 * 1024 * 1024 }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct DiscoveryConfig {
+    /// Advertise this node over mDNS and add the nodes it finds to the registry
+    pub enabled: bool,
+    pub interval_secs: u64,
+    /// Time to live of the advertised records
+    pub ttl_secs: u32,
+    pub ipv6: bool,
+    /// Advertise 127.0.0.1 and ::1, for nodes sharing a host
+    pub loopback: bool,
+}
+impl Default for DiscoveryConfig {
+    fn default() -> DiscoveryConfig {
+        DiscoveryConfig { enabled: true, interval_secs: 10, ttl_secs: 60, ipv6: true, loopback: false }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct HeartbeatConfig {
+    /// Probe every known node and track which are online
+    pub enabled: bool,
+    pub interval_secs: u64,
+    /// Time a node has to answer a probe
+    pub timeout_secs: u64,
+    /// Probes missed in a row before a node is offline
+    pub max_misses: u32,
+    /// Offline nodes not seen for this long are removed from the registry, never when unset
+    pub evict_after_secs: Option<u64>,
+}
+impl Default for HeartbeatConfig {
+    fn default() -> HeartbeatConfig {
+        HeartbeatConfig { enabled: true, interval_secs: 30, timeout_secs: 5, max_misses: 3, evict_after_secs: Some(7 * 24 * 60 * 60) }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ServiceConfig {
+    pub enabled: bool,
+    /// Model used when a request does not name one
+    pub default_model: Option<String>,
+}
+impl Default for ServiceConfig {
+    fn default() -> ServiceConfig {
+        ServiceConfig { enabled: true, default_model: None }
+    }
+}
+
+/// Result of a reload
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReloadReport {
+    pub path: String,
+    /// Keys that changed in the file but keep their old value until a restart
+    pub restart_required: Vec<String>,
+}
+
+static CONFIG: Mutex<Option<(String, Config)>> = Mutex::new(None);
+
+/// --config, then THALAMUS_CONFIG, then thalamus.toml in the state directory
+pub fn path(args: &crate::Args) -> String {
+    if let Some(path) = &args.config {
+        return path.clone();
+    }
+    if let Ok(path) = std::env::var("THALAMUS_CONFIG") {
+        if path.len() > 0 {
+            return path;
+        }
+    }
+    let paths = crate::thalamus::paths::Paths::from_args(args);
+    return std::path::Path::new(&paths.state).join("thalamus.toml").display().to_string();
+}
+
+/// A missing file is an empty config
+pub fn load(path: &str) -> Result<Config> {
+    if !std::path::Path::new(path).exists() {
+        return Ok(Config::default());
+    }
+    let data = std::fs::read_to_string(path).chain_err(|| format!("unable to read {}", path))?;
+    let config: Config = toml::from_str(&data).chain_err(|| format!("unable to parse {}", path))?;
+    return Ok(config);
+}
+
+impl Config {
+    /// Fills in every flag that was not given on the command line
+    pub fn apply(&self, args: &mut crate::Args, matches: &clap::ArgMatches) {
+        let from_cli = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+
+        macro_rules! merge {
+            ($($field:ident),*) => {$(
+                if let Some(value) = &self.$field {
+                    if !from_cli(stringify!($field)) {
+                        args.$field = value.clone();
+                    }
+                }
+            )*};
+        }
+        macro_rules! merge_optional {
+            ($($field:ident),*) => {$(
+                if self.$field.is_some() && !from_cli(stringify!($field)) {
+                    args.$field = self.$field.clone();
+                }
+            )*};
+        }
+
+        merge!(lang, max_threads, www_port, p2p_port, encrypt, key, allow_default_key, api_docs, tls, tls_insecure, shutdown_timeout);
+        merge_optional!(tls_cert, tls_key, tls_pin, data_root, models_dir, bin_dir, tmp_dir, state_dir);
+    }
+
+    /// Top level keys whose value differs between the two configs
+    fn restart_required(&self, other: &Config) -> Vec<String> {
+        let mut changed = Vec::new();
+        let ours = serde_json::to_value(self).unwrap_or_default();
+        let theirs = serde_json::to_value(other).unwrap_or_default();
+        if let (Some(ours), Some(theirs)) = (ours.as_object(), theirs.as_object()) {
+            for (key, value) in ours.iter() {
+                if !RELOADABLE.contains(&key.as_str()) && theirs.get(key) != Some(value) {
+                    changed.push(key.clone());
+                }
+            }
+        }
+        if self.tts.opentts_port != other.tts.opentts_port {
+            changed.push("tts.opentts_port".to_string());
+        }
+        return changed;
+    }
+}
+
+pub fn init(path: String, config: Config) {
+    let mut current = CONFIG.lock().unwrap();
+    *current = Some((path, config));
+}
+
+/// Path the running configuration was read from
+pub fn loaded_path() -> Option<String> {
+    return CONFIG.lock().unwrap().as_ref().map(|(path, _)| path.clone()).filter(|path| path.len() > 0);
+}
+
+/// The running configuration
+pub fn get() -> Config {
+    match CONFIG.lock().unwrap().as_ref() {
+        Some((_, config)) => return config.clone(),
+        None => return Config::default(),
+    }
+}
+
+pub fn tts() -> TtsConfig {
+    return get().tts;
+}
+
+pub fn service(name: &str) -> ServiceConfig {
+    return get().services.get(name).cloned().unwrap_or_default();
+}
+
+/// Re-reads the file, keeping the old value of anything that needs a restart
+pub fn reload() -> Result<ReloadReport> {
+    let mut current = CONFIG.lock().unwrap();
+    let (path, old) = current.clone().unwrap_or((String::new(), Config::default()));
+    if path.len() == 0 {
+        return Err("no configuration file was loaded".into());
+    }
+
+    let mut config = load(&path)?;
+    let restart_required = old.restart_required(&config);
+    for key in restart_required.iter() {
+        log::warn!("{} changed in {}, restart to apply it", key, path);
+    }
+
+    // Only the reloadable tables are taken from the new file
+    let opentts_port = old.tts.opentts_port;
+    let mut next = old;
+    config.tts.opentts_port = opentts_port;
+    next.tts = config.tts;
+    next.tmp = config.tmp;
+    next.cache = config.cache;
+    next.storage = config.storage;
+    next.forward = config.forward;
+    next.heartbeat = config.heartbeat;
+    next.services = config.services;
+    *current = Some((path.clone(), next));
+
+    log::info!("Reloaded {}", path);
+    return Ok(ReloadReport { path: path, restart_required: restart_required });
+}
+
+/// Reloads on every SIGHUP
+pub async fn watch_sighup() {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut hangup = match signal(SignalKind::hangup()) {
+        Ok(hangup) => hangup,
+        Err(e) => {
+            log::error!("Unable to listen for SIGHUP: {}", e);
+            return;
+        }
+    };
+    while hangup.recv().await.is_some() {
+        if let Err(e) = reload() {
+            log::error!("Unable to reload configuration: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_and_restart_required() {
+        let config: Config = toml::from_str("www_port = 9000\n[tts]\ndefault_voice = \"larynx:x\"\n[services.srgan]\nenabled = false\n").unwrap();
+        assert_eq!(config.www_port, Some(9000));
+        assert_eq!(config.tts.default_voice, "larynx:x");
+        assert_eq!(config.tts.opentts_port, 5500);
+        assert!(!config.services.get("srgan").unwrap().enabled);
+
+        let mut changed = config.clone();
+        changed.www_port = Some(9001);
+        changed.tts.timeout_secs = 30;
+        assert_eq!(config.restart_required(&changed), vec!["www_port".to_string()]);
+    }
+}
@@ -0,0 +1,324 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// mDNS advertisement and discovery
+// Every node advertises itself as <pid>._thalamus._tcp.local on each of its IPv4 and IPv6
+// addresses, with TXT records carrying its pid, version and capabilities (the services
+// enabled in thalamus.toml). The same responder browses for the other nodes every
+// [discovery] interval_secs, adding them to the registry or refreshing the address,
+// version and capabilities of the ones it already knows. Nodes running a release without
+// TXT records are identified through /api/thalamus/version instead. The advertisement is
+// rebuilt whenever the node's addresses change. Loopback addresses are only advertised
+// with [discovery] loopback = true, for meshes on a single host. Nodes that stop answering
+// are left to the heartbeat. New nodes and nodes seen again are emitted as node events.
+
+use simple_mdns::async_discovery::ServiceDiscovery;
+use simple_mdns::InstanceInformation;
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use local_ip_address::list_afinet_netifas;
+
+use crate::thalamus::config::DiscoveryConfig;
+use crate::thalamus::events::{EventKind, NodeEvent};
+use crate::{ThalamusClient, ThalamusNode, ThalamusNodeCapability};
+
+pub const SERVICE_NAME: &str = "_thalamus._tcp.local";
+
+const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
+
+// Advertised as capabilities when enabled, named as in [services.<name>]
+const SERVICES: [&str; 6] = ["llama", "whisper", "tts", "srgan", "yolov7", "nst"];
+
+/// A node as it describes itself in its TXT records
+#[derive(Debug, Clone, PartialEq)]
+pub struct Advert {
+    pub pid: String,
+    pub version: String,
+    /// None when the node did not say, which leaves the registry's capabilities alone
+    pub capabilities: Option<Vec<String>>,
+}
+
+/// An advertised node and the address it is reached on
+#[derive(Debug, Clone, PartialEq)]
+pub struct Discovered {
+    pub advert: Advert,
+    pub ip_address: IpAddr,
+    pub port: u16,
+}
+
+/// Services this node serves
+pub fn capabilities() -> Vec<String> {
+    return SERVICES.iter()
+        .filter(|service| **service != "nst" || cfg!(feature = "pytorch"))
+        .filter(|service| crate::thalamus::config::service(service).enabled)
+        .map(|service| service.to_string())
+        .collect();
+}
+
+/// TXT records for an advert
+pub fn attributes(advert: &Advert) -> HashMap<String, Option<String>> {
+    let mut attributes = HashMap::new();
+    attributes.insert("pid".to_string(), Some(advert.pid.clone()));
+    attributes.insert("version".to_string(), Some(advert.version.clone()));
+    if let Some(capabilities) = &advert.capabilities {
+        attributes.insert("capabilities".to_string(), Some(capabilities.join(",")));
+    }
+    return attributes;
+}
+
+/// Reads an advert back from TXT records, None when there is no pid
+pub fn parse_attributes(attributes: &HashMap<String, Option<String>>) -> Option<Advert> {
+    let value = |key: &str| attributes.iter().find(|(k, _)| k.eq_ignore_ascii_case(key)).and_then(|(_, v)| v.clone());
+    let pid = value("pid").filter(|pid| pid.trim().len() > 0)?;
+    return Some(Advert {
+        pid: pid.trim().to_string(),
+        version: value("version").unwrap_or_default(),
+        capabilities: value("capabilities").map(|capabilities| capabilities.split(',').map(|c| c.trim().to_string()).filter(|c| c.len() > 0).collect()),
+    });
+}
+
+// Link local IPv6 addresses need a scope id that urls can't carry
+fn is_usable(ip: &IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(_) => return !ip.is_unspecified() && !ip.is_multicast(),
+        IpAddr::V6(ipv6) => return !ip.is_unspecified() && !ip.is_multicast() && (ipv6.segments()[0] & 0xffc0) != 0xfe80,
+    }
+}
+
+/// Addresses worth advertising out of the node's interfaces
+pub fn advertised_addresses(interfaces: &[(String, IpAddr)], config: &DiscoveryConfig) -> Vec<IpAddr> {
+    let mut addresses: Vec<IpAddr> = Vec::new();
+    for (_, ip) in interfaces.iter() {
+        if ip.is_ipv6() && !config.ipv6 {
+            continue;
+        }
+        if ip.is_loopback() && !config.loopback {
+            continue;
+        }
+        if is_usable(ip) && !addresses.contains(ip) {
+            addresses.push(*ip);
+        }
+    }
+    return addresses;
+}
+
+/// The address a peer is registered under, IPv4 first as ThalamusNode keeps only one
+pub fn preferred_address(addresses: &[IpAddr]) -> Option<IpAddr> {
+    let mut usable: Vec<IpAddr> = addresses.iter().filter(|ip| is_usable(ip)).cloned().collect();
+    usable.sort_by_key(|ip| (ip.is_ipv6(), ip.is_loopback()));
+    return usable.first().cloned();
+}
+
+/// Adds a discovered node to the registry or refreshes it, returning the change it made.
+/// The caller saves client
+pub fn merge(client: &mut ThalamusClient, local_pid: Option<&str>, discovered: &Discovered) -> Option<EventKind> {
+    // A node is not its own peer
+    if Some(discovered.advert.pid.as_str()) == local_pid {
+        return None;
+    }
+    let capabilities = discovered.advert.capabilities.as_ref().map(|tags| tags.iter().map(|tag| ThalamusNodeCapability { tag: tag.clone() }).collect::<Vec<ThalamusNodeCapability>>());
+    match client.nodes.iter().position(|node| node.pid == discovered.advert.pid) {
+        Some(index) => {
+            let node = &mut client.nodes[index];
+            node.ip_address = discovered.ip_address.to_string();
+            node.port = discovered.port;
+            if discovered.advert.version.len() > 0 {
+                node.version = discovered.advert.version.clone();
+            }
+            if capabilities.is_some() {
+                node.capablities = capabilities;
+            }
+            node.last_ping = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+            if !node.is_online {
+                node.is_online = true;
+                return Some(EventKind::Online);
+            }
+            return None;
+        },
+        None => {
+            let mut node = ThalamusNode::new(discovered.advert.pid.clone(), discovered.advert.version.clone(), discovered.ip_address.to_string(), discovered.port);
+            node.capablities = capabilities;
+            log::info!("NEW_NODE: {:?}", node.clone());
+            client.nodes.push(node);
+            return Some(EventKind::Discovered);
+        }
+    }
+}
+
+// Starts answering for this node on the given addresses
+async fn advertise(addresses: &[IpAddr], port: u16, config: &DiscoveryConfig) -> Result<ServiceDiscovery, Box<dyn std::error::Error + Send + Sync>> {
+    let advert = Advert {
+        pid: crate::local_pid().ok_or("this node has no pid yet")?,
+        version: VERSION.unwrap_or("unknown").to_string(),
+        capabilities: Some(capabilities()),
+    };
+    return advertise_as(&advert, addresses, port, config).await;
+}
+
+async fn advertise_as(advert: &Advert, addresses: &[IpAddr], port: u16, config: &DiscoveryConfig) -> Result<ServiceDiscovery, Box<dyn std::error::Error + Send + Sync>> {
+    let mut discovery = ServiceDiscovery::new(&advert.pid, SERVICE_NAME, config.ttl_secs)?;
+    let mut info = InstanceInformation::new();
+    info.ip_addresses = addresses.to_vec();
+    info.ports = vec![port];
+    info.attributes = attributes(advert);
+    discovery.add_service_info(info).await?;
+    log::info!("Advertising {}.{} on {:?} port {}", advert.pid, SERVICE_NAME, addresses, port);
+    return Ok(discovery);
+}
+
+// Nodes the responder has heard from
+async fn browse(discovery: &ServiceDiscovery) -> Vec<Discovered> {
+    let mut found = Vec::new();
+    for info in discovery.get_known_services().await {
+        let (ip_address, port) = match (preferred_address(&info.ip_addresses), info.ports.first()) {
+            (Some(ip_address), Some(port)) => (ip_address, *port),
+            _ => continue,
+        };
+        let advert = match parse_attributes(&info.attributes) {
+            Some(advert) => advert,
+            // Releases from before the TXT records
+            None => match crate::async_fetch_version(&crate::host(&ip_address.to_string()), port).await.map_err(|e| e.to_string()) {
+                Ok(version) => Advert { pid: version.pid, version: version.version, capabilities: None },
+                Err(e) => {
+                    log::error!("fetch_thalamus_version_error: {}:{}: {}", ip_address, port, e);
+                    continue;
+                }
+            },
+        };
+        found.push(Discovered { advert: advert, ip_address: ip_address, port: port });
+    }
+    return found;
+}
+
+/// Advertises this node and feeds the registry until the process exits
+pub async fn run(thalamus: Arc<Mutex<ThalamusClient>>, port: u16) {
+    let config = crate::thalamus::config::get().discovery;
+    let mut discovery: Option<ServiceDiscovery> = None;
+    let mut advertised: Vec<IpAddr> = Vec::new();
+    loop {
+        let addresses = match list_afinet_netifas() {
+            Ok(interfaces) => advertised_addresses(&interfaces, &config),
+            Err(e) => {
+                log::error!("Unable to list network interfaces: {}", e);
+                advertised.clone()
+            }
+        };
+        if discovery.is_none() || addresses != advertised {
+            // Dropping the old responder withdraws the old addresses
+            discovery = None;
+            match advertise(&addresses, port, &config).await {
+                Ok(responder) => {
+                    discovery = Some(responder);
+                    advertised = addresses;
+                },
+                Err(e) => log::error!("Unable to advertise over mDNS: {}", e),
+            }
+        }
+
+        if let Some(responder) = &discovery {
+            let found = browse(responder).await;
+            let local_pid = crate::local_pid();
+            let mut events = Vec::new();
+            // Scoped so the lock is never held across an await
+            {
+                let mut thalamus_x = thalamus.lock().unwrap();
+                for node in found.iter() {
+                    if let Some(kind) = merge(&mut thalamus_x, local_pid.as_deref(), node) {
+                        if let Some(merged) = thalamus_x.nodes.iter().find(|merged| merged.pid == node.advert.pid) {
+                            events.push((NodeEvent::new(kind, merged), node.clone()));
+                        }
+                    }
+                }
+                if found.len() > 0 {
+                    thalamus_x.save();
+                }
+            }
+
+            for (event, node) in events {
+                let kind = event.event;
+                crate::thalamus::events::emit(event);
+                if kind == EventKind::Discovered {
+                    crate::calc_stats(Arc::clone(&thalamus), node.advert.pid, node.advert.version, node.ip_address.to_string(), node.port);
+                }
+            }
+        }
+
+        tokio::time::sleep(Duration::from_secs(config.interval_secs)).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_adverts_and_merge() {
+        let advert = Advert { pid: "abc".to_string(), version: "0.0.14".to_string(), capabilities: Some(vec!["llama".to_string(), "tts".to_string()]) };
+        assert_eq!(parse_attributes(&attributes(&advert)), Some(advert.clone()));
+        assert_eq!(parse_attributes(&HashMap::new()), None);
+
+        let interfaces: Vec<(String, IpAddr)> = vec![
+            ("lo".to_string(), "127.0.0.1".parse().unwrap()),
+            ("lo".to_string(), "::1".parse().unwrap()),
+            ("eth0".to_string(), "fe80::1".parse().unwrap()),
+            ("eth0".to_string(), "10.0.0.1".parse().unwrap()),
+        ];
+        let mut config = DiscoveryConfig::default();
+        assert_eq!(advertised_addresses(&interfaces, &config), vec!["10.0.0.1".parse::<IpAddr>().unwrap()]);
+        config.loopback = true;
+        assert_eq!(advertised_addresses(&interfaces, &config).len(), 3);
+        config.ipv6 = false;
+        assert_eq!(advertised_addresses(&interfaces, &config).len(), 2);
+
+        let ip_address = preferred_address(&["::1".parse().unwrap(), "127.0.0.1".parse().unwrap()]).unwrap();
+        assert_eq!(ip_address.to_string(), "127.0.0.1");
+
+        let mut client = ThalamusClient::new();
+        let discovered = Discovered { advert: advert.clone(), ip_address: "::1".parse().unwrap(), port: 8050 };
+        assert_eq!(merge(&mut client, Some("abc"), &discovered), None);
+        assert_eq!(merge(&mut client, Some("self"), &discovered), Some(EventKind::Discovered));
+        assert_eq!(client.nodes[0].url("/api/thalamus/version"), format!("{}://[::1]:8050/api/thalamus/version", crate::scheme()));
+
+        let moved = Discovered { advert: Advert { capabilities: None, ..advert }, ip_address: ip_address, port: 8051 };
+        client.nodes[0].is_online = false;
+        assert_eq!(merge(&mut client, Some("self"), &moved), Some(EventKind::Online));
+        assert_eq!(client.nodes.len(), 1);
+        assert_eq!(client.nodes[0].ip_address, "127.0.0.1");
+        assert_eq!(client.nodes[0].capablities.as_ref().unwrap().len(), 2);
+    }
+
+    // Two responders on one host, as two nodes sharing it with [discovery] loopback = true
+    #[tokio::test]
+    async fn test_loopback_discovery() {
+        let mut config = DiscoveryConfig::default();
+        config.loopback = true;
+        let interfaces: Vec<(String, IpAddr)> = vec![("lo".to_string(), "127.0.0.1".parse().unwrap())];
+        let addresses = advertised_addresses(&interfaces, &config);
+        let advert = |pid: &str| Advert { pid: pid.to_string(), version: "0.0.14".to_string(), capabilities: Some(vec!["llama".to_string()]) };
+
+        let first = advertise_as(&advert("loopback-first"), &addresses, 18050, &config).await.unwrap();
+        let second = advertise_as(&advert("loopback-second"), &addresses, 18051, &config).await.unwrap();
+
+        let finds = |found: &[Discovered], pid: &str, port: u16| found.iter().any(|node| node.advert.pid == pid && node.port == port && node.ip_address.is_loopback());
+        let mut found = (Vec::new(), Vec::new());
+        for _ in 0..40 {
+            found = (browse(&first).await, browse(&second).await);
+            if finds(&found.0, "loopback-second", 18051) && finds(&found.1, "loopback-first", 18050) {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        }
+        assert!(finds(&found.0, "loopback-second", 18051), "first responder found {:?}", found.0);
+        assert!(finds(&found.1, "loopback-first", 18050), "second responder found {:?}", found.1);
+        assert_eq!(found.1.iter().find(|node| node.advert.pid == "loopback-first").unwrap().advert.capabilities, Some(vec!["llama".to_string()]));
+    }
+}
@@ -0,0 +1,165 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// Node events
+// The heartbeat, mDNS discovery and peers leaving the mesh report node state changes here
+// as they happen. In-process listeners take a channel from subscribe(), dashboards follow
+// the same events as server-sent events on GET /api/nodex/events. Each of those streams
+// holds a web server thread for as long as it is open, so at most half of --max-threads
+// can be open at once and the rest get a 503. Events are not kept, thalamus.db holds the
+// online/offline history served by /api/nodex/status.
+
+use serde::{Serialize, Deserialize};
+use std::io::Read;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, Sender};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use crate::ThalamusNode;
+
+// How often an idle stream sends a ping, and checks for shutdown
+const KEEP_ALIVE: Duration = Duration::from_secs(15);
+const POLL: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    /// Added to the registry
+    Discovered,
+    /// Answering again after being offline
+    Online,
+    Offline,
+    /// Removed from the registry after being offline too long
+    Evicted,
+}
+impl EventKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            EventKind::Discovered => return "discovered",
+            EventKind::Online => return "online",
+            EventKind::Offline => return "offline",
+            EventKind::Evicted => return "evicted",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct NodeEvent {
+    pub event: EventKind,
+    pub pid: String,
+    pub ip_address: String,
+    pub port: u16,
+    pub at: i64,
+}
+impl NodeEvent {
+    pub fn new(event: EventKind, node: &ThalamusNode) -> NodeEvent {
+        NodeEvent {
+            event: event,
+            pid: node.pid.clone(),
+            ip_address: node.ip_address.clone(),
+            port: node.port,
+            at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
+        }
+    }
+}
+
+static SUBSCRIBERS: Mutex<Vec<Sender<NodeEvent>>> = Mutex::new(Vec::new());
+
+// EventStreams open right now
+static STREAMS: AtomicUsize = AtomicUsize::new(0);
+
+/// Every event emitted from now on
+pub fn subscribe() -> Receiver<NodeEvent> {
+    let (sender, receiver) = mpsc::channel();
+    SUBSCRIBERS.lock().unwrap().push(sender);
+    return receiver;
+}
+
+pub fn emit(event: NodeEvent) {
+    match event.event {
+        EventKind::Offline | EventKind::Evicted => log::warn!("Node {} ({}:{}) is {}", event.pid, event.ip_address, event.port, event.event.name()),
+        _ => log::info!("Node {} ({}:{}) is {}", event.pid, event.ip_address, event.port, event.event.name()),
+    }
+    // ThalamusClient::call stops avoiding a node as soon as it answers again
+    if event.event == EventKind::Online {
+        crate::clear_degraded(&event.pid);
+    }
+    // Dropped receivers are forgotten
+    SUBSCRIBERS.lock().unwrap().retain(|subscriber| subscriber.send(event.clone()).is_ok());
+}
+
+/// Reader of server-sent events for one subscriber, ends when the node shuts down
+pub struct EventStream {
+    events: Receiver<NodeEvent>,
+    pending: Vec<u8>,
+    last_sent: Instant,
+}
+impl EventStream {
+    pub fn new(events: Receiver<NodeEvent>) -> EventStream {
+        STREAMS.fetch_add(1, Ordering::SeqCst);
+        return EventStream { events: events, pending: Vec::new(), last_sent: Instant::now() };
+    }
+
+    /// A stream of every event from now on, None when max_streams are already open
+    pub fn open(max_streams: usize) -> Option<EventStream> {
+        STREAMS.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |open| if open < max_streams { Some(open + 1) } else { None }).ok()?;
+        return Some(EventStream { events: subscribe(), pending: Vec::new(), last_sent: Instant::now() });
+    }
+}
+impl Drop for EventStream {
+    fn drop(&mut self) {
+        STREAMS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+impl Read for EventStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pending.is_empty() {
+            if crate::thalamus::shutdown::is_shutting_down() {
+                return Ok(0);
+            }
+            match self.events.recv_timeout(POLL) {
+                Ok(event) => self.pending = crate::thalamus::http::sse_event(Some(event.event.name()), serde_json::to_value(&event)?),
+                Err(RecvTimeoutError::Timeout) if self.last_sent.elapsed() >= KEEP_ALIVE => self.pending = crate::thalamus::http::sse_event(Some("ping"), serde_json::json!({})),
+                Err(RecvTimeoutError::Timeout) => {},
+                Err(RecvTimeoutError::Disconnected) => return Ok(0),
+            }
+        }
+        self.last_sent = Instant::now();
+
+        let n = std::cmp::min(buf.len(), self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        return Ok(n);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_event_stream() {
+        let mut stream = EventStream::new(subscribe());
+        let node = ThalamusNode::new("abc".to_string(), "0.0.14".to_string(), "127.0.0.1".to_string(), 8050);
+        emit(NodeEvent::new(EventKind::Offline, &node));
+
+        let mut frame = vec![0u8; 8192];
+        let n = stream.read(&mut frame).unwrap();
+        let frame = String::from_utf8_lossy(&frame[..n]).to_string();
+        assert!(frame.starts_with("event: offline\ndata: {\"event\":\"offline\",\"pid\":\"abc\""));
+
+        let open = STREAMS.load(Ordering::SeqCst);
+        assert!(EventStream::open(open).is_none());
+        let extra = EventStream::open(open + 1).unwrap();
+        assert!(EventStream::open(open + 1).is_none());
+        std::mem::drop(extra);
+        assert!(EventStream::open(open + 1).is_some());
+    }
+}
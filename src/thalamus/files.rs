@@ -0,0 +1,211 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// Content-addressed file store
+// POST /api/files keeps an upload in files/<sha256> with its mime type and name in
+// files/<sha256>.json, and GET /api/files/{id} serves it back. Uploading the same bytes
+// twice returns the same id. whisper, srgan, yolo and nst accept these ids in place of an
+// upload, so large media only crosses the network once.
+
+use rouille::input::post::BufferedFile;
+use rouille::post_input;
+use rouille::Request;
+use rouille::Response;
+use serde::{Serialize, Deserialize};
+use sha2::{Sha256, Digest};
+use std::fs::File;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use error_chain::error_chain;
+error_chain! {
+    foreign_links {
+        Io(std::io::Error);
+        JSONError(serde_json::Error);
+    }
+
+    errors {
+        InvalidId(id: String) {
+            description("invalid file id")
+            display("{} is not a file id, expected a sha256 hex digest", id)
+        }
+        FileNotFound(id: String) {
+            description("file not found")
+            display("no file with id {}", id)
+        }
+    }
+}
+
+/// A stored file, the sidecar next to its data
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct FileRecord {
+    /// sha256 of the contents
+    pub id: String,
+    pub mime_type: String,
+    pub size: u64,
+    /// Name the file was first uploaded with
+    pub name: Option<String>,
+    pub created_at: i64,
+}
+
+pub fn dir() -> String {
+    return crate::thalamus::paths::root("files");
+}
+
+pub fn is_valid_id(id: &str) -> bool {
+    return id.len() == 64 && id.chars().all(|c| c.is_ascii_digit() || ('a'..='f').contains(&c));
+}
+
+fn data_path(dir: &str, id: &str) -> Result<String> {
+    if !is_valid_id(id) {
+        return Err(ErrorKind::InvalidId(id.to_string()).into());
+    }
+    return Ok(Path::new(dir).join(id).display().to_string());
+}
+
+fn record_path(dir: &str, id: &str) -> Result<String> {
+    return Ok(format!("{}.json", data_path(dir, id)?));
+}
+
+/// Stores the bytes unless a file with the same contents is already there
+pub fn put(data: &[u8], name: Option<&str>, mime_type: Option<&str>) -> Result<FileRecord> {
+    return put_in(&dir(), data, name, mime_type);
+}
+
+fn put_in(dir: &str, data: &[u8], name: Option<&str>, mime_type: Option<&str>) -> Result<FileRecord> {
+    let id = format!("{:x}", Sha256::digest(data));
+    if let Ok(record) = get_in(dir, &id) {
+        return Ok(record);
+    }
+
+    // An explicit type wins, octet-stream is what most clients send when they don't know
+    let mime_type = match (mime_type, name) {
+        (Some(mime_type), _) if mime_type != "application/octet-stream" => mime_type.to_string(),
+        (_, Some(name)) if name.contains('.') => crate::thalamus::tools::find_mimetype(&name.to_string()),
+        _ => "application/octet-stream".to_string(),
+    };
+    let record = FileRecord {
+        id: id.clone(),
+        mime_type: mime_type,
+        size: data.len() as u64,
+        name: name.map(|name| name.to_string()),
+        created_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
+    };
+
+    std::fs::create_dir_all(dir)?;
+    crate::thalamus::tools::write_atomic(&data_path(dir, &id)?, data)?;
+    // Written last, a file only exists once its record does
+    crate::thalamus::tools::write_atomic(&record_path(dir, &id)?, serde_json::to_string(&record)?.as_bytes())?;
+    return Ok(record);
+}
+
+pub fn get(id: &str) -> Result<FileRecord> {
+    return get_in(&dir(), id);
+}
+
+fn get_in(dir: &str, id: &str) -> Result<FileRecord> {
+    let record_path = record_path(dir, id)?;
+    if !Path::new(&record_path).exists() || !Path::new(&data_path(dir, id)?).exists() {
+        return Err(ErrorKind::FileNotFound(id.to_string()).into());
+    }
+    return Ok(serde_json::from_str(&std::fs::read_to_string(record_path)?)?);
+}
+
+/// Path of a stored file's contents
+pub fn path(id: &str) -> Result<String> {
+    get(id)?;
+    return data_path(&dir(), id);
+}
+
+/// Copies a stored file to dest, so tools writing next to their input never touch the store
+pub fn copy_to(id: &str, dest: &str) -> Result<FileRecord> {
+    let record = get(id)?;
+    std::fs::copy(data_path(&dir(), id)?, dest)?;
+    return Ok(record);
+}
+
+/// Writes a service input to dest, taken from the upload or from the stored file named by
+/// <field>_id. Returns the name and mime type the input came with
+pub fn service_input(upload: Option<BufferedFile>, id: Option<String>, field: &str, dest: &str) -> crate::thalamus::http::Result<(String, String)> {
+    match (upload, id) {
+        (Some(_), Some(_)) => {
+            return Err(crate::thalamus::http::ErrorKind::BadRequest(format!("send either {} or {}_id, not both", field, field)).into());
+        },
+        (Some(upload), None) => {
+            std::fs::write(dest, &upload.data)?;
+            let name = upload.filename.unwrap_or_default();
+            return Ok((name, upload.mime));
+        },
+        (None, Some(id)) => {
+            let record = copy_to(&id, dest)?;
+            return Ok((record.name.unwrap_or(record.id), record.mime_type));
+        },
+        (None, None) => {
+            return Err(crate::thalamus::http::ErrorKind::BadRequest(format!("missing field: {} or {}_id", field, field)).into());
+        },
+    }
+}
+
+// POST /api/files
+pub fn handle_upload(request: &Request) -> crate::thalamus::http::Result<Response> {
+    let input = post_input!(request, {
+        file: BufferedFile,
+    })?;
+    let record = put(&input.file.data, input.file.filename.as_deref(), Some(input.file.mime.as_str()))?;
+    log::info!("Stored file {} ({} bytes, {})", record.id, record.size, record.mime_type);
+
+    let location = format!("/api/files/{}", record.id);
+    return Ok(Response::json(&record).with_status_code(201).with_unique_header("Location", location));
+}
+
+// GET /api/files/{id}, contents never change so the id doubles as the etag
+pub fn handle_download(request: &Request, id: &str) -> crate::thalamus::http::Result<Response> {
+    let record = get(id)?;
+    let file = File::open(data_path(&dir(), id)?)?;
+    return Ok(Response::from_file(record.mime_type, file).with_etag(request, record.id).with_public_cache(365 * 24 * 60 * 60));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_ids() {
+        let id = format!("{:x}", Sha256::digest(b"thalamus"));
+        assert!(is_valid_id(&id));
+        assert!(!is_valid_id(&id.to_uppercase()));
+        assert!(!is_valid_id("../../etc/passwd"));
+        assert!(data_path(&dir(), "../keys.json").is_err());
+    }
+
+    #[test]
+    fn test_put_get() {
+        let dir = std::env::temp_dir().join(format!("thalamus-files-{}", std::process::id())).display().to_string();
+        let _ = std::fs::remove_dir_all(&dir);
+
+        let record = put_in(&dir, b"thalamus", Some("notes.txt"), Some("application/octet-stream")).unwrap();
+        assert_eq!(record.id, format!("{:x}", Sha256::digest(b"thalamus")));
+        assert_eq!(record.mime_type, "text/plain");
+        assert_eq!(record.size, 8);
+        assert_eq!(record.name, Some("notes.txt".to_string()));
+        assert_eq!(std::fs::read(data_path(&dir, &record.id).unwrap()).unwrap(), b"thalamus");
+
+        let sidecar: FileRecord = serde_json::from_str(&std::fs::read_to_string(format!("{}/{}.json", dir, record.id)).unwrap()).unwrap();
+        assert_eq!(sidecar, record);
+        assert_eq!(get_in(&dir, &record.id).unwrap(), record);
+
+        // The same bytes under another name keep the first upload's record
+        let again = put_in(&dir, b"thalamus", Some("copy.bin"), None).unwrap();
+        assert_eq!(again, record);
+        assert_eq!(std::fs::read_dir(&dir).unwrap().count(), 2);
+
+        assert!(get_in(&dir, &format!("{:x}", Sha256::digest(b"missing"))).is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
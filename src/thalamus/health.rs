@@ -0,0 +1,221 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// Liveness and readiness reports for load balancers and the systemd watchdog
+// /api/health only says the process is serving, /api/ready checks what each service
+// needs on disk (binaries, models by size then sha256) and the OpenTTS container.
+
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::os::unix::fs::PermissionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime};
+
+use crate::thalamus::tools::Artifact;
+
+pub fn opentts_url() -> String {
+    return format!("http://localhost:{}/api/voices", crate::thalamus::config::tts().opentts_port);
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HealthReply {
+    pub status: String,
+    pub version: String,
+    pub pid: u32,
+    pub uptime: i64,
+}
+
+/// One dependency of a service
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Check {
+    pub name: String,
+    pub ok: bool,
+    pub detail: Option<String>,
+}
+impl Check {
+    fn ok(name: &str) -> Check {
+        Check { name: name.to_string(), ok: true, detail: None }
+    }
+
+    fn failed(name: &str, detail: String) -> Check {
+        Check { name: name.to_string(), ok: false, detail: Some(detail) }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ServiceReadiness {
+    pub service: String,
+    pub ready: bool,
+    pub checks: Vec<Check>,
+}
+impl ServiceReadiness {
+    fn new(service: &str, checks: Vec<Check>) -> ServiceReadiness {
+        ServiceReadiness {
+            service: service.to_string(),
+            ready: checks.iter().all(|check| check.ok),
+            checks: checks,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ReadyReport {
+    pub ready: bool,
+    pub services: Vec<ServiceReadiness>,
+}
+
+pub fn check_binary(path: &str) -> Check {
+    match std::fs::metadata(path) {
+        Ok(metadata) => {
+            if metadata.permissions().mode() & 0o111 == 0 {
+                return Check::failed(path, "not executable".to_string());
+            }
+            return Check::ok(path);
+        },
+        Err(e) => return Check::failed(path, format!("{}", e)),
+    }
+}
+
+pub fn check_file(path: &str) -> Check {
+    if Path::new(path).exists() {
+        return Check::ok(path);
+    }
+    return Check::failed(path, "missing".to_string());
+}
+
+// sha256 of large models keyed by path, only recomputed when the file changes
+static HASHES: Mutex<Option<HashMap<String, (SystemTime, u64, String)>>> = Mutex::new(None);
+
+fn cached_hash(path: &str, modified: SystemTime, size: u64) -> Option<String> {
+    let hashes = HASHES.lock().unwrap();
+    match hashes.as_ref().and_then(|hashes| hashes.get(path)) {
+        Some((cached_modified, cached_size, hash)) if *cached_modified == modified && *cached_size == size => return Some(hash.clone()),
+        _ => return None,
+    }
+}
+
+/// Same policy as safe_download: a matching size is trusted, otherwise the sha256 has to match
+pub fn check_artifact(artifact: &Artifact) -> Check {
+    let local_path = artifact.local_path();
+    let path = local_path.as_str();
+    let metadata = match std::fs::metadata(path) {
+        Ok(metadata) => metadata,
+        Err(e) => return Check::failed(path, format!("{}", e)),
+    };
+
+    if let Some(size) = artifact.size {
+        if metadata.len() as i64 == size {
+            return Check::ok(path);
+        }
+    }
+
+    let expected = match artifact.sha256 {
+        Some(expected) => expected,
+        None => match artifact.size {
+            Some(size) => return Check::failed(path, format!("expected {} bytes, found {}", size, metadata.len())),
+            None => return Check::ok(path),
+        },
+    };
+
+    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+    let hash = match cached_hash(path, modified, metadata.len()) {
+        Some(hash) => hash,
+        None => match crate::thalamus::tools::hash_check(path) {
+            Ok(hash) => {
+                let mut hashes = HASHES.lock().unwrap();
+                hashes.get_or_insert_with(HashMap::new).insert(path.to_string(), (modified, metadata.len(), hash.clone()));
+                hash
+            },
+            Err(e) => return Check::failed(path, format!("unable to hash: {}", e)),
+        },
+    };
+
+    if hash != expected {
+        return Check::failed(path, format!("sha256 mismatch, expected {} found {}", expected, hash));
+    }
+    return Check::ok(path);
+}
+
+pub fn check_opentts() -> Check {
+    let client = match reqwest::blocking::Client::builder().timeout(Duration::from_secs(2)).build() {
+        Ok(client) => client,
+        Err(e) => return Check::failed("opentts", format!("{}", e)),
+    };
+    let url = opentts_url();
+    match client.get(url.as_str()).send() {
+        Ok(response) if response.status().is_success() => return Check::ok("opentts"),
+        Ok(response) => return Check::failed("opentts", format!("{} answered {}", url, response.status())),
+        Err(e) => return Check::failed("opentts", format!("{}", e)),
+    }
+}
+
+pub fn services() -> Vec<ServiceReadiness> {
+    let mut services = Vec::new();
+
+    let mut whisper: Vec<Check> = crate::thalamus::services::whisper::BINARIES.iter().map(|name| check_binary(crate::thalamus::paths::bin(name).as_str())).collect();
+    whisper.extend(crate::thalamus::services::whisper::ARTIFACTS.iter().map(check_artifact));
+    services.push(ServiceReadiness::new("whisper", whisper));
+
+    let mut llama: Vec<Check> = crate::thalamus::services::llama::BINARIES.iter().map(|name| check_binary(crate::thalamus::paths::bin(name).as_str())).collect();
+    llama.extend(crate::thalamus::services::llama::ARTIFACTS.iter().map(check_artifact));
+    services.push(ServiceReadiness::new("llama", llama));
+
+    let srgan: Vec<Check> = crate::thalamus::services::image::srgan::BINARIES.iter().map(|name| check_binary(crate::thalamus::paths::bin(name).as_str())).collect();
+    services.push(ServiceReadiness::new("srgan", srgan));
+
+    let mut yolo: Vec<Check> = crate::thalamus::services::image::yolo::BINARIES.iter().map(|name| check_binary(crate::thalamus::paths::bin(name).as_str())).collect();
+    yolo.extend(crate::thalamus::services::image::yolo::model_files().iter().map(|path| check_file(path)));
+    services.push(ServiceReadiness::new("yolov7", yolo));
+
+    services.push(ServiceReadiness::new("tts", vec![check_opentts()]));
+
+    #[cfg(feature = "pytorch")]
+    {
+        let nst: Vec<Check> = crate::thalamus::services::image::nst::ARTIFACTS.iter().map(check_artifact).collect();
+        services.push(ServiceReadiness::new("nst", nst));
+    }
+
+    services.retain(|s| crate::thalamus::config::service(&s.service).enabled);
+    return services;
+}
+
+/// Readiness of every service, or just the named one
+pub fn ready(service: Option<&str>) -> ReadyReport {
+    let services: Vec<ServiceReadiness> = services().into_iter()
+        .filter(|s| service.map_or(true, |name| s.service == name))
+        .collect();
+    return ReadyReport {
+        ready: services.len() > 0 && services.iter().all(|s| s.ready),
+        services: services,
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_artifact() {
+        let path = std::env::temp_dir().join(format!("thalamus_health_{}.bin", std::process::id()));
+        std::fs::write(&path, b"thalamus").unwrap();
+        let path: &'static str = Box::leak(path.display().to_string().into_boxed_str());
+
+        let sized = Artifact { path: path, url: "", sha256: None, size: Some(8) };
+        assert!(check_artifact(&sized).ok);
+
+        let hashed = Artifact { path: path, url: "", sha256: Some("2f0a5c2f1f7c4b3ad4f09b4de51d0d4e09c3e01ee75d3c0a5d5c1d1bc5ef5ba4"), size: Some(9) };
+        assert!(!check_artifact(&hashed).ok);
+
+        let missing = Artifact { path: "/nonexistent/thalamus.bin", url: "", sha256: None, size: None };
+        assert!(!check_artifact(&missing).ok);
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
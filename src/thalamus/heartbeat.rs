@@ -0,0 +1,162 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// Heartbeat
+// Probes every node in the registry on /api/thalamus/version each [heartbeat]
+// interval_secs, all at once and with timeout_secs to answer. A node has answered when
+// the reply carries its pid, so an address taken over by another node counts as a miss.
+// An answer updates last_ping and brings an offline node back online, max_misses probes
+// in a row without one take it offline, and a node that stays offline and unseen for
+// evict_after_secs is removed from the registry. Every change is emitted as a node event.
+// The [heartbeat] table is re-read before each round.
+
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::thalamus::config::HeartbeatConfig;
+use crate::thalamus::events::{EventKind, NodeEvent};
+use crate::{ThalamusClient, ThalamusNode};
+
+// Probes missed in a row, by pid
+static MISSES: Mutex<Option<HashMap<String, u32>>> = Mutex::new(None);
+
+fn probe(node: &ThalamusNode, timeout: Duration) -> Result<bool, Box<dyn std::error::Error>> {
+    let reply: crate::VersionReply = node.http_client_with_timeout(Some(timeout))?.get(node.url("/api/thalamus/version")).send()?.error_for_status()?.json()?;
+    return Ok(reply.pid == node.pid);
+}
+
+/// Applies one probe to a node and its run of misses, returning the change it made
+pub fn observe(node: &mut ThalamusNode, answered: bool, misses: &mut u32, max_misses: u32, now: i64) -> Option<EventKind> {
+    if answered {
+        *misses = 0;
+        node.last_ping = now;
+        if !node.is_online {
+            node.is_online = true;
+            return Some(EventKind::Online);
+        }
+        return None;
+    }
+    *misses += 1;
+    if node.is_online && *misses >= max_misses.max(1) {
+        node.is_online = false;
+        return Some(EventKind::Offline);
+    }
+    return None;
+}
+
+/// Offline and unseen for [heartbeat] evict_after_secs
+pub fn is_evictable(node: &ThalamusNode, config: &HeartbeatConfig, now: i64) -> bool {
+    match config.evict_after_secs {
+        Some(secs) => return !node.is_online && now - node.last_ping >= secs as i64,
+        None => return false,
+    }
+}
+
+/// Probes every peer once and applies the results, returning the events it emitted
+pub fn round(thalamus: &Arc<Mutex<ThalamusClient>>, config: &HeartbeatConfig) -> Vec<NodeEvent> {
+    let local_pid = crate::local_pid();
+    let thalamus_x = thalamus.lock().unwrap();
+    let peers: Vec<ThalamusNode> = thalamus_x.nodes.iter().filter(|node| Some(&node.pid) != local_pid.as_ref()).cloned().collect();
+    std::mem::drop(thalamus_x);
+
+    // In parallel, so dead nodes cost one timeout per round rather than one each
+    let timeout = Duration::from_secs(config.timeout_secs);
+    let answers: Vec<(String, bool)> = std::thread::scope(|scope| {
+        let probes: Vec<_> = peers.iter().map(|node| (node, scope.spawn(move || probe(node, timeout).map_err(|e| e.to_string())))).collect();
+        probes.into_iter().map(|(node, probe)| {
+            match probe.join() {
+                Ok(Ok(answered)) => (node.pid.clone(), answered),
+                Ok(Err(e)) => {
+                    log::info!("Heartbeat missed by {} ({}:{}): {}", node.pid, node.ip_address, node.port, e);
+                    (node.pid.clone(), false)
+                },
+                Err(_) => (node.pid.clone(), false),
+            }
+        }).collect()
+    });
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let mut events = Vec::new();
+    let mut misses_x = MISSES.lock().unwrap();
+    let misses = misses_x.get_or_insert_with(HashMap::new);
+    let mut thalamus_x = thalamus.lock().unwrap();
+    for (pid, answered) in answers.iter() {
+        // Nodes removed while the probes ran are skipped
+        if let Some(node) = thalamus_x.nodes.iter_mut().find(|node| node.pid == *pid) {
+            if let Some(kind) = observe(node, *answered, misses.entry(pid.clone()).or_insert(0), config.max_misses, now) {
+                events.push(NodeEvent::new(kind, node));
+            }
+        }
+    }
+    let mut evicted = Vec::new();
+    thalamus_x.nodes.retain(|node| {
+        if Some(&node.pid) != local_pid.as_ref() && is_evictable(node, config, now) {
+            evicted.push(NodeEvent::new(EventKind::Evicted, node));
+            return false;
+        }
+        return true;
+    });
+    for event in evicted.iter() {
+        misses.remove(&event.pid);
+    }
+    events.extend(evicted);
+    if answers.len() > 0 || events.len() > 0 {
+        thalamus_x.save();
+    }
+    std::mem::drop(thalamus_x);
+    std::mem::drop(misses_x);
+
+    for event in events.iter() {
+        crate::thalamus::events::emit(event.clone());
+    }
+    return events;
+}
+
+/// Runs a round every [heartbeat] interval_secs until shutdown
+pub fn spawn(thalamus: Arc<Mutex<ThalamusClient>>) {
+    std::thread::Builder::new().name("heartbeat".to_string()).spawn(move || {
+        while !crate::thalamus::shutdown::is_shutting_down() {
+            let config = crate::thalamus::config::get().heartbeat;
+            if config.enabled {
+                round(&thalamus, &config);
+            }
+            std::thread::sleep(Duration::from_secs(config.interval_secs.max(1)));
+        }
+    }).expect("failed to spawn the heartbeat");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_observe_and_evict() {
+        let mut node = ThalamusNode::new("abc".to_string(), "0.0.14".to_string(), "127.0.0.1".to_string(), 8050);
+        let mut misses = 0;
+        assert_eq!(observe(&mut node, false, &mut misses, 3, 100), None);
+        assert_eq!(observe(&mut node, false, &mut misses, 3, 130), None);
+        assert_eq!(observe(&mut node, false, &mut misses, 3, 160), Some(EventKind::Offline));
+        assert!(!node.is_online);
+        assert_eq!(observe(&mut node, false, &mut misses, 3, 190), None);
+
+        assert_eq!(observe(&mut node, true, &mut misses, 3, 200), Some(EventKind::Online));
+        assert_eq!((node.last_ping, misses, node.is_online), (200, 0, true));
+        assert_eq!(observe(&mut node, true, &mut misses, 3, 230), None);
+
+        let mut config = HeartbeatConfig::default();
+        config.evict_after_secs = Some(1000);
+        assert!(!is_evictable(&node, &config, 5000));
+        node.is_online = false;
+        assert!(!is_evictable(&node, &config, 1000));
+        assert!(is_evictable(&node, &config, 1230));
+        config.evict_after_secs = None;
+        assert!(!is_evictable(&node, &config, 1230));
+    }
+}
@@ -15,6 +15,17 @@ use rouille::Response;
 use serde::{Serialize, Deserialize};
 use std::sync::Arc;
 use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+pub mod auth;
+pub mod errors;
+pub mod forward;
+pub mod openapi;
+pub mod router;
+
+use router::{Router, RouteParams};
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
 
 // store application version as a const
 const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
@@ -30,8 +41,64 @@ error_chain! {
         JSONParseError(serde_json::Error);
         InternalServiceError(crate::thalamus::services::Error);
         ToolKitError(crate::thalamus::tools::Error);
+        ConfigError(crate::thalamus::config::Error);
+        StoreError(crate::thalamus::store::Error);
+        FilesError(crate::thalamus::files::Error);
+        StorageError(crate::thalamus::storage::Error);
+        BundleError(crate::thalamus::bundle::Error);
         // SamMemoryError(crate::sam::memory::Error);
     }
+
+    errors {
+        BadRequest(detail: String) {
+            description("bad request")
+            display("bad request: {}", detail)
+        }
+        RouteNotFound(path: String) {
+            description("route not found")
+            display("no route for {}", path)
+        }
+        MethodNotAllowed(method: String, allowed: String) {
+            description("method not allowed")
+            display("{} is not allowed, expected one of: {}", method, allowed)
+        }
+        Unauthorized(detail: String) {
+            description("unauthorized")
+            display("unauthorized: {}", detail)
+        }
+        Forbidden(detail: String) {
+            description("forbidden")
+            display("forbidden: {}", detail)
+        }
+        ShuttingDown {
+            description("shutting down")
+            display("node is shutting down")
+        }
+        TooManySubscribers(max_streams: usize) {
+            description("too many subscribers")
+            display("{} event streams are already open", max_streams)
+        }
+        PayloadTooLarge(max_bytes: u64) {
+            description("payload too large")
+            display("request body is over {} bytes", max_bytes)
+        }
+        JobNotFound(oid: String) {
+            description("job not found")
+            display("no job with oid {}", oid)
+        }
+        JobNotFinished(oid: String, status: String) {
+            description("job not finished")
+            display("job {} is {}", oid, status)
+        }
+        ServiceDisabled(service: String) {
+            description("service disabled")
+            display("{} is disabled on this node", service)
+        }
+        NoNodeAvailable(service: String) {
+            description("no node available")
+            display("no online node has benchmarked {}", service)
+        }
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -41,39 +108,420 @@ pub struct VersionHeader {
 }
 
 
-pub fn handle(request: &Request, thalamus: Arc<Mutex<crate::ThalamusClient>>) -> Result<Response> {
+/// Shared state handed to every route handler
+#[derive(Clone)]
+pub struct HttpContext {
+    pub args: crate::Args,
+    pub thalamus: Arc<Mutex<crate::ThalamusClient>>,
+    pub router: Arc<Router>,
+    pub keys: Arc<auth::KeyStore>,
+    pub jobs: Arc<crate::thalamus::jobs::JobRegistry>,
+    pub started_at: i64,
+}
+impl HttpContext {
+    pub fn new(args: crate::Args, thalamus: Arc<Mutex<crate::ThalamusClient>>) -> HttpContext {
+        let keys = auth::KeyStore::new(&crate::thalamus::paths::state("keys.json"), args.key.clone());
+        HttpContext {
+            args: args,
+            thalamus: thalamus,
+            router: Arc::new(routes()),
+            keys: Arc::new(keys),
+            jobs: Arc::new(crate::thalamus::jobs::JobRegistry::new()),
+            started_at: SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64,
+        }
+    }
+}
+
+/// The route table for every endpoint served by the node
+pub fn routes() -> Router {
+    let mut router = Router::new();
+
+    router.get("/", index);
+    router.get("/api/thalamus/version", version);
+    router.get("/api/thalamus/certificate", certificate);
+    router.get("/metrics", metrics);
+    router.get("/api/health", health);
+    router.get("/api/ready", ready);
+    router.get("/api/nodex", nodex);
+    router.post("/api/nodex/leave", nodex_leave);
+    router.get("/api/nodex/status", nodex_status);
+    router.get("/api/nodex/select", nodex_select);
+    router.get("/api/nodex/events", nodex_events);
+    router.get("/api/nodex/{pid}/benchmarks", nodex_benchmarks);
+    router.post("/api/files", |request, _, _| crate::thalamus::files::handle_upload(request));
+    router.get("/api/files/{id}", |request, params, _| crate::thalamus::files::handle_download(request, params.get("id").unwrap_or_default()));
+    router.get("/api/jobs", jobs);
+    router.get("/api/jobs/{oid}", job);
+    router.get("/api/jobs/{oid}/result", job_result);
+    router.get("/api/openapi.json", openapi_json);
+    router.get("/api/docs", openapi_docs);
+    router.post("/api/admin/reload", admin_reload);
+    router.get("/api/admin/storage", admin_storage);
+    router.post("/api/admin/storage/evict", admin_storage_evict);
+    router.get("/api/admin/export", admin_export);
+    router.post("/api/admin/import", admin_import);
+
+    router.post("/api/services/llama", |request, _, context| { enabled("llama")?; crate::thalamus::services::llama::handle(request, context) });
+    router.post("/api/services/whisper", |request, _, context| { enabled("whisper")?; crate::thalamus::services::whisper::handle_stt(request, context) });
+    router.post("/api/services/whisper/vwav", |request, _, context| { enabled("whisper")?; crate::thalamus::services::whisper::handle_vwav(request, context) });
+    router.get("/api/services/tts", |request, _, context| { enabled("tts")?; crate::thalamus::services::tts::handle(request, context) });
+    router.post("/api/services/tts", |request, _, context| { enabled("tts")?; crate::thalamus::services::tts::handle(request, context) });
+    router.get("/api/services/tts/voices", |request, _, _| { enabled("tts")?; crate::thalamus::services::tts::handle_voices(request) });
+    router.post("/api/services/image/srgan", |request, _, context| { enabled("srgan")?; crate::thalamus::services::image::srgan::handle(request, context) });
+    router.post("/api/services/image/yolo/v7", |request, _, context| { enabled("yolov7")?; crate::thalamus::services::image::yolo::handle(request, context) });
+
+    #[cfg(feature = "pytorch")]
+    {
+        router.get("/api/services/image/nst/styles", |request, _, _| { enabled("nst")?; crate::thalamus::services::image::nst::handle_styles(request) });
+        router.post("/api/services/image/nst/run", |request, _, context| { enabled("nst")?; crate::thalamus::services::image::nst::handle_run(request, context) });
+    }
+
+    return router;
+}
+
+pub fn handle(request: &Request, context: &HttpContext) -> Result<Response> {
+    let _in_flight = crate::thalamus::shutdown::InFlight::start();
+    if crate::thalamus::shutdown::is_shutting_down() {
+        return Err(ErrorKind::ShuttingDown.into());
+    }
+
+    if let Some(key_name) = context.keys.authorize(request)? {
+        log::info!("HTTP: {} {} authorized with key '{}'", request.method(), request.url(), key_name);
+    }
+    return forward::dispatch(request, context);
+}
+
+// tiny_http copies streamed bodies into a chunked encoder that is never flushed and only
+// sends once 8192 bytes (less its 6 byte header) are buffered, so every event is padded
+// with an SSE comment to exactly that size to get it onto the wire immediately. Streams
+// that produce many small events batch them first, see services::llama::FLUSH_INTERVAL
+const SSE_BLOCK: usize = 8192 - 6;
+
+/// One server-sent event, padded to go out as soon as it is written
+pub fn sse_event(event: Option<&str>, data: serde_json::Value) -> Vec<u8> {
+    let mut frame = String::new();
+    if let Some(event) = event {
+        frame.push_str(format!("event: {}\n", event).as_str());
+    }
+    frame.push_str(format!("data: {}\n\n", data).as_str());
+    if frame.len() + 2 <= SSE_BLOCK {
+        frame.push(':');
+        frame.push_str(" ".repeat(SSE_BLOCK - frame.len() - 1).as_str());
+        frame.push('\n');
+    }
+    return frame.into_bytes();
+}
+
+/// Streams a reader of sse_event frames as text/event-stream
+pub fn event_stream<R: std::io::Read + Send + 'static>(events: R) -> Response {
+    let mut response = Response::text("")
+        .with_unique_header("Content-Type", "text/event-stream")
+        .with_unique_header("Cache-Control", "no-cache")
+        .with_unique_header("X-Accel-Buffering", "no");
+    response.data = rouille::ResponseBody::from_reader(crate::thalamus::shutdown::Tracked::new(events));
+    return response;
+}
+
+/// Route pattern used to label metrics, so /api/jobs/abc and /api/jobs/def count together
+pub fn route_label(request: &Request, context: &HttpContext) -> String {
+    match context.router.find(request.method(), request.url().as_str()) {
+        router::RouteMatch::Found(route, _) => return route.pattern.clone(),
+        _ => return "unmatched".to_string(),
+    }
+}
+
+/// Use the caller's X-Request-Id when it looks sane, otherwise mint a new one
+pub fn request_id(request: &Request) -> String {
+    match request.header("X-Request-Id") {
+        Some(id) if id.len() > 0 && id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_') => {
+            return id.to_string();
+        },
+        _ => {
+            return thread_rng().sample_iter(&Alphanumeric).take(15).map(char::from).collect();
+        }
+    }
+}
+
+fn version(_request: &Request, _params: &RouteParams, _context: &HttpContext) -> Result<Response> {
+    let pid = std::fs::read_to_string(&crate::thalamus::paths::state("pid"))?;
+    return Ok(Response::json(&VersionHeader{version: VERSION.ok_or("UNKNOWN")?.to_string(), pid: pid}));
+}
+
+fn metrics(_request: &Request, _params: &RouteParams, context: &HttpContext) -> Result<Response> {
+    use crate::thalamus::metrics::Sample;
+    let mut gauges: Vec<Sample> = Vec::new();
+
+    let jobs = context.jobs.list();
+    for status in [crate::thalamus::jobs::STATUS_QUEUED, crate::thalamus::jobs::STATUS_RUNNING, crate::thalamus::jobs::STATUS_COMPLETED, crate::thalamus::jobs::STATUS_FAILED] {
+        let count = jobs.iter().filter(|job| job.status.as_deref() == Some(status)).count();
+        gauges.push(Sample { name: "thalamus_jobs", labels: vec![("status", status.to_string())], value: count as f64 });
+    }
+
+    let thalamus_x = context.thalamus.lock().unwrap();
+    let nodes = thalamus_x.nodes.clone();
+    std::mem::drop(thalamus_x);
+
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let online = nodes.iter().filter(|node| node.is_online).count();
+    gauges.push(Sample { name: "thalamus_mesh_nodes", labels: vec![("state", "online".to_string())], value: online as f64 });
+    gauges.push(Sample { name: "thalamus_mesh_nodes", labels: vec![("state", "offline".to_string())], value: (nodes.len() - online) as f64 });
+    for node in nodes.iter() {
+        gauges.push(Sample {
+            name: "thalamus_mesh_node_last_ping_age_seconds",
+            labels: vec![("node", format!("{}:{}", node.ip_address, node.port))],
+            value: (now - node.last_ping) as f64,
+        });
+    }
+
+    return Ok(Response::from_data("text/plain; version=0.0.4", crate::thalamus::metrics::render(gauges)));
+}
+
+fn health(_request: &Request, _params: &RouteParams, context: &HttpContext) -> Result<Response> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    return Ok(Response::json(&crate::thalamus::health::HealthReply {
+        status: "ok".to_string(),
+        version: VERSION.unwrap_or("UNKNOWN").to_string(),
+        pid: std::process::id(),
+        uptime: now - context.started_at,
+    }));
+}
+
+// 503 until every service (or ?service=<name>) has what it needs installed
+fn ready(request: &Request, _params: &RouteParams, _context: &HttpContext) -> Result<Response> {
+    let service = request.get_param("service");
+    let report = crate::thalamus::health::ready(service.as_deref());
+    let status = if report.ready { 200 } else { 503 };
+    return Ok(Response::json(&report).with_status_code(status));
+}
+
+// Services can be switched off in thalamus.toml without a restart
+fn enabled(service: &str) -> Result<()> {
+    if !crate::thalamus::config::service(service).enabled {
+        return Err(ErrorKind::ServiceDisabled(service.to_string()).into());
+    }
+    return Ok(());
+}
+
+// Same as sending the process a SIGHUP
+fn admin_reload(request: &Request, _params: &RouteParams, context: &HttpContext) -> Result<Response> {
+    let key_name = context.keys.authorize_admin(request)?;
+    log::warn!("Reloading the config for key '{}'", key_name);
+    return Ok(Response::json(&crate::thalamus::config::reload()?));
+}
+
+// Signed with this node's --key
+// keys.json only goes in with ?include_keys=true
+fn admin_export(request: &Request, _params: &RouteParams, context: &HttpContext) -> Result<Response> {
+    let key_name = context.keys.authorize_admin(request)?;
+    let include_keys = request.get_param("include_keys").map_or(false, |value| value == "true" || value == "1");
+    log::warn!("Exporting a bundle for key '{}'{}", key_name, if include_keys { " with keys.json" } else { "" });
+
+    let thalamus_x = context.thalamus.lock().unwrap();
+    let client = thalamus_x.clone();
+    std::mem::drop(thalamus_x);
+
+    let bundle = crate::thalamus::bundle::export(&client, &context.args.key, include_keys)?;
+    return Ok(Response::json(&bundle).with_unique_header("Content-Disposition", "attachment; filename=\"thalamus-bundle.json\""));
+}
+
+// Nodes join the running registry, thalamus.toml and with ?restore_identity=true the
+// exporter's pid apply on the next start
+fn admin_import(request: &Request, _params: &RouteParams, context: &HttpContext) -> Result<Response> {
+    let key_name = context.keys.authorize_admin(request)?;
+    log::warn!("Importing a bundle for key '{}'", key_name);
+    let input = rouille::post_input!(request, {
+        bundle: rouille::input::post::BufferedFile,
+    })?;
+    let bundle: crate::thalamus::bundle::SignedBundle = serde_json::from_slice(&input.bundle.data).map_err(|e| ErrorKind::BadRequest(format!("not a bundle: {}", e)))?;
+    let config_path = crate::thalamus::config::path(&context.args);
 
-    if request.url().contains("/api/thalamus/version"){
-        let pid = std::fs::read_to_string("/opt/thalamus/pid").expect("Unable to read file");
-        return Ok(Response::json(&VersionHeader{version: VERSION.ok_or("UNKNOWN")?.to_string(), pid: pid}));
+    let mut thalamus_x = context.thalamus.lock().unwrap();
+    let restore_identity = request.get_param("restore_identity").map_or(false, |value| value == "true" || value == "1");
+    let report = crate::thalamus::bundle::import(&bundle, &context.args.key, &mut thalamus_x, &config_path, restore_identity)?;
+    thalamus_x.save();
+    std::mem::drop(thalamus_x);
+
+    return Ok(Response::json(&report));
+}
+
+fn admin_storage(request: &Request, _params: &RouteParams, context: &HttpContext) -> Result<Response> {
+    context.keys.authorize_admin(request)?;
+    return Ok(Response::json(&crate::thalamus::storage::report()?));
+}
+
+// Evicts one model by name, or every model idle for unused_for_secs and then the least
+// recently used ones until the models directory is back under budget
+fn admin_storage_evict(request: &Request, _params: &RouteParams, context: &HttpContext) -> Result<Response> {
+    let key_name = context.keys.authorize_admin(request)?;
+    log::warn!("Evicting models for key '{}'", key_name);
+    let input = rouille::post_input!(request, {
+        unused_for_secs: Option<i64>,
+        model: Option<String>,
+    })?;
+    match input.model {
+        Some(model) => return Ok(Response::json(&crate::thalamus::storage::evict_model(&model)?)),
+        None => return Ok(Response::json(&crate::thalamus::storage::evict(input.unused_for_secs)?)),
     }
+}
 
-    if request.url().contains("/api/services/image"){
-        return Ok(crate::thalamus::services::image::handle(request)?);
+// Lets peers fetch our certificate to build their --tls-pin bundle
+fn certificate(request: &Request, _params: &RouteParams, context: &HttpContext) -> Result<Response> {
+    if !context.args.tls {
+        return Err(ErrorKind::RouteNotFound(request.url()).into());
     }
+    let (cert_path, _key_path) = crate::thalamus::tls::paths(&context.args);
+    let cert = std::fs::read(cert_path)?;
+    return Ok(Response::from_data("application/x-pem-file", cert));
+}
+
+fn nodex(_request: &Request, _params: &RouteParams, context: &HttpContext) -> Result<Response> {
+    let thalamus_x = context.thalamus.lock().unwrap();
+    let thx_clone = thalamus_x.clone();
+    std::mem::drop(thalamus_x);
+
+    return Ok(Response::json(&thx_clone.nodes));
+}
+
+// A peer announcing it is shutting down, identified by its address and the port it serves on
+fn nodex_leave(request: &Request, _params: &RouteParams, context: &HttpContext) -> Result<Response> {
+    let input = rouille::post_input!(request, {
+        port: u16,
+    })?;
+    let ip_address = request.remote_addr().ip().to_string();
 
-    if request.url().contains("/api/nodex"){
-        let thalamus_x = thalamus.lock().unwrap();
-        let thx_clone = thalamus_x.clone();
-        std::mem::drop(thalamus_x);
-        
-        return Ok(Response::json(&thx_clone.nodes));
+    let mut thalamus_x = context.thalamus.lock().unwrap();
+    let mut left = Vec::new();
+    for node in thalamus_x.nodes.iter_mut() {
+        if node.ip_address == ip_address && node.port == input.port {
+            if node.is_online {
+                left.push(crate::thalamus::events::NodeEvent::new(crate::thalamus::events::EventKind::Offline, node));
+            }
+            node.is_online = false;
+        }
+    }
+    if left.len() > 0 {
+        log::warn!("Node {}:{} left the mesh", ip_address, input.port);
+        thalamus_x.save();
     }
+    std::mem::drop(thalamus_x);
 
-    if request.url().contains("/api/services/llama"){
-        return Ok(crate::thalamus::services::llama::handle(request)?);
+    for event in left {
+        crate::thalamus::events::emit(event);
     }
 
-    if request.url().contains("/api/services/whisper"){
-        return Ok(crate::thalamus::services::whisper::handle(request)?);
+    return Ok(Response::empty_204());
+}
+
+// Fastest online node for ?service= and optionally ?model=, by benchmark score
+fn nodex_select(request: &Request, _params: &RouteParams, context: &HttpContext) -> Result<Response> {
+    let service = request.get_param("service").ok_or(ErrorKind::BadRequest("service is required".to_string()))?;
+    let model = request.get_param("model");
+    let max_age_secs = match request.get_param("max_age_secs") {
+        Some(value) => value.parse::<i64>().map_err(|_| ErrorKind::BadRequest("max_age_secs must be a number".to_string()))?,
+        None => crate::STALE_PING_SECS,
+    };
+
+    let thalamus_x = context.thalamus.lock().unwrap();
+    let ranking = thalamus_x.rank_nodes(&service, model.as_deref(), max_age_secs);
+    std::mem::drop(thalamus_x);
+
+    let ranking = ranking.map_err(|e| ErrorKind::BadRequest(e.to_string()))?;
+    match ranking.into_iter().next() {
+        Some(ranked) => return Ok(Response::json(&ranked)),
+        None => return Err(ErrorKind::NoNodeAvailable(format!("{}{}", service, model.map(|m| format!(" {}", m)).unwrap_or_default())).into()),
     }
+}
 
-    if request.url().contains("/api/services/tts"){
-        return Ok(crate::thalamus::services::tts::handle(request)?);
+// Node state changes as they happen, as server-sent events
+// Half the web server threads at most, so subscribers can't starve every other request
+fn nodex_events(_request: &Request, _params: &RouteParams, context: &HttpContext) -> Result<Response> {
+    let max_streams = context.args.max_threads as usize / 2;
+    let events = crate::thalamus::events::EventStream::open(max_streams).ok_or(ErrorKind::TooManySubscribers(max_streams))?;
+    return Ok(event_stream(events));
+}
+
+// Unix time from the query string, or the default when it is missing
+fn unix_param(request: &Request, name: &str, default: i64) -> Result<i64> {
+    match request.get_param(name) {
+        Some(value) => return Ok(value.parse::<i64>().map_err(|_| ErrorKind::BadRequest(format!("{} must be a unix time", name)))?),
+        None => return Ok(default),
+    }
+}
+
+fn store() -> Result<Arc<crate::thalamus::store::Store>> {
+    return Ok(crate::thalamus::store::get().ok_or("the store is not open")?);
+}
+
+// Status changes and the nodes that were offline in a window, the last day by default
+fn nodex_status(request: &Request, _params: &RouteParams, _context: &HttpContext) -> Result<Response> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let from = unix_param(request, "from", now - 24 * 60 * 60)?;
+    let to = unix_param(request, "to", now)?;
+
+    let store = store()?;
+    return Ok(Response::json(&crate::thalamus::store::StatusReport {
+        from: from,
+        to: to,
+        offline: store.offline_between(from, to)?,
+        changes: store.status_history(from, to)?,
+    }));
+}
+
+// Scores of one stats metric for one node, the last week by default
+fn nodex_benchmarks(request: &Request, params: &RouteParams, _context: &HttpContext) -> Result<Response> {
+    let pid = params.get("pid").unwrap_or_default();
+    let metric = request.get_param("metric").ok_or(ErrorKind::BadRequest("metric is required".to_string()))?;
+    if !crate::thalamus::store::metrics().contains(&metric) {
+        return Err(ErrorKind::BadRequest(format!("unknown metric {}", metric)).into());
+    }
+    let now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let since = unix_param(request, "since", now - 7 * 24 * 60 * 60)?;
+    return Ok(Response::json(&store()?.benchmark_history(pid, &metric, since)?));
+}
+
+fn jobs(_request: &Request, _params: &RouteParams, context: &HttpContext) -> Result<Response> {
+    return Ok(Response::json(&context.jobs.list()));
+}
+
+fn job(_request: &Request, params: &RouteParams, context: &HttpContext) -> Result<Response> {
+    let oid = params.get("oid").unwrap_or_default();
+    match context.jobs.get(oid) {
+        Some(entry) => return Ok(Response::json(&entry.job)),
+        None => return Err(ErrorKind::JobNotFound(oid.to_string()).into()),
+    }
+}
+
+fn job_result(request: &Request, params: &RouteParams, context: &HttpContext) -> Result<Response> {
+    let oid = params.get("oid").unwrap_or_default();
+    let entry = context.jobs.get(oid).ok_or(ErrorKind::JobNotFound(oid.to_string()))?;
+
+    if let Some(failure) = entry.failure {
+        let reply = errors::ErrorReply {
+            code: failure.code,
+            message: failure.message,
+            request_id: request_id(request),
+        };
+        return Ok(Response::json(&reply).with_status_code(failure.status));
     }
 
+    match entry.output {
+        Some(output) => return output.into_response(),
+        None => return Err(ErrorKind::JobNotFinished(oid.to_string(), entry.job.status.unwrap_or_default()).into()),
+    }
+}
+
+fn openapi_json(_request: &Request, _params: &RouteParams, context: &HttpContext) -> Result<Response> {
+    return Ok(Response::json(&openapi::spec(&context.router)));
+}
 
+fn openapi_docs(request: &Request, _params: &RouteParams, context: &HttpContext) -> Result<Response> {
+    if !context.args.api_docs {
+        return Err(ErrorKind::RouteNotFound(request.url()).into());
+    }
+    return Ok(Response::html(openapi::docs_html()));
+}
 
+fn index(_request: &Request, _params: &RouteParams, _context: &HttpContext) -> Result<Response> {
     return Ok(Response::html(format!("<pre> 
 ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████
    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██     
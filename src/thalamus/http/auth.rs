@@ -0,0 +1,233 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// Bearer token authentication for the web api
+// Keys come from --key (named "default") plus keys.json in the state directory, which is re-read
+// whenever it changes on disk so keys can be rotated without restarting the node:
+//
+// {
+//     "keys": [{"name": "frontend", "key": "..."}, {"name": "ops", "key": "...", "admin": true}],
+//     "allowlist": ["/api/services/tts/voices"],
+//     "disable_cli_key": false
+// }
+//
+// Exporting and importing node bundles, the storage report and eviction and reloading the
+// config also need an admin key, --key never is one as every peer in the mesh holds it.
+
+use rouille::Request;
+use serde::{Serialize, Deserialize};
+use std::sync::Mutex;
+use std::time::SystemTime;
+
+use crate::thalamus::http::{ErrorKind, Result};
+
+/// --key out of the box, the node refuses to start with it unless --allow-default-key
+pub const DEFAULT_KEY: &str = "thalamus";
+
+// Everything under these prefixes needs a key unless it is allowlisted
+pub const PROTECTED_PREFIXES: [&str; 1] = ["/api/"];
+
+// Always reachable without a key so peers can discover each other
+pub const DEFAULT_ALLOWLIST: [&str; 6] = ["/api/thalamus/version", "/api/thalamus/certificate", "/api/health", "/api/ready", "/api/openapi.json", "/api/docs"];
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    pub name: String,
+    pub key: String,
+    /// Allowed to export and import node bundles
+    #[serde(default)]
+    pub admin: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct KeysFile {
+    #[serde(default)]
+    pub keys: Vec<ApiKey>,
+    #[serde(default)]
+    pub allowlist: Vec<String>,
+    #[serde(default)]
+    pub disable_cli_key: bool,
+}
+
+pub struct KeyStore {
+    pub path: String,
+    cli_key: String,
+    cache: Mutex<Option<(SystemTime, KeysFile)>>,
+}
+impl KeyStore {
+    pub fn new(path: &str, cli_key: String) -> KeyStore {
+        KeyStore {
+            path: path.to_string(),
+            cli_key: cli_key,
+            cache: Mutex::new(None),
+        }
+    }
+
+    /// Current keys file, re-read from disk if it was modified since the last request
+    pub fn keys_file(&self) -> KeysFile {
+        let modified = match std::fs::metadata(self.path.as_str()).and_then(|m| m.modified()) {
+            Ok(modified) => modified,
+            Err(_) => return KeysFile::default(),
+        };
+
+        let mut cache = self.cache.lock().unwrap();
+        if let Some((cached_at, keys)) = cache.as_ref() {
+            if *cached_at == modified {
+                return keys.clone();
+            }
+        }
+
+        let keys: KeysFile = match std::fs::read_to_string(self.path.as_str()) {
+            Ok(data) => match serde_json::from_str(&data) {
+                Ok(keys) => keys,
+                Err(e) => {
+                    log::error!("Unable to parse {}: {}", self.path, e);
+                    return cache.as_ref().map(|(_, keys)| keys.clone()).unwrap_or_default();
+                }
+            },
+            Err(e) => {
+                log::error!("Unable to read {}: {}", self.path, e);
+                return cache.as_ref().map(|(_, keys)| keys.clone()).unwrap_or_default();
+            }
+        };
+
+        log::warn!("Loaded {} api keys from {}", keys.keys.len(), self.path);
+        *cache = Some((modified, keys.clone()));
+        return keys;
+    }
+
+    pub fn keys(&self) -> Vec<ApiKey> {
+        let file = self.keys_file();
+        let mut keys = Vec::new();
+        if !file.disable_cli_key && self.cli_key.len() > 0 {
+            keys.push(ApiKey { name: "default".to_string(), key: self.cli_key.clone(), admin: false });
+        }
+        keys.extend(file.keys);
+        return keys;
+    }
+
+    pub fn is_protected(&self, path: &str) -> bool {
+        // Normalized so //api/... can't slip past the prefix check
+        let path = crate::thalamus::http::router::normalize(path);
+        if !PROTECTED_PREFIXES.iter().any(|prefix| path.starts_with(prefix)) {
+            return false;
+        }
+        if DEFAULT_ALLOWLIST.contains(&path.as_str()) {
+            return false;
+        }
+        return !self.keys_file().allowlist.iter().any(|allowed| crate::thalamus::http::router::normalize(allowed) == path);
+    }
+
+    /// True while the default key is accepted, keys.json can turn it off with disable_cli_key
+    pub fn accepts_default_key(&self) -> bool {
+        return self.cli_key == DEFAULT_KEY && !self.keys_file().disable_cli_key;
+    }
+
+    /// Checks the bearer token, returns the name of the matching key if the route needs one
+    pub fn authorize(&self, request: &Request) -> Result<Option<String>> {
+        if !self.is_protected(request.url().as_str()) {
+            return Ok(None);
+        }
+
+        let token = bearer_token(request)?;
+        for key in self.keys() {
+            if constant_time_eq(key.key.as_bytes(), token.as_bytes()) {
+                return Ok(Some(key.name));
+            }
+        }
+
+        return Err(ErrorKind::Unauthorized("invalid api key".to_string()).into());
+    }
+
+    /// Checks the bearer token is an admin key, returns its name
+    pub fn authorize_admin(&self, request: &Request) -> Result<String> {
+        let token = bearer_token(request)?;
+        for key in self.keys() {
+            if constant_time_eq(key.key.as_bytes(), token.as_bytes()) {
+                if !key.admin {
+                    return Err(ErrorKind::Forbidden(format!("key '{}' is not an admin key", key.name)).into());
+                }
+                return Ok(key.name);
+            }
+        }
+
+        return Err(ErrorKind::Unauthorized("invalid api key".to_string()).into());
+    }
+}
+
+fn bearer_token(request: &Request) -> Result<&str> {
+    match request.header("Authorization") {
+        Some(header) => match header.strip_prefix("Bearer ") {
+            Some(token) => return Ok(token.trim()),
+            None => return Err(ErrorKind::Unauthorized("expected a Bearer token".to_string()).into()),
+        },
+        None => return Err(ErrorKind::Unauthorized("missing Authorization header".to_string()).into()),
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    if a.len() != b.len() {
+        return false;
+    }
+    let mut diff = 0u8;
+    for (x, y) in a.iter().zip(b.iter()) {
+        diff |= x ^ y;
+    }
+    return diff == 0;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_allowlist() {
+        let keys = KeyStore::new("/nonexistent/keys.json", "thalamus".to_string());
+        assert!(!keys.is_protected("/"));
+        assert!(!keys.is_protected("/api/thalamus/version"));
+        assert!(!keys.is_protected("/api/thalamus/version/"));
+        assert!(keys.is_protected("/api/services/llama"));
+        assert!(keys.is_protected("/api/nodex"));
+        assert!(keys.is_protected("//api/nodex"));
+        assert!(keys.is_protected("//api//services/llama"));
+        assert!(!keys.is_protected("//api/thalamus/version"));
+        assert_eq!(keys.keys().len(), 1);
+        assert!(keys.accepts_default_key());
+
+        let request = Request::fake_http("POST", "//api/services/llama", vec![], vec![]);
+        match keys.authorize(&request) {
+            Err(e) => assert_eq!(crate::thalamus::http::errors::classify(&e).status, 401),
+            Ok(name) => panic!("expected 401, authorized as {:?}", name),
+        }
+        let request = Request::fake_http("GET", "//api/nodex", vec![("Authorization".to_string(), "Bearer thalamus".to_string())], vec![]);
+        assert_eq!(keys.authorize(&request).unwrap(), Some("default".to_string()));
+    }
+
+    #[test]
+    fn test_authorize_admin() {
+        let path = std::env::temp_dir().join(format!("thalamus-keys-{}.json", std::process::id()));
+        std::fs::write(&path, r#"{"keys": [{"name": "frontend", "key": "front"}, {"name": "ops", "key": "secret", "admin": true}]}"#).unwrap();
+        let keys = KeyStore::new(path.to_str().unwrap(), "mesh".to_string());
+        let request = |token: &str| Request::fake_http("GET", "/api/admin/export", vec![("Authorization".to_string(), format!("Bearer {}", token))], vec![]);
+        let status = |result: Result<String>| crate::thalamus::http::errors::classify(&result.unwrap_err()).status;
+
+        assert_eq!(keys.authorize_admin(&request("secret")).unwrap(), "ops");
+        assert_eq!(status(keys.authorize_admin(&request("front"))), 403);
+        assert_eq!(status(keys.authorize_admin(&request("mesh"))), 403);
+        assert_eq!(status(keys.authorize_admin(&request("nope"))), 401);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"thalamus", b"thalamus"));
+        assert!(!constant_time_eq(b"thalamus", b"thalamux"));
+        assert!(!constant_time_eq(b"thalamus", b"thal"));
+    }
+}
@@ -0,0 +1,219 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// Maps http/services/tools errors onto status codes and a JSON error body
+// 4xx means the caller has to fix the request, 503/504 are safe to retry
+
+use rouille::Response;
+use serde::{Serialize, Deserialize};
+
+use crate::thalamus::http::{Error, ErrorKind};
+
+/// JSON body returned with every failed request
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorReply {
+    pub code: String,
+    pub message: String,
+    pub request_id: String,
+}
+
+/// Status code and machine readable code for an error
+#[derive(Debug, Clone, PartialEq)]
+pub struct ErrorClass {
+    pub status: u16,
+    pub code: &'static str,
+}
+impl ErrorClass {
+    fn new(status: u16, code: &'static str) -> ErrorClass {
+        ErrorClass { status: status, code: code }
+    }
+
+    pub fn is_retryable(&self) -> bool {
+        return self.status == 503 || self.status == 504;
+    }
+}
+
+pub fn classify(err: &Error) -> ErrorClass {
+    match err.kind() {
+        ErrorKind::BadRequest(_) => ErrorClass::new(400, "bad_request"),
+        ErrorKind::PostError(_) => ErrorClass::new(400, "invalid_input"),
+        ErrorKind::Unauthorized(_) => ErrorClass::new(401, "unauthorized"),
+        ErrorKind::Forbidden(_) => ErrorClass::new(403, "forbidden"),
+        ErrorKind::ShuttingDown => ErrorClass::new(503, "shutting_down"),
+        ErrorKind::PayloadTooLarge(_) => ErrorClass::new(413, "payload_too_large"),
+        ErrorKind::TooManySubscribers(_) => ErrorClass::new(503, "too_many_subscribers"),
+        ErrorKind::JobNotFound(_) => ErrorClass::new(404, "job_not_found"),
+        ErrorKind::JobNotFinished(_, _) => ErrorClass::new(409, "job_not_finished"),
+        ErrorKind::ServiceDisabled(_) => ErrorClass::new(404, "service_disabled"),
+        ErrorKind::NoNodeAvailable(_) => ErrorClass::new(503, "no_node_available"),
+        ErrorKind::ConfigError(_) => ErrorClass::new(422, "invalid_config"),
+        ErrorKind::RouteNotFound(_) => ErrorClass::new(404, "not_found"),
+        ErrorKind::MethodNotAllowed(_, _) => ErrorClass::new(405, "method_not_allowed"),
+        ErrorKind::JSONParseError(_) => ErrorClass::new(500, "invalid_output"),
+        ErrorKind::InternalServiceError(e) => classify_service(e),
+        ErrorKind::ToolKitError(e) => classify_tools(e),
+        ErrorKind::FilesError(e) => classify_files(e),
+        ErrorKind::StorageError(e) => classify_storage(e),
+        ErrorKind::BundleError(e) => classify_bundle(e),
+        _ => ErrorClass::new(500, "internal_error"),
+    }
+}
+
+pub fn classify_service(err: &crate::thalamus::services::Error) -> ErrorClass {
+    match err.kind() {
+        crate::thalamus::services::ErrorKind::PostError(_) => ErrorClass::new(400, "invalid_input"),
+        crate::thalamus::services::ErrorKind::InvalidInput(_) => ErrorClass::new(400, "invalid_input"),
+        crate::thalamus::services::ErrorKind::UnknownModel(_) => ErrorClass::new(400, "unknown_model"),
+        crate::thalamus::services::ErrorKind::ModelNotInstalled(_) => ErrorClass::new(503, "model_not_installed"),
+        crate::thalamus::services::ErrorKind::InternalToolsError(e) => classify_tools(e),
+        crate::thalamus::services::ErrorKind::FilesError(e) => classify_files(e),
+        crate::thalamus::services::ErrorKind::ExternalRequestError(e) => {
+            if e.is_timeout() {
+                return ErrorClass::new(504, "upstream_timeout");
+            }
+            return ErrorClass::new(503, "upstream_unavailable");
+        },
+        _ => ErrorClass::new(500, "internal_error"),
+    }
+}
+
+pub fn classify_tools(err: &crate::thalamus::tools::Error) -> ErrorClass {
+    match err.kind() {
+        crate::thalamus::tools::ErrorKind::ProcessFailed(_, _) => ErrorClass::new(503, "process_failed"),
+        _ => ErrorClass::new(500, "internal_error"),
+    }
+}
+
+pub fn classify_files(err: &crate::thalamus::files::Error) -> ErrorClass {
+    match err.kind() {
+        crate::thalamus::files::ErrorKind::InvalidId(_) => ErrorClass::new(400, "invalid_file_id"),
+        crate::thalamus::files::ErrorKind::FileNotFound(_) => ErrorClass::new(404, "file_not_found"),
+        _ => ErrorClass::new(500, "internal_error"),
+    }
+}
+
+pub fn classify_storage(err: &crate::thalamus::storage::Error) -> ErrorClass {
+    match err.kind() {
+        crate::thalamus::storage::ErrorKind::OverBudget(_, _, _) => ErrorClass::new(507, "insufficient_storage"),
+        crate::thalamus::storage::ErrorKind::UnknownModel(_) => ErrorClass::new(404, "unknown_model"),
+        _ => ErrorClass::new(500, "internal_error"),
+    }
+}
+
+pub fn classify_bundle(err: &crate::thalamus::bundle::Error) -> ErrorClass {
+    match err.kind() {
+        crate::thalamus::bundle::ErrorKind::BadSignature => ErrorClass::new(400, "bad_signature"),
+        crate::thalamus::bundle::ErrorKind::UnsupportedFormat(_) => ErrorClass::new(400, "unsupported_bundle"),
+        crate::thalamus::bundle::ErrorKind::DefaultKey => ErrorClass::new(403, "default_key"),
+        // Msg is a registry that does not parse
+        crate::thalamus::bundle::ErrorKind::Msg(_) | crate::thalamus::bundle::ErrorKind::JSONError(_) | crate::thalamus::bundle::ErrorKind::TomlError(_) => ErrorClass::new(400, "invalid_bundle"),
+        _ => ErrorClass::new(500, "internal_error"),
+    }
+}
+
+pub fn error_response(err: &Error, request_id: &str) -> Response {
+    let class = classify(err);
+
+    let reply = ErrorReply {
+        code: class.code.to_string(),
+        message: format!("{}", err),
+        request_id: request_id.to_string(),
+    };
+
+    let mut response = Response::json(&reply)
+        .with_status_code(class.status)
+        .with_unique_header("X-Request-Id", request_id.to_string());
+
+    if let ErrorKind::MethodNotAllowed(_, allowed) = err.kind() {
+        response = response.with_unique_header("Allow", allowed.clone());
+    }
+
+    if let ErrorKind::Unauthorized(_) = err.kind() {
+        response = response.with_unique_header("WWW-Authenticate", "Bearer realm=\"thalamus\"");
+    }
+
+    if class.is_retryable() {
+        response = response.with_unique_header("Retry-After", "5");
+    }
+
+    return response;
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+
+    fn status(err: Error) -> (u16, &'static str) {
+        let class = classify(&err);
+        return (class.status, class.code);
+    }
+
+    #[test]
+    fn test_classify() {
+        assert_eq!(status(ErrorKind::BadRequest("x".to_string()).into()), (400, "bad_request"));
+        assert_eq!(status(ErrorKind::Unauthorized("x".to_string()).into()), (401, "unauthorized"));
+        assert_eq!(status(ErrorKind::Forbidden("x".to_string()).into()), (403, "forbidden"));
+        assert_eq!(status(ErrorKind::ShuttingDown.into()), (503, "shutting_down"));
+        assert_eq!(status(ErrorKind::PayloadTooLarge(10).into()), (413, "payload_too_large"));
+        assert_eq!(status(ErrorKind::TooManySubscribers(3).into()), (503, "too_many_subscribers"));
+        assert_eq!(status(ErrorKind::JobNotFound("x".to_string()).into()), (404, "job_not_found"));
+        assert_eq!(status(ErrorKind::JobNotFinished("x".to_string(), "running".to_string()).into()), (409, "job_not_finished"));
+        assert_eq!(status(ErrorKind::ServiceDisabled("x".to_string()).into()), (404, "service_disabled"));
+        assert_eq!(status(ErrorKind::NoNodeAvailable("x".to_string()).into()), (503, "no_node_available"));
+        assert_eq!(status(ErrorKind::RouteNotFound("x".to_string()).into()), (404, "not_found"));
+        assert_eq!(status(ErrorKind::MethodNotAllowed("GET".to_string(), "POST".to_string()).into()), (405, "method_not_allowed"));
+        assert_eq!(status(ErrorKind::ConfigError(crate::thalamus::config::Error::from("x")).into()), (422, "invalid_config"));
+        assert_eq!(status(ErrorKind::JSONParseError(serde_json::from_str::<u8>("x").unwrap_err()).into()), (500, "invalid_output"));
+        assert_eq!(status(ErrorKind::Io(std::io::Error::new(std::io::ErrorKind::Other, "x")).into()), (500, "internal_error"));
+
+        let service = |kind: crate::thalamus::services::ErrorKind| status(ErrorKind::InternalServiceError(kind.into()).into());
+        assert_eq!(service(crate::thalamus::services::ErrorKind::InvalidInput("x".to_string())), (400, "invalid_input"));
+        assert_eq!(service(crate::thalamus::services::ErrorKind::UnknownModel("x".to_string())), (400, "unknown_model"));
+        assert_eq!(service(crate::thalamus::services::ErrorKind::ModelNotInstalled("x".to_string())), (503, "model_not_installed"));
+
+        let tools = crate::thalamus::tools::Error::from(crate::thalamus::tools::ErrorKind::ProcessFailed("llama".to_string(), "x".to_string()));
+        assert_eq!(status(ErrorKind::ToolKitError(tools).into()), (503, "process_failed"));
+        let files = |kind: crate::thalamus::files::ErrorKind| status(ErrorKind::FilesError(kind.into()).into());
+        assert_eq!(files(crate::thalamus::files::ErrorKind::InvalidId("x".to_string())), (400, "invalid_file_id"));
+        assert_eq!(files(crate::thalamus::files::ErrorKind::FileNotFound("x".to_string())), (404, "file_not_found"));
+        let storage = |kind: crate::thalamus::storage::ErrorKind| status(ErrorKind::StorageError(kind.into()).into());
+        assert_eq!(storage(crate::thalamus::storage::ErrorKind::OverBudget("x".to_string(), 2, 1)), (507, "insufficient_storage"));
+        assert_eq!(storage(crate::thalamus::storage::ErrorKind::UnknownModel("x".to_string())), (404, "unknown_model"));
+        let bundle = |kind: crate::thalamus::bundle::ErrorKind| status(ErrorKind::BundleError(kind.into()).into());
+        assert_eq!(bundle(crate::thalamus::bundle::ErrorKind::BadSignature), (400, "bad_signature"));
+        assert_eq!(bundle(crate::thalamus::bundle::ErrorKind::UnsupportedFormat(9)), (400, "unsupported_bundle"));
+        assert_eq!(bundle(crate::thalamus::bundle::ErrorKind::DefaultKey), (403, "default_key"));
+    }
+
+    #[test]
+    fn test_error_response() {
+        let response = error_response(&ErrorKind::JobNotFound("abc".to_string()).into(), "req1");
+        assert_eq!(response.status_code, 404);
+        assert!(response.headers.iter().all(|(name, _)| name != "Retry-After"));
+
+        let (mut reader, _) = response.data.into_reader_and_size();
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+        let reply: serde_json::Value = serde_json::from_str(&body).unwrap();
+        assert_eq!(reply, serde_json::json!({"code": "job_not_found", "message": "no job with oid abc", "request_id": "req1"}));
+
+        for (err, code) in [(Error::from(ErrorKind::ShuttingDown), 503), (Error::from(ErrorKind::NoNodeAvailable("x".to_string())), 503)] {
+            let response = error_response(&err, "req2");
+            assert_eq!(response.status_code, code);
+            assert!(response.headers.iter().any(|(name, value)| name == "Retry-After" && value == "5"));
+        }
+        let timeout = ErrorClass::new(504, "upstream_timeout");
+        assert!(timeout.is_retryable());
+        for status in [400, 401, 404, 409, 422, 500, 507] {
+            assert!(!ErrorClass::new(status, "x").is_retryable());
+        }
+    }
+}
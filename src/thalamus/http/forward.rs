@@ -0,0 +1,231 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// Forwarding /api/services/* across the mesh, off unless [forward] enabled = true
+// A service request this node cannot serve (model not installed, service disabled) is sent
+// on to the best peer in the registry, as is one arriving while the node is overloaded
+// ([forward] max_in_flight) or when a peer's benchmark is faster by [forward]
+// faster_peer_ratio. A peer that fails to connect or answers with a 5xx is skipped for
+// the next one, and past the last one the request is served here. The peer's reply is
+// streamed back as it arrives, with X-Thalamus-Forwarded-To naming the peer. Forwarded
+// requests carry X-Thalamus-Hops and are never forwarded again past [forward] max_hops, so
+// two nodes can't bounce a request between them. Async jobs always run where they were
+// submitted so their job urls resolve, and bodies over [forward] max_body_bytes are
+// served here as forwarding has to buffer them.
+
+use rouille::Request;
+use rouille::Response;
+use rouille::ResponseBody;
+use std::borrow::Cow;
+use std::io::Read;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::thalamus::http::{ErrorKind, HttpContext, Result};
+use crate::ThalamusNode;
+
+pub const HOPS_HEADER: &str = "X-Thalamus-Hops";
+pub const FORWARDED_TO_HEADER: &str = "X-Thalamus-Forwarded-To";
+
+// Forwardable routes and the benchmark that ranks peers for them, yolov7 has none
+const SERVICES: [(&str, &str); 7] = [
+    ("/api/services/llama", "llama"),
+    ("/api/services/whisper", "whisper_stt"),
+    ("/api/services/whisper/vwav", "whisper_vwav"),
+    ("/api/services/tts", "tts"),
+    ("/api/services/image/srgan", "srgan"),
+    ("/api/services/image/yolo/v7", "yolov7"),
+    ("/api/services/image/nst/run", "nst"),
+];
+
+// Not copied between the caller, this node and the peer
+const SKIPPED_HEADERS: [&str; 7] = ["host", "authorization", "content-length", "connection", "transfer-encoding", "keep-alive", "x-thalamus-hops"];
+
+pub fn service_for(path: &str) -> Option<&'static str> {
+    return SERVICES.iter().find(|(route, _)| *route == path).map(|(_, service)| *service);
+}
+
+/// Hops the request has already made, 0 when it comes from a client
+pub fn hops(request: &Request) -> Result<u32> {
+    match request.header(HOPS_HEADER) {
+        Some(value) => return Ok(value.trim().parse::<u32>().map_err(|_| ErrorKind::BadRequest(format!("{} must be a number", HOPS_HEADER)))?),
+        None => return Ok(0),
+    }
+}
+
+/// Why a request should go to a peer before trying it here, if it should
+pub fn forward_first(config: &crate::thalamus::config::ForwardConfig, in_flight: usize, local_score: Option<i64>, peer_score: Option<i64>) -> Option<&'static str> {
+    if let Some(max_in_flight) = config.max_in_flight {
+        if in_flight > max_in_flight {
+            return Some("overloaded");
+        }
+    }
+    if let (Some(ratio), Some(local_score), Some(peer_score)) = (config.faster_peer_ratio, local_score, peer_score) {
+        if (peer_score as f64) < (local_score as f64) * ratio {
+            return Some("faster peer");
+        }
+    }
+    return None;
+}
+
+// Errors a peer might not have
+fn should_forward(err: &crate::thalamus::http::Error) -> bool {
+    let code = crate::thalamus::http::errors::classify(err).code;
+    return code == "model_not_installed" || code == "service_disabled";
+}
+
+// Peers worth trying, best first, and this node's own score if it benchmarked itself
+fn peers(context: &HttpContext, service: &str) -> (Vec<(ThalamusNode, Option<i64>)>, Option<i64>) {
+    let thalamus_x = context.thalamus.lock().unwrap();
+    let client = thalamus_x.clone();
+    std::mem::drop(thalamus_x);
+
+    let pid = crate::local_pid();
+    let local_score = client.nodes.iter().find(|node| Some(&node.pid) == pid.as_ref()).and_then(|node| node.stats.score(service, None));
+    let mut peers: Vec<(ThalamusNode, Option<i64>)> = match client.rank_nodes(service, None, crate::STALE_PING_SECS) {
+        Ok(ranking) => ranking.into_iter().map(|ranked| (ranked.node, Some(ranked.score))).collect(),
+        // No benchmark for this service, the least busy fresh nodes go first
+        Err(_) => {
+            let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+            let mut nodes: Vec<ThalamusNode> = client.nodes.into_iter().filter(|node| node.is_online && now - node.last_ping <= crate::STALE_PING_SECS).collect();
+            nodes.sort_by_key(|node| node.active_jobs());
+            nodes.into_iter().map(|node| (node, None)).collect()
+        }
+    };
+    peers.retain(|(node, _)| Some(&node.pid) != pid.as_ref());
+    return (peers, local_score);
+}
+
+fn header_pairs(request: &Request) -> Vec<(String, String)> {
+    return request.headers().map(|(name, value)| (name.to_string(), value.to_string())).collect();
+}
+
+// Sends the buffered request to a peer, the reply body is streamed back as it is read
+fn forward(request: &Request, body: &[u8], node: &ThalamusNode, hops: u32, timeout: Duration) -> std::result::Result<Response, Box<dyn std::error::Error>> {
+    let method = reqwest::Method::from_bytes(request.method().as_bytes())?;
+    let mut builder = node.http_client()?.request(method, node.url(request.raw_url())).timeout(timeout);
+    for (name, value) in header_pairs(request) {
+        if !SKIPPED_HEADERS.contains(&name.to_lowercase().as_str()) {
+            builder = builder.header(name, value);
+        }
+    }
+    let reply = builder.header(HOPS_HEADER, (hops + 1).to_string()).body(body.to_vec()).send()?;
+    if reply.status().is_server_error() {
+        return Err(format!("answered {}", reply.status()).into());
+    }
+
+    let mut headers: Vec<(Cow<'static, str>, Cow<'static, str>)> = Vec::new();
+    for (name, value) in reply.headers().iter() {
+        if !SKIPPED_HEADERS.contains(&name.as_str()) {
+            headers.push((name.as_str().to_string().into(), value.to_str()?.to_string().into()));
+        }
+    }
+    headers.push((FORWARDED_TO_HEADER.into(), node.pid.clone().into()));
+    return Ok(Response {
+        status_code: reply.status().as_u16(),
+        headers: headers,
+        data: ResponseBody::from_reader(crate::thalamus::shutdown::Tracked::new(reply)),
+        upgrade: None,
+    });
+}
+
+// First peer that answers, connection failures and 5xx replies move on to the next one
+fn forward_to_peers(request: &Request, body: &[u8], peers: &[(ThalamusNode, Option<i64>)], hops: u32, reason: &str) -> Option<Response> {
+    let timeout = Duration::from_secs(crate::thalamus::config::get().forward.timeout_secs);
+    for (node, _) in peers.iter() {
+        match forward(request, body, node, hops, timeout) {
+            Ok(response) => {
+                log::warn!("Forwarded {} {} to {} ({}:{}): {}", request.method(), request.url(), node.pid, node.ip_address, node.port, reason);
+                return Some(response);
+            },
+            Err(e) => log::error!("Unable to forward {} to {}: {}", request.url(), node.pid, e),
+        }
+    }
+    return None;
+}
+
+// Declared body size, None for chunked uploads
+fn content_length(request: &Request) -> Option<u64> {
+    return request.header("Content-Length").and_then(|value| value.trim().parse::<u64>().ok());
+}
+
+/// Serves a service request here or on a peer, anything else goes straight to the router
+pub fn dispatch(request: &Request, context: &HttpContext) -> Result<Response> {
+    let config = crate::thalamus::config::get().forward;
+    let service = match service_for(&request.url()) {
+        Some(service) => service,
+        None => return context.router.dispatch(request, context),
+    };
+    let hops = hops(request)?;
+    if !config.enabled || hops >= config.max_hops || crate::thalamus::jobs::wants_async(request) {
+        return context.router.dispatch(request, context);
+    }
+    if content_length(request).map_or(false, |length| length > config.max_body_bytes) {
+        return context.router.dispatch(request, context);
+    }
+    let (peers, local_score) = peers(context, service);
+    if peers.len() == 0 {
+        return context.router.dispatch(request, context);
+    }
+
+    // Buffered so the same body can be served here or sent to a peer, a chunked upload
+    // can't be handed back to the router once read so one over the limit is refused
+    let mut body = Vec::new();
+    if let Some(data) = request.data() {
+        data.take(config.max_body_bytes + 1).read_to_end(&mut body)?;
+    }
+    if body.len() as u64 > config.max_body_bytes {
+        return Err(ErrorKind::PayloadTooLarge(config.max_body_bytes).into());
+    }
+    let local = if request.is_secure() {
+        Request::fake_https_from(*request.remote_addr(), request.method(), request.raw_url(), header_pairs(request), body.clone())
+    } else {
+        Request::fake_http_from(*request.remote_addr(), request.method(), request.raw_url(), header_pairs(request), body.clone())
+    };
+
+    if let Some(reason) = forward_first(&config, crate::thalamus::shutdown::in_flight(), local_score, peers.first().and_then(|(_, score)| *score)) {
+        if let Some(response) = forward_to_peers(request, &body, &peers, hops, reason) {
+            return Ok(response);
+        }
+    }
+
+    match context.router.dispatch(&local, context) {
+        Err(err) if should_forward(&err) => {
+            match forward_to_peers(request, &body, &peers, hops, &err.to_string()) {
+                Some(response) => return Ok(response),
+                None => return Err(err),
+            }
+        },
+        other => return other,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_forward_decisions() {
+        assert_eq!(service_for("/api/services/whisper/vwav"), Some("whisper_vwav"));
+        assert_eq!(service_for("/api/services/tts/voices"), None);
+
+        let mut config = crate::thalamus::config::ForwardConfig::default();
+        assert!(!config.enabled);
+        assert_eq!(forward_first(&config, 100, Some(1000), Some(10)), None);
+
+        config.max_in_flight = Some(4);
+        config.faster_peer_ratio = Some(0.5);
+        assert_eq!(forward_first(&config, 5, None, None), Some("overloaded"));
+        assert_eq!(forward_first(&config, 1, Some(1000), Some(400)), Some("faster peer"));
+        assert_eq!(forward_first(&config, 1, Some(1000), Some(600)), None);
+        assert_eq!(forward_first(&config, 1, None, Some(10)), None);
+
+        let request = Request::fake_http("POST", "/api/services/llama", vec![("Content-Length".to_string(), "11".to_string())], b"prompt=test".to_vec());
+        assert_eq!(content_length(&request), Some(11));
+    }
+}
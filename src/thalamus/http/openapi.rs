@@ -0,0 +1,684 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// OpenAPI 3 document for the node's web api
+// Paths come straight from the route table so nothing served can be missing from the spec,
+// the request/response details for each operation are described below.
+
+use serde_json::{json, Map, Value};
+
+use crate::thalamus::http::auth;
+use crate::thalamus::http::router::{Route, Router};
+
+const VERSION: Option<&'static str> = option_env!("CARGO_PKG_VERSION");
+
+pub fn spec(router: &Router) -> Value {
+    let mut paths = Map::new();
+
+    for route in router.routes() {
+        let mut operation = operation(route);
+
+        let path_params = path_parameters(route.pattern.as_str());
+        if path_params.len() > 0 {
+            let mut parameters = match operation.get("parameters") {
+                Some(Value::Array(existing)) => existing.clone(),
+                _ => Vec::new(),
+            };
+            parameters.extend(path_params);
+            operation["parameters"] = Value::Array(parameters);
+        }
+
+        if CACHED_ROUTES.iter().any(|(method, pattern)| *method == route.method && *pattern == route.pattern) {
+            operation = cached_operation(operation);
+        }
+
+        let responses = operation["responses"].as_object_mut().unwrap();
+        responses.insert("default".to_string(), json!({
+            "description": "Error",
+            "content": {"application/json": {"schema": {"$ref": "#/components/schemas/ErrorReply"}}}
+        }));
+
+        if auth::PROTECTED_PREFIXES.iter().any(|prefix| route.pattern.starts_with(prefix)) && !auth::DEFAULT_ALLOWLIST.contains(&route.pattern.as_str()) {
+            operation["security"] = json!([{"bearerAuth": []}]);
+        }
+
+        let entry = paths.entry(route.pattern.clone()).or_insert(json!({}));
+        entry[route.method.to_lowercase()] = operation;
+    }
+
+    return json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "Thalamus",
+            "description": "A deep learning mesh node server platform for linux/mac/unix",
+            "version": VERSION.unwrap_or("UNKNOWN"),
+            "license": {"name": "GPL-3.0-only"}
+        },
+        "servers": [{"url": "/"}],
+        "paths": Value::Object(paths),
+        "components": {
+            "schemas": schemas(),
+            "securitySchemes": {"bearerAuth": {"type": "http", "scheme": "bearer", "description": "--key or a key from keys.json in the state directory"}}
+        }
+    });
+}
+
+fn path_parameters(pattern: &str) -> Vec<Value> {
+    return pattern.split('/')
+        .filter(|segment| segment.starts_with('{') && segment.ends_with('}'))
+        .map(|segment| json!({
+            "name": &segment[1..segment.len() - 1],
+            "in": "path",
+            "required": true,
+            "schema": {"type": "string"}
+        }))
+        .collect();
+}
+
+fn json_reply(description: &str, schema: Value) -> Value {
+    return json!({"200": {"description": description, "content": {"application/json": {"schema": schema}}}});
+}
+
+fn binary_reply(description: &str, mime: &str) -> Value {
+    return json!({"200": {"description": description, "content": {mime: {"schema": {"type": "string", "format": "binary"}}}}});
+}
+
+fn schema_ref(name: &str) -> Value {
+    return json!({"$ref": format!("#/components/schemas/{}", name)});
+}
+
+fn form_body(content_type: &str, properties: Value, required: Vec<&str>) -> Value {
+    let mut schema = json!({"type": "object", "properties": properties});
+    // OpenAPI 3.0 does not allow an empty required list
+    if required.len() > 0 {
+        schema["required"] = json!(required);
+    }
+    return json!({
+        "required": true,
+        "content": {content_type: {"schema": schema}}
+    });
+}
+
+// Services that can run as a job with ?async=true
+fn job_operation(mut operation: Value) -> Value {
+    let mut parameters = match operation.get("parameters") {
+        Some(Value::Array(existing)) => existing.clone(),
+        _ => Vec::new(),
+    };
+    parameters.push(json!({
+        "name": "async", "in": "query", "required": false, "schema": {"type": "boolean"},
+        "description": "Queue the work as a job and reply 202 (same as \"Prefer: respond-async\")"
+    }));
+    operation["parameters"] = Value::Array(parameters);
+    operation["responses"]["202"] = json!({
+        "description": "Job queued, poll /api/jobs/{oid} and fetch /api/jobs/{oid}/result",
+        "content": {"application/json": {"schema": schema_ref("ThalamusNodeJob")}}
+    });
+    return operation;
+}
+
+// Services behind the result cache when [cache] enabled = true
+const CACHED_ROUTES: [(&str, &str); 5] = [
+    ("POST", "/api/services/whisper"),
+    ("GET", "/api/services/tts"),
+    ("POST", "/api/services/tts"),
+    ("POST", "/api/services/image/srgan"),
+    ("POST", "/api/services/image/yolo/v7"),
+];
+
+fn cached_operation(mut operation: Value) -> Value {
+    let mut parameters = match operation.get("parameters") {
+        Some(Value::Array(existing)) => existing.clone(),
+        _ => Vec::new(),
+    };
+    parameters.push(json!({
+        "name": "cache", "in": "query", "required": false, "schema": {"type": "boolean"},
+        "description": "false skips the result cache (same as \"Cache-Control: no-store\"), \"Cache-Control: no-cache\" recomputes and replaces the cached result"
+    }));
+    operation["parameters"] = Value::Array(parameters);
+    operation["responses"]["200"]["headers"] = json!({
+        "X-Thalamus-Cache": {"schema": {"type": "string", "enum": ["hit", "miss", "bypass"]}, "description": "Only sent when the cache is enabled"}
+    });
+    return operation;
+}
+
+// multipart field names match the post_input! calls in each service
+fn operation(route: &Route) -> Value {
+    let whisper_form = form_body("multipart/form-data", json!({
+        "speech": {"type": "string", "format": "binary", "description": "Audio file, converted to 16khz wav before decoding"},
+        "speech_id": {"type": "string", "description": "Id from POST /api/files, instead of speech"},
+        "method": {"type": "string", "enum": crate::thalamus::services::whisper::MODELS, "description": "Whisper model, defaults to [services.whisper] default_model"}
+    }), vec![]);
+
+    match (route.method, route.pattern.as_str()) {
+        ("GET", "/") => json!({
+            "summary": "Node landing page",
+            "responses": {"200": {"description": "ASCII art banner and version", "content": {"text/html": {}}}}
+        }),
+        ("GET", "/api/thalamus/version") => json!({
+            "summary": "Node version and pid",
+            "responses": json_reply("Version header", schema_ref("VersionHeader"))
+        }),
+        ("GET", "/api/thalamus/certificate") => json!({
+            "summary": "The node's tls certificate, for pinning (only when started with --tls)",
+            "responses": {"200": {"description": "PEM certificate", "content": {"application/x-pem-file": {"schema": {"type": "string"}}}}}
+        }),
+        ("GET", "/metrics") => json!({
+            "summary": "Prometheus metrics: requests, inference durations, process failures, jobs and mesh nodes",
+            "responses": {"200": {"description": "Prometheus text format", "content": {"text/plain": {"schema": {"type": "string"}}}}}
+        }),
+        ("GET", "/api/health") => json!({
+            "summary": "Liveness, answers as long as the process is serving",
+            "responses": json_reply("Process info", schema_ref("HealthReply"))
+        }),
+        ("GET", "/api/ready") => json!({
+            "summary": "Readiness of each service's binaries, models and containers (503 when anything is missing)",
+            "parameters": [{"name": "service", "in": "query", "required": false, "schema": {"type": "string", "enum": ["whisper", "llama", "srgan", "yolov7", "tts", "nst"]}, "description": "Only check one service"}],
+            "responses": {
+                "200": {"description": "Ready", "content": {"application/json": {"schema": schema_ref("ReadyReport")}}},
+                "503": {"description": "Not ready", "content": {"application/json": {"schema": schema_ref("ReadyReport")}}}
+            }
+        }),
+        ("GET", "/api/nodex") => json!({
+            "summary": "Mesh nodes known to this node",
+            "responses": json_reply("Known nodes", json!({"type": "array", "items": schema_ref("ThalamusNode")}))
+        }),
+        ("POST", "/api/nodex/leave") => json!({
+            "summary": "Sent by a peer that is shutting down, marks it offline",
+            "requestBody": form_body("application/x-www-form-urlencoded", json!({
+                "port": {"type": "integer", "description": "Port the leaving node serves on, its address is taken from the connection"}
+            }), vec!["port"]),
+            "responses": {"204": {"description": "Noted"}}
+        }),
+        ("GET", "/api/nodex/status") => json!({
+            "summary": "Nodes that went online or offline in a window, from thalamus.db",
+            "parameters": [
+                {"name": "from", "in": "query", "required": false, "schema": {"type": "integer"}, "description": "Unix time, defaults to a day ago"},
+                {"name": "to", "in": "query", "required": false, "schema": {"type": "integer"}, "description": "Unix time, defaults to now"}
+            ],
+            "responses": json_reply("Status changes", schema_ref("StatusReport"))
+        }),
+        ("GET", "/api/nodex/select") => json!({
+            "summary": "Fastest online node for a service by benchmark score, ties go to the node with fewer jobs (503 when none has a score)",
+            "parameters": [
+                {"name": "service", "in": "query", "required": true, "schema": {"type": "string", "enum": crate::SELECTABLE_SERVICES}},
+                {"name": "model", "in": "query", "required": false, "schema": {"type": "string"}, "description": "whisper or llama model, the overall score is used without one"},
+                {"name": "max_age_secs", "in": "query", "required": false, "schema": {"type": "integer"}, "description": "Skip nodes not pinged for this long, defaults to 600"}
+            ],
+            "responses": json_reply("Selected node", schema_ref("RankedNode"))
+        }),
+        ("GET", "/api/nodex/events") => json!({
+            "summary": "Nodes discovered, going online or offline and evicted, as they happen",
+            "responses": {"200": {"description": "Server-sent events named after the change, plus a ping every 15 seconds", "content": {
+                "text/event-stream": {"schema": {"type": "string", "description": "event: <discovered|online|offline|evicted> then data: a NodeEvent"}}
+            }}, "503": {"description": "Half of --max-threads streams are already open, retry after Retry-After", "content": {"application/json": {"schema": schema_ref("ErrorReply")}}}}
+        }),
+        ("GET", "/api/nodex/{pid}/benchmarks") => json!({
+            "summary": "Every stats calculation of one metric for a node, from thalamus.db",
+            "parameters": [
+                {"name": "metric", "in": "query", "required": true, "schema": {"type": "string", "enum": crate::thalamus::store::metrics()}},
+                {"name": "since", "in": "query", "required": false, "schema": {"type": "integer"}, "description": "Unix time, defaults to a week ago"}
+            ],
+            "responses": json_reply("Benchmark runs, oldest first", json!({"type": "array", "items": schema_ref("BenchmarkRun")}))
+        }),
+        ("POST", "/api/files") => json!({
+            "summary": "Store a file, services take its id instead of an upload. The same bytes always get the same id",
+            "requestBody": form_body("multipart/form-data", json!({
+                "file": {"type": "string", "format": "binary", "description": "Its content type, or else its name, sets the mime type it is served with"}
+            }), vec!["file"]),
+            "responses": {"201": {"description": "Stored", "content": {"application/json": {"schema": schema_ref("FileRecord")}}}}
+        }),
+        ("GET", "/api/files/{id}") => json!({
+            "summary": "A stored file, with the mime type it was stored with",
+            "responses": {
+                "200": {"description": "File contents", "content": {"*/*": {"schema": {"type": "string", "format": "binary"}}}},
+                "304": {"description": "Not modified, the id is the etag"}
+            }
+        }),
+        ("GET", "/api/jobs") => json!({
+            "summary": "Jobs on this node, finished jobs are kept for an hour",
+            "responses": json_reply("Jobs", json!({"type": "array", "items": schema_ref("ThalamusNodeJob")}))
+        }),
+        ("GET", "/api/jobs/{oid}") => json!({
+            "summary": "Job status and progress",
+            "responses": json_reply("Job", schema_ref("ThalamusNodeJob"))
+        }),
+        ("GET", "/api/jobs/{oid}/result") => json!({
+            "summary": "Job output, same body the synchronous call would have returned (409 until the job is completed)",
+            "responses": {"200": {"description": "Job output"}}
+        }),
+        ("GET", "/api/openapi.json") => json!({
+            "summary": "This document",
+            "responses": json_reply("OpenAPI 3 document", json!({"type": "object"}))
+        }),
+        ("GET", "/api/docs") => json!({
+            "summary": "Swagger UI for this document (only when started with --api-docs)",
+            "responses": {"200": {"description": "HTML viewer", "content": {"text/html": {}}}}
+        }),
+        ("POST", "/api/admin/reload") => json!({
+            "summary": "Re-read thalamus.toml, same as SIGHUP. Only [tts] and [services.*] apply without a restart. Needs an admin key",
+            "responses": json_reply("Reloaded", schema_ref("ReloadReport"))
+        }),
+        ("GET", "/api/admin/export") => json!({
+            "summary": "Bundle the node registry, thalamus.toml and pid, signed with this node's --key. Needs an admin key",
+            "parameters": [{"name": "include_keys", "in": "query", "required": false, "schema": {"type": "boolean"}, "description": "Also bundle keys.json"}],
+            "responses": json_reply("Signed bundle", schema_ref("SignedBundle"))
+        }),
+        ("POST", "/api/admin/import") => json!({
+            "summary": "Apply a bundle signed with the same --key. Nodes are merged into the registry, thalamus.toml applies on the next start. Needs an admin key",
+            "parameters": [{"name": "restore_identity", "in": "query", "required": false, "schema": {"type": "boolean"}, "description": "Take the exporting node's pid on the next start, for a reinstall"}],
+            "requestBody": form_body("multipart/form-data", json!({
+                "bundle": {"type": "string", "format": "binary", "description": "File from GET /api/admin/export or --export-bundle"}
+            }), vec!["bundle"]),
+            "responses": json_reply("Imported", schema_ref("ImportReport"))
+        }),
+        ("GET", "/api/admin/storage") => json!({
+            "summary": "Disk used by each model, the result cache, stored files and tmp/, against the [storage] budget. Needs an admin key",
+            "responses": json_reply("Storage usage", schema_ref("StorageReport"))
+        }),
+        ("POST", "/api/admin/storage/evict") => json!({
+            "summary": "Remove models, the next install downloads them again. Needs an admin key",
+            "requestBody": form_body("application/x-www-form-urlencoded", json!({
+                "model": {"type": "string", "description": "One model as <service>/<name>, e.g. whisper/medium"},
+                "unused_for_secs": {"type": "integer", "description": "Evict every model not used for this long"}
+            }), vec![]),
+            "responses": json_reply("Evicted models", schema_ref("EvictReport"))
+        }),
+        ("POST", "/api/services/llama") => job_operation(json!({
+            "summary": "Generate text with llama.cpp",
+            "requestBody": form_body("application/x-www-form-urlencoded", json!({
+                "prompt": {"type": "string"},
+                "model": {"type": "string", "enum": crate::thalamus::services::llama::MODELS, "description": "Defaults to [services.llama] default_model"}
+            }), vec!["prompt"]),
+            "parameters": [{
+                "name": "stream", "in": "query", "required": false, "schema": {"type": "boolean"},
+                "description": "Stream tokens as server-sent events (same as \"Accept: text/event-stream\")"
+            }],
+            "responses": {"200": {"description": "Raw llama output, or token events when streaming", "content": {
+                "text/plain": {"schema": {"type": "string"}},
+                "text/event-stream": {"schema": {"type": "string", "description": "data: {\"token\": \"...\"} per chunk, then event: done or event: error"}}
+            }}}
+        })),
+        ("POST", "/api/services/whisper") => job_operation(json!({
+            "summary": "Speech to text with whisper.cpp",
+            "requestBody": whisper_form,
+            "responses": json_reply("Transcription", schema_ref("STTReply"))
+        })),
+        ("POST", "/api/services/whisper/vwav") => job_operation(json!({
+            "summary": "Render a karaoke style video of the transcription",
+            "requestBody": whisper_form,
+            "responses": binary_reply("Rendered video", "video/mp4")
+        })),
+        ("GET", "/api/services/tts") => job_operation(json!({
+            "summary": "Text to speech",
+            "parameters": [
+                {"name": "text", "in": "query", "required": true, "schema": {"type": "string"}},
+                {"name": "primary", "in": "query", "required": false, "schema": {"type": "string"}, "description": "Voice tag from /api/services/tts/voices, defaults to [tts] default_voice"},
+                {"name": "fallback", "in": "query", "required": false, "schema": {"type": "string"}, "description": "Voice tag used if the primary voice fails, defaults to [tts] fallback_voice"}
+            ],
+            "responses": binary_reply("Synthesized speech", "audio/wav")
+        })),
+        ("POST", "/api/services/tts") => job_operation(json!({
+            "summary": "Text to speech",
+            "requestBody": form_body("application/x-www-form-urlencoded", json!({
+                "text": {"type": "string"},
+                "primary": {"type": "string", "description": "Voice tag from /api/services/tts/voices, defaults to [tts] default_voice"},
+                "fallback": {"type": "string", "description": "Voice tag used if the primary voice fails, defaults to [tts] fallback_voice"}
+            }), vec!["text"]),
+            "responses": binary_reply("Synthesized speech", "audio/wav")
+        })),
+        ("GET", "/api/services/tts/voices") => json!({
+            "summary": "Supported TTS voices",
+            "responses": json_reply("Voices", json!({"type": "array", "items": schema_ref("ThalamusTTSVoice")}))
+        }),
+        ("POST", "/api/services/image/srgan") => job_operation(json!({
+            "summary": "Super resolution with SRGAN",
+            "requestBody": form_body("multipart/form-data", json!({
+                "input_file": {"type": "string", "format": "binary", "description": "png or jpeg, the filename extension picks the reply mime type"},
+                "input_file_id": {"type": "string", "description": "Id from POST /api/files, instead of input_file"}
+            }), vec![]),
+            "responses": binary_reply("Upscaled image", "image/*")
+        })),
+        ("POST", "/api/services/image/yolo/v7") => job_operation(json!({
+            "summary": "Object detection with YOLOv7",
+            "requestBody": form_body("multipart/form-data", json!({
+                "image_file": {"type": "string", "format": "binary"},
+                "image_file_id": {"type": "string", "description": "Id from POST /api/files, instead of image_file"}
+            }), vec![]),
+            "responses": json_reply("Detected objects", schema_ref("YoloV7Output"))
+        })),
+        ("GET", "/api/services/image/nst/styles") => json!({
+            "summary": "Available neural style transfer styles",
+            "responses": json_reply("Styles", json!({"type": "array", "items": schema_ref("Style")}))
+        }),
+        ("POST", "/api/services/image/nst/run") => json!({
+            "summary": "Start a neural style transfer",
+            "requestBody": form_body("application/x-www-form-urlencoded", json!({
+                "image_id": {"type": "string", "description": "Id from POST /api/files"},
+                "nst_style": {"type": "string", "description": "Style name from /api/services/image/nst/styles"}
+            }), vec!["image_id", "nst_style"]),
+            "responses": {"202": {"description": "Style transfer queued as a job", "content": {"application/json": {"schema": schema_ref("ThalamusNodeJob")}}}}
+        }),
+        (method, pattern) => json!({
+            "summary": format!("{} {}", method, pattern),
+            "responses": {"200": {"description": "OK"}}
+        }),
+    }
+}
+
+fn schemas() -> Value {
+    return json!({
+        "ErrorReply": {
+            "type": "object",
+            "required": ["code", "message", "request_id"],
+            "properties": {
+                "code": {"type": "string", "description": "Machine readable error code, e.g. invalid_input, unknown_model, process_failed"},
+                "message": {"type": "string"},
+                "request_id": {"type": "string"}
+            }
+        },
+        "VersionHeader": {
+            "type": "object",
+            "required": ["version", "pid"],
+            "properties": {
+                "version": {"type": "string"},
+                "pid": {"type": "string"}
+            }
+        },
+        "HealthReply": {
+            "type": "object",
+            "required": ["status", "version", "pid", "uptime"],
+            "properties": {
+                "status": {"type": "string"},
+                "version": {"type": "string"},
+                "pid": {"type": "integer"},
+                "uptime": {"type": "integer", "description": "Seconds since the web server started"}
+            }
+        },
+        "SignedBundle": {
+            "type": "object",
+            "required": ["payload", "signature"],
+            "properties": {
+                "payload": {"type": "string", "description": "JSON with format_version, created_at, version, pid, registry, config and keys"},
+                "signature": {"type": "string", "description": "Hex HMAC-SHA256 of the payload keyed by --key"}
+            }
+        },
+        "ImportReport": {
+            "type": "object",
+            "required": ["nodes_added", "nodes_updated", "config", "keys", "restart_required"],
+            "properties": {
+                "nodes_added": {"type": "integer"},
+                "nodes_updated": {"type": "integer"},
+                "pid": {"type": "string", "nullable": true, "description": "The pid this node has after the import"},
+                "config": {"type": "boolean"},
+                "keys": {"type": "boolean"},
+                "restart_required": {"type": "boolean", "description": "The pid or thalamus.toml changed"}
+            }
+        },
+        "ReloadReport": {
+            "type": "object",
+            "required": ["path", "restart_required"],
+            "properties": {
+                "path": {"type": "string"},
+                "restart_required": {"type": "array", "items": {"type": "string"}, "description": "Keys that changed but only apply after a restart"}
+            }
+        },
+        "ReadyReport": {
+            "type": "object",
+            "required": ["ready", "services"],
+            "properties": {
+                "ready": {"type": "boolean"},
+                "services": {"type": "array", "items": {
+                    "type": "object",
+                    "required": ["service", "ready", "checks"],
+                    "properties": {
+                        "service": {"type": "string"},
+                        "ready": {"type": "boolean"},
+                        "checks": {"type": "array", "items": {
+                            "type": "object",
+                            "required": ["name", "ok"],
+                            "properties": {
+                                "name": {"type": "string"},
+                                "ok": {"type": "boolean"},
+                                "detail": {"type": "string", "nullable": true}
+                            }
+                        }}
+                    }
+                }}
+            }
+        },
+        "STTReply": {
+            "type": "object",
+            "required": ["text", "time"],
+            "properties": {
+                "text": {"type": "string"},
+                "time": {"type": "number"},
+                "response_type": {"type": "string", "nullable": true}
+            }
+        },
+        "ThalamusTTSVoice": {
+            "type": "object",
+            "required": ["tag", "gender", "language", "locale", "engine", "online_api"],
+            "properties": {
+                "tag": {"type": "string"},
+                "gender": {"type": "string"},
+                "language": {"type": "string"},
+                "locale": {"type": "string"},
+                "engine": {"type": "string"},
+                "online_api": {"type": "boolean"}
+            }
+        },
+        "YoloV7Output": {
+            "type": "object",
+            "required": ["objects"],
+            "properties": {
+                "objects": {"type": "array", "items": schema_ref("Yolov7Object")}
+            }
+        },
+        "Yolov7Object": {
+            "type": "object",
+            "required": ["class_id", "name", "coordinates", "confidence"],
+            "properties": {
+                "class_id": {"type": "integer"},
+                "name": {"type": "string"},
+                "coordinates": schema_ref("Coordinates"),
+                "confidence": {"type": "number"}
+            }
+        },
+        "Coordinates": {
+            "type": "object",
+            "required": ["x", "y", "width", "height"],
+            "properties": {
+                "x": {"type": "integer"},
+                "y": {"type": "integer"},
+                "width": {"type": "integer"},
+                "height": {"type": "integer"}
+            }
+        },
+        "Style": {
+            "type": "object",
+            "required": ["name", "file_path"],
+            "properties": {
+                "name": {"type": "string"},
+                "file_path": {"type": "string"}
+            }
+        },
+        "ThalamusNode": {
+            "type": "object",
+            "properties": {
+                "pid": {"type": "string"},
+                "ip_address": {"type": "string"},
+                "version": {"type": "string"},
+                "port": {"type": "integer"},
+                "jobs": {"type": "array", "items": schema_ref("ThalamusNodeJob")},
+                "last_ping": {"type": "integer"},
+                "stats": {"type": "object", "additionalProperties": {"type": "integer", "nullable": true}},
+                "is_online": {"type": "boolean"}
+            }
+        },
+        "RankedNode": {
+            "type": "object",
+            "required": ["node", "score", "active_jobs"],
+            "properties": {
+                "node": schema_ref("ThalamusNode"),
+                "score": {"type": "integer", "description": "Benchmark time in milliseconds"},
+                "active_jobs": {"type": "integer"}
+            }
+        },
+        "NodeEvent": {
+            "type": "object",
+            "required": ["event", "pid", "ip_address", "port", "at"],
+            "properties": {
+                "event": {"type": "string", "enum": ["discovered", "online", "offline", "evicted"]},
+                "pid": {"type": "string"},
+                "ip_address": {"type": "string"},
+                "port": {"type": "integer"},
+                "at": {"type": "integer"}
+            }
+        },
+        "FileRecord": {
+            "type": "object",
+            "required": ["id", "mime_type", "size", "created_at"],
+            "properties": {
+                "id": {"type": "string", "description": "sha256 of the contents"},
+                "mime_type": {"type": "string"},
+                "size": {"type": "integer"},
+                "name": {"type": "string", "nullable": true, "description": "Name the file was first uploaded with"},
+                "created_at": {"type": "integer"}
+            }
+        },
+        "StatusReport": {
+            "type": "object",
+            "required": ["from", "to", "offline", "changes"],
+            "properties": {
+                "from": {"type": "integer"},
+                "to": {"type": "integer"},
+                "offline": {"type": "array", "items": {"type": "string"}, "description": "Pids of nodes that were offline at some point in the window"},
+                "changes": {"type": "array", "items": {
+                    "type": "object",
+                    "properties": {
+                        "pid": {"type": "string"},
+                        "at": {"type": "integer"},
+                        "is_online": {"type": "boolean"}
+                    }
+                }}
+            }
+        },
+        "ModelUsage": {
+            "type": "object",
+            "required": ["service", "name", "bytes", "installed"],
+            "properties": {
+                "service": {"type": "string"},
+                "name": {"type": "string"},
+                "bytes": {"type": "integer"},
+                "installed": {"type": "boolean"},
+                "last_used": {"type": "integer", "nullable": true, "description": "Unix time of the last request that used it"},
+                "installed_at": {"type": "integer", "nullable": true, "description": "Unix time its files were last written, eviction counts never used models as idle from then"}
+            }
+        },
+        "StorageReport": {
+            "type": "object",
+            "required": ["models_bytes", "cache_bytes", "files_bytes", "tmp_bytes", "models"],
+            "properties": {
+                "max_bytes": {"type": "integer", "nullable": true, "description": "[storage] max_bytes, null when unlimited"},
+                "models_bytes": {"type": "integer"},
+                "cache_bytes": {"type": "integer"},
+                "files_bytes": {"type": "integer"},
+                "tmp_bytes": {"type": "integer"},
+                "models": {"type": "array", "items": schema_ref("ModelUsage")}
+            }
+        },
+        "EvictReport": {
+            "type": "object",
+            "required": ["evicted", "freed_bytes"],
+            "properties": {
+                "evicted": {"type": "array", "items": schema_ref("ModelUsage")},
+                "freed_bytes": {"type": "integer"}
+            }
+        },
+        "BenchmarkRun": {
+            "type": "object",
+            "required": ["at", "score"],
+            "properties": {
+                "at": {"type": "integer"},
+                "score": {"type": "integer"}
+            }
+        },
+        "ThalamusNodeJob": {
+            "type": "object",
+            "properties": {
+                "oid": {"type": "string"},
+                "job_identifier": {"type": "string"},
+                "url": {"type": "string", "nullable": true, "description": "Where the result can be fetched"},
+                "status": {"type": "string", "nullable": true, "enum": ["queued", "running", "completed", "failed"]},
+                "progress": {"type": "number", "nullable": true, "description": "0.0 to 1.0"},
+                "started_at": {"type": "integer"}
+            }
+        }
+    });
+}
+
+pub fn docs_html() -> String {
+    return String::from(r##"<!DOCTYPE html>
+<html>
+<head>
+    <title>Thalamus API</title>
+    <link rel="stylesheet" href="https://unpkg.com/swagger-ui-dist@5/swagger-ui.css" />
+</head>
+<body>
+    <div id="swagger-ui"></div>
+    <script src="https://unpkg.com/swagger-ui-dist@5/swagger-ui-bundle.js"></script>
+    <script>
+        window.onload = function() {
+            SwaggerUIBundle({ url: "/api/openapi.json", dom_id: "#swagger-ui" });
+        };
+    </script>
+</body>
+</html>
+"##);
+}
+
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn refs(value: &Value, found: &mut Vec<String>) {
+        match value {
+            Value::Object(map) => {
+                for (key, value) in map.iter() {
+                    match (key.as_str(), value) {
+                        ("$ref", Value::String(reference)) => found.push(reference.clone()),
+                        _ => refs(value, found),
+                    }
+                }
+            },
+            Value::Array(values) => values.iter().for_each(|value| refs(value, found)),
+            _ => {},
+        }
+    }
+
+    #[test]
+    fn test_every_route_is_documented() {
+        let router = crate::thalamus::http::routes();
+        let spec = spec(&router);
+        for route in router.routes() {
+            let operation = &spec["paths"][route.pattern.as_str()][route.method.to_lowercase()];
+            assert!(operation.is_object(), "{} {} is missing from the spec", route.method, route.pattern);
+            assert_ne!(operation["summary"], json!(format!("{} {}", route.method, route.pattern)), "{} {} has no description", route.method, route.pattern);
+        }
+
+        let mut found = Vec::new();
+        refs(&spec, &mut found);
+        for reference in found {
+            let name = reference.trim_start_matches("#/components/schemas/");
+            assert!(spec["components"]["schemas"][name].is_object(), "{} does not resolve", reference);
+        }
+    }
+}
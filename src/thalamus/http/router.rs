@@ -0,0 +1,230 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// Declarative route table for the web api
+// Paths are matched exactly, segments wrapped in {} are captured as parameters
+// e.g. /api/jobs/{oid} matches /api/jobs/abc123 with oid=abc123. A single trailing slash
+// is ignored, a path with empty segments (//api/nodex) matches nothing.
+
+use rouille::Request;
+use rouille::Response;
+
+use crate::thalamus::http::{ErrorKind, HttpContext, Result};
+
+pub type Handler = fn(&Request, &RouteParams, &HttpContext) -> Result<Response>;
+
+/// Path parameters captured while matching a route
+#[derive(Debug, Clone, Default)]
+pub struct RouteParams {
+    params: Vec<(String, String)>,
+}
+impl RouteParams {
+    pub fn get(&self, name: &str) -> Option<&str> {
+        return self.params.iter().find(|(k, _)| k == name).map(|(_, v)| v.as_str());
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+enum Segment {
+    Static(String),
+    Param(String),
+}
+
+/// A single method + path pattern bound to a handler
+#[derive(Clone)]
+pub struct Route {
+    pub method: &'static str,
+    pub pattern: String,
+    segments: Vec<Segment>,
+    pub handler: Handler,
+}
+impl Route {
+    pub fn new(method: &'static str, pattern: &str, handler: Handler) -> Route {
+        let segments = split_path(pattern).into_iter().map(|segment| {
+            if segment.starts_with('{') && segment.ends_with('}') {
+                Segment::Param(segment[1..segment.len() - 1].to_string())
+            } else {
+                Segment::Static(segment.to_string())
+            }
+        }).collect();
+
+        Route {
+            method: method,
+            pattern: pattern.to_string(),
+            segments: segments,
+            handler: handler,
+        }
+    }
+
+    /// Returns the captured parameters if the path matches this route's pattern
+    pub fn matches(&self, path: &str) -> Option<RouteParams> {
+        let parts = split_path(path);
+        if parts.len() != self.segments.len() {
+            return None;
+        }
+
+        let mut params = RouteParams::default();
+        for (segment, part) in self.segments.iter().zip(parts.iter()) {
+            match segment {
+                Segment::Static(s) => {
+                    if s != part {
+                        return None;
+                    }
+                },
+                Segment::Param(name) => {
+                    if part.is_empty() {
+                        return None;
+                    }
+                    params.params.push((name.clone(), part.to_string()));
+                }
+            }
+        }
+        return Some(params);
+    }
+}
+
+/// Result of looking a request up in the route table
+pub enum RouteMatch<'a> {
+    Found(&'a Route, RouteParams),
+    MethodNotAllowed(Vec<&'static str>),
+    NotFound,
+}
+
+#[derive(Clone, Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+impl Router {
+    pub fn new() -> Router {
+        Router { routes: Vec::new() }
+    }
+
+    pub fn route(&mut self, method: &'static str, pattern: &str, handler: Handler) -> &mut Router {
+        self.routes.push(Route::new(method, pattern, handler));
+        self
+    }
+
+    pub fn get(&mut self, pattern: &str, handler: Handler) -> &mut Router {
+        self.route("GET", pattern, handler)
+    }
+
+    pub fn post(&mut self, pattern: &str, handler: Handler) -> &mut Router {
+        self.route("POST", pattern, handler)
+    }
+
+    pub fn delete(&mut self, pattern: &str, handler: Handler) -> &mut Router {
+        self.route("DELETE", pattern, handler)
+    }
+
+    pub fn routes(&self) -> &Vec<Route> {
+        &self.routes
+    }
+
+    pub fn find(&self, method: &str, path: &str) -> RouteMatch {
+        let mut allowed: Vec<&'static str> = Vec::new();
+        for route in &self.routes {
+            if let Some(params) = route.matches(path) {
+                if route.method.eq_ignore_ascii_case(method) {
+                    return RouteMatch::Found(route, params);
+                }
+                if !allowed.contains(&route.method) {
+                    allowed.push(route.method);
+                }
+            }
+        }
+
+        if allowed.len() > 0 {
+            return RouteMatch::MethodNotAllowed(allowed);
+        }
+        return RouteMatch::NotFound;
+    }
+
+    pub fn dispatch(&self, request: &Request, context: &HttpContext) -> Result<Response> {
+        match self.find(request.method(), request.url().as_str()) {
+            RouteMatch::Found(route, params) => {
+                return (route.handler)(request, &params, context);
+            },
+            RouteMatch::MethodNotAllowed(allowed) => {
+                return Err(ErrorKind::MethodNotAllowed(request.method().to_string(), allowed.join(", ")).into());
+            },
+            RouteMatch::NotFound => {
+                return Err(ErrorKind::RouteNotFound(request.url()).into());
+            }
+        }
+    }
+}
+
+fn split_path(path: &str) -> Vec<&str> {
+    let trimmed = path.strip_prefix('/').unwrap_or(path);
+    let trimmed = trimmed.strip_suffix('/').unwrap_or(trimmed);
+    if trimmed.is_empty() {
+        return Vec::new();
+    }
+    return trimmed.split('/').collect();
+}
+
+/// The path with empty segments and the trailing slash removed, /api//nodex/ is /api/nodex
+pub fn normalize(path: &str) -> String {
+    return format!("/{}", path.split('/').filter(|segment| !segment.is_empty()).collect::<Vec<&str>>().join("/"));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noop(_request: &Request, _params: &RouteParams, _context: &HttpContext) -> Result<Response> {
+        Ok(Response::empty_204())
+    }
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.get("/", noop);
+        router.get("/api/thalamus/version", noop);
+        router.post("/api/services/whisper", noop);
+        router.get("/api/jobs/{oid}", noop);
+        router.get("/api/jobs/{oid}/result", noop);
+        router
+    }
+
+    #[test]
+    fn test_exact_match() {
+        let router = router();
+        assert!(matches!(router.find("GET", "/api/thalamus/version"), RouteMatch::Found(_, _)));
+        assert!(matches!(router.find("GET", "/api/thalamus/version/"), RouteMatch::Found(_, _)));
+        assert!(matches!(router.find("GET", "/api/thalamus/versions"), RouteMatch::NotFound));
+        assert!(matches!(router.find("POST", "/api/services/whisper/extra"), RouteMatch::NotFound));
+        assert!(matches!(router.find("GET", "/"), RouteMatch::Found(_, _)));
+        assert!(matches!(router.find("GET", "//api/thalamus/version"), RouteMatch::NotFound));
+        assert!(matches!(router.find("GET", "/api//thalamus/version"), RouteMatch::NotFound));
+        assert!(matches!(router.find("GET", "/api/thalamus/version//"), RouteMatch::NotFound));
+        assert_eq!(normalize("//api//nodex/"), "/api/nodex");
+        assert_eq!(normalize("/"), "/");
+    }
+
+    #[test]
+    fn test_path_params() {
+        let router = router();
+        match router.find("GET", "/api/jobs/abc123/result") {
+            RouteMatch::Found(route, params) => {
+                assert_eq!(route.pattern, "/api/jobs/{oid}/result");
+                assert_eq!(params.get("oid"), Some("abc123"));
+            },
+            _ => panic!("expected a match"),
+        }
+    }
+
+    #[test]
+    fn test_method_not_allowed() {
+        let router = router();
+        match router.find("GET", "/api/services/whisper") {
+            RouteMatch::MethodNotAllowed(allowed) => assert_eq!(allowed, vec!["POST"]),
+            _ => panic!("expected 405"),
+        }
+    }
+}
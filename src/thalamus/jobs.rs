@@ -0,0 +1,268 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// Background jobs for long running inference
+// Services parse their input on the http worker, then either run the work inline or
+// (with ?async=true or "Prefer: respond-async") hand it to a job thread and reply 202
+// with the ThalamusNodeJob. Progress and results are served from /api/jobs/{oid}.
+// Results that are already known (cache hits) are recorded as completed jobs, so async
+// callers get the same 202 and job to poll either way.
+
+use rouille::Request;
+use rouille::Response;
+use std::collections::HashMap;
+use std::fs::File;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::ThalamusNodeJob;
+
+pub const STATUS_QUEUED: &str = "queued";
+pub const STATUS_RUNNING: &str = "running";
+pub const STATUS_COMPLETED: &str = "completed";
+pub const STATUS_FAILED: &str = "failed";
+
+// Finished jobs are forgotten after an hour
+pub const RETENTION_SECS: i64 = 60 * 60;
+
+/// What a job produced, turned into a response when the result is fetched
+#[derive(Debug, Clone)]
+pub enum JobOutput {
+    Json(serde_json::Value),
+    Text(String),
+    Data(String, Vec<u8>),
+    File(String, String),
+    /// A file inside a scratch directory, which is removed once the output is dropped
+    ScratchFile(String, String, Arc<crate::thalamus::scratch::ScratchDir>),
+}
+impl JobOutput {
+    pub fn into_response(self) -> crate::thalamus::http::Result<Response> {
+        match self {
+            JobOutput::Json(value) => return Ok(Response::json(&value)),
+            JobOutput::Text(text) => return Ok(Response::text(text)),
+            JobOutput::Data(mime, data) => return Ok(Response::from_data(mime, data)),
+            JobOutput::File(mime, path) => return Ok(Response::from_file(mime, File::open(path)?)),
+            // The open handle keeps the file readable after the directory is removed
+            JobOutput::ScratchFile(mime, path, _scratch) => return Ok(Response::from_file(mime, File::open(path)?)),
+        }
+    }
+}
+
+/// Status, code and message of a failed job, kept so the result endpoint can replay the error
+#[derive(Debug, Clone)]
+pub struct JobFailure {
+    pub status: u16,
+    pub code: String,
+    pub message: String,
+}
+
+#[derive(Debug, Clone)]
+pub struct JobEntry {
+    pub job: ThalamusNodeJob,
+    pub output: Option<JobOutput>,
+    pub failure: Option<JobFailure>,
+    pub finished_at: Option<i64>,
+}
+
+/// Passed to the work closure so it can report progress
+#[derive(Clone)]
+pub struct JobHandle {
+    pub oid: Option<String>,
+    registry: Option<Arc<JobRegistry>>,
+}
+impl JobHandle {
+    /// Handle for work running inline on the http worker, progress goes nowhere
+    pub fn inline() -> JobHandle {
+        JobHandle { oid: None, registry: None }
+    }
+
+    pub fn progress(&self, progress: f64) {
+        if let (Some(oid), Some(registry)) = (&self.oid, &self.registry) {
+            registry.update(oid.as_str(), |entry| entry.job.progress = Some(progress.max(0.0).min(1.0)));
+        }
+    }
+}
+
+#[derive(Default)]
+pub struct JobRegistry {
+    jobs: Mutex<HashMap<String, JobEntry>>,
+}
+impl JobRegistry {
+    pub fn new() -> JobRegistry {
+        JobRegistry { jobs: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn get(&self, oid: &str) -> Option<JobEntry> {
+        return self.jobs.lock().unwrap().get(oid).cloned();
+    }
+
+    pub fn list(&self) -> Vec<ThalamusNodeJob> {
+        let mut jobs: Vec<ThalamusNodeJob> = self.jobs.lock().unwrap().values().map(|entry| entry.job.clone()).collect();
+        jobs.sort_by_key(|job| job.started_at);
+        return jobs;
+    }
+
+    fn update<F: FnOnce(&mut JobEntry)>(&self, oid: &str, f: F) {
+        if let Some(entry) = self.jobs.lock().unwrap().get_mut(oid) {
+            f(entry);
+        }
+    }
+
+    // Keeps the job table in thalamus.db in step, progress updates are not written
+    fn persist(&self, oid: &str) {
+        if let (Some(store), Some(entry)) = (crate::thalamus::store::get(), self.get(oid)) {
+            let error = entry.failure.as_ref().map(|failure| failure.message.as_str());
+            if let Err(e) = store.record_job(&entry.job, entry.finished_at, error) {
+                log::error!("Unable to record job {}: {}", oid, e);
+            }
+        }
+    }
+
+    fn prune(&self) {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        self.jobs.lock().unwrap().retain(|_, entry| match entry.finished_at {
+            Some(finished_at) => now - finished_at < RETENTION_SECS,
+            None => true,
+        });
+    }
+
+    /// Queues the work on its own thread and returns the job describing it
+    pub fn submit<F>(self: &Arc<Self>, job_identifier: &str, work: F) -> crate::thalamus::http::Result<ThalamusNodeJob>
+    where F: FnOnce(&JobHandle) -> crate::thalamus::http::Result<JobOutput> + Send + 'static {
+        self.prune();
+
+        let mut job = ThalamusNodeJob::new(job_identifier.to_string());
+        job.status = Some(STATUS_QUEUED.to_string());
+        job.progress = Some(0.0);
+        job.url = Some(format!("/api/jobs/{}/result", job.oid));
+
+        self.jobs.lock().unwrap().insert(job.oid.clone(), JobEntry {
+            job: job.clone(),
+            output: None,
+            failure: None,
+            finished_at: None,
+        });
+        self.persist(&job.oid);
+
+        let registry = Arc::clone(self);
+        let handle = JobHandle { oid: Some(job.oid.clone()), registry: Some(Arc::clone(self)) };
+        let oid = job.oid.clone();
+        std::thread::Builder::new().name(format!("job_{}", oid)).spawn(move || {
+            registry.update(oid.as_str(), |entry| entry.job.status = Some(STATUS_RUNNING.to_string()));
+            registry.persist(&oid);
+
+            let result = work(&handle);
+            let finished_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+            registry.update(oid.as_str(), |entry| {
+                entry.finished_at = Some(finished_at);
+                match result {
+                    Ok(output) => {
+                        entry.job.status = Some(STATUS_COMPLETED.to_string());
+                        entry.job.progress = Some(1.0);
+                        entry.output = Some(output);
+                    },
+                    Err(err) => {
+                        log::error!("JOB[{}] {} failed: {}", entry.job.oid, entry.job.job_identifier, err);
+                        let class = crate::thalamus::http::errors::classify(&err);
+                        entry.job.status = Some(STATUS_FAILED.to_string());
+                        entry.failure = Some(JobFailure {
+                            status: class.status,
+                            code: class.code.to_string(),
+                            message: format!("{}", err),
+                        });
+                    }
+                }
+            });
+            registry.persist(&oid);
+        })?;
+
+        return Ok(job);
+    }
+
+    /// Records a job whose output is already known, it is completed as soon as it exists
+    pub fn completed(self: &Arc<Self>, job_identifier: &str, output: JobOutput) -> ThalamusNodeJob {
+        self.prune();
+
+        let mut job = ThalamusNodeJob::new(job_identifier.to_string());
+        job.status = Some(STATUS_COMPLETED.to_string());
+        job.progress = Some(1.0);
+        job.url = Some(format!("/api/jobs/{}/result", job.oid));
+
+        let finished_at = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+        self.jobs.lock().unwrap().insert(job.oid.clone(), JobEntry {
+            job: job.clone(),
+            output: Some(output),
+            failure: None,
+            finished_at: Some(finished_at),
+        });
+        self.persist(&job.oid);
+
+        return job;
+    }
+}
+
+/// True when the caller asked for a job instead of waiting on the response
+pub fn wants_async(request: &Request) -> bool {
+    if let Some(value) = request.get_param("async") {
+        return value == "true" || value == "1";
+    }
+    return match request.header("Prefer") {
+        Some(prefer) => prefer.split(',').any(|p| p.trim() == "respond-async"),
+        None => false,
+    };
+}
+
+/// Runs the work inline, or as a job when the caller asked for async
+pub fn respond<F>(request: &Request, context: &crate::thalamus::http::HttpContext, job_identifier: &str, work: F) -> crate::thalamus::http::Result<Response>
+where F: FnOnce(&JobHandle) -> crate::thalamus::http::Result<JobOutput> + Send + 'static {
+    if wants_async(request) {
+        return accepted(context.jobs.submit(job_identifier, work)?);
+    }
+    return work(&JobHandle::inline())?.into_response();
+}
+
+/// Replies with output that is already at hand, as a completed job when the caller asked for async
+pub fn respond_completed(request: &Request, context: &crate::thalamus::http::HttpContext, job_identifier: &str, output: JobOutput) -> crate::thalamus::http::Result<Response> {
+    if wants_async(request) {
+        return accepted(context.jobs.completed(job_identifier, output));
+    }
+    return output.into_response();
+}
+
+/// 202 reply pointing at the job's status url
+pub fn accepted(job: ThalamusNodeJob) -> crate::thalamus::http::Result<Response> {
+    let location = format!("/api/jobs/{}", job.oid);
+    return Ok(Response::json(&job).with_status_code(202).with_unique_header("Location", location));
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_completed_job() {
+        let registry = Arc::new(JobRegistry::new());
+        let job = registry.completed("tts", JobOutput::Data("audio/wav".to_string(), vec![1, 2, 3]));
+        assert_eq!(job.status, Some(STATUS_COMPLETED.to_string()));
+        assert_eq!(job.progress, Some(1.0));
+        assert_eq!(job.url, Some(format!("/api/jobs/{}/result", job.oid)));
+
+        let entry = registry.get(&job.oid).unwrap();
+        assert!(entry.finished_at.is_some());
+        assert!(entry.failure.is_none());
+        match entry.output {
+            Some(JobOutput::Data(mime, data)) => {
+                assert_eq!(mime, "audio/wav");
+                assert_eq!(data, vec![1, 2, 3]);
+            },
+            other => panic!("unexpected output: {:?}", other),
+        }
+        assert_eq!(registry.list().len(), 1);
+    }
+}
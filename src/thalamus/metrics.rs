@@ -0,0 +1,165 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// Prometheus metrics served at /metrics in the text exposition format
+// Counters and histograms are recorded as requests and inference run,
+// gauges (jobs, mesh nodes) are sampled when /metrics is scraped.
+
+use std::collections::BTreeMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+const HTTP_BUCKETS: [f64; 11] = [0.005, 0.01, 0.05, 0.1, 0.25, 0.5, 1.0, 5.0, 10.0, 30.0, 60.0];
+const INFERENCE_BUCKETS: [f64; 11] = [0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0];
+
+// name, type, help
+const DEFINITIONS: [(&str, &str, &str); 7] = [
+    ("thalamus_http_requests_total", "counter", "HTTP requests by method, route and status"),
+    ("thalamus_http_request_duration_seconds", "histogram", "Time spent in the route handler"),
+    ("thalamus_inference_duration_seconds", "histogram", "Inference time by service and model"),
+    ("thalamus_process_failures_total", "counter", "External processes that failed to start or exited non-zero"),
+    ("thalamus_jobs", "gauge", "Jobs in the registry by status"),
+    ("thalamus_mesh_nodes", "gauge", "Known mesh nodes by state"),
+    ("thalamus_mesh_node_last_ping_age_seconds", "gauge", "Seconds since each mesh node was last seen"),
+];
+
+struct Histogram {
+    buckets: &'static [f64],
+    counts: Vec<u64>,
+    sum: f64,
+    count: u64,
+}
+impl Histogram {
+    fn new(buckets: &'static [f64]) -> Histogram {
+        Histogram { buckets: buckets, counts: vec![0; buckets.len()], sum: 0.0, count: 0 }
+    }
+
+    fn observe(&mut self, value: f64) {
+        for (i, bound) in self.buckets.iter().enumerate() {
+            if value <= *bound {
+                self.counts[i] += 1;
+            }
+        }
+        self.sum += value;
+        self.count += 1;
+    }
+}
+
+#[derive(Default)]
+struct Registry {
+    counters: BTreeMap<(String, String), f64>,
+    histograms: BTreeMap<(String, String), Histogram>,
+}
+
+static REGISTRY: Mutex<Option<Registry>> = Mutex::new(None);
+
+/// A gauge value sampled at scrape time
+pub struct Sample {
+    pub name: &'static str,
+    pub labels: Vec<(&'static str, String)>,
+    pub value: f64,
+}
+
+fn escape(value: &str) -> String {
+    return value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n");
+}
+
+fn labels(pairs: &[(&str, &str)]) -> String {
+    return pairs.iter().map(|(k, v)| format!("{}=\"{}\"", k, escape(v))).collect::<Vec<String>>().join(",");
+}
+
+fn with_labels(name: &str, labels: &str) -> String {
+    if labels.is_empty() {
+        return name.to_string();
+    }
+    return format!("{}{{{}}}", name, labels);
+}
+
+pub fn inc_counter(name: &str, pairs: &[(&str, &str)]) {
+    let mut registry = REGISTRY.lock().unwrap();
+    let registry = registry.get_or_insert_with(Registry::default);
+    *registry.counters.entry((name.to_string(), labels(pairs))).or_insert(0.0) += 1.0;
+}
+
+pub fn observe(name: &str, buckets: &'static [f64], pairs: &[(&str, &str)], value: f64) {
+    let mut registry = REGISTRY.lock().unwrap();
+    let registry = registry.get_or_insert_with(Registry::default);
+    registry.histograms.entry((name.to_string(), labels(pairs))).or_insert_with(|| Histogram::new(buckets)).observe(value);
+}
+
+pub fn observe_request(method: &str, route: &str, status: u16, elapsed: Duration) {
+    let status = status.to_string();
+    inc_counter("thalamus_http_requests_total", &[("method", method), ("route", route), ("status", status.as_str())]);
+    observe("thalamus_http_request_duration_seconds", &HTTP_BUCKETS, &[("method", method), ("route", route)], elapsed.as_secs_f64());
+}
+
+pub fn process_failed(program: &str) {
+    inc_counter("thalamus_process_failures_total", &[("program", program)]);
+}
+
+/// Runs an inference call and records how long it took
+pub fn time_inference<T, F: FnOnce() -> T>(service: &str, model: &str, f: F) -> T {
+    let started = Instant::now();
+    let result = f();
+    observe_inference(service, model, started.elapsed());
+    return result;
+}
+
+/// Records an inference that time_inference can't wrap, like a streamed one
+pub fn observe_inference(service: &str, model: &str, elapsed: Duration) {
+    observe("thalamus_inference_duration_seconds", &INFERENCE_BUCKETS, &[("service", service), ("model", model)], elapsed.as_secs_f64());
+}
+
+pub fn render(gauges: Vec<Sample>) -> String {
+    let mut out = String::new();
+    let registry = REGISTRY.lock().unwrap();
+
+    for (name, kind, help) in DEFINITIONS.iter() {
+        out.push_str(format!("# HELP {} {}\n# TYPE {} {}\n", name, help, name, kind).as_str());
+
+        if let Some(registry) = registry.as_ref() {
+            for ((metric, labels), value) in registry.counters.iter().filter(|((metric, _), _)| metric == name) {
+                out.push_str(format!("{} {}\n", with_labels(metric, labels), value).as_str());
+            }
+
+            for ((metric, labels), histogram) in registry.histograms.iter().filter(|((metric, _), _)| metric == name) {
+                let prefix = if labels.is_empty() { String::new() } else { format!("{},", labels) };
+                for (bound, count) in histogram.buckets.iter().zip(histogram.counts.iter()) {
+                    out.push_str(format!("{}_bucket{{{}le=\"{}\"}} {}\n", metric, prefix, bound, count).as_str());
+                }
+                out.push_str(format!("{}_bucket{{{}le=\"+Inf\"}} {}\n", metric, prefix, histogram.count).as_str());
+                out.push_str(format!("{} {}\n", with_labels(format!("{}_sum", metric).as_str(), labels), histogram.sum).as_str());
+                out.push_str(format!("{} {}\n", with_labels(format!("{}_count", metric).as_str(), labels), histogram.count).as_str());
+            }
+        }
+
+        for sample in gauges.iter().filter(|sample| sample.name == *name) {
+            let pairs: Vec<(&str, &str)> = sample.labels.iter().map(|(k, v)| (*k, v.as_str())).collect();
+            out.push_str(format!("{} {}\n", with_labels(sample.name, labels(&pairs).as_str()), sample.value).as_str());
+        }
+    }
+
+    return out;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render() {
+        observe_request("GET", "/api/test/metrics", 200, Duration::from_millis(20));
+        let text = render(vec![Sample { name: "thalamus_mesh_nodes", labels: vec![("state", "online".to_string())], value: 2.0 }]);
+        assert!(text.contains("# TYPE thalamus_http_requests_total counter"));
+        assert!(text.contains("thalamus_http_requests_total{method=\"GET\",route=\"/api/test/metrics\",status=\"200\"} 1"));
+        assert!(text.contains("thalamus_http_request_duration_seconds_bucket{method=\"GET\",route=\"/api/test/metrics\",le=\"0.05\"} 1"));
+        assert!(text.contains("thalamus_http_request_duration_seconds_bucket{method=\"GET\",route=\"/api/test/metrics\",le=\"0.01\"} 0"));
+        assert!(text.contains("thalamus_mesh_nodes{state=\"online\"} 2"));
+    }
+}
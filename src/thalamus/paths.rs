@@ -0,0 +1,161 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// Where Thalamus keeps its files
+// Everything lives under one data root (--data-root, THALAMUS_ROOT, default /opt/thalamus,
+// or $XDG_DATA_HOME/thalamus with --rootless) and models, binaries, scratch files and
+// state can each be moved somewhere else:
+//   models  --models-dir  THALAMUS_MODELS_DIR  <root>/models
+//   bin     --bin-dir     THALAMUS_BIN_DIR     <root>/bin
+//   tmp     --tmp-dir     THALAMUS_TMP_DIR     <root>/tmp
+//   state   --state-dir   THALAMUS_STATE_DIR   <root>        (thalamus.db, keys.json, pid, tls/)
+
+use std::path::Path;
+use std::sync::Mutex;
+
+pub const DEFAULT_ROOT: &str = "/opt/thalamus";
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Paths {
+    pub rootless: bool,
+    pub root: String,
+    pub models: String,
+    pub bin: String,
+    pub tmp: String,
+    pub state: String,
+}
+impl Paths {
+    /// Command line first, then the environment, then the default layout under the root
+    pub fn resolve(rootless: bool, root: Option<String>, models: Option<String>, bin: Option<String>, tmp: Option<String>, state: Option<String>) -> Paths {
+        let root = root.or(env("THALAMUS_ROOT")).unwrap_or(default_root(rootless));
+        Paths {
+            rootless: rootless,
+            models: models.or(env("THALAMUS_MODELS_DIR")).unwrap_or(join(&root, "models")),
+            bin: bin.or(env("THALAMUS_BIN_DIR")).unwrap_or(join(&root, "bin")),
+            tmp: tmp.or(env("THALAMUS_TMP_DIR")).unwrap_or(join(&root, "tmp")),
+            state: state.or(env("THALAMUS_STATE_DIR")).unwrap_or(root.clone()),
+            root: root,
+        }
+    }
+
+    pub fn from_args(args: &crate::Args) -> Paths {
+        return Paths::resolve(is_rootless(args), args.data_root.clone(), args.models_dir.clone(), args.bin_dir.clone(), args.tmp_dir.clone(), args.state_dir.clone());
+    }
+
+    /// Command line flags that reproduce this layout, for service files
+    pub fn flags(&self) -> String {
+        let default = Paths::resolve(self.rootless, Some(self.root.clone()), None, None, None, None);
+        let mut flags = String::new();
+        if self.rootless {
+            flags.push_str(" --rootless");
+        }
+        if self.root != default_root(self.rootless) {
+            flags.push_str(format!(" --data-root {}", self.root).as_str());
+        }
+        if self.models != default.models {
+            flags.push_str(format!(" --models-dir {}", self.models).as_str());
+        }
+        if self.bin != default.bin {
+            flags.push_str(format!(" --bin-dir {}", self.bin).as_str());
+        }
+        if self.tmp != default.tmp {
+            flags.push_str(format!(" --tmp-dir {}", self.tmp).as_str());
+        }
+        if self.state != default.state {
+            flags.push_str(format!(" --state-dir {}", self.state).as_str());
+        }
+        return flags;
+    }
+}
+
+static PATHS: Mutex<Option<Paths>> = Mutex::new(None);
+
+fn env(name: &str) -> Option<String> {
+    return std::env::var(name).ok().filter(|value| value.len() > 0);
+}
+
+fn home() -> String {
+    return env("HOME").unwrap_or(".".to_string());
+}
+
+/// $XDG_DATA_HOME, ~/.local/share when unset
+pub fn xdg_data_home() -> String {
+    return env("XDG_DATA_HOME").unwrap_or(join(&home(), ".local/share"));
+}
+
+/// $XDG_CONFIG_HOME, ~/.config when unset
+pub fn xdg_config_home() -> String {
+    return env("XDG_CONFIG_HOME").unwrap_or(join(&home(), ".config"));
+}
+
+pub fn default_root(rootless: bool) -> String {
+    if rootless {
+        return join(&xdg_data_home(), "thalamus");
+    }
+    return DEFAULT_ROOT.to_string();
+}
+
+/// --rootless or THALAMUS_ROOTLESS=1, known before the command line is fully merged with thalamus.toml
+pub fn is_rootless(args: &crate::Args) -> bool {
+    return args.rootless || env("THALAMUS_ROOTLESS").map_or(false, |value| value == "1" || value == "true");
+}
+
+// Absolute paths are kept as they are
+fn join(dir: &str, path: &str) -> String {
+    if path.len() == 0 {
+        return dir.to_string();
+    }
+    return Path::new(dir).join(path).display().to_string();
+}
+
+pub fn init(args: &crate::Args) {
+    let mut paths = PATHS.lock().unwrap();
+    *paths = Some(Paths::from_args(args));
+}
+
+/// The configured layout, taken from the environment if init was never called
+pub fn get() -> Paths {
+    let mut paths = PATHS.lock().unwrap();
+    return paths.get_or_insert_with(|| Paths::resolve(false, None, None, None, None, None)).clone();
+}
+
+pub fn root(path: &str) -> String {
+    return join(&get().root, path);
+}
+
+pub fn model(path: &str) -> String {
+    return join(&get().models, path);
+}
+
+pub fn bin(path: &str) -> String {
+    return join(&get().bin, path);
+}
+
+pub fn tmp(path: &str) -> String {
+    return join(&get().tmp, path);
+}
+
+pub fn state(path: &str) -> String {
+    return join(&get().state, path);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_resolve() {
+        let paths = Paths::resolve(false, Some("/srv/thalamus".to_string()), Some("/mnt/models".to_string()), None, None, None);
+        assert_eq!(paths.models, "/mnt/models");
+        assert_eq!(paths.bin, "/srv/thalamus/bin");
+        assert_eq!(paths.state, "/srv/thalamus");
+        assert_eq!(paths.flags(), " --data-root /srv/thalamus --models-dir /mnt/models");
+        assert_eq!(join(&paths.models, "/tmp/ggml-tiny.bin"), "/tmp/ggml-tiny.bin");
+    }
+}
@@ -0,0 +1,204 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// Per-request scratch directories and the tmp/ sweeper
+// Each upload gets its own tmp/<id>/ (tmp/srgan/<id>/ for srgan) with a random id, so two
+// requests in the same second no longer overwrite each other, and the directory goes away
+// with everything whisper/ffmpeg/srgan left in it (.16.wav, .txt, .wts, .mp4) when the
+// ScratchDir is dropped. The sweeper catches whatever a crash or an older version left
+// behind: entries older than [tmp] ttl_secs go first, then the oldest ones until tmp/ fits
+// in [tmp] max_bytes. Directories still owned by a ScratchDir are never swept.
+
+use rand::distributions::Alphanumeric;
+use rand::{thread_rng, Rng};
+use std::collections::HashSet;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+// Scratch directories that are alive
+static LIVE: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+/// A directory under tmp/ removed with its contents when dropped
+#[derive(Debug)]
+pub struct ScratchDir {
+    path: String,
+}
+impl ScratchDir {
+    /// Creates tmp/<parent>/<id>, parent is "" for tmp/ itself
+    pub fn new(parent: &str) -> io::Result<ScratchDir> {
+        let base = crate::thalamus::paths::tmp(parent);
+        fs::create_dir_all(&base)?;
+        loop {
+            let millis = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_millis();
+            let suffix: String = thread_rng().sample_iter(&Alphanumeric).take(10).map(char::from).collect();
+            let path = Path::new(&base).join(format!("{}-{}", millis, suffix)).display().to_string();
+            match fs::create_dir(&path) {
+                Ok(_) => {
+                    LIVE.lock().unwrap().get_or_insert_with(HashSet::new).insert(path.clone());
+                    return Ok(ScratchDir { path: path });
+                },
+                Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    pub fn path(&self) -> &str {
+        return self.path.as_str();
+    }
+
+    /// Path of a file inside the directory
+    pub fn file(&self, name: &str) -> String {
+        return Path::new(&self.path).join(name).display().to_string();
+    }
+}
+impl Drop for ScratchDir {
+    fn drop(&mut self) {
+        if let Some(live) = LIVE.lock().unwrap().as_mut() {
+            live.remove(&self.path);
+        }
+        if let Err(e) = fs::remove_dir_all(&self.path) {
+            if e.kind() != io::ErrorKind::NotFound {
+                log::error!("Unable to remove {}: {}", self.path, e);
+            }
+        }
+    }
+}
+
+fn is_live(path: &str) -> bool {
+    return LIVE.lock().unwrap().as_ref().map(|live| live.contains(path)).unwrap_or(false);
+}
+
+/// Extension of an uploaded file name, only kept when it is plain alphanumerics
+pub fn extension(file_name: &str, default: &str) -> String {
+    match Path::new(file_name).extension().and_then(|e| e.to_str()) {
+        Some(ext) if ext.len() > 0 && ext.len() <= 8 && ext.chars().all(|c| c.is_ascii_alphanumeric()) => return ext.to_lowercase(),
+        _ => return default.to_string(),
+    }
+}
+
+/// What one sweep removed
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SweepReport {
+    pub removed: usize,
+    pub freed_bytes: u64,
+}
+
+// Size and newest modification time of a file or directory tree
+fn usage(path: &Path) -> io::Result<(u64, SystemTime)> {
+    let metadata = fs::symlink_metadata(path)?;
+    let mut size = metadata.len();
+    let mut modified = metadata.modified()?;
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            let (entry_size, entry_modified) = usage(&entry?.path())?;
+            size += entry_size;
+            modified = modified.max(entry_modified);
+        }
+    }
+    return Ok((size, modified));
+}
+
+/// Removes entries of dir older than ttl, then the oldest ones until dir is under max_bytes.
+/// Names in skip (nested swept directories like srgan) are left alone
+pub fn sweep(dir: &str, skip: &[&str], ttl: Duration, max_bytes: u64) -> io::Result<SweepReport> {
+    let mut report = SweepReport::default();
+    if !Path::new(dir).exists() {
+        return Ok(report);
+    }
+
+    let mut entries: Vec<(String, u64, SystemTime)> = Vec::new();
+    let mut total: u64 = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if skip.iter().any(|name| entry.file_name() == *name) {
+            continue;
+        }
+        let path = entry.path().display().to_string();
+        let (size, modified) = match usage(&entry.path()) {
+            Ok(usage) => usage,
+            // Removed while we were looking at it
+            Err(_) => continue,
+        };
+        total += size;
+        if !is_live(&path) {
+            entries.push((path, size, modified));
+        }
+    }
+    entries.sort_by_key(|(_, _, modified)| *modified);
+
+    let now = SystemTime::now();
+    for (path, size, modified) in entries.iter() {
+        let expired = now.duration_since(*modified).unwrap_or_default() > ttl;
+        if !expired && total <= max_bytes {
+            break;
+        }
+        let removed = match fs::metadata(path) {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path),
+            _ => fs::remove_file(path),
+        };
+        match removed {
+            Ok(_) => {
+                total = total.saturating_sub(*size);
+                report.removed += 1;
+                report.freed_bytes += size;
+            },
+            Err(e) => log::error!("Unable to remove {}: {}", path, e),
+        }
+    }
+    return Ok(report);
+}
+
+/// Sweeps tmp/ and tmp/srgan every [tmp] sweep_interval_secs until shutdown
+pub fn spawn_sweeper() {
+    std::thread::Builder::new().name("tmp_sweeper".to_string()).spawn(|| {
+        while !crate::thalamus::shutdown::is_shutting_down() {
+            let config = crate::thalamus::config::get().tmp;
+            let ttl = Duration::from_secs(config.ttl_secs);
+            for (dir, skip) in [(crate::thalamus::paths::tmp("srgan"), vec![]), (crate::thalamus::paths::get().tmp, vec!["srgan"])] {
+                match sweep(&dir, &skip, ttl, config.max_bytes) {
+                    Ok(report) if report.removed > 0 => log::info!("Swept {} entries ({} bytes) from {}", report.removed, report.freed_bytes, dir),
+                    Ok(_) => {},
+                    Err(e) => log::error!("Unable to sweep {}: {}", dir, e),
+                }
+            }
+            std::thread::sleep(Duration::from_secs(config.sweep_interval_secs.max(1)));
+        }
+    }).expect("failed to spawn the tmp sweeper");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_sweep() {
+        let dir = std::env::temp_dir().join(format!("thalamus_sweep_{}", std::process::id()));
+        fs::create_dir_all(dir.join("srgan")).unwrap();
+        fs::write(dir.join("old.wav"), vec![0u8; 64]).unwrap();
+        fs::write(dir.join("new.wav"), vec![0u8; 64]).unwrap();
+        fs::write(dir.join("srgan").join("keep.png"), vec![0u8; 64]).unwrap();
+        let dir = dir.display().to_string();
+
+        // Under budget and nothing expired
+        assert_eq!(sweep(&dir, &["srgan"], Duration::from_secs(3600), 1024).unwrap().removed, 0);
+
+        // Over budget, the oldest goes first
+        let report = sweep(&dir, &["srgan"], Duration::from_secs(3600), 100).unwrap();
+        assert_eq!(report, SweepReport { removed: 1, freed_bytes: 64 });
+        assert!(Path::new(&dir).join("srgan").join("keep.png").exists());
+
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(extension("photo.JPG", "jpg"), "jpg");
+        assert_eq!(extension("../../etc/passwd", "png"), "png");
+    }
+}
@@ -18,6 +18,7 @@ error_chain! {
         PostError(rouille::input::post::PostError);
         InternalToolsError(crate::thalamus::tools::Error);
         ExternalRequestError(reqwest::Error);
+        FilesError(crate::thalamus::files::Error);
         // Postgres(postgres::Error);
         // PostError(rouille::input::post::PostError);
         // RustTubeError(rustube::Error);
@@ -29,4 +30,19 @@ error_chain! {
     foreign_links {
         TchError(tch::TchError);
     }
+
+    errors {
+        UnknownModel(model: String) {
+            description("unknown model")
+            display("unknown model: {}", model)
+        }
+        ModelNotInstalled(model: String) {
+            description("model is not installed on this node")
+            display("model is not installed on this node: {}", model)
+        }
+        InvalidInput(detail: String) {
+            description("invalid input")
+            display("invalid input: {}", detail)
+        }
+    }
 }
\ No newline at end of file
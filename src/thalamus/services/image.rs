@@ -14,11 +14,6 @@ pub mod yolo;
 
 
 
-use rouille::Request;
-use rouille::Response;
-
-
-
 pub fn install() -> Result<(), crate::thalamus::setup::Error> {
     // match nst::install(){
     //     Ok(_) => {
@@ -31,22 +26,3 @@ pub fn install() -> Result<(), crate::thalamus::setup::Error> {
 
     Ok(())
 }
-
-
-pub fn handle(request: &Request) -> Result<Response, crate::thalamus::http::Error> {
-    
-    if request.url().contains("/api/services/image/ocnn"){
-        return Ok(crate::thalamus::services::image::ocnn::handle(request)?);
-    }
-    
-    if request.url().contains("/api/services/image/srgan"){
-        return Ok(crate::thalamus::services::image::srgan::handle(request)?);
-    }
-
-    #[cfg(feature = "pytorch")]
-    if request.url().contains("/api/services/image/nst"){
-        return Ok(crate::thalamus::services::image::nst::handle(request)?);
-    }
-
-    return Ok(Response::empty_404());
-}
\ No newline at end of file
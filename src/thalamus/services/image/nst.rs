@@ -15,7 +15,6 @@
 //   https://github.com/LaurentMazare/tch-rs/releases/download/mw/vgg16.ot
 use tch::vision::{imagenet, vgg};
 use tch::{nn, nn::OptimizerConfig, Device, Tensor};
-use std::path::Path;
 use std::fs;
 use std::fs::File;
 
@@ -24,7 +23,6 @@ use serde::{Serialize, Deserialize};
 use rouille::post_input;
 use rouille::Request;
 use rouille::Response;
-use std::thread;
 
 use titlecase::titlecase;
 
@@ -38,43 +36,33 @@ const STYLE_INDEXES: [usize; 5] = [0, 2, 5, 7, 10];
 const CONTENT_INDEXES: [usize; 1] = [7];
 
 
-pub fn handle(request: &Request) -> Result<Response, crate::thalamus::http::Error> {
-    if request.url().contains("/styles"){
-        return Ok(Response::json(&styles().unwrap()));
-    }
+pub fn handle_styles(_request: &Request) -> Result<Response, crate::thalamus::http::Error> {
+    return Ok(Response::json(&styles()?));
+}
 
-    if request.url().contains("/run"){
+pub fn handle_run(request: &Request, context: &crate::thalamus::http::HttpContext) -> Result<Response, crate::thalamus::http::Error> {
 
-        let input = post_input!(request, {
-            image_id: String, // oid:<oid>, dropbox:<id>
-            nst_style: String, // Fra Angelico, Vincent Van Gogh
-        })?;
+    let input = post_input!(request, {
+        image_id: String, // file id from POST /api/files, optionally prefixed with oid:
+        nst_style: String, // Fra Angelico, Vincent Van Gogh
+    })?;
 
-        let mut selected_style = format!("/opt/thalamus/models/nst/vincent_van_gogh.jpg");
-        for style in styles()?{
-            if style.name == input.nst_style.as_str() {
-                selected_style = style.file_path.to_string();
-            }
-        }
-
-        // file
-        if input.image_id.contains("oid:") {
-            let oid = input.image_id.replace("oid:", "");
-            if Path::new(format!("/opt/thalamus/files/{}", oid).as_str()).exists(){
-                thread::Builder::new().name("nst_thread".to_string()).spawn(move || {
-                    match run(&selected_style, format!("/opt/thalamus/files/{}", oid).as_str(), oid, input.nst_style){
-                        Ok(_) => (),
-                        Err(e) => log::error!("{}", e),
-                    }
-                })?;
-            }
+    let mut selected_style = crate::thalamus::paths::model("nst/vincent_van_gogh.jpg");
+    for style in styles()?{
+        if style.name == input.nst_style.as_str() {
+            selected_style = style.file_path.to_string();
         }
+    }
 
-        
+    let oid = input.image_id.trim_start_matches("oid:").to_string();
+    let content_path = crate::thalamus::files::path(&oid)?;
 
-        // return Ok(Response::json(&styles().unwrap()));
-    }
-    return Ok(Response::empty_404());
+    // Style transfer always takes minutes, so it is always a job
+    let job = context.jobs.submit(format!("nst:{}", input.nst_style).as_str(), move |job| {
+        let output_path = run(&selected_style, content_path.as_str(), oid, input.nst_style, job)?;
+        return Ok(crate::thalamus::jobs::JobOutput::File("image/jpeg".to_string(), output_path));
+    })?;
+    return crate::thalamus::jobs::accepted(job);
 }
 
 fn gram_matrix(m: &Tensor) -> Tensor {
@@ -88,7 +76,7 @@ fn style_loss(m1: &Tensor, m2: &Tensor) -> Tensor {
     gram_matrix(m1).mse_loss(&gram_matrix(m2), tch::Reduction::Mean)
 }
 
-pub fn run(style_img: &str, content_img: &str, _oid: String, _style: String) -> Result<(), crate::thalamus::services::Error> {
+pub fn run(style_img: &str, content_img: &str, oid: String, _style: String, job: &crate::thalamus::jobs::JobHandle) -> Result<String, crate::thalamus::services::Error> {
 
     log::info!("NST");
     log::info!("style image: {:?}", style_img);
@@ -99,7 +87,8 @@ pub fn run(style_img: &str, content_img: &str, _oid: String, _style: String) ->
 
     let mut net_vs = tch::nn::VarStore::new(device);
     let net = vgg::vgg16(&net_vs.root(), imagenet::CLASS_COUNT);
-    net_vs.load("/opt/thalamus/models/vgg16.ot")?;
+    net_vs.load(&crate::thalamus::paths::model("vgg16.ot"))?;
+    crate::thalamus::storage::touch("nst", "vgg16");
     net_vs.freeze();
 
     let style_img = imagenet::load_image(&style_img)?
@@ -116,6 +105,9 @@ pub fn run(style_img: &str, content_img: &str, _oid: String, _style: String) ->
     let input_var = vs.root().var_copy("img", &content_img);
     let mut opt = nn::Adam::default().build(&vs, LEARNING_RATE)?;
 
+    let output_id = job.oid.clone().unwrap_or(oid.clone());
+    let scratch = crate::thalamus::scratch::ScratchDir::new("")?;
+    let mut output_path = String::new();
     for step_idx in 1..(1 + TOTAL_STEPS) {
         let input_layers = net.forward_all_t(&input_var, false, Some(max_layer));
         let style_loss: Tensor =
@@ -127,14 +119,21 @@ pub fn run(style_img: &str, content_img: &str, _oid: String, _style: String) ->
         let loss = style_loss * STYLE_WEIGHT + content_loss;
         opt.backward_step(&loss);
         // log::info!("{} {}", step_idx, f64::from(loss.clone(&loss)));
+        if step_idx % 100 == 0 {
+            job.progress(step_idx as f64 / TOTAL_STEPS as f64);
+        }
         if step_idx % 1000 == 0 {
             // log::info!("{} {}", step_idx, f64::from(loss));
-            imagenet::save_image(&input_var, &format!("/opt/thalamus/files/out{}.jpg", step_idx))?;
-
+            // Every checkpoint lands in the file store, the last one is the job's output
+            let step_path = scratch.file(&format!("{}-nst-{}.jpg", output_id, step_idx));
+            imagenet::save_image(&input_var, &step_path)?;
 
-            let mut file = File::open(format!("/opt/thalamus/files/out{}.jpg", step_idx))?;
+            let mut file = File::open(step_path.as_str())?;
             let mut buf = Vec::new();
             file.read_to_end(&mut buf)?;
+            let record = crate::thalamus::files::put(&buf, Some(&format!("{}-nst-{}.jpg", output_id, step_idx)), Some("image/jpeg"))?;
+            log::info!("NST step {} stored as file {}", step_idx, record.id);
+            output_path = crate::thalamus::files::path(&record.id)?;
 
             // let mut file = crate::thalamus::memory::FileStorage::new();
             // file.file_name = format!("{}-{}-{}.jpg", oid, style, step_idx);
@@ -146,7 +145,7 @@ pub fn run(style_img: &str, content_img: &str, _oid: String, _style: String) ->
         }
     }
 
-    Ok(())
+    Ok(output_path)
 }
 
 
@@ -160,13 +159,13 @@ pub struct Style {
 
 pub fn styles() -> Result<Vec<Style>, crate::thalamus::services::Error> {
     let mut styles: Vec<Style> = Vec::new();
-    let paths = fs::read_dir("/opt/thalamus/models/nst/")?;
+    let paths = fs::read_dir(&crate::thalamus::paths::model("nst/"))?;
     for path in paths {
 
         let pth = path.unwrap().path().display().to_string();
 
         let style = Style{
-            name: titlecase(&format!("{}", pth.clone()).replace("/opt/thalamus/models/nst/", "").replace(".jpg", "").replace("_", " ")),
+            name: titlecase(&format!("{}", pth.clone()).replace(&crate::thalamus::paths::model("nst/"), "").replace(".jpg", "").replace("_", " ")),
             file_path: pth.clone(),
         };
 
@@ -175,49 +174,44 @@ pub fn styles() -> Result<Vec<Style>, crate::thalamus::services::Error> {
     return Ok(styles);
 }
 
-pub fn install() -> Result<(), crate::thalamus::services::Error> {
-
-
-        crate::thalamus::tools::safe_download(
-            "/opt/thalamus/models/vgg16.ot", 
-            "https://github.com/LaurentMazare/tch-rs/releases/download/mw/vgg16.ot",
-            Some("9669f5421e84d23178e2b0ef721264dfcbb90596b64ae8feb5aead389cf04b52"), 
-            Some(553437974)
-        );
-    
-
-
-        crate::thalamus::tools::safe_download(
-            "/opt/thalamus/models/nst/fra_angelico.jpg",
-            "https://www.dropbox.com/s/nx2jupfw386yvm4/fra_angelico.jpg?dl=1", 
-            Some("bb1d52da5ea76f17bd88dc6f51a2eaa8de3a088b124c364d9abe7bd1cc065cde"), 
-            Some(3088813)
-        );
-
-
-        crate::thalamus::tools::safe_download(
-            "/opt/thalamus/models/nst/paul_cézanne.jpg", 
-            "https://www.dropbox.com/s/7cxzty6f1ad1wst/paul_c%C3%A9zanne.jpg?dl=1", 
-            Some("01a456c76287c7ddd9ac241d7837f72723e23af762fa60e28679741a8cca7ffe"), 
-            Some(5756479)
-        );
-
-
-        crate::thalamus::tools::safe_download(
-            "/opt/thalamus/models/nst/sassetta.jpg", 
-            "https://www.dropbox.com/s/iv5y3n3li09v7uj/sassetta.jpg?dl=1", 
-            Some("5530e98d468213b64370094864ae13c3f8135f452da7b866cc70fc162ac662d4"), 
-            Some(3447646)
-        );
+pub const ARTIFACTS: [crate::thalamus::tools::Artifact; 5] = [
+    crate::thalamus::tools::Artifact {
+        path: "vgg16.ot",
+        url: "https://github.com/LaurentMazare/tch-rs/releases/download/mw/vgg16.ot",
+        sha256: Some("9669f5421e84d23178e2b0ef721264dfcbb90596b64ae8feb5aead389cf04b52"),
+        size: Some(553437974),
+    },
+    crate::thalamus::tools::Artifact {
+        path: "nst/fra_angelico.jpg",
+        url: "https://www.dropbox.com/s/nx2jupfw386yvm4/fra_angelico.jpg?dl=1",
+        sha256: Some("bb1d52da5ea76f17bd88dc6f51a2eaa8de3a088b124c364d9abe7bd1cc065cde"),
+        size: Some(3088813),
+    },
+    crate::thalamus::tools::Artifact {
+        path: "nst/paul_cézanne.jpg",
+        url: "https://www.dropbox.com/s/7cxzty6f1ad1wst/paul_c%C3%A9zanne.jpg?dl=1",
+        sha256: Some("01a456c76287c7ddd9ac241d7837f72723e23af762fa60e28679741a8cca7ffe"),
+        size: Some(5756479),
+    },
+    crate::thalamus::tools::Artifact {
+        path: "nst/sassetta.jpg",
+        url: "https://www.dropbox.com/s/iv5y3n3li09v7uj/sassetta.jpg?dl=1",
+        sha256: Some("5530e98d468213b64370094864ae13c3f8135f452da7b866cc70fc162ac662d4"),
+        size: Some(3447646),
+    },
+    crate::thalamus::tools::Artifact {
+        path: "nst/vincent_van_gogh.jpg",
+        url: "https://www.dropbox.com/s/wpyuuw2qiir7c2i/vincent_van_gogh.jpg?dl=1",
+        sha256: Some("f4c90a682979037d55d53a8abb2600063fd7e64431970359037bb07dc4ddd3ce"),
+        size: Some(5407122),
+    },
+];
 
+pub fn install() -> Result<(), crate::thalamus::services::Error> {
 
-        crate::thalamus::tools::safe_download(
-            "/opt/thalamus/models/nst/vincent_van_gogh.jpg", 
-            "https://www.dropbox.com/s/wpyuuw2qiir7c2i/vincent_van_gogh.jpg?dl=1", 
-            Some("f4c90a682979037d55d53a8abb2600063fd7e64431970359037bb07dc4ddd3ce"), 
-            Some(5407122)
-        );
-    
+    for artifact in ARTIFACTS.iter() {
+        crate::thalamus::tools::safe_download_artifact(artifact);
+    }
 
     return Ok(());
 }
\ No newline at end of file
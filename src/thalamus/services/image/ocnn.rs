@@ -26,7 +26,7 @@ use tract_tensorflow::prelude::*;
 pub fn execc() -> TractResult<()> {
     let model = tract_tensorflow::tensorflow()
         // load the model
-        .model_for_path("/opt/thalamus/models/ocnn/mobilenet_v2_1.4_224_frozen.pb")?
+        .model_for_path(&crate::thalamus::paths::model("ocnn/mobilenet_v2_1.4_224_frozen.pb"))?
         // specify input type and shape
         .with_input_fact(0, f32::fact([1, 224, 224, 3]).into())?
         // optimize the model
@@ -35,7 +35,7 @@ pub fn execc() -> TractResult<()> {
         .into_runnable()?;
 
     // open image, resize it and make a Tensor out of it
-    let image = image::open("/opt/thalamus/models/ocnn/grace_hopper.jpg").unwrap().to_rgb8();
+    let image = image::open(&crate::thalamus::paths::model("ocnn/grace_hopper.jpg")).unwrap().to_rgb8();
     let resized =
         image::imageops::resize(&image, 224, 224, ::image::imageops::FilterType::Triangle);
     let image: Tensor = tract_ndarray::Array4::from_shape_fn((1, 224, 224, 3), |(_, y, x, c)| {
@@ -62,21 +62,21 @@ pub fn install() -> Result<(), crate::thalamus::setup::Error> {
 
     // Download mobilenetv2 Open Sam Foundation (OSF)
     crate::thalamus::tools::safe_download(
-        "/opt/thalamus/models/ocnn/mobilenet_v2_1.4_224_frozen.pb", 
+        &crate::thalamus::paths::model("ocnn/mobilenet_v2_1.4_224_frozen.pb"), 
         "https://www.dropbox.com/scl/fi/heoxedvxhh033hfwmck1w/mobilenet_v2_1.4_224_frozen.pb?rlkey=q8hou6tytx6dpzvkgtz7gdsoa&dl=1", 
         Some("111479258f3841c93d0a7a377c976c24e8281077818991931429d2277dd88590"), 
         Some(24508794)
     );
 
     crate::thalamus::tools::safe_download(
-        "/opt/thalamus/models/ocnn/imagenet_slim_labels.txt",
+        &crate::thalamus::paths::model("ocnn/imagenet_slim_labels.txt"),
         "https://www.dropbox.com/scl/fi/lsbmxydwjt3xw85w0r8ew/imagenet_slim_labels.txt?rlkey=0lkio7z653um7sa14494jejc6&dl=1", 
         Some("e8d2cef25bb7b3c8c6923ad3c463b47de8b8535cadf4bd62a2ca2532c587eb9f"), 
         Some(10479)
     );
 
     crate::thalamus::tools::safe_download(
-        "/opt/thalamus/models/ocnn/grace_hopper.jpg", 
+        &crate::thalamus::paths::model("ocnn/grace_hopper.jpg"), 
         "https://www.dropbox.com/scl/fi/pigjncag76xm9wf6g1tsf/grace_hopper.jpg?rlkey=jqt9pnhg1ovsz22vqiq05re7b&dl=1", 
         Some("e1f57e98cf38076c0f9a058d74ffddf90f20453e436033784606b63c8ed2e49a"), 
         Some(73746)
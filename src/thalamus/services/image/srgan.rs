@@ -13,67 +13,69 @@
 use std::path::Path;
 use rouille::Request;
 use rouille::Response;
-use std::fs::File;
-use std::io::Write;
 // use std::io::Read;
 use rouille::post_input;
 use rouille::input::post::BufferedFile;
 
-pub fn handle(request: &Request) -> Result<Response, crate::thalamus::http::Error> {
-    if request.url().contains("/api/services/image/srgan"){
+pub fn handle(request: &Request, context: &crate::thalamus::http::HttpContext) -> Result<Response, crate::thalamus::http::Error> {
 
-        let input = post_input!(request, {
-            input_file: BufferedFile,
-        })?;
+    let input = post_input!(request, {
+        input_file: Option<BufferedFile>,
+        input_file_id: Option<String>,
+    })?;
 
-        let xyz = input.input_file.filename.unwrap();
+    // Written under a fixed name, then renamed once the extension is known
+    let scratch = crate::thalamus::scratch::ScratchDir::new("srgan")?;
+    let (xyz, _) = crate::thalamus::files::service_input(input.input_file, input.input_file_id, "input_file", &scratch.file("input"))?;
 
-        let mime_type = crate::thalamus::tools::find_mimetype(&xyz.clone());
+    let mime_type = crate::thalamus::tools::find_mimetype(&xyz.clone());
 
-        let tmp_file_path = format!("/opt/thalamus/tmp/srgan/{}", xyz.clone());
-        let out_file_path = format!("/opt/thalamus/tmp/srgan/SRGAN_{}", xyz.clone());
-        let mut file = File::create(tmp_file_path.clone())?;
-        file.write_all(&input.input_file.data)?;
+    // Only the extension of the uploaded name is used, so names can't collide or escape tmp/srgan
+    let extension = crate::thalamus::scratch::extension(&xyz, "png");
+    let tmp_file_path = scratch.file(&format!("input.{}", extension));
+    let out_file_path = scratch.file(&format!("SRGAN_input.{}", extension));
+    std::fs::rename(scratch.file("input"), &tmp_file_path)?;
 
-        match crate::thalamus::tools::srgan(tmp_file_path.as_str(), out_file_path.clone().as_str()){
-            Ok(_) => {
-
-                let outfile = File::open(out_file_path.as_str()).unwrap();
+    let mode = crate::thalamus::cache::mode(request);
+    let mut cache_key = String::new();
+    if mode.active() {
+        cache_key = crate::thalamus::cache::key("srgan", &crate::thalamus::tools::hash_check(&tmp_file_path)?, &[extension.as_str()]);
+    }
+    if let Some(cached) = crate::thalamus::cache::lookup(mode, &cache_key) {
+        return crate::thalamus::cache::hit(request, context, "srgan", crate::thalamus::jobs::JobOutput::Data(cached.mime, cached.data));
+    }
 
-                let response = Response::from_file(mime_type, outfile);
-                return Ok(response);
-            },
-            Err(e) => {
-                log::error!("{:?}", e);
-            }
+    let response = crate::thalamus::jobs::respond(request, context, "srgan", move |_job| {
+        crate::thalamus::metrics::time_inference("srgan", "srgan", || crate::thalamus::tools::srgan(tmp_file_path.as_str(), out_file_path.clone().as_str()))?;
+        if mode.active() {
+            crate::thalamus::cache::store(mode, &cache_key, &mime_type, &std::fs::read(&out_file_path)?);
         }
-
-        return Ok(Response::empty_404());
-
-
-    }
-    return Ok(Response::empty_404());
+        return Ok(crate::thalamus::jobs::JobOutput::ScratchFile(mime_type, out_file_path, std::sync::Arc::new(scratch)));
+    })?;
+    return Ok(crate::thalamus::cache::label(response, mode));
 }
 
+pub const BINARIES: [&str; 1] = ["srgan"];
+
 pub fn install() -> Result<(), crate::thalamus::setup::Error> {
-    if !Path::new("/opt/thalamus/bin/srgan").exists(){
+    if !Path::new(&crate::thalamus::paths::bin("srgan")).exists(){
         #[cfg(all(target_arch = "x86_64", target_os = "linux"))] {
             log::info!("Unpacking SRGAN");
-            crate::thalamus::tools::safe_download("/opt/thalamus/bin/srgan", "https://www.dropbox.com/s/l4smcanvwjf3huy/srgan?dl=1", None, None);
+            crate::thalamus::tools::safe_download(&crate::thalamus::paths::bin("srgan"), "https://www.dropbox.com/s/l4smcanvwjf3huy/srgan?dl=1", None, None);
         }
 
         #[cfg(all(target_arch = "aarch64", target_os = "linux"))] {
             log::info!("Unpacking SRGAN");
-            crate::thalamus::tools::safe_download("/opt/thalamus/bin/srgan", "https://www.dropbox.com/s/sgf76zwss8m4xu3/srgan?dl=1", None, None);
+            crate::thalamus::tools::safe_download(&crate::thalamus::paths::bin("srgan"), "https://www.dropbox.com/s/sgf76zwss8m4xu3/srgan?dl=1", None, None);
         }
 
         #[cfg(all(target_arch = "aarch64", target_os = "macos"))] {
             log::info!("Unpacking SRGAN");
-            crate::thalamus::tools::safe_download("/opt/thalamus/bin/srgan", "https://www.dropbox.com/s/52imqf6clftie47/srgan?dl=1", None, None);
+            crate::thalamus::tools::safe_download(&crate::thalamus::paths::bin("srgan"), "https://www.dropbox.com/s/52imqf6clftie47/srgan?dl=1", None, None);
         }
     }
 
-    match crate::thalamus::tools::mark_as_executable("/opt/thalamus/bin/srgan"){
+    match crate::thalamus::tools::mark_as_executable(&crate::thalamus::paths::bin("srgan")){
         Ok(_) => (),
         Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to chmod whisper").into())
     }
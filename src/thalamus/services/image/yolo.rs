@@ -11,37 +11,28 @@
 // Yolo9k CoreML https://github.com/seph14/Cinder-Yolo9k/tree/master
 
 // use std::fs;
-use std::fs::File;
-use std::io::{Write};
 use rouille::Request;
 use rouille::Response;
 use rouille::input::post::BufferedFile;
 use rouille::post_input;
 use std::path::Path;
-use std::time::{SystemTime, UNIX_EPOCH};
-use std::process::{Command, Stdio};
+use std::process::Command;
 use serde::{Serialize, Deserialize};
 
 
-pub fn yolov7(file_path: String) -> Result<String, String> {
+pub fn yolov7(file_path: String) -> Result<String, crate::thalamus::services::Error> {
+    crate::thalamus::storage::touch("yolov7", "yolov7");
 
     #[cfg(target_os = "linux")]{
-        let child = Command::new("/opt/thalamus/bin/yolov7")
+        let output = crate::thalamus::tools::run_process("yolov7", Command::new(&crate::thalamus::paths::bin("yolov7"))
         .arg(file_path)
-        .arg("/opt/thalamus/models/yolov7.onnx")
+        .arg(&crate::thalamus::paths::model("yolov7.onnx"))
         .arg("640")
-        .arg("640")
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("failed to execute child");
-    
-        let output = child
-            .wait_with_output()
-            .expect("failed to wait on child");
-        let yolo = String::from_utf8_lossy(&output.stdout).to_string().replace("\n", "");
+        .arg("640"))?;
+        let yolo = output.replace("\n", "");
     
         if yolo.to_lowercase().contains("error") || yolo.len() == 0 {
-            return Err(format!("yolo_v7_error: {}", yolo))
+            return Err(crate::thalamus::tools::Error::from_kind(crate::thalamus::tools::ErrorKind::ProcessFailed("yolov7".to_string(), yolo)).into());
         }
 
       
@@ -51,19 +42,12 @@ pub fn yolov7(file_path: String) -> Result<String, String> {
     }
 
     #[cfg(target_os = "macos")]{
-        let child = Command::new("/opt/thalamus/bin/yolov7")
-        .arg(file_path)
-        .stdout(Stdio::piped())
-        .spawn()
-        .expect("failed to execute child");
-    
-        let output = child
-            .wait_with_output()
-            .expect("failed to wait on child");
-        let yolo = String::from_utf8_lossy(&output.stdout).to_string().replace("\n", "");
+        let output = crate::thalamus::tools::run_process("yolov7", Command::new(&crate::thalamus::paths::bin("yolov7"))
+        .arg(file_path))?;
+        let yolo = output.replace("\n", "");
     
         if yolo.to_lowercase().contains("error") || yolo.len() == 0 {
-            return Err(format!("yolo_v7_error: {}", yolo))
+            return Err(crate::thalamus::tools::Error::from_kind(crate::thalamus::tools::ErrorKind::ProcessFailed("yolov7".to_string(), yolo)).into());
         }
     
         return Ok(yolo);
@@ -74,32 +58,44 @@ pub fn yolov7(file_path: String) -> Result<String, String> {
 
 }
 
+pub const BINARIES: [&str; 1] = ["yolov7"];
+
+/// The onnx model run on Linux, its size is taken from the server before downloading
+pub const MODEL: crate::thalamus::tools::Artifact = crate::thalamus::tools::Artifact {
+    path: "yolov7.onnx",
+    url: "https://www.dropbox.com/s/yaxcikpiq9v6i1d/yolov7.onnx?dl=1",
+    sha256: None,
+    size: None,
+};
+
+// Linux runs the onnx model, macOS the CoreML one unpacked next to the binary
+#[cfg(target_os = "linux")]
+pub fn model_files() -> Vec<String> {
+    return vec![MODEL.local_path()];
+}
+#[cfg(not(target_os = "linux"))]
+pub fn model_files() -> Vec<String> {
+    return vec![crate::thalamus::paths::bin("yolov7.mlmodelc")];
+}
+
 pub fn install() -> Result<(), crate::thalamus::setup::Error> {
 
 
     // Linux
     #[cfg(all(target_arch = "x86_64", target_os = "linux"))] {
-        if !Path::new("/opt/thalamus/bin/yolov7").exists(){
-            log::info!("Installing yolov7 (x86_64) /opt/thalamus/bin/yolov7");
+        if !Path::new(&crate::thalamus::paths::bin("yolov7")).exists(){
+            log::info!("Installing yolov7 (x86_64) {}", crate::thalamus::paths::bin("yolov7"));
             crate::thalamus::tools::safe_download(
-                "/opt/thalamus/bin/yolov7", 
+                &crate::thalamus::paths::bin("yolov7"), 
                 "https://www.dropbox.com/s/rc4v0zpxoze6i4s/yolov7?dl=1", 
                 None, 
                 None
             );
         }
 
-        if !Path::new("/opt/thalamus/models/yolov7.onnx").exists(){
-            log::info!("Downloading yolov7 model /opt/thalamus/models/yolov7.onnx");
-            crate::thalamus::tools::safe_download(
-                "/opt/thalamus/models/yolov7.onnx", 
-                "https://www.dropbox.com/s/yaxcikpiq9v6i1d/yolov7.onnx?dl=1", 
-                None, 
-                None
-            );
-        }
+        crate::thalamus::tools::safe_download_artifact(&MODEL);
 
-        match crate::thalamus::tools::mark_as_executable("/opt/thalamus/bin/yolov7"){
+        match crate::thalamus::tools::mark_as_executable(&crate::thalamus::paths::bin("yolov7")){
             Ok(_) => (),
             Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to chmod yolov7").into())
         }
@@ -108,26 +104,26 @@ pub fn install() -> Result<(), crate::thalamus::setup::Error> {
     // Apple M1/M2
     #[cfg(all(target_arch = "aarch64", target_os = "macos"))] {
         crate::thalamus::tools::safe_download(
-            "/opt/thalamus/bin/yolov7", 
+            &crate::thalamus::paths::bin("yolov7"), 
             "https://www.dropbox.com/s/0rj0shcmctiy6n6/yolov7?dl=1", 
             Some("4abbd78cf05ab703b99b3d984b893f2525b7045c37dc8454773aaa15e92a7bcd"), 
             Some(3153216)
         );
 
         crate::thalamus::tools::safe_download(
-            "/opt/thalamus/bin/yolov7.mlmodelc.tar.xz", 
+            &crate::thalamus::paths::bin("yolov7.mlmodelc.tar.xz"), 
             "https://www.dropbox.com/s/3wm6tgv9w7d3iyp/yolov7.mlmodelc.tar.xz?dl=1", 
             Some("8364636d8863f73eeaa344fbb5547102f3b8e22c0c73574678d8d1f8303f069c"), 
             Some(111855848)
         );
     
-        match crate::thalamus::tools::untar("/opt/thalamus/bin/yolov7.mlmodelc.tar.xz", "/opt/thalamus/bin/"){
+        match crate::thalamus::tools::untar(&crate::thalamus::paths::bin("yolov7.mlmodelc.tar.xz"), &crate::thalamus::paths::get().bin){
             Ok(_) => (),
             Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to extract coreML model for yolov7").into())
         }
         
 
-        match crate::thalamus::tools::mark_as_executable("/opt/thalamus/bin/yolov7"){
+        match crate::thalamus::tools::mark_as_executable(&crate::thalamus::paths::bin("yolov7")){
             Ok(_) => (),
             Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to chmod yolov7").into())
         }
@@ -137,25 +133,34 @@ pub fn install() -> Result<(), crate::thalamus::setup::Error> {
 }
 
 
-pub fn handle(request: &Request) -> Result<Response, crate::thalamus::http::Error> {
-    
-    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+pub fn handle(request: &Request, context: &crate::thalamus::http::HttpContext) -> Result<Response, crate::thalamus::http::Error> {
 
     let input = post_input!(request, {
-        image_file: BufferedFile,
+        image_file: Option<BufferedFile>,
+        image_file_id: Option<String>,
     })?;
 
-    let tmp_file_path = format!("/opt/thalamus/tmp/{}.jpg", timestamp.clone());
-    let mut file = File::create(tmp_file_path.clone())?;
-    file.write_all(&input.image_file.data)?;
-    
-    if request.url() == "/api/services/image/yolo/v7" {
-        let yolo = yolov7(tmp_file_path)?;
-        let reply: YoloV7Output = serde_json::from_str(&yolo)?;
-        return Ok(Response::json(&reply));
+    let scratch = crate::thalamus::scratch::ScratchDir::new("")?;
+    let tmp_file_path = scratch.file("image.jpg");
+    crate::thalamus::files::service_input(input.image_file, input.image_file_id, "image_file", &tmp_file_path)?;
+
+    let mode = crate::thalamus::cache::mode(request);
+    let mut cache_key = String::new();
+    if mode.active() {
+        cache_key = crate::thalamus::cache::key("yolov7", &crate::thalamus::tools::hash_check(&tmp_file_path)?, &[]);
     }
-    
-    return Ok(Response::empty_404());
+    if let Some(cached) = crate::thalamus::cache::lookup(mode, &cache_key) {
+        return crate::thalamus::cache::hit(request, context, "yolov7", crate::thalamus::jobs::JobOutput::Data(cached.mime, cached.data));
+    }
+
+    let response = crate::thalamus::jobs::respond(request, context, "yolov7", move |_job| {
+        let yolo = crate::thalamus::metrics::time_inference("yolov7", "yolov7", || yolov7(tmp_file_path))?;
+        std::mem::drop(scratch);
+        let reply: YoloV7Output = serde_json::from_str(&yolo)?;
+        crate::thalamus::cache::store(mode, &cache_key, "application/json", &serde_json::to_vec(&reply)?);
+        return Ok(crate::thalamus::jobs::JobOutput::Json(serde_json::to_value(&reply)?));
+    })?;
+    return Ok(crate::thalamus::cache::label(response, mode));
 }
 
 #[derive(Default, Debug, Clone, PartialEq, Serialize, Deserialize)]
@@ -20,63 +20,237 @@ use rouille::Response;
 
 use rouille::post_input;
 
+use std::io::Read;
 use std::path::Path;
+use std::process::Child;
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError};
+use std::time::{Duration, Instant};
 
-// curl -d "prompt=tell me about abe lincoln&model=7B" -X POST http://172.16.0.15:8050/api/services/llama
-pub fn handle(request: &Request) -> Result<Response, crate::thalamus::http::Error> {
-    
-    if request.url().contains("/api/services/llama"){
-
-        let input = post_input!(request, {
-            prompt: String, // Hello World!
-            model: String, // 7B
-        })?;
-
-        match crate::thalamus::tools::llama(input.model.as_str(), input.prompt.as_str()){
-            Ok(output) => {
-                return Ok(Response::text(output));
-            },
-            Err(e) => {
-                return Err(e.into());
+// curl -H "Authorization: Bearer thalamus" -d "prompt=tell me about abe lincoln&model=7B" -X POST http://172.16.0.15:8050/api/services/llama
+pub fn handle(request: &Request, context: &crate::thalamus::http::HttpContext) -> Result<Response, crate::thalamus::http::Error> {
+
+    let input = post_input!(request, {
+        prompt: String, // Hello World!
+        model: Option<String>, // 7B, defaults to [services.llama] default_model
+    })?;
+    let model = input.model.or(crate::thalamus::config::service("llama").default_model)
+        .ok_or(crate::thalamus::http::ErrorKind::BadRequest("missing field: model".to_string()))?;
+    check_model(model.as_str())?;
+
+    if wants_stream(request) {
+        return stream(model.as_str(), input.prompt.as_str());
+    }
+
+    return crate::thalamus::jobs::respond(request, context, format!("llama:{}", model).as_str(), move |_job| {
+        let output = crate::thalamus::metrics::time_inference("llama", model.as_str(), || llama(model.as_str(), input.prompt.as_str()))?;
+        return Ok(crate::thalamus::jobs::JobOutput::Text(output));
+    });
+}
+
+// curl -N -H "Authorization: Bearer thalamus" -d "prompt=tell me about abe lincoln&model=7B" -X POST "http://172.16.0.15:8050/api/services/llama?stream=true"
+pub fn wants_stream(request: &Request) -> bool {
+    if let Some(value) = request.get_param("stream") {
+        return value == "true" || value == "1";
+    }
+    return match request.header("Accept") {
+        Some(accept) => accept.contains("text/event-stream"),
+        None => false,
+    };
+}
+
+/// Server-sent events: a "data: {\"token\": ...}" event per burst of llama output,
+/// then "event: done" or "event: error" once the process exits
+pub fn stream(model: &str, prompt: &str) -> Result<Response, crate::thalamus::http::Error> {
+    crate::thalamus::storage::touch("llama", model);
+    let child = crate::thalamus::tools::llama_spawn(model, prompt)?;
+    let events = LlamaEvents::new(child, model)?;
+    return Ok(crate::thalamus::http::event_stream(events));
+}
+
+// Every event goes out padded to a full chunk (see http::sse_event), so output arriving
+// within this long of the first token is sent together instead of a chunk per token
+const FLUSH_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Reader over llama's stdout that yields server-sent events, kills llama if dropped early
+pub struct LlamaEvents {
+    child: Child,
+    model: String,
+    started: Instant,
+    output: Receiver<Vec<u8>>,
+    partial: Vec<u8>,
+    pending: Vec<u8>,
+    finished: bool,
+}
+impl LlamaEvents {
+    pub fn new(mut child: Child, model: &str) -> Result<LlamaEvents, crate::thalamus::services::Error> {
+        let mut stdout = child.stdout.take().ok_or(crate::thalamus::services::ErrorKind::InvalidInput("llama stdout is not piped".to_string()))?;
+
+        // Read on its own thread so the stream can wait for more output with a timeout,
+        // it ends when llama closes stdout or is killed
+        let (sender, output) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut chunk = [0u8; 256];
+            loop {
+                match stdout.read(&mut chunk) {
+                    Ok(0) => break,
+                    Ok(n) => {
+                        if sender.send(chunk[..n].to_vec()).is_err() {
+                            break;
+                        }
+                    },
+                    Err(e) => {
+                        log::error!("Unable to read llama output: {}", e);
+                        break;
+                    }
+                }
             }
+        });
+
+        return Ok(LlamaEvents {
+            child: child,
+            model: model.to_string(),
+            started: Instant::now(),
+            output: output,
+            partial: Vec::new(),
+            pending: Vec::new(),
+            finished: false,
+        });
+    }
+
+    // Waits for llama's next output and takes whatever else it prints within
+    // FLUSH_INTERVAL, true once stdout is closed
+    fn gather(&mut self) -> bool {
+        match self.output.recv() {
+            Ok(bytes) => self.partial.extend_from_slice(&bytes),
+            Err(_) => return true,
         }
+        let deadline = Instant::now() + FLUSH_INTERVAL;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return false;
+            }
+            match self.output.recv_timeout(deadline - now) {
+                Ok(bytes) => self.partial.extend_from_slice(&bytes),
+                Err(RecvTimeoutError::Timeout) => return false,
+                Err(RecvTimeoutError::Disconnected) => return true,
+            }
+        }
+    }
 
+    // The done or error event once llama has exited
+    fn finish(&mut self) -> std::io::Result<Vec<u8>> {
+        self.finished = true;
+        crate::thalamus::metrics::observe_inference("llama", &self.model, self.started.elapsed());
+        let status = self.child.wait();
+        crate::thalamus::shutdown::unregister_child(self.child.id());
+        let status = status?;
+        if status.success() {
+            return Ok(crate::thalamus::http::sse_event(Some("done"), serde_json::json!({})));
+        }
+        crate::thalamus::metrics::process_failed("llama");
+        return Ok(crate::thalamus::http::sse_event(Some("error"), serde_json::json!({"message": format!("llama exited with {}", status)})));
+    }
+}
+impl Read for LlamaEvents {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        while self.pending.is_empty() {
+            if self.finished {
+                return Ok(0);
+            }
+            let closed = self.gather();
 
+            // Only hand out complete utf-8 sequences, the rest waits for the next read
+            let valid = match std::str::from_utf8(&self.partial) {
+                Ok(text) => text.len(),
+                Err(e) if e.error_len().is_some() || closed => self.partial.len(),
+                Err(e) => e.valid_up_to(),
+            };
+            if valid > 0 {
+                let token = String::from_utf8_lossy(&self.partial[..valid]).to_string();
+                self.partial.drain(..valid);
+                self.pending = crate::thalamus::http::sse_event(None, serde_json::json!({"token": token}));
+            }
+            if closed {
+                let last = self.finish()?;
+                self.pending.extend(last);
+            }
+        }
+
+        let n = std::cmp::min(buf.len(), self.pending.len());
+        buf[..n].copy_from_slice(&self.pending[..n]);
+        self.pending.drain(..n);
+        return Ok(n);
     }
-    
-    
-    return Ok(Response::empty_404());
+}
+impl Drop for LlamaEvents {
+    fn drop(&mut self) {
+        if !self.finished {
+            // Counted up to the client going away
+            crate::thalamus::metrics::observe_inference("llama", &self.model, self.started.elapsed());
+            let _ = self.child.kill();
+            let _ = self.child.wait();
+            crate::thalamus::shutdown::unregister_child(self.child.id());
+        }
+    }
+}
+
+pub const MODELS: [&str; 4] = ["7B", "13B", "30B", "65B"];
+
+// Make sure the requested model exists and is installed before spawning llama
+pub fn check_model(model: &str) -> Result<(), crate::thalamus::services::Error> {
+    if !MODELS.contains(&model) {
+        return Err(crate::thalamus::services::ErrorKind::UnknownModel(model.to_string()).into());
+    }
+    if !Path::new(crate::thalamus::paths::model(&format!("llama/{}/ggml-model-q4_0.gguf", model)).as_str()).exists() {
+        return Err(crate::thalamus::services::ErrorKind::ModelNotInstalled(format!("llama {}", model)).into());
+    }
+    return Ok(());
 }
 
+pub fn llama(model: &str, prompt: &str) -> Result<String, crate::thalamus::services::Error> {
+    check_model(model)?;
+    crate::thalamus::storage::touch("llama", model);
+    return Ok(crate::thalamus::tools::llama(model, prompt)?);
+}
+
+pub const BINARIES: [&str; 1] = ["llama"];
+
+pub const ARTIFACTS: [crate::thalamus::tools::Artifact; 1] = [
+    crate::thalamus::tools::Artifact {
+        path: "llama/7B/ggml-model-q4_0.gguf",
+        url: "https://www.dropbox.com/scl/fi/6faxqth8re7dgn1ygwsbr/ggml-model-q4_0.gguf?rlkey=b1ozpsxx6nqz5f6vutva0mlz5&dl=1",
+        sha256: Some("f1c4e91ce7a6f0eaa0f4229caf473c882ad642fa7e30b4b7fb4a1377b76f6d0a"),
+        size: Some(3825806912),
+    },
+];
+
 // TODO: Patch linux to 1.1 version of llama
 // TODO: Add support for 13B, 30B, and 65B
 pub fn install() -> Result<(), crate::thalamus::setup::Error> {
 
-    if !Path::new("/opt/thalamus/bin/llama").exists(){
+    if !Path::new(&crate::thalamus::paths::bin("llama")).exists(){
         #[cfg(all(target_arch = "aarch64", target_os = "macos"))] {
-            crate::thalamus::tools::safe_download("/opt/thalamus/bin/llama", "https://www.dropbox.com/scl/fi/4ljqsqbvtwcmtqz3sr7db/main?rlkey=a0ktgg402tyoxmcyuy9fzii7k&dl=1", None, None);
+            crate::thalamus::tools::safe_download(&crate::thalamus::paths::bin("llama"), "https://www.dropbox.com/scl/fi/4ljqsqbvtwcmtqz3sr7db/main?rlkey=a0ktgg402tyoxmcyuy9fzii7k&dl=1", None, None);
         }
         #[cfg(all(target_arch = "aarch64", target_os = "linux"))] {
-            crate::thalamus::tools::safe_download("/opt/thalamus/bin/llama", "https://www.dropbox.com/s/5cxh3hduwwjv0vv/main?dl=1", None, None);
+            crate::thalamus::tools::safe_download(&crate::thalamus::paths::bin("llama"), "https://www.dropbox.com/s/5cxh3hduwwjv0vv/main?dl=1", None, None);
         }
         #[cfg(all(target_arch = "x86_64", target_os = "linux"))] {
-            crate::thalamus::tools::safe_download("/opt/thalamus/bin/llama", "https://www.dropbox.com/s/93sj2fruleo80y0/main?dl=1", None, None);
+            crate::thalamus::tools::safe_download(&crate::thalamus::paths::bin("llama"), "https://www.dropbox.com/s/93sj2fruleo80y0/main?dl=1", None, None);
         }
     }
 
-    match crate::thalamus::tools::mark_as_executable("/opt/thalamus/bin/llama"){
+    match crate::thalamus::tools::mark_as_executable(&crate::thalamus::paths::bin("llama")){
         Ok(_) => (),
         Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to chmod whisper").into())
     }
 
     
     // Download quantized 7B llama model from Open Sam Foundation (OSF)
-    crate::thalamus::tools::safe_download(
-        "/opt/thalamus/models/llama/7B/ggml-model-q4_0.gguf", 
-        "https://www.dropbox.com/scl/fi/6faxqth8re7dgn1ygwsbr/ggml-model-q4_0.gguf?rlkey=b1ozpsxx6nqz5f6vutva0mlz5&dl=1", 
-        Some("f1c4e91ce7a6f0eaa0f4229caf473c882ad642fa7e30b4b7fb4a1377b76f6d0a"),
-        Some(3825806912)
-    );
+    for artifact in ARTIFACTS.iter() {
+        crate::thalamus::tools::safe_download_artifact(artifact);
+    }
 
     Ok(())
 }
@@ -9,7 +9,7 @@
 
 use rouille::Request;
 use rouille::Response;
-
+use rouille::post_input;
 
 use serde::{Serialize, Deserialize};
 
@@ -20,20 +20,40 @@ use std::process::{Command, Stdio};
 
 
 
-// http://localhost:8050/api/services/tts?text=hello%20there%20human&primary=larynx:southern_english_female-glow_tts&fallback=opensamfoundation
-pub fn handle(request: &Request) -> Result<Response, crate::thalamus::http::Error> {
-    if request.url() == "/api/services/tts" {
-        let input = request.get_param("text").unwrap();
-        let primary = request.get_param("primary").unwrap();
-        let fallback = request.get_param("fallback").unwrap();
-        return Ok(Response::from_data("audio/wav", crate::thalamus::services::tts::get(input, primary.as_str(), fallback.as_str()).unwrap()));
+// curl -H "Authorization: Bearer thalamus" "http://localhost:8050/api/services/tts?text=hello%20there%20human&primary=larynx:southern_english_female-glow_tts&fallback=opensamfoundation"
+pub fn handle(request: &Request, context: &crate::thalamus::http::HttpContext) -> Result<Response, crate::thalamus::http::Error> {
+    let (input, primary, fallback) = if request.method() == "POST" {
+        let input = post_input!(request, {
+            text: String,
+            primary: Option<String>,
+            fallback: Option<String>,
+        })?;
+        (input.text, input.primary, input.fallback)
+    } else {
+        let input = request.get_param("text").ok_or(crate::thalamus::http::ErrorKind::BadRequest("missing query parameter: text".to_string()))?;
+        (input, request.get_param("primary"), request.get_param("fallback"))
+    };
+    let config = crate::thalamus::config::tts();
+    let primary = primary.unwrap_or(config.default_voice);
+    let fallback = fallback.unwrap_or(config.fallback_voice);
+
+    let mode = crate::thalamus::cache::mode(request);
+    let cache_key = crate::thalamus::cache::key("tts", &crate::thalamus::cache::text_hash(&input), &[primary.as_str(), fallback.as_str()]);
+    if let Some(cached) = crate::thalamus::cache::lookup(mode, &cache_key) {
+        return crate::thalamus::cache::hit(request, context, "tts", crate::thalamus::jobs::JobOutput::Data(cached.mime, cached.data));
     }
 
-    if request.url() == "/api/services/tts/voices" {
-        return Ok(Response::json(&get_supported_voices()));
-    }
+    let response = crate::thalamus::jobs::respond(request, context, "tts", move |_job| {
+        let engine = primary.split(':').next().unwrap_or("unknown").to_string();
+        let wav = crate::thalamus::metrics::time_inference("tts", engine.as_str(), || crate::thalamus::services::tts::get(input, primary.as_str(), fallback.as_str()))?;
+        crate::thalamus::cache::store(mode, &cache_key, "audio/wav", &wav);
+        return Ok(crate::thalamus::jobs::JobOutput::Data("audio/wav".to_string(), wav));
+    })?;
+    return Ok(crate::thalamus::cache::label(response, mode));
+}
 
-    return Ok(Response::empty_404());
+pub fn handle_voices(_request: &Request) -> Result<Response, crate::thalamus::http::Error> {
+    return Ok(Response::json(&get_supported_voices()));
 }
 
 
@@ -81,19 +101,23 @@ pub fn init(args: crate::Args){
 
 
 
-        let child = Command::new("/opt/thalamus/bin/docker")
+        let child = Command::new(&crate::thalamus::paths::bin("docker"))
         .arg("run")
         .arg("-it")
         .arg("-p")
-        .arg("5500:5500")
+        .arg(format!("{}:5500", crate::thalamus::config::tts().opentts_port))
         .arg(format!("synesthesiam/opentts:{}", args.lang).as_str())
         .stdout(Stdio::piped())
         .spawn().unwrap();
     
+        // docker proxies the SIGTERM sent on shutdown to the container
+        let pid = child.id();
+        crate::thalamus::shutdown::register_child(pid, "opentts");
     
         let output = child
         .wait_with_output()
         .expect("failed to wait on child");
+        crate::thalamus::shutdown::unregister_child(pid);
     
         log::info!("{}", String::from_utf8_lossy(&output.stdout).to_string());
 
@@ -169,7 +193,7 @@ pub fn get(text: String, primary: &str, fallback: &str) -> Result<Vec<u8>, crate
     }
 
     // Default
-    match fetch_opentts(text.clone(), format!("coqui-tts:en_ljspeech")) {
+    match fetch_opentts(text.clone(), crate::thalamus::config::tts().default_voice) {
         Ok(x) => {
             return Ok(x);
         },
@@ -183,15 +207,16 @@ pub fn fetch_opensam(text: String) -> Result<Vec<u8>, crate::thalamus::services:
     let client = reqwest::blocking::Client::new();
     let bytes = client.get(format!("https://tts.opensam.foundation/api/tts?text={}&speaker_id=&style_wav=", text))
         .basic_auth("sam", Some("87654321"))
-        .timeout(Duration::from_secs(5))
+        .timeout(Duration::from_secs(crate::thalamus::config::tts().timeout_secs))
         .send()?.bytes()?;
     Ok(bytes.to_vec())
 }
 
 pub fn fetch_opentts(text: String, voice: String) -> Result<Vec<u8>, crate::thalamus::services::Error> {
+    let config = crate::thalamus::config::tts();
     let client = reqwest::blocking::Client::new();
-    let bytes = client.get(format!("http://localhost:5500/api/tts?text={}&voice={}", text, voice))
-        .timeout(Duration::from_secs(5))
+    let bytes = client.get(format!("http://localhost:{}/api/tts?text={}&voice={}", config.opentts_port, text, voice))
+        .timeout(Duration::from_secs(config.timeout_secs))
         .send()?.bytes()?;
     Ok(bytes.to_vec())
 }
@@ -14,18 +14,32 @@ use rouille::post_input;
 use serde::{Serialize, Deserialize};
 
 use std::path::Path;
-use std::fs::File;
 
 use std::time::{SystemTime, UNIX_EPOCH};
 
-use std::io::Write;
 
 
 
 
+pub const MODELS: [&str; 4] = ["tiny", "base", "medium", "large"];
+
+// Make sure the requested model exists and is installed before spawning whisper
+pub fn check_model(method: &str) -> Result<(), crate::thalamus::services::Error> {
+    if !MODELS.contains(&method) {
+        return Err(crate::thalamus::services::ErrorKind::UnknownModel(method.to_string()).into());
+    }
+    if !Path::new(crate::thalamus::paths::model(&format!("ggml-{}.bin", method)).as_str()).exists() {
+        return Err(crate::thalamus::services::ErrorKind::ModelNotInstalled(format!("whisper {}", method)).into());
+    }
+    return Ok(());
+}
+
 // /opt/thalamus/bin/whisper -m /opt/thalamus/models/ggml-* -f ./output.wav -otxt
 pub fn whisper(file_path: String, method: &str) -> Result<String, crate::thalamus::services::Error> {
 
+    check_model(method)?;
+    crate::thalamus::storage::touch("whisper", method);
+
     // Force all input to become wav@16khz
     match crate::thalamus::tools::wav_to_16000(file_path.clone()){
         Ok(_) => (),
@@ -33,22 +47,12 @@ pub fn whisper(file_path: String, method: &str) -> Result<String, crate::thalamu
     };
 
     // Execute Whisper
-    match method {
-        "tiny" => log::warn!("{}", crate::thalamus::tools::whisper("tiny", file_path.as_str())?),
-        "base" => log::warn!("{}", crate::thalamus::tools::whisper("base", file_path.as_str())?),
-        "medium" => log::warn!("{}", crate::thalamus::tools::whisper("medium", file_path.as_str())?),
-        "large" => log::warn!("{}", crate::thalamus::tools::whisper("large", file_path.as_str())?),
-        &_ => log::warn!("{}", crate::thalamus::tools::whisper("tiny", file_path.as_str())?)
-    };
+    log::warn!("{}", crate::thalamus::tools::whisper(method, file_path.as_str())?);
     
     // Copy the results to memory
     let data = std::fs::read_to_string(format!("{}.16.wav.txt", file_path).as_str())?;
 
-    // Cleanup
-    // thread::spawn(move || {
-    //     thread::sleep(Duration::from_millis(60000));
-    //     crate::thalamus::tools::rm(format!("{}*", file_path).as_str()).unwrap();
-    // });
+    // Byproducts are removed with the caller's scratch directory
 
     // Return the results
     return Ok(data);
@@ -57,6 +61,9 @@ pub fn whisper(file_path: String, method: &str) -> Result<String, crate::thalamu
 
 pub fn whisper_vwav(file_path: String, method: &str) -> Result<String, crate::thalamus::services::Error> {
 
+    check_model(method)?;
+    crate::thalamus::storage::touch("whisper", method);
+
     // Force all input to become wav@16khz
     match crate::thalamus::tools::wav_to_16000(file_path.clone()){
         Ok(_) => (),
@@ -66,17 +73,11 @@ pub fn whisper_vwav(file_path: String, method: &str) -> Result<String, crate::th
 
 
     // Execute Whisper
-    match method {
-        "tiny" => log::warn!("{}", crate::thalamus::tools::whisper_owts("tiny", file_path.as_str())?),
-        "base" => log::warn!("{}", crate::thalamus::tools::whisper_owts("base", file_path.as_str())?),
-        "medium" => log::warn!("{}", crate::thalamus::tools::whisper_owts("medium", file_path.as_str())?),
-        "large" => log::warn!("{}", crate::thalamus::tools::whisper_owts("large", file_path.as_str())?),
-        &_ => log::warn!("{}", crate::thalamus::tools::whisper_owts("tiny", file_path.as_str())?)
-    };
+    log::warn!("{}", crate::thalamus::tools::whisper_owts(method, file_path.as_str())?);
     
     // linux only patch
 
-    crate::thalamus::services::whisper::patch_whisper_wts(format!("{}.16.wav.wts", file_path.clone())).unwrap();
+    crate::thalamus::services::whisper::patch_whisper_wts(format!("{}.16.wav.wts", file_path.clone()))?;
     
     
     match crate::thalamus::tools::mark_as_executable(format!("{}.16.wav.wts", file_path.clone()).as_str()){
@@ -103,33 +104,41 @@ pub fn whisper_vwav(file_path: String, method: &str) -> Result<String, crate::th
 // Patch linux whisper WTS files
 pub fn patch_whisper_wts(file_path: String) -> Result<(), crate::thalamus::services::Error>{
     let mut data = std::fs::read_to_string(format!("{}", file_path).as_str())?;
-    data = data.replace("ffmpeg", "/opt/thalamus/bin/ffmpeg").replace("/System/Library/Fonts/Supplemental/Courier New Bold.ttf","/opt/thalamus/fonts/courier.ttf");
+    data = data.replace("ffmpeg", &crate::thalamus::paths::bin("ffmpeg")).replace("/System/Library/Fonts/Supplemental/Courier New Bold.ttf",&crate::thalamus::paths::root("fonts/courier.ttf"));
     std::fs::remove_file(format!("{}", file_path).as_str())?;
     std::fs::write(file_path, data)?;
     return Ok(());
 }
 
-pub fn install() -> Result<(), crate::thalamus::setup::Error> {
+// Names in the bin directory
+pub const BINARIES: [&str; 2] = ["whisper", "ffmpeg"];
+
+pub const ARTIFACTS: [crate::thalamus::tools::Artifact; 3] = [
+    crate::thalamus::tools::Artifact {
+        path: "ggml-tiny.bin",
+        url: "https://huggingface.co/ggerganov/whisper.cpp/resolve/main/ggml-tiny.bin",
+        sha256: Some("be07e048e1e599ad46341c8d2a135645097a538221678b7acdd1b1919c6e1b21"),
+        size: Some(77691713),
+    },
+    crate::thalamus::tools::Artifact {
+        path: "ggml-base.bin",
+        url: "https://huggingface.co/ggerganov/whisper.cpp/resolve/main/ggml-base.bin",
+        sha256: Some("60ed5bc3dd14eea856493d334349b405782ddcaf0028d4b5df4088345fba2efe"),
+        size: Some(147951465),
+    },
+    crate::thalamus::tools::Artifact {
+        path: "ggml-medium.bin",
+        url: "https://huggingface.co/ggerganov/whisper.cpp/resolve/main/ggml-medium.bin",
+        sha256: Some("6c14d5adee5f86394037b4e4e8b59f1673b6cee10e3cf0b11bbdbee79c156208"),
+        size: Some(1533763059),
+    },
+];
 
-    
+pub fn install() -> Result<(), crate::thalamus::setup::Error> {
 
-    crate::thalamus::tools::safe_download(
-        "/opt/thalamus/models/ggml-tiny.bin",
-        "https://huggingface.co/ggerganov/whisper.cpp/resolve/main/ggml-tiny.bin", 
-        Some("be07e048e1e599ad46341c8d2a135645097a538221678b7acdd1b1919c6e1b21"), Some(77691713)
-    );
-    
-    crate::thalamus::tools::safe_download(
-        "/opt/thalamus/models/ggml-base.bin",
-        "https://huggingface.co/ggerganov/whisper.cpp/resolve/main/ggml-base.bin", 
-        Some("60ed5bc3dd14eea856493d334349b405782ddcaf0028d4b5df4088345fba2efe"), Some(147951465)
-    );
-
-    crate::thalamus::tools::safe_download(
-        "/opt/thalamus/models/ggml-medium.bin",
-        "https://huggingface.co/ggerganov/whisper.cpp/resolve/main/ggml-medium.bin", 
-        Some("6c14d5adee5f86394037b4e4e8b59f1673b6cee10e3cf0b11bbdbee79c156208"), Some(1533763059)
-    );
+    for artifact in ARTIFACTS.iter() {
+        crate::thalamus::tools::safe_download_artifact(artifact);
+    }
 
     // if !Path::new("/opt/thalamus/models/ggml-large.bin").exists(){
     //     log::warn!("ggml-large.bin is missing.....downloading it from https://huggingface.co/");
@@ -168,16 +177,16 @@ pub fn install() -> Result<(), crate::thalamus::setup::Error> {
     // }
 
     #[cfg(target_arch = "x86_64")]{
-        if !Path::new("/opt/thalamus/bin/whisper").exists(){
-            log::info!("Installing whisper (x86_64) /opt/thalamus/bin/whisper");
-            crate::thalamus::tools::safe_download("/opt/thalamus/bin/whisper", "https://www.dropbox.com/s/ovcjbhmdysnlyyn/main?dl=1", None, None);
+        if !Path::new(&crate::thalamus::paths::bin("whisper")).exists(){
+            log::info!("Installing whisper (x86_64) {}", crate::thalamus::paths::bin("whisper"));
+            crate::thalamus::tools::safe_download(&crate::thalamus::paths::bin("whisper"), "https://www.dropbox.com/s/ovcjbhmdysnlyyn/main?dl=1", None, None);
         }
 
-        if !Path::new("/opt/thalamus/bin/ffmpeg").exists(){
-            log::info!("Installing ffmpeg (x86_64) /opt/thalamus/bin/ffmpeg");
-            crate::thalamus::tools::safe_download("/opt/thalamus/bin/ffmpeg", "https://www.dropbox.com/s/j91btel44c37g98/ffmpeg?dl=1", None, None);
+        if !Path::new(&crate::thalamus::paths::bin("ffmpeg")).exists(){
+            log::info!("Installing ffmpeg (x86_64) {}", crate::thalamus::paths::bin("ffmpeg"));
+            crate::thalamus::tools::safe_download(&crate::thalamus::paths::bin("ffmpeg"), "https://www.dropbox.com/s/j91btel44c37g98/ffmpeg?dl=1", None, None);
         }
-        match crate::thalamus::tools::mark_as_executable("/opt/thalamus/bin/ffmpeg"){
+        match crate::thalamus::tools::mark_as_executable(&crate::thalamus::paths::bin("ffmpeg")){
             Ok(_) => (),
             Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to chmod ffmpeg").into())
         }
@@ -187,45 +196,45 @@ pub fn install() -> Result<(), crate::thalamus::setup::Error> {
     // Apple M1/M2
     #[cfg(all(target_arch = "aarch64", target_os = "macos"))] {
 
-        if !Path::new("/opt/thalamus/bin/whisper").exists(){
-            log::info!("Installing whisper (aarch64) /opt/thalamus/bin");
-            crate::thalamus::tools::safe_download("/opt/thalamus/bin/whisper", "https://www.dropbox.com/s/1fl35hlp5op2pfn/main?dl=1", None, None);
+        if !Path::new(&crate::thalamus::paths::bin("whisper")).exists(){
+            log::info!("Installing whisper (aarch64) {}", crate::thalamus::paths::get().bin);
+            crate::thalamus::tools::safe_download(&crate::thalamus::paths::bin("whisper"), "https://www.dropbox.com/s/1fl35hlp5op2pfn/main?dl=1", None, None);
         }
 
-        if !Path::new("/opt/thalamus/models/convert-whisper-to-coreml.py").exists(){
+        if !Path::new(&crate::thalamus::paths::model("convert-whisper-to-coreml.py")).exists(){
             log::info!("Unpacking convert-whisper-to-coreml.py...");
-            crate::thalamus::tools::safe_download("/opt/thalamus/models/convert-whisper-to-coreml.py", "https://www.dropbox.com/s/hu40n989phv0igk/convert-whisper-to-coreml.py?dl=1", None, None);
+            crate::thalamus::tools::safe_download(&crate::thalamus::paths::model("convert-whisper-to-coreml.py"), "https://www.dropbox.com/s/hu40n989phv0igk/convert-whisper-to-coreml.py?dl=1", None, None);
         }
 
-        if !Path::new("/opt/thalamus/models/generate-coreml-model.sh").exists(){
+        if !Path::new(&crate::thalamus::paths::model("generate-coreml-model.sh")).exists(){
             log::info!("Unpacking generate-coreml-model.sh...");
-            crate::thalamus::tools::safe_download("/opt/thalamus/models/generate-coreml-model.sh", "https://www.dropbox.com/s/8h59bw07q8tbaak/generate-coreml-model.sh?dl=1", None, None);
+            crate::thalamus::tools::safe_download(&crate::thalamus::paths::model("generate-coreml-model.sh"), "https://www.dropbox.com/s/8h59bw07q8tbaak/generate-coreml-model.sh?dl=1", None, None);
         }
 
-        if !Path::new("/opt/thalamus/models/coreml.sh").exists(){
+        if !Path::new(&crate::thalamus::paths::model("coreml.sh")).exists(){
             log::info!("Unpacking coreml.sh...");
-            crate::thalamus::tools::safe_download("/opt/thalamus/models/coreml.sh", "https://www.dropbox.com/s/ico9dlti77v6k6u/coreml.sh?dl=1", None, None);
+            crate::thalamus::tools::safe_download(&crate::thalamus::paths::model("coreml.sh"), "https://www.dropbox.com/s/ico9dlti77v6k6u/coreml.sh?dl=1", None, None);
         }
 
         // Fix permissions
-        match crate::thalamus::tools::fix_permissions("/opt/thalamus/models"){
+        match crate::thalamus::tools::fix_permissions(&crate::thalamus::paths::get().models){
             Ok(_) => {},
-            Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to chmod /opt/thalamus").into()),
+            Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to chmod models directory").into()),
         }
-        match crate::thalamus::tools::mark_as_executable("/opt/thalamus/models/coreml.sh"){
+        match crate::thalamus::tools::mark_as_executable(&crate::thalamus::paths::model("coreml.sh")){
             Ok(_) => {},
             Err(_) => {},
         }
 
-        match crate::thalamus::tools::mark_as_executable("/opt/thalamus/models/generate-coreml-model.sh"){
+        match crate::thalamus::tools::mark_as_executable(&crate::thalamus::paths::model("generate-coreml-model.sh")){
             Ok(_) => {},
             Err(_) => {},
         }
 
         // Configure Miniconda and Generate ML models if necessary
-        if !Path::new("/opt/thalamus/models/coreml-encoder-tiny.mlpackage").exists(){
+        if !Path::new(&crate::thalamus::paths::model("coreml-encoder-tiny.mlpackage")).exists(){
             log::warn!("CoreML Encoders are missing...please be patient while they are being generated. This may take a while. Future launches will be faster.");
-            match crate::thalamus::tools::sh("/opt/thalamus/models/coreml.sh"){
+            match crate::thalamus::tools::sh(&crate::thalamus::paths::model("coreml.sh")){
                 Ok(_) => {},
                 Err(_) => {},
             }  
@@ -233,11 +242,11 @@ pub fn install() -> Result<(), crate::thalamus::setup::Error> {
         }
     }
 
-    if !Path::new("/opt/thalamus/fonts/courier.ttf").exists(){
-        crate::thalamus::tools::safe_download("/opt/thalamus/fonts/courier.ttf", "https://www.dropbox.com/s/qip7w9ik3a15qso/courier.ttf?dl=1", None, None);
+    if !Path::new(&crate::thalamus::paths::root("fonts/courier.ttf")).exists(){
+        crate::thalamus::tools::safe_download(&crate::thalamus::paths::root("fonts/courier.ttf"), "https://www.dropbox.com/s/qip7w9ik3a15qso/courier.ttf?dl=1", None, None);
     }
 
-    match crate::thalamus::tools::mark_as_executable("/opt/thalamus/bin/whisper"){
+    match crate::thalamus::tools::mark_as_executable(&crate::thalamus::paths::bin("whisper")){
         Ok(_) => (),
         Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to chmod whisper").into())
     }
@@ -257,25 +266,42 @@ pub struct STTReply {
 
 
 
-pub fn handle(request: &Request) -> Result<Response, crate::thalamus::http::Error> {
-    
-   
+pub fn handle_stt(request: &Request, context: &crate::thalamus::http::HttpContext) -> Result<Response, crate::thalamus::http::Error> {
 
-    
-    if request.url() == "/api/services/whisper" {
+    let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
 
-        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
+    let input = post_input!(request, {
+        speech: Option<BufferedFile>,
+        speech_id: Option<String>,
+        method: Option<String>
+    })?;
+    let method = input.method.or(crate::thalamus::config::service("whisper").default_model)
+        .ok_or(crate::thalamus::http::ErrorKind::BadRequest("missing field: method".to_string()))?;
+    check_model(method.as_str())?;
 
-        let input = post_input!(request, {
-            speech: BufferedFile,
-            method: String
-        })?;
+    let scratch = crate::thalamus::scratch::ScratchDir::new("")?;
+    let tmp_file_path = scratch.file("speech.wav");
+    crate::thalamus::files::service_input(input.speech, input.speech_id, "speech", &tmp_file_path)?;
 
-        let tmp_file_path = format!("/opt/thalamus/tmp/{}.wav", timestamp.clone());
-        let mut file = File::create(tmp_file_path.clone())?;
-        file.write_all(&input.speech.data)?;
+    // Only the transcription is cached, the reply is rebuilt with this request's time
+    let mode = crate::thalamus::cache::mode(request);
+    let mut cache_key = String::new();
+    if mode.active() {
+        cache_key = crate::thalamus::cache::key("whisper", &crate::thalamus::tools::hash_check(&tmp_file_path)?, &[method.as_str()]);
+    }
+    if let Some(cached) = crate::thalamus::cache::lookup(mode, &cache_key) {
+        let reply = STTReply{
+            text: String::from_utf8_lossy(&cached.data).to_string(),
+            time: timestamp as f64,
+            response_type: None
+        };
+        return crate::thalamus::cache::hit(request, context, format!("whisper_stt:{}", method).as_str(), crate::thalamus::jobs::JobOutput::Json(serde_json::to_value(&reply)?));
+    }
 
-        let stt = whisper(tmp_file_path, input.method.as_str())?;
+    let response = crate::thalamus::jobs::respond(request, context, format!("whisper_stt:{}", method).as_str(), move |_job| {
+        let stt = crate::thalamus::metrics::time_inference("whisper", method.as_str(), || whisper(tmp_file_path, method.as_str()))?;
+        std::mem::drop(scratch);
+        crate::thalamus::cache::store(mode, &cache_key, "text/plain", stt.as_bytes());
 
         let reply = STTReply{
             text: stt,
@@ -285,34 +311,28 @@ pub fn handle(request: &Request) -> Result<Response, crate::thalamus::http::Erro
 
         log::info!("{}", reply.text.clone());
 
-        return Ok(Response::json(&reply));
-      
-    }
-
-
-    if request.url() == "/api/services/whisper/vwav"{
-
-        let timestamp = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs() as i64;
-
-        let input = post_input!(request, {
-            speech: BufferedFile,
-            method: String
-        })?;
-
-        let tmp_file_path = format!("/opt/thalamus/tmp/{}.wav", timestamp.clone());
-        let mut file = File::create(tmp_file_path.clone())?;
-        file.write_all(&input.speech.data)?;
-
-        let output_path = whisper_vwav(tmp_file_path, input.method.as_str())?;
-
-        let outfile = File::open(output_path.as_str()).unwrap();
-
-        let response = Response::from_file("video/mp4", outfile);
-        return Ok(response);
-       
-                
-    }
+        return Ok(crate::thalamus::jobs::JobOutput::Json(serde_json::to_value(&reply)?));
+    })?;
+    return Ok(crate::thalamus::cache::label(response, mode));
+}
 
-    
-    return Ok(Response::empty_404());
-}
\ No newline at end of file
+pub fn handle_vwav(request: &Request, context: &crate::thalamus::http::HttpContext) -> Result<Response, crate::thalamus::http::Error> {
+
+    let input = post_input!(request, {
+        speech: Option<BufferedFile>,
+        speech_id: Option<String>,
+        method: Option<String>
+    })?;
+    let method = input.method.or(crate::thalamus::config::service("whisper").default_model)
+        .ok_or(crate::thalamus::http::ErrorKind::BadRequest("missing field: method".to_string()))?;
+    check_model(method.as_str())?;
+
+    let scratch = crate::thalamus::scratch::ScratchDir::new("")?;
+    let tmp_file_path = scratch.file("speech.wav");
+    crate::thalamus::files::service_input(input.speech, input.speech_id, "speech", &tmp_file_path)?;
+
+    return crate::thalamus::jobs::respond(request, context, format!("whisper_vwav:{}", method).as_str(), move |_job| {
+        let output_path = crate::thalamus::metrics::time_inference("whisper_vwav", method.as_str(), || whisper_vwav(tmp_file_path, method.as_str()))?;
+        return Ok(crate::thalamus::jobs::JobOutput::ScratchFile("video/mp4".to_string(), output_path, std::sync::Arc::new(scratch)));
+    });
+}
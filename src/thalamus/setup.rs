@@ -22,154 +22,176 @@ error_chain! {
     }
 }
 
-pub fn install(args: crate::Args) -> Result<()> {
-    
-    match crate::thalamus::tools::mkdir("/opt"){
-        Ok(_) => {},
-        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create /opt directory").into()),
-    }
-
-    match crate::thalamus::tools::mkdir("/opt/thalamus"){
-        Ok(_) => {},
-        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create /opt/thalamus directory").into()),
-    }
+// Installed by the root install, only checked for in rootless mode
+#[cfg(target_os = "macos")]
+const PREREQUISITES: [(&str, &str); 6] = [
+    ("homebrew", "/opt/homebrew/bin/brew"),
+    ("miniconda", "/opt/homebrew/bin/conda"),
+    ("openssl@1.1", "/opt/homebrew/bin/openssl"),
+    ("docker", "/opt/homebrew/bin/docker"),
+    ("wget", "/opt/homebrew/bin/wget"),
+    ("ffmpeg", "/opt/homebrew/bin/ffmpeg"),
+];
+#[cfg(not(target_os = "macos"))]
+const PREREQUISITES: [(&str, &str); 4] = [
+    ("wget", "/bin/wget"),
+    ("docker", "/bin/docker"),
+    ("libclblast-dev", "/usr/share/doc/libclblast-dev"),
+    ("libopenblas-dev", "/usr/share/doc/libopenblas-dev"),
+];
+
+pub fn missing_prerequisites() -> Vec<String> {
+    return PREREQUISITES.iter()
+        .filter(|(_, path)| !Path::new(path).exists())
+        .map(|(name, _)| name.to_string())
+        .collect();
+}
 
-    match crate::thalamus::tools::fix_permissions("/opt/thalamus"){
-        Ok(_) => {},
-        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to chmod /opt/thalamus").into()),
-    }
+pub fn install(args: crate::Args) -> Result<()> {
+    let rootless = crate::thalamus::paths::get().rootless;
 
-    match crate::thalamus::tools::mkdir("/opt/thalamus/models"){
-        Ok(_) => {},
-        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create /opt/thalamus/models directory").into()),
+    if rootless {
+        let missing = missing_prerequisites();
+        if missing.len() > 0 {
+            return Err(format!("rootless installs do not use the package manager, install these first: {}", missing.join(", ")).into());
+        }
+    } else {
+        match crate::thalamus::tools::mkdir("/opt"){
+            Ok(_) => {},
+            Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create /opt directory").into()),
+        }
     }
 
-    match crate::thalamus::tools::mkdir("/opt/thalamus/models/nst"){
+    match crate::thalamus::tools::mkdir(&crate::thalamus::paths::get().root){
         Ok(_) => {},
-        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create /opt/thalamus/models/nst directory").into()),
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create data root").into()),
     }
 
-    match crate::thalamus::tools::mkdir("/opt/thalamus/models/llama"){
-        Ok(_) => {},
-        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create /opt/thalamus/models/llama directory").into()),
+    if !rootless {
+        match crate::thalamus::tools::fix_permissions(&crate::thalamus::paths::get().root){
+            Ok(_) => {},
+            Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to chmod data root").into()),
+        }
     }
 
-    match crate::thalamus::tools::mkdir("/opt/thalamus/models/llama/7B"){
+    match crate::thalamus::tools::mkdir(&crate::thalamus::paths::get().models){
         Ok(_) => {},
-        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create /opt/thalamus/models/llama directory").into()),
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create models directory").into()),
     }
 
-    match crate::thalamus::tools::mkdir("/opt/thalamus/models/llama/13B"){
+    match crate::thalamus::tools::mkdir(&crate::thalamus::paths::model("nst")){
         Ok(_) => {},
-        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create /opt/thalamus/models/llama directory").into()),
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create models/nst directory").into()),
     }
 
-    match crate::thalamus::tools::mkdir("/opt/thalamus/models/llama/30B"){
+    match crate::thalamus::tools::mkdir(&crate::thalamus::paths::model("llama")){
         Ok(_) => {},
-        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create /opt/thalamus/models/llama directory").into()),
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create models/llama directory").into()),
     }
 
-    match crate::thalamus::tools::mkdir("/opt/thalamus/models/llama/65B"){
+    match crate::thalamus::tools::mkdir(&crate::thalamus::paths::model("llama/7B")){
         Ok(_) => {},
-        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create /opt/thalamus/models/llama directory").into()),
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create models/llama directory").into()),
     }
 
-    match crate::thalamus::tools::mkdir("/opt/thalamus/models/ocnn"){
+    match crate::thalamus::tools::mkdir(&crate::thalamus::paths::model("ocnn")){
         Ok(_) => {},
-        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create /opt/thalamus/models/ocnn directory").into()),
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create models/ocnn directory").into()),
     }
 
-    match crate::thalamus::tools::mkdir("/opt/thalamus/bin"){
+    match crate::thalamus::tools::mkdir(&crate::thalamus::paths::get().bin){
         Ok(_) => {},
-        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create /opt/thalamus/bin directory").into()),
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create bin directory").into()),
     }
 
-    match crate::thalamus::tools::mkdir("/opt/thalamus/tmp"){
+    match crate::thalamus::tools::mkdir(&crate::thalamus::paths::get().tmp){
         Ok(_) => {},
-        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create /opt/thalamus/tmp directory").into()),
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create tmp directory").into()),
     }
 
-    match crate::thalamus::tools::mkdir("/opt/thalamus/tmp/srgan"){
+    match crate::thalamus::tools::mkdir(&crate::thalamus::paths::tmp("srgan")){
         Ok(_) => {},
-        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create /opt/thalamus/tmp/srgan directory").into()),
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create tmp/srgan directory").into()),
     }
 
-    match crate::thalamus::tools::mkdir("/opt/thalamus/fonts"){
+    match crate::thalamus::tools::mkdir(&crate::thalamus::paths::root("fonts")){
         Ok(_) => {},
-        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create /opt/thalamus/fonts directory").into()),
+        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to create fonts directory").into()),
     }
 
 
     // Apple M1/M2
     #[cfg(all(target_os = "macos"))] {
 
-        // Install Homebrew
-        if !Path::new("/opt/homebrew/bin/brew").exists(){
-            match crate::thalamus::tools::dbash("\"$(curl -fsSL https://raw.githubusercontent.com/Homebrew/install/HEAD/install.sh)\""){
-                Ok(_) => {},
-                Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install homebrew").into()),
+        if !rootless {
+            // Install Homebrew
+            if !Path::new("/opt/homebrew/bin/brew").exists(){
+                match crate::thalamus::tools::dbash("\"$(curl -fsSL https://raw.githubusercontent.com/Homebrew/install/HEAD/install.sh)\""){
+                    Ok(_) => {},
+                    Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install homebrew").into()),
+                }
             }
-        }
 
-        // Install Miniconda
-        if !Path::new("/opt/homebrew/bin/conda").exists(){
-            match crate::thalamus::tools::brew_install("miniconda"){
-                Ok(_) => {},
-                Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install miniconda").into()),
+            // Install Miniconda
+            if !Path::new("/opt/homebrew/bin/conda").exists(){
+                match crate::thalamus::tools::brew_install("miniconda"){
+                    Ok(_) => {},
+                    Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install miniconda").into()),
+                }
             }
-        }
 
-        // Install openssl@1.1
-        if !Path::new("/opt/homebrew/bin/openssl").exists(){
-            match crate::thalamus::tools::brew_install("openssl@1.1"){
-                Ok(_) => {},
-                Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install openssl@1.1").into()),
+            // Install openssl@1.1
+            if !Path::new("/opt/homebrew/bin/openssl").exists(){
+                match crate::thalamus::tools::brew_install("openssl@1.1"){
+                    Ok(_) => {},
+                    Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install openssl@1.1").into()),
+                }
             }
-        }
 
-        // Install docker
-        // TODO: brew install --cask docker
-        // brew install colima docker
-        // colima start
-        if !Path::new("/opt/homebrew/bin/docker").exists(){
-            match crate::thalamus::tools::brew_install("docker"){
-                Ok(_) => {},
-                Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install docker").into()),
+            // Install docker
+            // TODO: brew install --cask docker
+            // brew install colima docker
+            // colima start
+            if !Path::new("/opt/homebrew/bin/docker").exists(){
+                match crate::thalamus::tools::brew_install("docker"){
+                    Ok(_) => {},
+                    Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install docker").into()),
+                }
             }
-        }
 
-        // Install wget
-        if !Path::new("/opt/homebrew/bin/wget").exists(){
-            match crate::thalamus::tools::brew_install("wget"){
-                Ok(_) => {},
-                Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install wget").into()),
+            // Install wget
+            if !Path::new("/opt/homebrew/bin/wget").exists(){
+                match crate::thalamus::tools::brew_install("wget"){
+                    Ok(_) => {},
+                    Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install wget").into()),
+                }
             }
-        }
 
-        // Install ffmpeg
-        if !Path::new("/opt/homebrew/bin/ffmpeg").exists(){
-            match crate::thalamus::tools::brew_install("ffmpeg"){
-                Ok(_) => {},
-                Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install ffmpeg").into()),
+            // Install ffmpeg
+            if !Path::new("/opt/homebrew/bin/ffmpeg").exists(){
+                match crate::thalamus::tools::brew_install("ffmpeg"){
+                    Ok(_) => {},
+                    Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install ffmpeg").into()),
+                }
             }
         }
 
-        if !Path::new("/opt/thalamus/bin/ffmpeg").exists(){
-            match crate::thalamus::tools::ln("/opt/homebrew/bin/ffmpeg", "/opt/thalamus/bin/ffmpeg"){
+        if !Path::new(&crate::thalamus::paths::bin("ffmpeg")).exists(){
+            match crate::thalamus::tools::ln("/opt/homebrew/bin/ffmpeg", &crate::thalamus::paths::bin("ffmpeg")){
                 Ok(_) => {},
                 Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to link ffmpeg").into()),
             }
         }
 
-        if !Path::new("/opt/thalamus/bin/wget").exists(){
-            match crate::thalamus::tools::ln("/opt/homebrew/bin/wget", "/opt/thalamus/bin/wget"){
+        if !Path::new(&crate::thalamus::paths::bin("wget")).exists(){
+            match crate::thalamus::tools::ln("/opt/homebrew/bin/wget", &crate::thalamus::paths::bin("wget")){
                 Ok(_) => {},
                 Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to link ffmpeg").into()),
             }
         }
 
-        if !Path::new("/opt/thalamus/bin/docker").exists(){
-            match crate::thalamus::tools::ln("/opt/homebrew/bin/docker", "/opt/thalamus/bin/docker"){
+        if !Path::new(&crate::thalamus::paths::bin("docker")).exists(){
+            match crate::thalamus::tools::ln("/opt/homebrew/bin/docker", &crate::thalamus::paths::bin("docker")){
                 Ok(_) => {},
                 Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to link ffmpeg").into()),
             }
@@ -177,10 +199,12 @@ pub fn install(args: crate::Args) -> Result<()> {
 
 
 
-        // Uninstall python
-        match crate::thalamus::tools::brew_uninstall("python"){
-            Ok(_) => {},
-            Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to uninstall python").into()),
+        if !rootless {
+            // Uninstall python
+            match crate::thalamus::tools::brew_uninstall("python"){
+                Ok(_) => {},
+                Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to uninstall python").into()),
+            }
         }
     }
 
@@ -189,66 +213,68 @@ pub fn install(args: crate::Args) -> Result<()> {
     #[cfg(all(target_os = "linux"))] {
 
 
-        // Install wget
-        if !Path::new("/bin/wget").exists(){
-            if Path::new("/bin/apt").exists(){
-                match crate::thalamus::tools::apt_install("wget"){
-                    Ok(_) => {},
-                    Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install wget").into()),
+        if !rootless {
+            // Install wget
+            if !Path::new("/bin/wget").exists(){
+                if Path::new("/bin/apt").exists(){
+                    match crate::thalamus::tools::apt_install("wget"){
+                        Ok(_) => {},
+                        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install wget").into()),
+                    }
                 }
-            }
 
-            if Path::new("/bin/dnf").exists(){
-                match crate::thalamus::tools::dnf_install("wget"){
-                    Ok(_) => {},
-                    Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install wget").into()),
+                if Path::new("/bin/dnf").exists(){
+                    match crate::thalamus::tools::dnf_install("wget"){
+                        Ok(_) => {},
+                        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install wget").into()),
+                    }
                 }
             }
-        }
 
-        // Install libclblast-dev
-        if !Path::new("/usr/share/doc/libclblast-dev").exists(){
-            if Path::new("/bin/apt").exists(){
-                match crate::thalamus::tools::apt_install("libclblast-dev"){
-                    Ok(_) => {},
-                    Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install libclblast-dev").into()),
+            // Install libclblast-dev
+            if !Path::new("/usr/share/doc/libclblast-dev").exists(){
+                if Path::new("/bin/apt").exists(){
+                    match crate::thalamus::tools::apt_install("libclblast-dev"){
+                        Ok(_) => {},
+                        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install libclblast-dev").into()),
+                    }
                 }
-            }
 
-            if Path::new("/bin/dnf").exists(){
-                match crate::thalamus::tools::dnf_install("libclblast-dev"){
-                    Ok(_) => {},
-                    Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install libclblast-dev").into()),
+                if Path::new("/bin/dnf").exists(){
+                    match crate::thalamus::tools::dnf_install("libclblast-dev"){
+                        Ok(_) => {},
+                        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install libclblast-dev").into()),
+                    }
                 }
             }
-        }
 
-        // Install libopenblas-dev
-        if !Path::new("/usr/share/doc/libopenblas-dev").exists(){
-            if Path::new("/bin/apt").exists(){
-                match crate::thalamus::tools::apt_install("libopenblas-dev"){
-                    Ok(_) => {},
-                    Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install libclblast-dev").into()),
+            // Install libopenblas-dev
+            if !Path::new("/usr/share/doc/libopenblas-dev").exists(){
+                if Path::new("/bin/apt").exists(){
+                    match crate::thalamus::tools::apt_install("libopenblas-dev"){
+                        Ok(_) => {},
+                        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install libclblast-dev").into()),
+                    }
                 }
-            }
 
-            if Path::new("/bin/dnf").exists(){
-                match crate::thalamus::tools::dnf_install("libopenblas-dev"){
-                    Ok(_) => {},
-                    Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install libclblast-dev").into()),
+                if Path::new("/bin/dnf").exists(){
+                    match crate::thalamus::tools::dnf_install("libopenblas-dev"){
+                        Ok(_) => {},
+                        Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to install libclblast-dev").into()),
+                    }
                 }
             }
         }
 
-        if !Path::new("/opt/thalamus/bin/wget").exists(){
-            match crate::thalamus::tools::ln("/bin/wget", "/opt/thalamus/bin/wget"){
+        if !Path::new(&crate::thalamus::paths::bin("wget")).exists(){
+            match crate::thalamus::tools::ln("/bin/wget", &crate::thalamus::paths::bin("wget")){
                 Ok(_) => {},
                 Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to link ffmpeg").into()),
             }
         }
 
-        if !Path::new("/opt/thalamus/bin/docker").exists(){
-            match crate::thalamus::tools::ln("/bin/docker", "/opt/thalamus/bin/docker"){
+        if !Path::new(&crate::thalamus::paths::bin("docker")).exists(){
+            match crate::thalamus::tools::ln("/bin/docker", &crate::thalamus::paths::bin("docker")){
                 Ok(_) => {},
                 Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to link docker").into()),
             }
@@ -298,41 +324,47 @@ pub fn install(args: crate::Args) -> Result<()> {
 
 
 
-    if !Path::new("/opt/thalamus/pid").exists() {
+    if !Path::new(&crate::thalamus::paths::state("pid")).exists() {
         let pid: String = thread_rng().sample_iter(&Alphanumeric).take(15).map(char::from).collect();
-        std::fs::write("/opt/thalamus/pid", pid).expect("Unable to write file");
+        std::fs::write(&crate::thalamus::paths::state("pid"), pid).expect("Unable to write file");
     }
 
     Ok(())
 }
 
 pub fn install_client() -> Result<()> {
-    match crate::thalamus::tools::mkdir("/opt"){
-        Ok(_) => {},
-        Err(_) => {},
+    let rootless = crate::thalamus::paths::get().rootless;
+
+    if !rootless {
+        match crate::thalamus::tools::mkdir("/opt"){
+            Ok(_) => {},
+            Err(_) => {},
+        }
     }
 
-    match crate::thalamus::tools::mkdir("/opt/thalamus"){
+    match crate::thalamus::tools::mkdir(&crate::thalamus::paths::get().root){
         Ok(_) => {},
         Err(_) => {},
     }
 
-    match crate::thalamus::tools::mkdir("/opt/thalamus/tmp"){
+    match crate::thalamus::tools::mkdir(&crate::thalamus::paths::get().tmp){
         Ok(_) => {},
         Err(_) => {},
     }
 
-    match crate::thalamus::tools::fix_permissions("/opt/thalamus"){
-        Ok(_) => {},
-        Err(_) => {},
+    if !rootless {
+        match crate::thalamus::tools::fix_permissions(&crate::thalamus::paths::get().root){
+            Ok(_) => {},
+            Err(_) => {},
+        }
     }
 
-    if !Path::new("/opt/thalamus/test.wav").exists(){
-        crate::thalamus::tools::safe_download("/opt/thalamus/test.wav", "https://www.dropbox.com/s/j55gxifpi5s62t4/test.wav?dl=1", None, None);
+    if !Path::new(&crate::thalamus::paths::root("test.wav")).exists(){
+        crate::thalamus::tools::safe_download(&crate::thalamus::paths::root("test.wav"), "https://www.dropbox.com/s/j55gxifpi5s62t4/test.wav?dl=1", None, None);
     }
 
-    if !Path::new("/opt/thalamus/test.jpg").exists(){
-        crate::thalamus::tools::safe_download("/opt/thalamus/test.jpg", "https://www.dropbox.com/s/socxvceshvxovpe/test.jpg?dl=1", None, None);
+    if !Path::new(&crate::thalamus::paths::root("test.jpg")).exists(){
+        crate::thalamus::tools::safe_download(&crate::thalamus::paths::root("test.jpg"), "https://www.dropbox.com/s/socxvceshvxovpe/test.jpg?dl=1", None, None);
     }
 
     return Ok(());
@@ -347,7 +379,9 @@ pub fn install_service(args: crate::Args) -> Result<()> {
     // Mac OS
     #[cfg(all(target_os = "macos"))] {
         update_osx_service_file(args.clone());
-        match crate::thalamus::tools::launchd_bootout("/Library/LaunchDaemons/com.opensamfoundation.thalamus.plist"){
+        let plist = osx_service_path();
+        let target = format!("{}/com.opensamfoundation.thalamus.plist", crate::thalamus::tools::launchd_domain());
+        match crate::thalamus::tools::launchd_bootout(&plist){
             Ok(_) => {},
             Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to launch thalamus as a service").into()),
         }
@@ -356,7 +390,7 @@ pub fn install_service(args: crate::Args) -> Result<()> {
         match std::env::current_exe() {
             Ok(exe_path) => {
                 let current_exe_path = format!("{}", exe_path.display());
-                match crate::thalamus::tools::cp(current_exe_path.as_str(), "/opt/thalamus/bin"){
+                match crate::thalamus::tools::cp(current_exe_path.as_str(), &crate::thalamus::paths::get().bin){
                     Ok(_) => {},
                     Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to copy thalamus binary").into()),
                 }
@@ -365,15 +399,15 @@ pub fn install_service(args: crate::Args) -> Result<()> {
         };
 
 
-        match crate::thalamus::tools::launchd_bootstrap("/Library/LaunchDaemons/com.opensamfoundation.thalamus.plist"){
+        match crate::thalamus::tools::launchd_bootstrap(&plist){
             Ok(_) => {},
                         Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to bootstrap thalamus as a service").into()),
         }
-        match crate::thalamus::tools::launchd_enable("system/com.opensamfoundation.thalamus.plist"){
+        match crate::thalamus::tools::launchd_enable(&target){
             Ok(_) => {},
                         Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to enable thalamus as a service").into()),
         }
-        match crate::thalamus::tools::launchd_kickstart("system/com.opensamfoundation.thalamus.plist"){
+        match crate::thalamus::tools::launchd_kickstart(&target){
             Ok(_) => {},
             Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to kickstart thalamus as a service").into()),
         }
@@ -399,7 +433,7 @@ pub fn install_service(args: crate::Args) -> Result<()> {
         match std::env::current_exe() {
             Ok(exe_path) => {
                 let current_exe_path = format!("{}", exe_path.display());
-                match crate::thalamus::tools::cp(current_exe_path.as_str(), "/opt/thalamus/bin"){
+                match crate::thalamus::tools::cp(current_exe_path.as_str(), &crate::thalamus::paths::get().bin){
                     Ok(_) => {},
                     Err(_) => return Err(std::io::Error::new(std::io::ErrorKind::Other, "Failed to copy thalamus binary").into()),
                 }
@@ -415,7 +449,12 @@ pub fn install_service(args: crate::Args) -> Result<()> {
     Ok(())
 }
 
-pub fn update_osx_service_file(_args: crate::Args){
+// Plist strings are XML text
+fn xml_escape(text: &str) -> String {
+    return text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;");
+}
+
+pub fn update_osx_service_file(args: crate::Args){
     let mut data = String::new();
     data.push_str("<?xml version=\"1.0\" encoding=\"UTF-8\"?>\n");
     data.push_str("<!DOCTYPE plist PUBLIC \"-//Apple//DTD PLIST 1.0//EN\" \"http://www.apple.com/DTDs/PropertyList-1.0.dtd\">\n");
@@ -425,7 +464,12 @@ pub fn update_osx_service_file(_args: crate::Args){
     data.push_str("<string>com.opensamfoundation.thalamus</string>\n");
     data.push_str("<key>ProgramArguments</key>\n");
     data.push_str("<array>\n");
-    data.push_str("<string>/opt/thalamus/bin/thalamus</string>\n");
+    data.push_str(format!("<string>{}</string>\n", crate::thalamus::paths::bin("thalamus")).as_str());
+    // Same flags as the systemd unit
+    let flags = format!("--lang {} --max-threads {} --http-port {} --p2p-port {}{} --key {}{}{}{}", args.lang, args.max_threads, args.www_port, args.p2p_port, if args.encrypt { " --encrypt" } else { "" }, args.key, if args.allow_default_key { " --allow-default-key" } else { "" }, tls_flags(&args), crate::thalamus::paths::get().flags());
+    for flag in flags.split_whitespace() {
+        data.push_str(format!("<string>{}</string>\n", xml_escape(flag)).as_str());
+    }
     data.push_str("</array>\n");
 
     data.push_str("<key>RunAtLoad</key>\n");
@@ -435,7 +479,49 @@ pub fn update_osx_service_file(_args: crate::Args){
     data.push_str("</dict>\n");
     data.push_str("</plist>\n");
 
-    std::fs::write("/Library/LaunchDaemons/com.opensamfoundation.thalamus.plist", data).expect("Unable to write file");
+    let path = osx_service_path();
+    if let Some(parent) = Path::new(&path).parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    std::fs::write(path, data).expect("Unable to write file");
+}
+
+// A LaunchAgent in the user's library for rootless installs
+fn osx_service_path() -> String {
+    if crate::thalamus::paths::get().rootless {
+        let home = std::env::var("HOME").unwrap_or(".".to_string());
+        return format!("{}/Library/LaunchAgents/com.opensamfoundation.thalamus.plist", home);
+    }
+    return "/Library/LaunchDaemons/com.opensamfoundation.thalamus.plist".to_string();
+}
+
+// A systemd --user unit for rootless installs
+fn linux_service_path() -> String {
+    if crate::thalamus::paths::get().rootless {
+        return format!("{}/systemd/user/thalamus.service", crate::thalamus::paths::xdg_config_home());
+    }
+    return "/lib/systemd/system/thalamus.service".to_string();
+}
+
+// Carries the --tls* flags over to the installed service
+fn tls_flags(args: &crate::Args) -> String {
+    let mut flags = String::new();
+    if args.tls {
+        flags.push_str(" --tls");
+    }
+    if let Some(cert) = &args.tls_cert {
+        flags.push_str(format!(" --tls-cert {}", cert).as_str());
+    }
+    if let Some(key) = &args.tls_key {
+        flags.push_str(format!(" --tls-key {}", key).as_str());
+    }
+    if let Some(pin) = &args.tls_pin {
+        flags.push_str(format!(" --tls-pin {}", pin).as_str());
+    }
+    if args.tls_insecure {
+        flags.push_str(" --tls-insecure");
+    }
+    return flags;
 }
 
 pub fn update_linux_service_file(args: crate::Args){
@@ -446,10 +532,13 @@ pub fn update_linux_service_file(args: crate::Args){
     data.push_str("After=systemd-user-sessions.service\n");
     data.push_str("After=network-online.target\n\n");
     data.push_str("[Service]\n");
+    let paths = crate::thalamus::paths::get();
+    let libtorch = crate::thalamus::paths::root("libtorch");
+    let thalamus = crate::thalamus::paths::bin("thalamus");
     if args.encrypt{
-        data.push_str(format!("ExecStart=/usr/bin/env LIBTORCH=/opt/thalamus/libtorch LD_LIBRARY_PATH=/opt/thalamus/libtorch/lib: /opt/thalamus/bin/thalamus --lang {} --max-threads {} --http-port {} --p2p-port {} --encrypt --key {}\n", args.lang, args.max_threads, args.www_port, args.p2p_port, args.key).as_str());
+        data.push_str(format!("ExecStart=/usr/bin/env LIBTORCH={} LD_LIBRARY_PATH={}/lib: {} --lang {} --max-threads {} --http-port {} --p2p-port {} --encrypt --key {}{}{}{}\n", libtorch, libtorch, thalamus, args.lang, args.max_threads, args.www_port, args.p2p_port, args.key, if args.allow_default_key { " --allow-default-key" } else { "" }, tls_flags(&args), paths.flags()).as_str());
     } else {
-        data.push_str(format!("ExecStart=/usr/bin/env LIBTORCH=/opt/thalamus/libtorch LD_LIBRARY_PATH=/opt/thalamus/libtorch/lib: /opt/thalamus/bin/thalamus --lang {} --max-threads {} --http-port {} --p2p-port {} --key {}\n", args.lang, args.max_threads, args.www_port, args.p2p_port, args.key).as_str());
+        data.push_str(format!("ExecStart=/usr/bin/env LIBTORCH={} LD_LIBRARY_PATH={}/lib: {} --lang {} --max-threads {} --http-port {} --p2p-port {} --key {}{}{}{}\n", libtorch, libtorch, thalamus, args.lang, args.max_threads, args.www_port, args.p2p_port, args.key, if args.allow_default_key { " --allow-default-key" } else { "" }, tls_flags(&args), paths.flags()).as_str());
     }
     data.push_str("TimeoutSec=30\n");
     data.push_str("Restart=on-failure\n");
@@ -457,7 +546,15 @@ pub fn update_linux_service_file(args: crate::Args){
     data.push_str("StartLimitInterval=350\n");
     data.push_str("StartLimitBurst=10\n\n");
     data.push_str("[Install]\n");
-    data.push_str("WantedBy=multi-user.target\n");
-    std::fs::write("/lib/systemd/system/thalamus.service", data).expect("Unable to write file");
+    if paths.rootless {
+        data.push_str("WantedBy=default.target\n");
+    } else {
+        data.push_str("WantedBy=multi-user.target\n");
+    }
+    let path = linux_service_path();
+    if let Some(parent) = Path::new(&path).parent() {
+        let _ = std::fs::create_dir_all(parent);
+    }
+    std::fs::write(path, data).expect("Unable to write file");
 }
 
@@ -0,0 +1,192 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// Graceful shutdown on SIGTERM/SIGINT
+// New requests are refused with 503 while requests already being handled, streamed
+// responses still being written and queued/running jobs get up to --shutdown-timeout
+// seconds to finish. The web server keeps polling until then so those responses get
+// written, then stops and any whisper/llama/ffmpeg children still alive are killed.
+
+use std::io::Read;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+static SHUTTING_DOWN: AtomicBool = AtomicBool::new(false);
+static STOPPED: AtomicBool = AtomicBool::new(false);
+static IN_FLIGHT: AtomicUsize = AtomicUsize::new(0);
+
+// pids of inference processes spawned by tools
+static CHILDREN: Mutex<Vec<(u32, String)>> = Mutex::new(Vec::new());
+
+pub fn begin() {
+    SHUTTING_DOWN.store(true, Ordering::SeqCst);
+}
+
+pub fn is_shutting_down() -> bool {
+    return SHUTTING_DOWN.load(Ordering::SeqCst);
+}
+
+/// Tells the web server to stop polling, once draining is over
+pub fn stop() {
+    STOPPED.store(true, Ordering::SeqCst);
+}
+
+pub fn is_stopped() -> bool {
+    return STOPPED.load(Ordering::SeqCst);
+}
+
+pub fn in_flight() -> usize {
+    return IN_FLIGHT.load(Ordering::SeqCst);
+}
+
+/// Counts a request as in flight until dropped
+pub struct InFlight;
+impl InFlight {
+    pub fn start() -> InFlight {
+        IN_FLIGHT.fetch_add(1, Ordering::SeqCst);
+        InFlight
+    }
+}
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        IN_FLIGHT.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+/// A streamed response body, in flight until it is dropped after the last read
+pub struct Tracked<R: Read> {
+    inner: R,
+    _in_flight: InFlight,
+}
+impl<R: Read> Tracked<R> {
+    pub fn new(inner: R) -> Tracked<R> {
+        return Tracked { inner: inner, _in_flight: InFlight::start() };
+    }
+}
+impl<R: Read> Read for Tracked<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        return self.inner.read(buf);
+    }
+}
+
+pub fn register_child(pid: u32, program: &str) {
+    CHILDREN.lock().unwrap().push((pid, program.to_string()));
+}
+
+pub fn unregister_child(pid: u32) {
+    CHILDREN.lock().unwrap().retain(|(child, _)| *child != pid);
+}
+
+/// Waits for a termination signal, returns its name
+pub async fn wait_for_signal() -> std::io::Result<&'static str> {
+    use tokio::signal::unix::{signal, SignalKind};
+    let mut terminate = signal(SignalKind::terminate())?;
+    let mut interrupt = signal(SignalKind::interrupt())?;
+    tokio::select! {
+        _ = terminate.recv() => return Ok("SIGTERM"),
+        _ = interrupt.recv() => return Ok("SIGINT"),
+    }
+}
+
+/// Blocks until nothing is in flight and no job is queued or running, false on timeout
+pub fn drain(jobs: &crate::thalamus::jobs::JobRegistry, timeout: Duration) -> bool {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let pending_jobs = jobs.list().iter().filter(|job| {
+            let status = job.status.as_deref();
+            status == Some(crate::thalamus::jobs::STATUS_QUEUED) || status == Some(crate::thalamus::jobs::STATUS_RUNNING)
+        }).count();
+
+        if in_flight() == 0 && pending_jobs == 0 {
+            return true;
+        }
+        if Instant::now() >= deadline {
+            log::warn!("Shutdown timed out with {} requests and {} jobs still running", in_flight(), pending_jobs);
+            return false;
+        }
+        std::thread::sleep(Duration::from_millis(250));
+    }
+}
+
+fn signal(pid: u32, signal: libc::c_int) {
+    // Safe for any pid, a child that already exited just makes kill fail with ESRCH
+    if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+        log::error!("Unable to signal pid {}: {}", pid, std::io::Error::last_os_error());
+    }
+}
+
+/// SIGTERM to every registered child, SIGKILL to whatever is still around after a grace period
+pub fn kill_children(grace: Duration) {
+    let children = CHILDREN.lock().unwrap().clone();
+    if children.len() == 0 {
+        return;
+    }
+
+    for (pid, program) in children.iter() {
+        log::warn!("Stopping {} (pid {})", program, pid);
+        signal(*pid, libc::SIGTERM);
+    }
+
+    let deadline = Instant::now() + grace;
+    while Instant::now() < deadline && CHILDREN.lock().unwrap().len() > 0 {
+        std::thread::sleep(Duration::from_millis(100));
+    }
+
+    for (pid, program) in CHILDREN.lock().unwrap().iter() {
+        log::warn!("Killing {} (pid {})", program, pid);
+        signal(*pid, libc::SIGKILL);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::os::unix::process::ExitStatusExt;
+
+    #[test]
+    fn test_tracked_and_drain() {
+        let jobs = crate::thalamus::jobs::JobRegistry::new();
+        let mut reader = Tracked::new(&b"body"[..]);
+        assert_eq!(in_flight(), 1);
+
+        // Gives up at the deadline while the body is still out
+        let started = Instant::now();
+        assert!(!drain(&jobs, Duration::from_millis(300)));
+        assert!(started.elapsed() >= Duration::from_millis(300));
+
+        let mut body = String::new();
+        reader.read_to_string(&mut body).unwrap();
+        assert_eq!(body, "body");
+        assert_eq!(in_flight(), 1);
+
+        // Returns as soon as the last reader is dropped
+        let writer = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(200));
+            std::mem::drop(reader);
+        });
+        let started = Instant::now();
+        assert!(drain(&jobs, Duration::from_secs(10)));
+        assert!(started.elapsed() < Duration::from_secs(5));
+        assert_eq!(in_flight(), 0);
+        writer.join().unwrap();
+    }
+
+    #[test]
+    fn test_kill_children() {
+        // Ignores SIGTERM so only the SIGKILL after the grace period stops it
+        let mut child = std::process::Command::new("sh").arg("-c").arg("trap '' TERM; sleep 30").spawn().unwrap();
+        std::thread::sleep(Duration::from_millis(200));
+        register_child(child.id(), "sh");
+
+        kill_children(Duration::from_millis(300));
+        assert_eq!(child.wait().unwrap().signal(), Some(libc::SIGKILL));
+        unregister_child(child.id());
+    }
+}
@@ -0,0 +1,285 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// Model storage management
+// Reports how much of the models directory each model takes, refuses downloads that would
+// push it past [storage] max_bytes, and evicts models by how long ago they were last used
+// (kept in thalamus.db) or, for models never used since, installed. Served from
+// /api/admin/storage to admin keys and the --storage-report and --evict-unused flags.
+// Evicted models are downloaded again by the next install.
+
+use serde::{Serialize, Deserialize};
+use std::fs;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use error_chain::error_chain;
+error_chain! {
+    foreign_links {
+        Io(std::io::Error);
+        StoreError(crate::thalamus::store::Error);
+    }
+
+    errors {
+        OverBudget(path: String, needed: u64, available: u64) {
+            description("storage budget exceeded")
+            display("{} needs {} bytes but only {} are left in the storage budget", path, needed, available)
+        }
+        UnknownModel(model: String) {
+            description("unknown model")
+            display("unknown model: {}, expected <service>/<name>", model)
+        }
+    }
+}
+
+/// A model and the files or directories it is made of
+#[derive(Debug, Clone)]
+pub struct Model {
+    pub service: &'static str,
+    pub name: String,
+    pub paths: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ModelUsage {
+    pub service: String,
+    pub name: String,
+    pub bytes: u64,
+    pub installed: bool,
+    /// Unix time of the last request that used it, None if never used since tracking began
+    pub last_used: Option<i64>,
+    /// Unix time its files were last written, None when not installed
+    #[serde(default)]
+    pub installed_at: Option<i64>,
+}
+impl ModelUsage {
+    /// When it was last used or installed, whichever is later, so a model that was
+    /// downloaded but never used yet does not count as idle since 1970
+    pub fn last_active(&self) -> i64 {
+        return self.last_used.max(self.installed_at).unwrap_or(0);
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StorageReport {
+    /// [storage] max_bytes, None when unlimited
+    pub max_bytes: Option<u64>,
+    /// Everything in the models directory
+    pub models_bytes: u64,
+    pub cache_bytes: u64,
+    pub files_bytes: u64,
+    pub tmp_bytes: u64,
+    pub models: Vec<ModelUsage>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EvictReport {
+    pub evicted: Vec<ModelUsage>,
+    pub freed_bytes: u64,
+}
+
+/// Every model a service can load
+pub fn models() -> Vec<Model> {
+    let mut models = Vec::new();
+    for name in crate::thalamus::services::whisper::MODELS.iter() {
+        models.push(Model { service: "whisper", name: name.to_string(), paths: vec![crate::thalamus::paths::model(&format!("ggml-{}.bin", name))] });
+    }
+    for name in crate::thalamus::services::llama::MODELS.iter() {
+        models.push(Model { service: "llama", name: name.to_string(), paths: vec![crate::thalamus::paths::model(&format!("llama/{}", name))] });
+    }
+    models.push(Model { service: "yolov7", name: "yolov7".to_string(), paths: crate::thalamus::services::image::yolo::model_files() });
+    #[cfg(feature = "pytorch")]
+    models.push(Model { service: "nst", name: "vgg16".to_string(), paths: vec![crate::thalamus::paths::model("vgg16.ot")] });
+    return models;
+}
+
+/// Bytes under a file or directory, 0 when it does not exist
+pub fn disk_usage(path: &Path) -> u64 {
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(_) => return 0,
+    };
+    if !metadata.is_dir() {
+        return metadata.len();
+    }
+    return match fs::read_dir(path) {
+        Ok(entries) => entries.flatten().map(|entry| disk_usage(&entry.path())).sum(),
+        Err(_) => 0,
+    };
+}
+
+/// Newest modification time under a file or directory, None when it does not exist
+pub fn modified(path: &Path) -> Option<i64> {
+    let metadata = fs::symlink_metadata(path).ok()?;
+    let own = metadata.modified().ok().and_then(|time| time.duration_since(UNIX_EPOCH).ok()).map(|time| time.as_secs() as i64);
+    if !metadata.is_dir() {
+        return own;
+    }
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries.flatten().filter_map(|entry| modified(&entry.path())).max(),
+        Err(_) => None,
+    };
+    return own.max(entries);
+}
+
+/// Records a use of a model, called by the services as they run
+pub fn touch(service: &str, name: &str) {
+    if let Some(store) = crate::thalamus::store::get() {
+        if let Err(e) = store.touch_model(service, name) {
+            log::error!("Unable to record use of {}/{}: {}", service, name, e);
+        }
+    }
+}
+
+pub fn usage() -> Result<Vec<ModelUsage>> {
+    let last_used = match crate::thalamus::store::get() {
+        Some(store) => store.model_usage()?,
+        None => Default::default(),
+    };
+    return Ok(models().into_iter().map(|model| {
+        let bytes: u64 = model.paths.iter().map(|path| disk_usage(Path::new(path))).sum();
+        ModelUsage {
+            service: model.service.to_string(),
+            name: model.name.clone(),
+            bytes: bytes,
+            // Empty llama directories are not an install
+            installed: bytes > 0,
+            last_used: last_used.get(&(model.service.to_string(), model.name.clone())).cloned(),
+            installed_at: model.paths.iter().filter_map(|path| modified(Path::new(path))).max(),
+        }
+    }).collect());
+}
+
+pub fn report() -> Result<StorageReport> {
+    let paths = crate::thalamus::paths::get();
+    return Ok(StorageReport {
+        max_bytes: crate::thalamus::config::get().storage.max_bytes,
+        models_bytes: disk_usage(Path::new(&paths.models)),
+        cache_bytes: disk_usage(Path::new(&crate::thalamus::cache::dir())),
+        files_bytes: disk_usage(Path::new(&crate::thalamus::files::dir())),
+        tmp_bytes: disk_usage(Path::new(&paths.tmp)),
+        models: usage()?,
+    });
+}
+
+/// Fails when writing `needed` more bytes to the models directory would go over budget
+pub fn check_download(path: &str, needed: u64) -> Result<()> {
+    let max_bytes = match crate::thalamus::config::get().storage.max_bytes {
+        Some(max_bytes) => max_bytes,
+        None => return Ok(()),
+    };
+    let used = disk_usage(Path::new(&crate::thalamus::paths::get().models));
+    let available = max_bytes.saturating_sub(used);
+    if needed > available {
+        return Err(ErrorKind::OverBudget(path.to_string(), needed, available).into());
+    }
+    return Ok(());
+}
+
+fn remove(model: &Model) -> Result<()> {
+    for path in model.paths.iter() {
+        match fs::symlink_metadata(path) {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path)?,
+            Ok(_) => fs::remove_file(path)?,
+            Err(_) => {},
+        }
+    }
+    log::warn!("Evicted model {}/{}", model.service, model.name);
+    return Ok(());
+}
+
+/// Removes one model, named <service>/<name>
+pub fn evict_model(model: &str) -> Result<EvictReport> {
+    let (service, name) = model.split_once('/').ok_or(ErrorKind::UnknownModel(model.to_string()))?;
+    let usage = usage()?.into_iter().find(|u| u.service == service && u.name == name).ok_or(ErrorKind::UnknownModel(model.to_string()))?;
+    let target = models().into_iter().find(|m| m.service == service && m.name == name).ok_or(ErrorKind::UnknownModel(model.to_string()))?;
+    remove(&target)?;
+    return Ok(EvictReport { freed_bytes: usage.bytes, evicted: vec![usage] });
+}
+
+/// Installed models in the order they should go, least recently used or installed first
+fn eviction_order(mut usage: Vec<ModelUsage>) -> Vec<ModelUsage> {
+    usage.retain(|u| u.installed);
+    usage.sort_by_key(|u| u.last_active());
+    return usage;
+}
+
+/// Models unused for at least unused_for_secs, then the least recently used ones until
+/// `used` bytes fit in max_bytes
+fn eviction_candidates(usage: Vec<ModelUsage>, unused_for_secs: Option<i64>, max_bytes: Option<u64>, mut used: u64, now: i64) -> Vec<ModelUsage> {
+    let mut candidates = Vec::new();
+    for usage in eviction_order(usage) {
+        let idle = match unused_for_secs {
+            Some(secs) => now - usage.last_active() >= secs,
+            None => false,
+        };
+        let over_budget = max_bytes.map_or(false, |max_bytes| used > max_bytes);
+        if idle || over_budget {
+            used = used.saturating_sub(usage.bytes);
+            candidates.push(usage);
+        }
+    }
+    return candidates;
+}
+
+/// Evicts models unused for at least unused_for_secs, then the least recently used
+/// ones until the models directory fits in [storage] max_bytes
+pub fn evict(unused_for_secs: Option<i64>) -> Result<EvictReport> {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+    let max_bytes = crate::thalamus::config::get().storage.max_bytes;
+    let used = disk_usage(Path::new(&crate::thalamus::paths::get().models));
+    let models = models();
+
+    let mut report = EvictReport { evicted: Vec::new(), freed_bytes: 0 };
+    for usage in eviction_candidates(usage()?, unused_for_secs, max_bytes, used, now) {
+        if let Some(model) = models.iter().find(|m| m.service == usage.service && m.name == usage.name) {
+            remove(model)?;
+            report.freed_bytes += usage.bytes;
+            report.evicted.push(usage);
+        }
+    }
+    return Ok(report);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn usage(name: &str, bytes: u64, last_used: Option<i64>, installed_at: Option<i64>) -> ModelUsage {
+        ModelUsage { service: "whisper".to_string(), name: name.to_string(), bytes: bytes, installed: bytes > 0, last_used: last_used, installed_at: installed_at }
+    }
+
+    fn names(usage: &[ModelUsage]) -> Vec<&str> {
+        return usage.iter().map(|u| u.name.as_str()).collect();
+    }
+
+    #[test]
+    fn test_eviction_order() {
+        let order = eviction_order(vec![usage("base", 10, Some(200), Some(50)), usage("tiny", 10, Some(100), Some(50)), usage("large", 0, None, None), usage("medium", 10, None, Some(150))]);
+        assert_eq!(names(&order), vec!["tiny", "medium", "base"]);
+    }
+
+    #[test]
+    fn test_eviction_candidates() {
+        let models = vec![
+            usage("tiny", 10, Some(100), Some(50)),
+            // Downloaded a minute ago and not used yet
+            usage("base", 10, None, Some(9940)),
+            usage("medium", 10, None, Some(1000)),
+            usage("large", 10, Some(9990), Some(50)),
+        ];
+        let idle = eviction_candidates(models.clone(), Some(3600), None, 40, 10000);
+        assert_eq!(names(&idle), vec!["tiny", "medium"]);
+
+        // Over budget evicts the least recently active until it fits, fresh installs last
+        let over = eviction_candidates(models.clone(), None, Some(15), 40, 10000);
+        assert_eq!(names(&over), vec!["tiny", "medium", "base"]);
+        assert!(eviction_candidates(models, None, None, 40, 10000).is_empty());
+    }
+}
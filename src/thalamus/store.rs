@@ -0,0 +1,392 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// SQLite store for the node registry, node status changes, benchmark runs and jobs
+// Lives in thalamus.db in the state directory. Saving the registry upserts one row per
+// node instead of rewriting clients.json, and every online/offline change and every
+// stats calculation is kept so history can be queried later. clients.json is imported
+// once when the database is empty and can still be written with --export-registry.
+
+use rusqlite::{params, Connection, OptionalExtension};
+use serde::{Serialize, Deserialize};
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::{ThalamusClient, ThalamusNode, ThalamusNodeJob, ThalamusNodeStats};
+
+use error_chain::error_chain;
+error_chain! {
+    foreign_links {
+        Io(std::io::Error);
+        SqliteError(rusqlite::Error);
+        JSONError(serde_json::Error);
+    }
+}
+
+pub const FILE: &str = "thalamus.db";
+
+// Applied in order, PRAGMA user_version records how many have run
+const MIGRATIONS: [&str; 2] = [
+    "CREATE TABLE nodes (
+        pid TEXT PRIMARY KEY,
+        ip_address TEXT NOT NULL,
+        port INTEGER NOT NULL,
+        version TEXT NOT NULL,
+        capablities TEXT,
+        last_ping INTEGER NOT NULL,
+        is_online INTEGER NOT NULL,
+        stats TEXT NOT NULL,
+        jobs TEXT NOT NULL
+    );
+    CREATE TABLE node_status (
+        pid TEXT NOT NULL,
+        at INTEGER NOT NULL,
+        is_online INTEGER NOT NULL
+    );
+    CREATE INDEX node_status_pid_at ON node_status (pid, at);
+    CREATE TABLE benchmarks (
+        pid TEXT NOT NULL,
+        at INTEGER NOT NULL,
+        metric TEXT NOT NULL,
+        score INTEGER NOT NULL
+    );
+    CREATE INDEX benchmarks_pid_metric_at ON benchmarks (pid, metric, at);
+    CREATE TABLE jobs (
+        oid TEXT PRIMARY KEY,
+        job_identifier TEXT NOT NULL,
+        url TEXT,
+        status TEXT,
+        progress REAL,
+        started_at INTEGER NOT NULL,
+        finished_at INTEGER,
+        error TEXT
+    );
+    CREATE INDEX jobs_started_at ON jobs (started_at);",
+    "CREATE TABLE model_usage (
+        service TEXT NOT NULL,
+        name TEXT NOT NULL,
+        last_used INTEGER NOT NULL,
+        PRIMARY KEY (service, name)
+    );",
+];
+
+/// One stats calculation for one metric
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct BenchmarkRun {
+    pub at: i64,
+    pub score: i64,
+}
+
+/// A node going online or offline
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct StatusChange {
+    pub pid: String,
+    pub at: i64,
+    pub is_online: bool,
+}
+
+/// Reply of GET /api/nodex/status
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StatusReport {
+    pub from: i64,
+    pub to: i64,
+    /// Nodes that were offline at some point in the window
+    pub offline: Vec<String>,
+    pub changes: Vec<StatusChange>,
+}
+
+/// Names of the benchmark metrics, the ThalamusNodeStats fields
+pub fn metrics() -> Vec<String> {
+    match serde_json::to_value(ThalamusNodeStats::new()) {
+        Ok(serde_json::Value::Object(fields)) => return fields.keys().cloned().collect(),
+        _ => return Vec::new(),
+    }
+}
+
+pub struct Store {
+    conn: Mutex<Connection>,
+}
+
+static STORE: Mutex<Option<Arc<Store>>> = Mutex::new(None);
+
+/// Opens the database every save and job goes to from now on
+pub fn init(path: &str) -> Result<()> {
+    if let Some(parent) = std::path::Path::new(path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let store = Store::open(path).chain_err(|| format!("unable to open {}", path))?;
+    let mut current = STORE.lock().unwrap();
+    *current = Some(Arc::new(store));
+    return Ok(());
+}
+
+/// The open store, None when running without one (tests, tools)
+pub fn get() -> Option<Arc<Store>> {
+    return STORE.lock().unwrap().clone();
+}
+
+fn now() -> i64 {
+    return SystemTime::now().duration_since(UNIX_EPOCH).unwrap().as_secs() as i64;
+}
+
+impl Store {
+    pub fn open(path: &str) -> Result<Store> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(std::time::Duration::from_secs(5))?;
+        conn.pragma_update(None, "journal_mode", "WAL")?;
+        return Store::migrate(conn);
+    }
+
+    pub fn open_in_memory() -> Result<Store> {
+        return Store::migrate(Connection::open_in_memory()?);
+    }
+
+    fn migrate(conn: Connection) -> Result<Store> {
+        let version: usize = conn.pragma_query_value(None, "user_version", |row| row.get(0))?;
+        if version > MIGRATIONS.len() {
+            return Err(format!("database is at version {} but this build only understands up to {}", version, MIGRATIONS.len()).into());
+        }
+        for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+            conn.execute_batch(migration)?;
+            conn.pragma_update(None, "user_version", i + 1)?;
+        }
+        return Ok(Store { conn: Mutex::new(conn) });
+    }
+
+    pub fn is_empty(&self) -> Result<bool> {
+        let conn = self.conn.lock().unwrap();
+        let count: i64 = conn.query_row("SELECT COUNT(*) FROM nodes", [], |row| row.get(0))?;
+        return Ok(count == 0);
+    }
+
+    /// Upserts every node, drops nodes no longer in the registry and logs status changes
+    pub fn save_client(&self, client: &ThalamusClient) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let at = now();
+
+        let mut pids: Vec<String> = Vec::new();
+        for node in client.nodes.iter() {
+            let was_online: Option<bool> = tx.query_row("SELECT is_online FROM nodes WHERE pid = ?1", params![node.pid], |row| row.get(0)).optional()?;
+            if was_online != Some(node.is_online) {
+                tx.execute("INSERT INTO node_status (pid, at, is_online) VALUES (?1, ?2, ?3)", params![node.pid, at, node.is_online])?;
+            }
+
+            tx.execute(
+                "INSERT INTO nodes (pid, ip_address, port, version, capablities, last_ping, is_online, stats, jobs)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                 ON CONFLICT(pid) DO UPDATE SET ip_address = ?2, port = ?3, version = ?4, capablities = ?5,
+                    last_ping = ?6, is_online = ?7, stats = ?8, jobs = ?9",
+                params![
+                    node.pid,
+                    node.ip_address,
+                    node.port,
+                    node.version,
+                    serde_json::to_string(&node.capablities)?,
+                    node.last_ping,
+                    node.is_online,
+                    serde_json::to_string(&node.stats)?,
+                    serde_json::to_string(&node.jobs)?,
+                ],
+            )?;
+            pids.push(node.pid.clone());
+        }
+
+        let mut stale: Vec<String> = Vec::new();
+        {
+            let mut statement = tx.prepare("SELECT pid FROM nodes")?;
+            for pid in statement.query_map([], |row| row.get::<_, String>(0))? {
+                let pid = pid?;
+                if !pids.contains(&pid) {
+                    stale.push(pid);
+                }
+            }
+        }
+        for pid in stale.iter() {
+            tx.execute("DELETE FROM nodes WHERE pid = ?1", params![pid])?;
+        }
+
+        tx.commit()?;
+        return Ok(());
+    }
+
+    pub fn load_client(&self) -> Result<ThalamusClient> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT pid, ip_address, port, version, capablities, last_ping, is_online, stats, jobs FROM nodes ORDER BY rowid")?;
+        let rows = statement.query_map([], |row| {
+            Ok((
+                row.get::<_, String>(0)?,
+                row.get::<_, String>(1)?,
+                row.get::<_, u16>(2)?,
+                row.get::<_, String>(3)?,
+                row.get::<_, Option<String>>(4)?,
+                row.get::<_, i64>(5)?,
+                row.get::<_, bool>(6)?,
+                row.get::<_, String>(7)?,
+                row.get::<_, String>(8)?,
+            ))
+        })?;
+
+        let mut client = ThalamusClient::new();
+        for row in rows {
+            let (pid, ip_address, port, version, capablities, last_ping, is_online, stats, jobs) = row?;
+            let mut node = ThalamusNode::new(pid, version, ip_address, port);
+            node.capablities = match capablities {
+                Some(capablities) => serde_json::from_str(&capablities)?,
+                None => None,
+            };
+            node.last_ping = last_ping;
+            node.is_online = is_online;
+            node.stats = serde_json::from_str(&stats)?;
+            node.jobs = serde_json::from_str(&jobs)?;
+            client.nodes.push(node);
+        }
+        return Ok(client);
+    }
+
+    /// Keeps every score of a stats calculation
+    pub fn record_benchmark(&self, pid: &str, stats: &ThalamusNodeStats) -> Result<()> {
+        let mut conn = self.conn.lock().unwrap();
+        let tx = conn.transaction()?;
+        let at = now();
+        if let serde_json::Value::Object(scores) = serde_json::to_value(stats)? {
+            for (metric, score) in scores.iter() {
+                if let Some(score) = score.as_i64() {
+                    tx.execute("INSERT INTO benchmarks (pid, at, metric, score) VALUES (?1, ?2, ?3, ?4)", params![pid, at, metric, score])?;
+                }
+            }
+        }
+        tx.commit()?;
+        return Ok(());
+    }
+
+    /// Scores of one metric (a ThalamusNodeStats field, e.g. whisper_stt_tiny) since a unix time
+    pub fn benchmark_history(&self, pid: &str, metric: &str, since: i64) -> Result<Vec<BenchmarkRun>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT at, score FROM benchmarks WHERE pid = ?1 AND metric = ?2 AND at >= ?3 ORDER BY at")?;
+        let runs = statement.query_map(params![pid, metric, since], |row| Ok(BenchmarkRun { at: row.get(0)?, score: row.get(1)? }))?;
+        return Ok(runs.collect::<rusqlite::Result<Vec<BenchmarkRun>>>()?);
+    }
+
+    /// Status changes between two unix times, oldest first
+    pub fn status_history(&self, from: i64, to: i64) -> Result<Vec<StatusChange>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT pid, at, is_online FROM node_status WHERE at >= ?1 AND at <= ?2 ORDER BY at, rowid")?;
+        let changes = statement.query_map(params![from, to], |row| Ok(StatusChange { pid: row.get(0)?, at: row.get(1)?, is_online: row.get(2)? }))?;
+        return Ok(changes.collect::<rusqlite::Result<Vec<StatusChange>>>()?);
+    }
+
+    /// Nodes that were offline at some point between two unix times, including
+    /// nodes that went offline before `from` and stayed that way
+    pub fn offline_between(&self, from: i64, to: i64) -> Result<Vec<String>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare(
+            "SELECT DISTINCT pid FROM node_status s
+             WHERE is_online = 0 AND (
+                (at >= ?1 AND at <= ?2)
+                OR at = (SELECT MAX(at) FROM node_status WHERE pid = s.pid AND at < ?1)
+             )
+             ORDER BY pid",
+        )?;
+        let pids = statement.query_map(params![from, to], |row| row.get::<_, String>(0))?;
+        return Ok(pids.collect::<rusqlite::Result<Vec<String>>>()?);
+    }
+
+    /// Inserts or updates a job, finished jobs carry their end time and error if any
+    pub fn record_job(&self, job: &ThalamusNodeJob, finished_at: Option<i64>, error: Option<&str>) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO jobs (oid, job_identifier, url, status, progress, started_at, finished_at, error)
+             VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8)
+             ON CONFLICT(oid) DO UPDATE SET url = ?3, status = ?4, progress = ?5, finished_at = ?7, error = ?8",
+            params![job.oid, job.job_identifier, job.url, job.status, job.progress, job.started_at, finished_at, error],
+        )?;
+        return Ok(());
+    }
+
+    /// Notes that a model was just used, for evicting the ones that sit idle
+    pub fn touch_model(&self, service: &str, name: &str) -> Result<()> {
+        let conn = self.conn.lock().unwrap();
+        conn.execute(
+            "INSERT INTO model_usage (service, name, last_used) VALUES (?1, ?2, ?3)
+             ON CONFLICT(service, name) DO UPDATE SET last_used = ?3",
+            params![service, name, now()],
+        )?;
+        return Ok(());
+    }
+
+    /// Last use of every model that has been used, keyed by (service, name)
+    pub fn model_usage(&self) -> Result<HashMap<(String, String), i64>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT service, name, last_used FROM model_usage")?;
+        let rows = statement.query_map([], |row| Ok(((row.get::<_, String>(0)?, row.get::<_, String>(1)?), row.get::<_, i64>(2)?)))?;
+        return Ok(rows.collect::<rusqlite::Result<HashMap<(String, String), i64>>>()?);
+    }
+
+    /// Jobs started since a unix time, oldest first
+    pub fn jobs_since(&self, since: i64) -> Result<Vec<ThalamusNodeJob>> {
+        let conn = self.conn.lock().unwrap();
+        let mut statement = conn.prepare("SELECT oid, job_identifier, url, status, progress, started_at FROM jobs WHERE started_at >= ?1 ORDER BY started_at")?;
+        let jobs = statement.query_map(params![since], |row| {
+            Ok(ThalamusNodeJob {
+                oid: row.get(0)?,
+                job_identifier: row.get(1)?,
+                url: row.get(2)?,
+                status: row.get(3)?,
+                progress: row.get(4)?,
+                started_at: row.get(5)?,
+            })
+        })?;
+        return Ok(jobs.collect::<rusqlite::Result<Vec<ThalamusNodeJob>>>()?);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_registry_and_history() {
+        let store = Store::open_in_memory().unwrap();
+        assert!(store.is_empty().unwrap());
+
+        let mut client = ThalamusClient::new();
+        client.nodes.push(ThalamusNode::new("abc".to_string(), "0.0.14".to_string(), "10.0.0.2".to_string(), 8050));
+        client.nodes.push(ThalamusNode::new("def".to_string(), "0.0.14".to_string(), "10.0.0.3".to_string(), 8050));
+        store.save_client(&client).unwrap();
+
+        client.nodes[1].is_online = false;
+        store.save_client(&client).unwrap();
+        store.save_client(&client).unwrap();
+
+        let loaded = store.load_client().unwrap();
+        assert_eq!(loaded.nodes.len(), 2);
+        assert_eq!(loaded.nodes[1].pid, "def");
+        assert!(!loaded.nodes[1].is_online);
+        assert_eq!(store.status_history(0, i64::MAX).unwrap().len(), 3);
+        assert_eq!(store.offline_between(0, i64::MAX).unwrap(), vec!["def".to_string()]);
+        assert_eq!(store.offline_between(i64::MAX - 1, i64::MAX).unwrap(), vec!["def".to_string()]);
+
+        let mut stats = ThalamusNodeStats::new();
+        stats.whisper_stt_tiny = Some(1200);
+        store.record_benchmark("abc", &stats).unwrap();
+        let runs = store.benchmark_history("abc", "whisper_stt_tiny", 0).unwrap();
+        assert_eq!(runs.len(), 1);
+        assert_eq!(runs[0].score, 1200);
+
+        client.nodes.remove(0);
+        store.save_client(&client).unwrap();
+        assert_eq!(store.load_client().unwrap().nodes.len(), 1);
+
+        store.touch_model("whisper", "base").unwrap();
+        store.touch_model("whisper", "base").unwrap();
+        assert_eq!(store.model_usage().unwrap().len(), 1);
+    }
+}
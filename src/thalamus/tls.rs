@@ -0,0 +1,97 @@
+// ████████ ██   ██  █████  ██       █████  ███    ███ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ████  ████ ██    ██ ██      
+//    ██    ███████ ███████ ██      ███████ ██ ████ ██ ██    ██ ███████ 
+//    ██    ██   ██ ██   ██ ██      ██   ██ ██  ██  ██ ██    ██      ██ 
+//    ██    ██   ██ ██   ██ ███████ ██   ██ ██      ██  ██████  ███████                                                                             
+// Copyright 2021-2023 The Open Sam Foundation (OSF)
+// Developed by Caleb Mitchell Smith (PixelCoda)
+// Licensed under GPLv3....see LICENSE file.
+
+// HTTPS support for the web server and the mesh clients
+// Without --tls-cert/--tls-key a self-signed certificate is generated on first boot,
+// its SANs cover the hostname and every local address so peers can pin it with --tls-pin
+
+use local_ip_address::list_afinet_netifas;
+use std::io::Write;
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+
+// Relative to the state directory
+pub const CERT_PATH: &str = "tls/cert.pem";
+pub const KEY_PATH: &str = "tls/key.pem";
+
+use error_chain::error_chain;
+error_chain! {
+    foreign_links {
+        Io(std::io::Error);
+        CertificateError(rcgen::RcgenError);
+        LocalIpError(local_ip_address::Error);
+    }
+}
+
+/// Certificate and key paths the server should use
+pub fn paths(args: &crate::Args) -> (String, String) {
+    let cert = args.tls_cert.clone().unwrap_or(crate::thalamus::paths::state(CERT_PATH));
+    let key = args.tls_key.clone().unwrap_or(crate::thalamus::paths::state(KEY_PATH));
+    return (cert, key);
+}
+
+/// Reads the PEM cert and key, generating a self-signed pair if neither was configured or exists yet
+pub fn load_or_generate(args: &crate::Args) -> Result<(Vec<u8>, Vec<u8>)> {
+    let (cert_path, key_path) = paths(args);
+
+    let user_supplied = args.tls_cert.is_some() || args.tls_key.is_some();
+    if !user_supplied && !(Path::new(cert_path.as_str()).exists() && Path::new(key_path.as_str()).exists()) {
+        log::warn!("Generating self-signed certificate at {}", cert_path);
+        let (cert, key) = generate_self_signed()?;
+        write_pem(cert_path.as_str(), cert.as_bytes(), 0o644)?;
+        write_pem(key_path.as_str(), key.as_bytes(), 0o600)?;
+    }
+
+    let cert = std::fs::read(cert_path.as_str()).chain_err(|| format!("unable to read certificate {}", cert_path))?;
+    let key = std::fs::read(key_path.as_str()).chain_err(|| format!("unable to read private key {}", key_path))?;
+    return Ok((cert, key));
+}
+
+pub fn generate_self_signed() -> Result<(String, String)> {
+    let mut names = vec!["localhost".to_string()];
+    if let Ok(hostname) = std::fs::read_to_string("/etc/hostname") {
+        let hostname = hostname.trim().to_string();
+        if hostname.len() > 0 {
+            names.push(format!("{}.local", hostname));
+            names.push(hostname);
+        }
+    }
+
+    let mut params = rcgen::CertificateParams::new(names);
+    params.distinguished_name = rcgen::DistinguishedName::new();
+    params.distinguished_name.push(rcgen::DnType::CommonName, "thalamus");
+    params.subject_alt_names.push(rcgen::SanType::IpAddress(std::net::IpAddr::V4(std::net::Ipv4Addr::LOCALHOST)));
+    for (_name, ip) in list_afinet_netifas()? {
+        let san = rcgen::SanType::IpAddress(ip);
+        if !params.subject_alt_names.contains(&san) {
+            params.subject_alt_names.push(san);
+        }
+    }
+
+    let cert = rcgen::Certificate::from_params(params)?;
+    return Ok((cert.serialize_pem()?, cert.serialize_private_key_pem()));
+}
+
+/// Splits a PEM bundle into its individual certificates
+pub fn pem_certificates(bundle: &str) -> Vec<String> {
+    let end = "-----END CERTIFICATE-----";
+    return bundle.split_inclusive(end)
+        .filter(|part| part.contains(end))
+        .map(|part| part.trim().to_string())
+        .collect();
+}
+
+fn write_pem(path: &str, data: &[u8], mode: u32) -> Result<()> {
+    if let Some(parent) = Path::new(path).parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let mut file = std::fs::OpenOptions::new().write(true).create(true).truncate(true).mode(mode).open(path)?;
+    file.write_all(data)?;
+    return Ok(());
+}
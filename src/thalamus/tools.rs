@@ -16,6 +16,7 @@ use error_chain::error_chain;
 use sha2::{Sha256, Digest};
 use std::{io, fs};
 use std::path::Path;
+use std::sync::atomic::{AtomicU64, Ordering};
 
 pub mod cidr;
 pub mod netscan;
@@ -25,6 +26,66 @@ error_chain! {
         Io(std::io::Error);
         // Hound(hound::Error);
     }
+
+    errors {
+        ProcessFailed(program: String, detail: String) {
+            description("external process failed")
+            display("{} failed: {}", program, detail)
+        }
+    }
+}
+
+// Numbers write_atomic's temp files, threads writing the same path each get their own
+static TMP_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+/// Writes to a temp file next to path, fsyncs it and renames it over path,
+/// so a crash leaves either the old or the new contents and never a truncated file
+pub fn write_atomic(path: &str, data: &[u8]) -> io::Result<()> {
+    let tmp_path = format!("{}.tmp.{}.{}", path, std::process::id(), TMP_COUNTER.fetch_add(1, Ordering::SeqCst));
+    let written = File::create(&tmp_path).and_then(|mut file| {
+        file.write_all(data)?;
+        file.sync_all()
+    }).and_then(|_| fs::rename(&tmp_path, path));
+
+    if let Err(e) = written {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(e);
+    }
+
+    // Persist the rename itself
+    if let Some(parent) = Path::new(path).parent() {
+        if let Ok(dir) = File::open(parent) {
+            let _ = dir.sync_all();
+        }
+    }
+    return Ok(());
+}
+
+/// Runs an inference binary to completion, failing if it can't be started or exits non-zero
+pub fn run_process(program: &str, command: &mut Command) -> Result<String>{
+    let child = match command.stdout(Stdio::piped()).stderr(Stdio::piped()).spawn() {
+        Ok(child) => child,
+        Err(e) => {
+            crate::thalamus::metrics::process_failed(program);
+            return Err(ErrorKind::ProcessFailed(program.to_string(), format!("unable to start: {}", e)).into());
+        },
+    };
+
+    // Registered so a shutdown can stop it if it is still running
+    let pid = child.id();
+    crate::thalamus::shutdown::register_child(pid, program);
+    let output = child.wait_with_output();
+    crate::thalamus::shutdown::unregister_child(pid);
+    let output = output?;
+
+    if !output.status.success() {
+        crate::thalamus::metrics::process_failed(program);
+        let stderr = String::from_utf8_lossy(&output.stderr).to_string();
+        let tail: String = stderr.trim().chars().rev().take(500).collect::<Vec<char>>().into_iter().rev().collect();
+        return Err(ErrorKind::ProcessFailed(program.to_string(), format!("{}: {}", output.status, tail)).into());
+    }
+
+    return Ok(String::from_utf8_lossy(&output.stdout).to_string());
 }
 
 
@@ -229,10 +290,21 @@ pub fn cp(source: &str, destination: &str) -> Result<String>{
     return Ok(String::from_utf8_lossy(&output.stdout).to_string());    
 }
 
+// "system" for the LaunchDaemon, the user's gui domain for a rootless LaunchAgent
+pub fn launchd_domain() -> String {
+    if !crate::thalamus::paths::get().rootless {
+        return "system".to_string();
+    }
+    let uid = Command::new("/usr/bin/id").arg("-u").output()
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_default();
+    return format!("gui/{}", uid);
+}
+
 pub fn launchd_bootstrap(destination: &str) -> Result<String>{
     let child = Command::new("/bin/launchctl")
     .arg("bootstrap")
-    .arg("system")
+    .arg(launchd_domain())
     .arg(destination)
     .stdout(Stdio::piped())
     .spawn()
@@ -249,7 +321,7 @@ pub fn launchd_bootstrap(destination: &str) -> Result<String>{
 pub fn launchd_bootout(destination: &str) -> Result<String>{
     let child = Command::new("/bin/launchctl")
     .arg("bootout")
-    .arg("system")
+    .arg(launchd_domain())
     .arg(destination)
     .stdout(Stdio::piped())
     .spawn()
@@ -296,8 +368,17 @@ pub fn launchd_kickstart(destination: &str) -> Result<String>{
     return Ok(String::from_utf8_lossy(&output.stdout).to_string());    
 }
 
+// systemctl, talking to the user's service manager for rootless installs
+fn systemctl() -> Command {
+    let mut command = Command::new("/bin/systemctl");
+    if crate::thalamus::paths::get().rootless {
+        command.arg("--user");
+    }
+    return command;
+}
+
 pub fn systemctl_reload() -> Result<String>{
-    let child = Command::new("/bin/systemctl")
+    let child = systemctl()
     .arg("daemon-reload")
     .stdout(Stdio::piped())
     .spawn()
@@ -312,7 +393,7 @@ pub fn systemctl_reload() -> Result<String>{
 }
 
 pub fn systemctl_start(service_name: &str) -> Result<String>{
-    let child = Command::new("/bin/systemctl")
+    let child = systemctl()
     .arg("start")
     .arg(service_name)
     .stdout(Stdio::piped())
@@ -328,7 +409,7 @@ pub fn systemctl_start(service_name: &str) -> Result<String>{
 }
 
 pub fn systemctl_stop(service_name: &str) -> Result<String>{
-    let child = Command::new("/bin/systemctl")
+    let child = systemctl()
     .arg("stop")
     .arg(service_name)
     .stdout(Stdio::piped())
@@ -344,7 +425,7 @@ pub fn systemctl_stop(service_name: &str) -> Result<String>{
 }
 
 pub fn systemctl_enable(service_name: &str) -> Result<String>{
-    let child = Command::new("/bin/systemctl")
+    let child = systemctl()
     .arg("enable")
     .arg(service_name)
     .stdout(Stdio::piped())
@@ -394,49 +475,23 @@ pub fn rmd(path: &str) -> Result<String>{
 
 
 pub fn whisper(model: &str, file_path: &str) -> Result<String>{
-    
-    
-    let child = Command::new("/opt/thalamus/bin/whisper")
+    return run_process("whisper", Command::new(&crate::thalamus::paths::bin("whisper"))
     .arg("-m")
-    .arg(format!("/opt/thalamus/models/ggml-{}.bin", model))
+    .arg(crate::thalamus::paths::model(&format!("ggml-{}.bin", model)))
     .arg("-f")
     .arg(format!("{}.16.wav", file_path))
-    .arg("-otxt")
-    .stdout(Stdio::piped())
-    .spawn()
-    .expect("failed to execute child");
-
-
-    let output = child
-    .wait_with_output()
-    .expect("failed to wait on child");
-
-    return Ok(String::from_utf8_lossy(&output.stdout).to_string());    
-    
+    .arg("-otxt"));
 }
 
 pub fn whisper_owts(model: &str, file_path: &str) -> Result<String>{
-    
-    
-    let child = Command::new("/opt/thalamus/bin/whisper")
+    return run_process("whisper", Command::new(&crate::thalamus::paths::bin("whisper"))
     .arg("-m")
-    .arg(format!("/opt/thalamus/models/ggml-{}.bin", model))
+    .arg(crate::thalamus::paths::model(&format!("ggml-{}.bin", model)))
     .arg("-f")
     .arg(format!("{}.16.wav", file_path))
     .arg("-fp")
-    .arg("/opt/thalamus/fonts/courier.ttf")
-    .arg("-owts")
-    .stdout(Stdio::piped())
-    .spawn()
-    .expect("failed to execute child");
-
-
-    let output = child
-    .wait_with_output()
-    .expect("failed to wait on child");
-
-    return Ok(String::from_utf8_lossy(&output.stdout).to_string());    
-    
+    .arg(&crate::thalamus::paths::root("fonts/courier.ttf"))
+    .arg("-owts"));
 }
             
 
@@ -446,6 +501,7 @@ pub fn mkdir(apath: &str) -> Result<String>{
     }
 
     let child = Command::new("/bin/mkdir")
+    .arg("-p")
     .arg(apath)
     .stdout(Stdio::piped())
     .spawn()
@@ -508,19 +564,56 @@ pub fn sh(script: &str) -> Result<String>{
 }
 
 pub fn srgan(input: &str, output: &str) -> Result<String>{
-    let child = Command::new("/opt/thalamus/bin/srgan")
+    return run_process("srgan", Command::new(&crate::thalamus::paths::bin("srgan"))
     .arg(input)
-    .arg(output)
-    .stdout(Stdio::piped())
-    .spawn()
-    .expect("failed to execute child");
+    .arg(output));
+}
 
+/// A downloaded file with the size and sha256 it should have once installed
+#[derive(Debug, Clone)]
+pub struct Artifact {
+    /// Relative to the models directory
+    pub path: &'static str,
+    pub url: &'static str,
+    pub sha256: Option<&'static str>,
+    pub size: Option<i64>,
+}
 
-    let output = child
-    .wait_with_output()
-    .expect("failed to wait on child");
+impl Artifact {
+    pub fn local_path(&self) -> String {
+        return crate::thalamus::paths::model(self.path);
+    }
 
-    return Ok(String::from_utf8_lossy(&output.stdout).to_string()); 
+    /// Bytes the download will take, asking the server when the size isn't pinned
+    pub fn expected_size(&self) -> u64 {
+        if let Some(size) = self.size {
+            return size as u64;
+        }
+        match remote_size(self.url) {
+            Some(size) => return size,
+            None => {
+                log::warn!("Unable to get the size of {}, it is not checked against the storage budget", self.url);
+                return 0;
+            }
+        }
+    }
+}
+
+/// Content-Length of a url, following redirects. Blocking, so never on a tokio runtime thread
+pub fn remote_size(url: &str) -> Option<u64> {
+    let client = reqwest::blocking::Client::builder().timeout(std::time::Duration::from_secs(30)).build().ok()?;
+    let response = client.head(url).send().ok()?.error_for_status().ok()?;
+    return response.content_length().filter(|size| *size > 0);
+}
+
+pub fn safe_download_artifact(artifact: &Artifact) -> (){
+    // Only new downloads count against the storage budget
+    if !Path::new(&artifact.local_path()).exists() {
+        if let Err(e) = crate::thalamus::storage::check_download(&artifact.local_path(), artifact.expected_size()) {
+            return log::error!("Skipping download: {}", e);
+        }
+    }
+    safe_download(artifact.local_path().as_str(), artifact.url, artifact.sha256, artifact.size);
 }
 
 pub fn safe_download(file_path: &str, online_path: &str, hash: Option<&str>, expected_file_size: Option<i64>) -> (){
@@ -580,7 +673,7 @@ pub fn safe_download(file_path: &str, online_path: &str, hash: Option<&str>, exp
 }
 
 pub fn wget(file_path: &str, url: &str) -> Result<bool>{
-    let child = Command::new("/opt/thalamus/bin/wget")
+    let child = Command::new(&crate::thalamus::paths::bin("wget"))
     .arg("-O")
     .arg(file_path)
     .arg(url)
@@ -597,7 +690,7 @@ pub fn wget(file_path: &str, url: &str) -> Result<bool>{
 }
 
 pub fn download(file_path: &str, url: &str) -> Result<bool>{
-    let child = Command::new("/opt/thalamus/bin/wget")
+    let child = Command::new(&crate::thalamus::paths::bin("wget"))
     .arg("-O")
     .arg(file_path)
     .arg(url)
@@ -614,7 +707,7 @@ pub fn download(file_path: &str, url: &str) -> Result<bool>{
 }
 
 pub fn wav_to_16000(input: String) -> Result<String>{
-    let child = Command::new("/opt/thalamus/bin/ffmpeg")
+    return run_process("ffmpeg", Command::new(&crate::thalamus::paths::bin("ffmpeg"))
     .arg("-y")
     .arg("-i")
     .arg(format!("{}", input))
@@ -624,17 +717,7 @@ pub fn wav_to_16000(input: String) -> Result<String>{
     .arg("1")
     .arg("-c:a")
     .arg("pcm_s16le")
-    .arg(format!("{}.16.wav", input))
-    .stdout(Stdio::piped())
-    .spawn()
-    .expect("failed to execute child");
-
-
-    let output = child
-    .wait_with_output()
-    .expect("failed to wait on child");
-
-    return Ok(String::from_utf8_lossy(&output.stdout).to_string());
+    .arg(format!("{}.16.wav", input)));
 }
 
 
@@ -651,22 +734,32 @@ pub fn touch(path: String) -> Result<()>{
 
 
 pub fn llama(model: &str, prompt: &str) -> Result<String>{
-    let child = Command::new("/opt/thalamus/bin/llama")
+    return run_process("llama", Command::new(&crate::thalamus::paths::bin("llama"))
     .arg("-m")
-    .arg(format!("/opt/thalamus/models/llama/{}/ggml-model-q4_0.gguf", model))
+    .arg(crate::thalamus::paths::model(&format!("llama/{}/ggml-model-q4_0.gguf", model)))
+    .arg("-p")
+    .arg(format!("\"{}\"", prompt)));
+}
+
+/// Starts llama with stdout piped so tokens can be read as they are generated
+pub fn llama_spawn(model: &str, prompt: &str) -> Result<std::process::Child>{
+    match Command::new(&crate::thalamus::paths::bin("llama"))
+    .arg("-m")
+    .arg(crate::thalamus::paths::model(&format!("llama/{}/ggml-model-q4_0.gguf", model)))
     .arg("-p")
     .arg(format!("\"{}\"", prompt))
     .stdout(Stdio::piped())
-    .spawn()
-    .expect("failed to execute child");
-
-
-    let output = child
-    .wait_with_output()
-    .expect("failed to wait on child");
-
-    return Ok(String::from_utf8_lossy(&output.stdout).to_string());    
-    
+    .stderr(Stdio::null())
+    .spawn() {
+        Ok(child) => {
+            crate::thalamus::shutdown::register_child(child.id(), "llama");
+            return Ok(child);
+        },
+        Err(e) => {
+            crate::thalamus::metrics::process_failed("llama");
+            return Err(ErrorKind::ProcessFailed("llama".to_string(), format!("unable to start: {}", e)).into());
+        },
+    }
 }
 
 // subshell
@@ -819,4 +912,36 @@ pub fn find_mimetype(filename: &String) -> String{
             None => mime::TEXT_PLAIN.to_string(),
         };
     res
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_concurrent_write_atomic() {
+        let dir = std::env::temp_dir().join(format!("thalamus-write-atomic-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("clients.json").to_str().unwrap().to_string();
+
+        let writers: Vec<_> = (0..8).map(|i| {
+            let path = path.clone();
+            std::thread::spawn(move || {
+                let data = format!("{}", i).repeat(100_000);
+                for _ in 0..10 {
+                    write_atomic(&path, data.as_bytes()).unwrap();
+                }
+            })
+        }).collect();
+        for writer in writers {
+            writer.join().unwrap();
+        }
+
+        // Always one writer's whole contents, and no temp files left behind
+        let data = fs::read_to_string(&path).unwrap();
+        assert_eq!(data.len(), 100_000);
+        assert!(data.chars().all(|c| c == data.chars().next().unwrap()));
+        assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}